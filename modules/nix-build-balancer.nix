@@ -6,19 +6,28 @@ let
 
   isController = cfg.role == "controller" || cfg.role == "both";
   isAgent = cfg.role == "agent" || cfg.role == "both";
+  # Hosts other than the controller's run nbb-hook against it over TCP.
+  isRemoteHook = !isController && cfg.remoteController != null;
 
   formatTarget = name: t:
     let
       base = "${name}=${t.tcpAddr}|${toString t.capacity}|${t.storeUri}|${t.builderLine}";
       flags =
         lib.optionalString t.isLocal "|is_local"
-        + lib.optionalString (t.speedMultiplier != 1.0) "|speed=${toString t.speedMultiplier}";
+        + lib.optionalString (t.speedMultiplier != 1.0) "|speed=${toString t.speedMultiplier}"
+        + lib.optionalString (t.cores != null) "|cores=${toString t.cores}"
+        + lib.optionalString (t.mac != null) "|mac=${t.mac}"
+        + lib.optionalString (t.wolAddress != null) "|wol=${t.wolAddress}";
     in base + flags;
 
   targetArgs =
     lib.concatLists
       (lib.mapAttrsToList (name: t: [ "--target" (formatTarget name t) ]) cfg.targets);
 
+  pnameCoresArgs =
+    lib.concatLists
+      (lib.mapAttrsToList (pname: cores: [ "--pname-cores" "${pname}=${toString cores}" ]) cfg.pnameCores);
+
   controllerArgs = [
     "--system" cfg.system
     "--data-dir" "/var/lib/nbb"
@@ -26,11 +35,52 @@ let
     "--hook-socket" "/run/nbb/decide.sock"
     "--poll-interval-ms" (toString cfg.pollIntervalMs)
     "--min-remote-mem-available-kb" (toString cfg.minRemoteMemAvailableKb)
+    "--min-store-free-bytes" (toString cfg.minStoreFreeBytes)
+    "--min-store-free-inodes" (toString cfg.minStoreFreeInodes)
     "--unknown-p95-ms" (toString cfg.unknownP95Ms)
+    "--cheap-threshold-ms" (toString cfg.cheapThresholdMs)
+    "--high-priority-reserve" (toString cfg.highPriorityReserve)
     "--max-samples-per-pname" (toString cfg.maxSamplesPerPname)
     "--ewma-alpha" (toString cfg.ewmaAlpha)
     "--ewma-z" (toString cfg.ewmaZ)
-  ] ++ targetArgs;
+    "--decision-log-retention-hours" (toString cfg.decisionLogRetentionHours)
+    "--checkpoint-interval-secs" (toString cfg.checkpointIntervalSecs)
+    "--vacuum-interval-hours" (toString cfg.vacuumIntervalHours)
+    "--max-observations" (toString cfg.maxObservations)
+    "--unhealthy-cooldown-secs" (toString cfg.unhealthyCooldownSecs)
+    "--failure-tails-per-pname" (toString cfg.failureTailsPerPname)
+    "--failure-window-secs" (toString cfg.failureWindowSecs)
+    "--quarantine-min-failures" (toString cfg.quarantineMinFailures)
+    "--quarantine-failure-rate" (toString cfg.quarantineFailureRate)
+    "--quarantine-secs" (toString cfg.quarantineSecs)
+    "--substitute-probe-ms" (toString cfg.substituteProbeMs)
+    "--wake-queue-ms" (toString cfg.wakeQueueMs)
+    "--wake-timeout-secs" (toString cfg.wakeTimeoutSecs)
+    "--idle-suspend-secs" (toString cfg.idleSuspendSecs)
+    "--link-probe-interval-secs" (toString cfg.linkProbeIntervalSecs)
+    "--link-probe-bytes" (toString cfg.linkProbeBytes)
+  ] ++ targetArgs ++ pnameCoresArgs
+    ++ lib.optionals (cfg.seedHistory != null) [ "--seed-history" (toString cfg.seedHistory) ]
+    ++ lib.optionals (cfg.hookListen != null) [
+      "--hook-listen" cfg.hookListen
+      "--hook-key-file" cfg.hookKeyFile
+    ];
+
+  # Nix passes the verbosity positionally; forward it.
+  localHook = pkgs.writeShellScript "nbb-hook" ''
+    exec ${package}/bin/nbb-hook \
+      --decision-timeout-ms ${toString cfg.decisionTimeoutMs} \
+      "$@"
+  '';
+
+  remoteHook = pkgs.writeShellScript "nbb-remote-hook" ''
+    exec ${package}/bin/nbb-hook \
+      --decision-timeout-ms ${toString cfg.decisionTimeoutMs} \
+      --controller-addr ${lib.escapeShellArg cfg.remoteController} \
+      --hook-key-file ${lib.escapeShellArg cfg.hookKeyFile} \
+      --hostname ${lib.escapeShellArg config.networking.hostName} \
+      "$@"
+  '';
 
   agentArgs = [
     "--bind" cfg.agentListen
@@ -38,14 +88,17 @@ let
     "--hostname" config.networking.hostName
     "--system" cfg.system
     "--capacity" (toString cfg.agentCapacity)
-  ];
+    "--spool-max-entries" (toString cfg.spoolMaxEntries)
+    "--spool-max-age-hours" (toString cfg.spoolMaxAgeHours)
+  ] ++ lib.optionals (cfg.suspendCommand != null) [ "--suspend-command" cfg.suspendCommand ];
 
   # Nix pre-build-hook invokes the binary directly. nbb-event is intentionally
   # tiny and fail-closed (any error → exit 0), so no shell wrapping is required.
   preBuildHook = pkgs.writeShellScript "nbb-pre-build-hook" ''
     drv_path="''${1:-}"
     if [ -n "$drv_path" ]; then
-      ${package}/bin/nbb-event --kind start --drv-path "$drv_path" >/dev/null 2>&1 || true
+      ${package}/bin/nbb-event --kind start --drv-path "$drv_path" \
+        --max-spool-entries ${toString cfg.spoolMaxEntries} >/dev/null 2>&1 || true
     fi
     exit 0
   '';
@@ -56,7 +109,8 @@ let
         --kind finish \
         --drv-path "$DRV_PATH" \
         --status success \
-        --out-paths "''${OUT_PATHS:-}" >/dev/null 2>&1 || true
+        --out-paths "''${OUT_PATHS:-}" \
+        --max-spool-entries ${toString cfg.spoolMaxEntries} >/dev/null 2>&1 || true
     fi
     exit 0
   '';
@@ -89,6 +143,22 @@ in
       description = "Local build capacity reported by the agent in AGENT_HELLO.";
     };
 
+    spoolMaxEntries = lib.mkOption {
+      type = lib.types.ints.positive;
+      default = 10000;
+      description = ''
+        Build events kept in /var/lib/nbb/spool while they cannot be
+        delivered. Beyond this the oldest are dropped and nbb-event stops
+        writing new ones.
+      '';
+    };
+
+    spoolMaxAgeHours = lib.mkOption {
+      type = lib.types.ints.positive;
+      default = 168;
+      description = "Undelivered build events older than this are dropped.";
+    };
+
     targets = lib.mkOption {
       type = lib.types.attrsOf (lib.types.submodule {
         options = {
@@ -123,6 +193,31 @@ in
             default = 1.0;
             description = "Per-target speed multiplier; 1.0 today.";
           };
+          cores = lib.mkOption {
+            type = lib.types.nullOr lib.types.ints.positive;
+            default = null;
+            description = ''
+              CPU cores on the target. When set, admitted builds also queue
+              as core-time against it, so several wide builds don't land on
+              the same host.
+            '';
+          };
+          mac = lib.mkOption {
+            type = lib.types.nullOr lib.types.str;
+            default = null;
+            example = "52:54:00:12:34:56";
+            description = ''
+              MAC address of a target that suspends when idle. When set, the
+              controller wakes it with a magic packet once the live targets
+              are backed up.
+            '';
+          };
+          wolAddress = lib.mkOption {
+            type = lib.types.nullOr lib.types.str;
+            default = null;
+            example = "10.171.0.255:9";
+            description = "Broadcast address for the magic packet; 255.255.255.255:9 when unset.";
+          };
         };
       });
       default = { };
@@ -140,6 +235,58 @@ in
       default = 1000000;
     };
 
+    minStoreFreeBytes = lib.mkOption {
+      type = lib.types.ints.unsigned;
+      default = 10737418240;
+      description = ''
+        Store space a target must keep free beyond the candidate's
+        expected output size, learned per pname from recent builds.
+      '';
+    };
+
+    minStoreFreeInodes = lib.mkOption {
+      type = lib.types.ints.unsigned;
+      default = 100000;
+      description = ''
+        Free inodes a target's store filesystem must have. Ignored on
+        filesystems without an inode limit.
+      '';
+    };
+
+    wakeQueueMs = lib.mkOption {
+      type = lib.types.ints.unsigned;
+      default = 300000;
+      description = ''
+        Wake a sleeping target (one with a mac) when a build would queue
+        longer than this on every live target. 0 disables waking.
+      '';
+    };
+
+    wakeTimeoutSecs = lib.mkOption {
+      type = lib.types.ints.positive;
+      default = 180;
+      description = "How long a woken target has to answer before it may be woken again.";
+    };
+
+    idleSuspendSecs = lib.mkOption {
+      type = lib.types.ints.unsigned;
+      default = 1800;
+      description = ''
+        Quiet period after which the controller tells a wakeable target's
+        agent it may suspend. 0 disables the hint.
+      '';
+    };
+
+    suspendCommand = lib.mkOption {
+      type = lib.types.nullOr lib.types.str;
+      default = null;
+      example = "systemctl suspend";
+      description = ''
+        Command the agent runs when the controller hints that this host is
+        idle and no build is in progress. Unset ignores the hints.
+      '';
+    };
+
     unknownP95Ms = lib.mkOption {
       type = lib.types.ints.positive;
       default = 60000;
@@ -149,6 +296,216 @@ in
       '';
     };
 
+    decisionLogRetentionHours = lib.mkOption {
+      type = lib.types.ints.positive;
+      default = 168;
+      description = ''
+        How long every scheduling decision (per-target queue, package and
+        completion estimates, exclusions, winner) is kept in the
+        controller database. Query with `nbb-controller decisions`.
+      '';
+    };
+
+    checkpointIntervalSecs = lib.mkOption {
+      type = lib.types.ints.unsigned;
+      default = 3600;
+      description = ''
+        How often the controller checkpoints the database's write-ahead
+        log and applies `maxObservations`.
+      '';
+    };
+
+    vacuumIntervalHours = lib.mkOption {
+      type = lib.types.ints.unsigned;
+      default = 0;
+      description = ''
+        How often the controller vacuums its database. 0 disables. Decisions
+        wait while it runs, which takes seconds on a large database, so
+        hooks may time out and build locally.
+      '';
+    };
+
+    maxObservations = lib.mkOption {
+      type = lib.types.ints.unsigned;
+      default = 200000;
+      description = ''
+        Build observations kept across all pnames, on top of
+        `maxSamplesPerPname`; the oldest are pruned first. 0 disables.
+      '';
+    };
+
+    seedHistory = lib.mkOption {
+      type = lib.types.nullOr lib.types.path;
+      default = null;
+      example = "/var/lib/nbb/history.csv";
+      description = ''
+        Output of `nbb-controller export-history` from another controller,
+        imported when this controller has no build history yet.
+      '';
+    };
+
+    hookListen = lib.mkOption {
+      type = lib.types.nullOr lib.types.str;
+      default = null;
+      example = "0.0.0.0:8766";
+      description = ''
+        TCP address where the controller also accepts nbb-hook from other
+        hosts, so their builds are balanced too. Requires hookKeyFile.
+      '';
+    };
+
+    hookKeyFile = lib.mkOption {
+      type = lib.types.nullOr lib.types.str;
+      default = null;
+      example = "/run/secrets/nbb-hook-key";
+      description = ''
+        Shared key remote hooks authenticate to the controller with. The
+        same file is needed on the controller (with hookListen) and on
+        hosts using remoteController. A string, so the key stays out of
+        the Nix store.
+      '';
+    };
+
+    remoteController = lib.mkOption {
+      type = lib.types.nullOr lib.types.str;
+      default = null;
+      example = "saya:8766";
+      description = ''
+        The controller's hookListen address. With scheduler.enable on a
+        host that is not the controller, its nbb-hook asks that controller,
+        authenticating as networking.hostName, which must be this host's
+        name in the controller's targets. Requires hookKeyFile.
+      '';
+    };
+
+    unhealthyCooldownSecs = lib.mkOption {
+      type = lib.types.ints.unsigned;
+      default = 120;
+      description = ''
+        After a delegated build fails for infrastructure reasons (ssh
+        dropped, remote store unreachable), the hook retries it on another
+        target and the controller skips the failed one for this long.
+      '';
+    };
+
+    failureTailsPerPname = lib.mkOption {
+      type = lib.types.ints.unsigned;
+      default = 5;
+      description = ''
+        How many failed delegated builds per pname keep the end of their
+        remote build log in the controller database. Read them with
+        `nbb-controller failures`.
+      '';
+    };
+
+    pnameCores = lib.mkOption {
+      type = lib.types.attrsOf (lib.types.either lib.types.ints.positive lib.types.float);
+      default = { };
+      example = { firefox-unwrapped = 16; };
+      description = ''
+        Cores a pname keeps busy while it builds, overriding what the
+        controller learns from the agents' CPU accounting.
+      '';
+    };
+
+    substituteProbeMs = lib.mkOption {
+      type = lib.types.ints.unsigned;
+      default = 0;
+      example = 200;
+      description = ''
+        How long (ms) the controller waits for agents to say whether they
+        already hold a derivation's outputs. A target that does gets the
+        build, so Nix just copies the outputs back instead of building
+        them. 0 disables the probe.
+      '';
+    };
+
+    linkProbeIntervalSecs = lib.mkOption {
+      type = lib.types.ints.unsigned;
+      default = 600;
+      description = ''
+        How often the controller measures round-trip time and throughput to
+        each agent. Routing charges slow links for copying outputs back;
+        `nbb-controller links` shows the history. 0 disables probing.
+      '';
+    };
+
+    linkProbeBytes = lib.mkOption {
+      type = lib.types.ints.between 0 1047552;
+      default = 1000000;
+      description = "Payload sent to each agent to measure throughput.";
+    };
+
+    decisionTimeoutMs = lib.mkOption {
+      type = lib.types.ints.positive;
+      default = 5000;
+      description = ''
+        How long (ms) nbb-hook waits for the controller's decision on a
+        candidate, connecting included, before declining it so Nix builds
+        locally. Must exceed substituteProbeMs (asserted on this host;
+        remote hooks should also exceed the controller's);
+        `nbb-controller latency` shows how long decisions take.
+      '';
+    };
+
+    failureWindowSecs = lib.mkOption {
+      type = lib.types.ints.positive;
+      default = 1800;
+      description = ''
+        How far back (seconds) a target's delegated build outcomes count
+        towards quarantining it.
+      '';
+    };
+
+    quarantineMinFailures = lib.mkOption {
+      type = lib.types.ints.unsigned;
+      default = 3;
+      description = ''
+        Failed or declined builds within the failure window before a
+        target can be quarantined. 0 disables quarantine.
+      '';
+    };
+
+    quarantineFailureRate = lib.mkOption {
+      type = lib.types.float;
+      default = 0.5;
+      description = ''
+        Fraction of a target's builds in the failure window that must have
+        failed (0.0–1.0) before it is quarantined.
+      '';
+    };
+
+    quarantineSecs = lib.mkOption {
+      type = lib.types.ints.positive;
+      default = 600;
+      description = ''
+        How long a quarantined target receives no delegated builds.
+      '';
+    };
+
+    cheapThresholdMs = lib.mkOption {
+      type = lib.types.ints.unsigned;
+      default = 5000;
+      description = ''
+        Derivations whose duration estimate is below this (ms) are built
+        locally without consulting any target. Derivations marked
+        `preferLocalBuild` or `allowSubstitutes = false` always are.
+        0 disables the estimate check.
+      '';
+    };
+
+    highPriorityReserve = lib.mkOption {
+      type = lib.types.float;
+      default = 0.25;
+      description = ''
+        Share of each target's capacity, rounded down to whole slots, kept
+        for high-priority builds (`nbb-controller priority high`, or
+        `NBB_PRIORITY=high` in the hook's environment). Normal builds are
+        not delegated into it; low-priority builds wait for an unreserved
+        slot or build locally. Must be in [0, 1).
+      '';
+    };
+
     maxSamplesPerPname = lib.mkOption {
       type = lib.types.ints.positive;
       default = 200;
@@ -191,16 +548,27 @@ in
     openFirewall = lib.mkOption {
       type = lib.types.bool;
       default = false;
-      description = "Open the agent's TCP port in the firewall.";
+      description = "Open the agent's (and the controller's hookListen) TCP port in the firewall.";
     };
   };
 
   config = lib.mkIf cfg.enable {
+    assertions = [
+      {
+        assertion = (cfg.hookListen != null || cfg.remoteController != null) -> cfg.hookKeyFile != null;
+        message = "me.nixBuildBalancer: hookListen and remoteController need hookKeyFile.";
+      }
+      {
+        assertion = cfg.substituteProbeMs < cfg.decisionTimeoutMs;
+        message = "me.nixBuildBalancer: substituteProbeMs must be less than decisionTimeoutMs.";
+      }
+    ];
+
     systemd.tmpfiles.rules = [
       "d /var/lib/nbb 0755 root root -"
       "d /var/lib/nbb/spool 0755 root root -"
       "d /run/nbb 0755 root root -"
-    ] ++ lib.optionals isController [
+    ] ++ lib.optionals (isController || isRemoteHook) [
       "d /run/nbb/inflight 0755 root root -"
     ];
 
@@ -244,12 +612,18 @@ in
         post-build-hook = postBuildHook;
       })
       // (lib.optionalAttrs (cfg.scheduler.enable && isController) {
-        build-hook = "${package}/bin/nbb-hook";
+        build-hook = "${localHook}";
+      })
+      // (lib.optionalAttrs (cfg.scheduler.enable && isRemoteHook) {
+        build-hook = "${remoteHook}";
       });
 
     networking.firewall.allowedTCPPorts =
       lib.optionals (isAgent && cfg.openFirewall) [
         (lib.toInt (lib.last (lib.splitString ":" cfg.agentListen)))
+      ]
+      ++ lib.optionals (isController && cfg.openFirewall && cfg.hookListen != null) [
+        (lib.toInt (lib.last (lib.splitString ":" cfg.hookListen)))
       ];
   };
 }
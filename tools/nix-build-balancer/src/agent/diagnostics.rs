@@ -0,0 +1,284 @@
+//! `nbb-agent --once`: what the agent would report, read straight off the
+//! host without a controller attached.
+//!
+//! Pending starts come from the spool. A running agent moves starts from
+//! the spool into memory as it scans, so on a host whose agent is up this
+//! lists only starts it has not reached yet; with the agent down it is the
+//! full set of builds that began and have not finished.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+
+use crate::agent::{apply_event, ApplyOutcome, PendingStart};
+use crate::protocol::frame::bincode_config;
+use crate::protocol::ops::SpoolEvent;
+use crate::spool::{self, FULL_MARKER};
+use crate::telemetry::{self, SlotState, Telemetry};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostics {
+    pub telemetry: Result<Telemetry, String>,
+    pub slots: Vec<SlotState>,
+    pub spool_backlog: usize,
+    pub spool_full: bool,
+    /// Finishes waiting in the spool for a controller.
+    pub undelivered_finishes: usize,
+    /// Spool entries that do not decode.
+    pub corrupt_entries: usize,
+    /// `(drv_path, start)`, oldest first.
+    pub pending_starts: Vec<(String, PendingStart)>,
+}
+
+pub fn collect(spool_dir: &Path, slot_dir: &Path, store_dir: &Path) -> io::Result<Diagnostics> {
+    let entries = spool::list_events(spool_dir)?;
+    let mut pending = HashMap::new();
+    let mut undelivered_finishes = 0;
+    let mut corrupt_entries = 0;
+    for path in &entries {
+        let Ok(bytes) = std::fs::read(path) else {
+            continue;
+        };
+        match bincode::decode_from_slice::<SpoolEvent, _>(&bytes, bincode_config()) {
+            Ok((event, _)) => {
+                if let ApplyOutcome::ForwardFinish(_) = apply_event(&mut pending, event) {
+                    undelivered_finishes += 1;
+                }
+            }
+            Err(_) => corrupt_entries += 1,
+        }
+    }
+    let mut pending_starts: Vec<(String, PendingStart)> = pending.into_iter().collect();
+    pending_starts.sort_by(|a, b| (a.1.ts_ms, &a.0).cmp(&(b.1.ts_ms, &b.0)));
+
+    Ok(Diagnostics {
+        telemetry: telemetry::sample(store_dir).map_err(|err| err.to_string()),
+        slots: telemetry::slot_states(slot_dir),
+        spool_backlog: entries.len(),
+        spool_full: spool_dir.join(FULL_MARKER).exists(),
+        undelivered_finishes,
+        corrupt_entries,
+        pending_starts,
+    })
+}
+
+impl Diagnostics {
+    /// `key=value` lines, one slot or pending start per line.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        match &self.telemetry {
+            Ok(t) => {
+                let _ = writeln!(out, "mem_available_kb={}", t.mem_available_kb);
+                match t.psi_memory_some_avg10 {
+                    Some(v) => {
+                        let _ = writeln!(out, "psi_memory_some_avg10={v}");
+                    }
+                    None => out.push_str("psi_memory_some_avg10=none\n"),
+                }
+                let _ = writeln!(out, "nix_slots_active={}", t.nix_slots_active);
+                match t.store_free {
+                    Some(free) => {
+                        let _ = writeln!(out, "store_free_bytes={}", free.bytes);
+                        match free.inodes {
+                            Some(inodes) => {
+                                let _ = writeln!(out, "store_free_inodes={inodes}");
+                            }
+                            None => out.push_str("store_free_inodes=none\n"),
+                        }
+                    }
+                    None => out.push_str("store_free_bytes=none\nstore_free_inodes=none\n"),
+                }
+                let _ = writeln!(out, "sampled_at_ms={}", t.sampled_at_ms);
+            }
+            Err(err) => {
+                let _ = writeln!(out, "telemetry_error={err}");
+            }
+        }
+        for slot in &self.slots {
+            let state = if slot.locked { "locked" } else { "stale" };
+            let _ = writeln!(out, "slot={} {state}", slot.name);
+        }
+        let _ = writeln!(out, "spool_backlog={}", self.spool_backlog);
+        let _ = writeln!(out, "spool_full={}", self.spool_full);
+        let _ = writeln!(out, "undelivered_finishes={}", self.undelivered_finishes);
+        let _ = writeln!(out, "corrupt_entries={}", self.corrupt_entries);
+        for (drv_path, start) in &self.pending_starts {
+            let _ = writeln!(
+                out,
+                "pending_start={drv_path} pname={} ts_ms={}",
+                start.pname, start.ts_ms
+            );
+        }
+        out
+    }
+
+    /// One JSON object, for scripts.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{");
+        match &self.telemetry {
+            Ok(t) => {
+                let psi = t
+                    .psi_memory_some_avg10
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| "null".to_string());
+                let free_bytes = t
+                    .store_free
+                    .map(|free| free.bytes.to_string())
+                    .unwrap_or_else(|| "null".to_string());
+                let free_inodes = t
+                    .store_free
+                    .and_then(|free| free.inodes)
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| "null".to_string());
+                let _ = write!(
+                    out,
+                    "\"telemetry\":{{\"mem_available_kb\":{},\"psi_memory_some_avg10\":{psi},\
+                     \"nix_slots_active\":{},\"store_free_bytes\":{free_bytes},\
+                     \"store_free_inodes\":{free_inodes},\"sampled_at_ms\":{}}},",
+                    t.mem_available_kb, t.nix_slots_active, t.sampled_at_ms
+                );
+            }
+            Err(err) => {
+                let _ = write!(
+                    out,
+                    "\"telemetry\":null,\"telemetry_error\":{},",
+                    json_str(err)
+                );
+            }
+        }
+        let slots: Vec<String> = self
+            .slots
+            .iter()
+            .map(|s| format!("{{\"name\":{},\"locked\":{}}}", json_str(&s.name), s.locked))
+            .collect();
+        let pending: Vec<String> = self
+            .pending_starts
+            .iter()
+            .map(|(drv_path, start)| {
+                format!(
+                    "{{\"drv_path\":{},\"pname\":{},\"ts_ms\":{}}}",
+                    json_str(drv_path),
+                    json_str(&start.pname),
+                    start.ts_ms
+                )
+            })
+            .collect();
+        let _ = write!(
+            out,
+            "\"slots\":[{}],\"spool_backlog\":{},\"spool_full\":{},\
+             \"undelivered_finishes\":{},\"corrupt_entries\":{},\"pending_starts\":[{}]}}",
+            slots.join(","),
+            self.spool_backlog,
+            self.spool_full,
+            self.undelivered_finishes,
+            self.corrupt_entries,
+            pending.join(",")
+        );
+        out
+    }
+}
+
+fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if u32::from(c) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", u32::from(c));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ops::BuildStatus;
+    use crate::util::now_ms;
+
+    fn start(drv: &str, ts_ms: u64) -> SpoolEvent {
+        SpoolEvent::Start {
+            drv_path: drv.to_string(),
+            pname: "foo".to_string(),
+            host: "tsugumi".to_string(),
+            ts_ms,
+        }
+    }
+
+    #[test]
+    fn spool_replay_finds_unfinished_starts() {
+        let dir = std::env::temp_dir().join(format!(
+            "nbb-diagnostics-{}-{}",
+            std::process::id(),
+            now_ms()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let entry = |n: u128| dir.join(format!("{}.evt", ulid::Ulid::from_parts(1_000, n)));
+        spool::rewrite_event(&entry(1), &start("/nix/store/aaa-foo.drv", 100)).unwrap();
+        spool::rewrite_event(&entry(2), &start("/nix/store/bbb-foo.drv", 200)).unwrap();
+        spool::rewrite_event(
+            &entry(3),
+            &SpoolEvent::Finish {
+                drv_path: "/nix/store/aaa-foo.drv".to_string(),
+                pname: "foo".to_string(),
+                host: "tsugumi".to_string(),
+                ts_ms: 300,
+                status: BuildStatus::Success,
+                out_paths: vec![],
+            },
+        )
+        .unwrap();
+        std::fs::write(dir.join("zzz.evt"), b"\xff garbage").unwrap();
+
+        let diag = collect(&dir, &dir.join("no-slots"), &dir).unwrap();
+        assert_eq!(diag.spool_backlog, 4);
+        assert_eq!(diag.undelivered_finishes, 1);
+        assert_eq!(diag.corrupt_entries, 1);
+        assert_eq!(
+            diag.pending_starts,
+            vec![(
+                "/nix/store/bbb-foo.drv".to_string(),
+                PendingStart {
+                    pname: "foo".to_string(),
+                    ts_ms: 200,
+                    cpu_ms: None,
+                    contention: Default::default(),
+                }
+            )]
+        );
+        assert!(diag
+            .to_text()
+            .contains("pending_start=/nix/store/bbb-foo.drv pname=foo ts_ms=200\n"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn json_output_escapes_and_nulls() {
+        let diag = Diagnostics {
+            telemetry: Err("no \"meminfo\"".to_string()),
+            slots: vec![SlotState {
+                name: "ssh-ng:__svein@tsugumi.local-0".to_string(),
+                locked: true,
+            }],
+            spool_backlog: 0,
+            spool_full: false,
+            undelivered_finishes: 0,
+            corrupt_entries: 0,
+            pending_starts: vec![],
+        };
+        assert_eq!(
+            diag.to_json(),
+            "{\"telemetry\":null,\"telemetry_error\":\"no \\\"meminfo\\\"\",\
+             \"slots\":[{\"name\":\"ssh-ng:__svein@tsugumi.local-0\",\"locked\":true}],\
+             \"spool_backlog\":0,\"spool_full\":false,\"undelivered_finishes\":0,\
+             \"corrupt_entries\":0,\"pending_starts\":[]}"
+        );
+    }
+}
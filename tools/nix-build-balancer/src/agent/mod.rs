@@ -6,7 +6,25 @@
 //! - Watch `/var/lib/nbb/spool/*.evt` for events written by `nbb-event`.
 //! - Match `Start` events to `Finish` events in memory; on a matched
 //!   finish, forward an [`EventBuildFinish`] frame to the controller.
-//! - Respond to `PING` with `PONG` and `TELEMETRY_GET` with `TELEMETRY`.
+//! - While builds are in progress, sample on every spool scan how many
+//!   there are and the host's CPU pressure, so each finish says how
+//!   contended its duration was ([`Contention`]).
+//! - Measure the outputs of each successful build in the local store, so
+//!   the controller learns how much store space a pname needs.
+//! - Respond to `PING` with `PONG`, `TELEMETRY_GET` with `TELEMETRY`,
+//!   `PATHS_QUERY` with `PATHS_PRESENT` and `LINK_PROBE` with
+//!   `LINK_PROBE_REPLY` ([`crate::link`]).
+//! - On `SUSPEND_HINT`, run the configured suspend command if no build is
+//!   in progress ([`crate::wake`]).
+//! - On `BUILD_ADMITTED`, remember the build's correlation id until its
+//!   finish, and log what happens to the build under it
+//!   ([`crate::logging`]). The id goes out in the finish's
+//!   [`EventBuildFinish::correlation`].
+//! - On `BUILD_CANCELLED`, forget the build's start (no finish will come:
+//!   Nix runs the post-build hook only for successes) and report with
+//!   `BUILD_GONE` whether its builder processes have exited.
+//! - On `GOODBYE`, stop pushing finishes, answer `GOODBYE` and close; the
+//!   controller is shutting down and reads no further than that answer.
 //!
 //! Spec invariants honored here:
 //!
@@ -19,22 +37,39 @@
 //! - On agent restart, the in-memory start map is empty. Finishes that
 //!   arrive without a matching start are forwarded with `duration_ms =
 //!   None`.
+//! - A finish that cannot be delivered is rewritten in place as
+//!   [`SpoolEvent::Matched`], so its start (already consumed from memory)
+//!   is not lost when delivery is retried, possibly after a restart.
+//! - The spool is kept within [`SpoolLimits`] after every scan; its size
+//!   is reported in `TELEMETRY`.
 
-use std::collections::HashMap;
+pub mod diagnostics;
+
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use tokio::io::AsyncWriteExt;
 use tokio::net::tcp::OwnedWriteHalf;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex as AsyncMutex;
+use tokio::task::JoinSet;
 use tokio::time::interval;
+use tracing::Instrument;
 
+use crate::drv;
+use crate::logging::build_span;
 use crate::protocol::frame::{read_frame_async, write_frame_async, Frame};
 use crate::protocol::handshake::perform_handshake_async;
-use crate::protocol::ops::{op, AgentHello, EventBuildFinish, SpoolEvent, TelemetryBody};
+use crate::protocol::ops::{
+    op, AgentHello, BuildAdmitted, BuildCancelled, BuildGone, BuildStatus, Contention,
+    EventBuildFinish, LinkProbe, LinkProbeReply, PathsPresent, PathsQuery, SpoolEvent,
+    TelemetryBody,
+};
+use crate::spool::{self, SpoolBacklog, SpoolLimits};
 use crate::telemetry::{self, Telemetry};
 use crate::util::now_ms;
 
@@ -45,12 +80,62 @@ pub struct AgentConfig {
     pub hostname: String,
     pub system: String,
     pub capacity: u32,
+    /// How often the spool directory is scanned for new events.
+    pub spool_poll_interval: Duration,
+    pub spool_limits: SpoolLimits,
+    /// cgroup whose CPU time is shared out among the builds in progress
+    /// (see [`account_cpu`]); `None` disables CPU accounting.
+    pub build_cgroup: Option<PathBuf>,
+    /// Nix store directory consulted for `PATHS_QUERY`, whose filesystem's
+    /// free space is reported in `TELEMETRY` and where build outputs are
+    /// measured.
+    pub store_dir: PathBuf,
+    /// `nix-store` used to tell which of the queried paths are valid, i.e.
+    /// registered in the Nix database rather than merely on disk.
+    pub nix_store_bin: PathBuf,
+    /// Shell command run on `SUSPEND_HINT` while no build is in progress,
+    /// e.g. `systemctl suspend`; `None` ignores the hint.
+    pub suspend_command: Option<String>,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct PendingStart {
     pub pname: String,
     pub ts_ms: u64,
+    /// CPU time attributed to this build so far; `None` until the first
+    /// build-cgroup sample after it started.
+    pub cpu_ms: Option<u64>,
+    pub contention: ContentionSamples,
+}
+
+/// Running sums behind a build's [`Contention`], one sample per spool scan
+/// while it is in progress.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContentionSamples {
+    samples: u32,
+    builds: u64,
+    cpu_psi_samples: u32,
+    cpu_psi: f64,
+}
+
+impl ContentionSamples {
+    pub fn add(&mut self, builds: usize, cpu_psi: Option<f64>) {
+        self.samples += 1;
+        self.builds += builds as u64;
+        if let Some(psi) = cpu_psi {
+            self.cpu_psi_samples += 1;
+            self.cpu_psi += psi;
+        }
+    }
+
+    /// `None` before the first sample.
+    pub fn mean(&self) -> Option<Contention> {
+        (self.samples > 0).then(|| Contention {
+            mean_builds: self.builds as f64 / f64::from(self.samples),
+            mean_cpu_psi: (self.cpu_psi_samples > 0)
+                .then(|| self.cpu_psi / f64::from(self.cpu_psi_samples)),
+        })
+    }
 }
 
 /// Pure logic decision returned by [`apply_event`]. The watcher decides
@@ -59,7 +144,7 @@ pub struct PendingStart {
 #[derive(Clone, Debug, PartialEq)]
 pub enum ApplyOutcome {
     StoredStart,
-    ForwardFinish(EventBuildFinish),
+    ForwardFinish(Box<EventBuildFinish>),
 }
 
 /// Apply one spool event to the agent's in-memory start map. Pure function
@@ -75,7 +160,15 @@ pub fn apply_event(
             host: _,
             ts_ms,
         } => {
-            pending_starts.insert(drv_path, PendingStart { pname, ts_ms });
+            pending_starts.insert(
+                drv_path,
+                PendingStart {
+                    pname,
+                    ts_ms,
+                    cpu_ms: None,
+                    contention: ContentionSamples::default(),
+                },
+            );
             ApplyOutcome::StoredStart
         }
         SpoolEvent::Finish {
@@ -88,24 +181,74 @@ pub fn apply_event(
         } => {
             let started = pending_starts.remove(&drv_path);
             let duration_ms = started.as_ref().and_then(|s| ts_ms.checked_sub(s.ts_ms));
+            let cpu_ms = started.as_ref().and_then(|s| s.cpu_ms);
+            let contention = started.as_ref().and_then(|s| s.contention.mean());
             let pname = started.map(|s| s.pname).unwrap_or(pname);
-            ApplyOutcome::ForwardFinish(EventBuildFinish {
+            ApplyOutcome::ForwardFinish(Box::new(EventBuildFinish {
                 drv_path,
                 pname,
                 host,
                 ts_ms,
                 duration_ms,
+                cpu_ms,
+                contention,
+                output_bytes: None,
                 status,
                 out_paths,
-            })
+                correlation: None,
+            }))
         }
+        SpoolEvent::Matched(event) => ApplyOutcome::ForwardFinish(Box::new(event)),
     }
 }
 
+/// How long a correlation id from `BUILD_ADMITTED` is kept. Failed builds
+/// never reach the post-build hook, so nothing else removes theirs.
+const ADMITTED_TTL_MS: u64 = 24 * 60 * 60 * 1000;
+
+struct Admitted {
+    correlation: String,
+    at_ms: u64,
+}
+
 struct AgentState {
     config: AgentConfig,
     pending_starts: HashMap<String, PendingStart>,
+    /// Correlation ids from `BUILD_ADMITTED`, by drv path.
+    admitted: HashMap<String, Admitted>,
     writer: Option<Arc<ConnectionWriter>>,
+    /// Undeliverable entries already rewritten as `Matched`; not re-read
+    /// until a controller connects.
+    parked: HashSet<PathBuf>,
+    backlog: SpoolBacklog,
+    /// Build cgroup's `usage_usec` at the previous scan.
+    last_cpu_usec: Option<u64>,
+}
+
+impl AgentState {
+    fn new(config: AgentConfig) -> Self {
+        Self {
+            config,
+            pending_starts: HashMap::new(),
+            admitted: HashMap::new(),
+            writer: None,
+            parked: HashSet::new(),
+            backlog: SpoolBacklog::default(),
+            last_cpu_usec: None,
+        }
+    }
+
+    fn note_admitted(&mut self, admitted: BuildAdmitted, now_ms: u64) {
+        self.admitted
+            .retain(|_, a| now_ms.saturating_sub(a.at_ms) < ADMITTED_TTL_MS);
+        self.admitted.insert(
+            admitted.drv_path,
+            Admitted {
+                correlation: admitted.correlation,
+                at_ms: now_ms,
+            },
+        );
+    }
 }
 
 struct ConnectionWriter {
@@ -117,34 +260,49 @@ impl ConnectionWriter {
         let mut g = self.inner.lock().await;
         write_frame_async(&mut *g, frame).await
     }
+
+    /// Write `frame` and shut the write half, so a push racing with it
+    /// fails (and stays spooled) instead of landing after it.
+    async fn write_last_frame(&self, frame: &Frame) -> io::Result<()> {
+        let mut g = self.inner.lock().await;
+        write_frame_async(&mut *g, frame).await?;
+        g.shutdown().await
+    }
 }
 
 pub async fn run(config: AgentConfig) -> io::Result<()> {
-    std::fs::create_dir_all(&config.spool_dir).ok();
     let listener = TcpListener::bind(config.bind_addr).await?;
+    serve(listener, config).await
+}
+
+/// Run the agent on an already-bound listener (`config.bind_addr` is not
+/// used). Every task the agent starts is owned by this future, so dropping
+/// or aborting it takes the agent down completely — the integration
+/// harness relies on that to script agent outages.
+pub async fn serve(listener: TcpListener, config: AgentConfig) -> io::Result<()> {
+    std::fs::create_dir_all(&config.spool_dir).ok();
     tracing::info!(
-        addr = %config.bind_addr,
+        addr = %listener.local_addr()?,
         host = %config.hostname,
         system = %config.system,
         "nbb-agent listening"
     );
 
-    let state = Arc::new(Mutex::new(AgentState {
-        config,
-        pending_starts: HashMap::new(),
-        writer: None,
-    }));
+    let period = config.spool_poll_interval;
+    let state = Arc::new(Mutex::new(AgentState::new(config)));
 
+    let mut tasks = JoinSet::new();
     let watcher_state = Arc::clone(&state);
-    tokio::spawn(async move {
-        spool_watcher_loop(watcher_state, Duration::from_secs(1)).await;
+    tasks.spawn(async move {
+        spool_watcher_loop(watcher_state, period).await;
     });
 
     loop {
         let (stream, peer) = listener.accept().await?;
         tracing::info!(?peer, "controller connected");
+        while tasks.try_join_next().is_some() {}
         let conn_state = Arc::clone(&state);
-        tokio::spawn(async move {
+        tasks.spawn(async move {
             if let Err(err) = handle_connection(stream, conn_state).await {
                 tracing::warn!(?err, "controller connection ended");
             }
@@ -172,7 +330,11 @@ async fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<AgentState>>)
         .write_frame(&Frame::with_body(op::AGENT_HELLO, &hello)?)
         .await?;
 
-    state.lock().expect("agent state mutex").writer = Some(Arc::clone(&writer));
+    {
+        let mut s = state.lock().expect("agent state mutex");
+        s.writer = Some(Arc::clone(&writer));
+        s.parked.clear();
+    }
 
     let result = loop {
         let frame = match read_frame_async(&mut reader).await {
@@ -187,15 +349,23 @@ async fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<AgentState>>)
                 }
             }
             op::TELEMETRY_GET => {
-                let body = match telemetry::sample() {
-                    Ok(t) => to_telemetry_body(&t),
+                let (backlog, store_dir) = {
+                    let s = state.lock().expect("agent state mutex");
+                    (s.backlog, s.config.store_dir.clone())
+                };
+                let body = match telemetry::sample(&store_dir) {
+                    Ok(t) => to_telemetry_body(&t, backlog),
                     Err(err) => {
                         tracing::warn!(?err, "telemetry sample failed");
                         TelemetryBody {
                             mem_available_kb: 0,
                             psi_memory_some_avg10: None,
                             nix_slots_active: 0,
+                            store_free_bytes: None,
+                            store_free_inodes: None,
                             sampled_at_ms: now_ms_u64(),
+                            spool_backlog: u32::try_from(backlog.entries).unwrap_or(u32::MAX),
+                            spool_full: backlog.full,
                         }
                     }
                 };
@@ -210,6 +380,113 @@ async fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<AgentState>>)
                     break Err(err);
                 }
             }
+            op::PATHS_QUERY => {
+                let query: PathsQuery = match frame.decode_body() {
+                    Ok(q) => q,
+                    Err(err) => {
+                        tracing::warn!(?err, "decoding PATHS_QUERY");
+                        continue;
+                    }
+                };
+                let (store_dir, nix_store_bin) = {
+                    let s = state.lock().expect("agent state mutex");
+                    (s.config.store_dir.clone(), s.config.nix_store_bin.clone())
+                };
+                let body = PathsPresent {
+                    id: query.id,
+                    present: present_paths(&store_dir, &nix_store_bin, query.paths).await,
+                };
+                let frame = match Frame::with_body(op::PATHS_PRESENT, &body) {
+                    Ok(f) => f,
+                    Err(err) => {
+                        tracing::error!(?err, "encoding PATHS_PRESENT");
+                        continue;
+                    }
+                };
+                if let Err(err) = writer.write_frame(&frame).await {
+                    break Err(err);
+                }
+            }
+            op::LINK_PROBE => {
+                let probe: LinkProbe = match frame.decode_body() {
+                    Ok(p) => p,
+                    Err(err) => {
+                        tracing::warn!(?err, "decoding LINK_PROBE");
+                        continue;
+                    }
+                };
+                let body = LinkProbeReply {
+                    id: probe.id,
+                    received_bytes: probe.payload.len() as u64,
+                };
+                let frame = match Frame::with_body(op::LINK_PROBE_REPLY, &body) {
+                    Ok(f) => f,
+                    Err(err) => {
+                        tracing::error!(?err, "encoding LINK_PROBE_REPLY");
+                        continue;
+                    }
+                };
+                if let Err(err) = writer.write_frame(&frame).await {
+                    break Err(err);
+                }
+            }
+            op::SUSPEND_HINT => suspend_if_idle(&state),
+            op::BUILD_ADMITTED => {
+                let admitted: BuildAdmitted = match frame.decode_body() {
+                    Ok(a) => a,
+                    Err(err) => {
+                        tracing::warn!(?err, "decoding BUILD_ADMITTED");
+                        continue;
+                    }
+                };
+                build_span(Some(&admitted.correlation), &admitted.drv_path)
+                    .in_scope(|| tracing::debug!("build admitted to this host"));
+                state
+                    .lock()
+                    .expect("agent state mutex")
+                    .note_admitted(admitted, now_ms_u64());
+            }
+            op::BUILD_CANCELLED => {
+                let cancelled: BuildCancelled = match frame.decode_body() {
+                    Ok(c) => c,
+                    Err(err) => {
+                        tracing::warn!(?err, "decoding BUILD_CANCELLED");
+                        continue;
+                    }
+                };
+                let store_dir = {
+                    let mut s = state.lock().expect("agent state mutex");
+                    s.pending_starts.remove(&cancelled.drv_path);
+                    s.admitted.remove(&cancelled.drv_path);
+                    s.config.store_dir.clone()
+                };
+                let span = build_span(Some(&cancelled.correlation), &cancelled.drv_path);
+                let writer = Arc::clone(&writer);
+                tokio::spawn(
+                    async move {
+                        tracing::info!("build cancelled by its hook");
+                        let lingering = lingering_builders(&store_dir, &cancelled.drv_path).await;
+                        let body = BuildGone {
+                            drv_path: cancelled.drv_path,
+                            correlation: cancelled.correlation,
+                            lingering,
+                        };
+                        let sent = match Frame::with_body(op::BUILD_GONE, &body) {
+                            Ok(frame) => writer.write_frame(&frame).await,
+                            Err(err) => Err(err),
+                        };
+                        if let Err(err) = sent {
+                            tracing::debug!(?err, "BUILD_GONE not sent");
+                        }
+                    }
+                    .instrument(span),
+                );
+            }
+            op::GOODBYE => {
+                tracing::info!("controller shutting down; holding finishes until one connects");
+                state.lock().expect("agent state mutex").writer = None;
+                break writer.write_last_frame(&Frame::empty(op::GOODBYE)).await;
+            }
             other => {
                 tracing::warn!(op = other, "agent received unexpected op_id");
             }
@@ -220,6 +497,64 @@ async fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<AgentState>>)
     result
 }
 
+/// Act on the controller's `SUSPEND_HINT`. The controller only knows its
+/// own admissions, so builds started locally or by another controller are
+/// checked for here.
+fn suspend_if_idle(state: &Arc<Mutex<AgentState>>) {
+    let (command, pending) = {
+        let s = state.lock().expect("agent state mutex");
+        (s.config.suspend_command.clone(), s.pending_starts.len())
+    };
+    let Some(command) = command else {
+        tracing::debug!("suspend hint ignored; no suspend command configured");
+        return;
+    };
+    let slots = telemetry::slot_states(telemetry::SLOT_DIR)
+        .iter()
+        .filter(|slot| slot.locked)
+        .count();
+    if pending > 0 || slots > 0 {
+        tracing::info!(pending, slots, "suspend hint ignored; builds in progress");
+        return;
+    }
+    tracing::info!(%command, "controller reports this host idle; suspending");
+    tokio::task::spawn_blocking(move || {
+        match std::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(&command)
+            .status()
+        {
+            Ok(status) if status.success() => {}
+            Ok(status) => tracing::warn!(%command, %status, "suspend command failed"),
+            Err(err) => tracing::warn!(%command, ?err, "suspend command did not run"),
+        }
+    });
+}
+
+/// How long the agent waits for a cancelled build's processes to exit
+/// before reporting them as lingering.
+const CANCEL_CONFIRM_TIMEOUT: Duration = Duration::from_secs(10);
+const CANCEL_CONFIRM_POLL: Duration = Duration::from_millis(500);
+
+/// Wait for the processes of a cancelled build to exit, identified by its
+/// output paths from the `.drv` in this host's store; how many remain at
+/// [`CANCEL_CONFIRM_TIMEOUT`]. `None` if the outputs are not known here.
+async fn lingering_builders(store_dir: &Path, drv_path: &str) -> Option<u32> {
+    let drv_file = store_dir.join(Path::new(drv_path).file_name()?);
+    let out_paths = drv::read(&drv_file).ok()?.output_paths()?;
+    let deadline = tokio::time::Instant::now() + CANCEL_CONFIRM_TIMEOUT;
+    loop {
+        let paths = out_paths.clone();
+        let count = tokio::task::spawn_blocking(move || telemetry::builder_processes(&paths))
+            .await
+            .ok()?;
+        if count == 0 || tokio::time::Instant::now() >= deadline {
+            return Some(u32::try_from(count).unwrap_or(u32::MAX));
+        }
+        tokio::time::sleep(CANCEL_CONFIRM_POLL).await;
+    }
+}
+
 async fn spool_watcher_loop(state: Arc<Mutex<AgentState>>, period: Duration) {
     let mut ticker = interval(period);
     loop {
@@ -231,21 +566,44 @@ async fn spool_watcher_loop(state: Arc<Mutex<AgentState>>, period: Duration) {
 }
 
 async fn tick(state: &Arc<Mutex<AgentState>>) -> io::Result<()> {
-    let spool_dir = state
-        .lock()
-        .expect("agent state mutex")
-        .config
-        .spool_dir
-        .clone();
-    let mut entries: Vec<PathBuf> = match std::fs::read_dir(&spool_dir) {
-        Ok(iter) => iter.flatten().map(|e| e.path()).collect(),
-        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
-        Err(err) => return Err(err),
+    let (spool_dir, limits, build_cgroup) = {
+        let s = state.lock().expect("agent state mutex");
+        (
+            s.config.spool_dir.clone(),
+            s.config.spool_limits,
+            s.config.build_cgroup.clone(),
+        )
     };
-    entries.sort();
+    if let Some(cgroup) = build_cgroup {
+        match telemetry::cgroup_cpu_usec(&cgroup) {
+            Ok(usec) => {
+                let mut s = state.lock().expect("agent state mutex");
+                let AgentState {
+                    pending_starts,
+                    last_cpu_usec,
+                    ..
+                } = &mut *s;
+                account_cpu(pending_starts, last_cpu_usec, usec);
+            }
+            Err(err) => {
+                tracing::debug!(cgroup = %cgroup.display(), ?err, "build cgroup unreadable")
+            }
+        }
+    }
+    {
+        let mut s = state.lock().expect("agent state mutex");
+        if !s.pending_starts.is_empty() {
+            sample_contention(&mut s.pending_starts, telemetry::read_psi_cpu_some_avg10());
+        }
+    }
 
-    for path in entries {
-        if path.extension().and_then(|s| s.to_str()) != Some("evt") {
+    for path in spool::list_events(&spool_dir)? {
+        if state
+            .lock()
+            .expect("agent state mutex")
+            .parked
+            .contains(&path)
+        {
             continue;
         }
         match process_one(state, &path).await {
@@ -253,7 +611,8 @@ async fn tick(state: &Arc<Mutex<AgentState>>) -> io::Result<()> {
                 let _ = std::fs::remove_file(&path);
             }
             Ok(false) => {
-                // Controller unreachable; retry next tick.
+                // Controller unreachable; retry once one connects.
+                state.lock().expect("agent state mutex").parked.insert(path);
             }
             Err(err) => {
                 tracing::warn!(path = %path.display(), ?err, "corrupt spool entry; removing");
@@ -261,9 +620,44 @@ async fn tick(state: &Arc<Mutex<AgentState>>) -> io::Result<()> {
             }
         }
     }
+
+    let backlog = spool::enforce_limits(&spool_dir, limits, now_ms_u64())?;
+    let mut s = state.lock().expect("agent state mutex");
+    s.parked.retain(|path| path.exists());
+    s.backlog = backlog;
     Ok(())
 }
 
+/// Share the build cgroup's CPU time since the previous sample equally
+/// among the builds in progress. The daemon's cgroup does not say which
+/// build used what, so concurrent builds of different widths blur into
+/// their average; the controller's per-pname EWMA smooths that out.
+pub fn account_cpu(
+    pending_starts: &mut HashMap<String, PendingStart>,
+    last_cpu_usec: &mut Option<u64>,
+    usage_usec: u64,
+) {
+    if let Some(previous) = last_cpu_usec.replace(usage_usec) {
+        let builds = pending_starts.len() as u64;
+        if builds == 0 {
+            return;
+        }
+        let share_ms = usage_usec.saturating_sub(previous) / 1000 / builds;
+        for start in pending_starts.values_mut() {
+            *start.cpu_ms.get_or_insert(0) += share_ms;
+        }
+    }
+}
+
+/// Record one contention sample against every build in progress: how many
+/// there are, counting the build itself, and the host's CPU pressure.
+pub fn sample_contention(pending_starts: &mut HashMap<String, PendingStart>, cpu_psi: Option<f64>) {
+    let builds = pending_starts.len();
+    for start in pending_starts.values_mut() {
+        start.contention.add(builds, cpu_psi);
+    }
+}
+
 async fn process_one(state: &Arc<Mutex<AgentState>>, path: &Path) -> io::Result<bool> {
     let bytes = std::fs::read(path)?;
     let (event, _) = bincode::decode_from_slice::<SpoolEvent, _>(
@@ -272,17 +666,69 @@ async fn process_one(state: &Arc<Mutex<AgentState>>, path: &Path) -> io::Result<
     )
     .map_err(io::Error::other)?;
 
-    let outcome = {
+    let matched_here = matches!(event, SpoolEvent::Finish { .. });
+    let drv_path = event.drv_path().to_string();
+    let (outcome, correlation) = {
         let mut s = state.lock().expect("agent state mutex");
-        apply_event(&mut s.pending_starts, event)
+        let outcome = apply_event(&mut s.pending_starts, event);
+        let correlation = match &outcome {
+            ApplyOutcome::StoredStart => s.admitted.get(&drv_path).map(|a| a.correlation.clone()),
+            ApplyOutcome::ForwardFinish(event) => {
+                let admitted = s.admitted.remove(&drv_path).map(|a| a.correlation);
+                event.correlation.clone().or(admitted)
+            }
+        };
+        (outcome, correlation)
     };
+    let span = build_span(correlation.as_deref(), &drv_path);
 
     match outcome {
-        ApplyOutcome::StoredStart => Ok(true),
-        ApplyOutcome::ForwardFinish(event) => forward_finish(state, &event).await,
+        ApplyOutcome::StoredStart => {
+            span.in_scope(|| tracing::debug!("build started"));
+            Ok(true)
+        }
+        ApplyOutcome::ForwardFinish(mut event) => {
+            event.correlation = correlation;
+            forward_or_park(state, path, *event, matched_here)
+                .instrument(span)
+                .await
+        }
     }
 }
 
+/// Forward a finish to the controller; if it cannot be, keep it in its
+/// spool file as [`SpoolEvent::Matched`] when it was matched just now.
+async fn forward_or_park(
+    state: &Arc<Mutex<AgentState>>,
+    path: &Path,
+    mut event: EventBuildFinish,
+    matched_here: bool,
+) -> io::Result<bool> {
+    if matched_here && event.status == BuildStatus::Success {
+        let store_dir = state
+            .lock()
+            .expect("agent state mutex")
+            .config
+            .store_dir
+            .clone();
+        let out_paths = event.out_paths.clone();
+        event.output_bytes = tokio::task::spawn_blocking(move || {
+            telemetry::store_paths_bytes(&store_dir, &out_paths)
+        })
+        .await
+        .map_err(io::Error::other)?;
+    }
+    if forward_finish(state, &event).await? {
+        return Ok(true);
+    }
+    if matched_here {
+        if let Err(err) = spool::rewrite_event(path, &SpoolEvent::Matched(event)) {
+            tracing::warn!(path = %path.display(), ?err, "compacting spool entry failed");
+        }
+    }
+    Ok(false)
+}
+
 async fn forward_finish(
     state: &Arc<Mutex<AgentState>>,
     event: &EventBuildFinish,
@@ -302,12 +748,74 @@ async fn forward_finish(
     }
 }
 
-fn to_telemetry_body(t: &Telemetry) -> TelemetryBody {
+/// The subset of `paths` that are valid store paths under `store_dir`.
+/// Only the file name is used, so a controller with a different store
+/// prefix cannot probe arbitrary files.
+///
+/// A path on disk is not necessarily valid: a substitution in progress,
+/// a failed build's leftovers or a path awaiting garbage collection are
+/// there too. The ones that exist are checked in one batch with
+/// `nix-store --check-validity --print-invalid`; if that cannot run,
+/// nothing is reported present.
+pub async fn present_paths(
+    store_dir: &Path,
+    nix_store_bin: &Path,
+    paths: Vec<String>,
+) -> Vec<String> {
+    let existing: Vec<(String, PathBuf)> = paths
+        .into_iter()
+        .filter_map(|path| {
+            let local = store_dir.join(Path::new(&path).file_name()?);
+            local.symlink_metadata().is_ok().then_some((path, local))
+        })
+        .collect();
+    if existing.is_empty() {
+        return Vec::new();
+    }
+    let mut command = std::process::Command::new(nix_store_bin);
+    command
+        .arg("--check-validity")
+        .arg("--print-invalid")
+        .args(existing.iter().map(|(_, local)| local))
+        .stdin(std::process::Stdio::null());
+    let output = tokio::task::spawn_blocking(move || command.output())
+        .await
+        .unwrap_or_else(|err| Err(io::Error::other(err)));
+    let invalid: HashSet<PathBuf> = match output {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(PathBuf::from)
+            .collect(),
+        Ok(output) => {
+            tracing::warn!(
+                status = %output.status,
+                stderr = %String::from_utf8_lossy(&output.stderr).trim(),
+                "nix-store --check-validity failed; reporting no paths present"
+            );
+            return Vec::new();
+        }
+        Err(err) => {
+            tracing::warn!(?err, "nix-store did not run; reporting no paths present");
+            return Vec::new();
+        }
+    };
+    existing
+        .into_iter()
+        .filter(|(_, local)| !invalid.contains(local))
+        .map(|(path, _)| path)
+        .collect()
+}
+
+fn to_telemetry_body(t: &Telemetry, backlog: SpoolBacklog) -> TelemetryBody {
     TelemetryBody {
         mem_available_kb: t.mem_available_kb,
         psi_memory_some_avg10: t.psi_memory_some_avg10,
         nix_slots_active: u32::try_from(t.nix_slots_active).unwrap_or(u32::MAX),
+        store_free_bytes: t.store_free.map(|free| free.bytes),
+        store_free_inodes: t.store_free.and_then(|free| free.inodes),
         sampled_at_ms: u64::try_from(t.sampled_at_ms).unwrap_or(u64::MAX),
+        spool_backlog: u32::try_from(backlog.entries).unwrap_or(u32::MAX),
+        spool_full: backlog.full,
     }
 }
 
@@ -413,6 +921,91 @@ mod tests {
         assert_eq!(event_a.duration_ms, Some(500));
     }
 
+    #[test]
+    fn cpu_is_shared_among_builds_in_progress() {
+        let mut pending = HashMap::new();
+        let mut last = None;
+        apply_event(&mut pending, start("/a.drv", "a", 100));
+        // The first sample only sets the baseline.
+        account_cpu(&mut pending, &mut last, 1_000_000);
+        assert_eq!(pending["/a.drv"].cpu_ms, None);
+        account_cpu(&mut pending, &mut last, 9_000_000);
+        apply_event(&mut pending, start("/b.drv", "b", 200));
+        account_cpu(&mut pending, &mut last, 13_000_000);
+
+        let ApplyOutcome::ForwardFinish(a) = apply_event(&mut pending, finish("/a.drv", "a", 900))
+        else {
+            panic!("expected forward");
+        };
+        assert_eq!(a.cpu_ms, Some(8_000 + 2_000));
+        assert_eq!(pending["/b.drv"].cpu_ms, Some(2_000));
+    }
+
+    #[test]
+    fn contention_is_averaged_over_the_build() {
+        let mut pending = HashMap::new();
+        apply_event(&mut pending, start("/a.drv", "a", 100));
+        sample_contention(&mut pending, None);
+        apply_event(&mut pending, start("/b.drv", "b", 200));
+        apply_event(&mut pending, start("/c.drv", "c", 300));
+        sample_contention(&mut pending, Some(40.0));
+        sample_contention(&mut pending, Some(20.0));
+
+        let ApplyOutcome::ForwardFinish(a) = apply_event(&mut pending, finish("/a.drv", "a", 900))
+        else {
+            panic!("expected forward");
+        };
+        assert_eq!(
+            a.contention,
+            Some(Contention {
+                mean_builds: 7.0 / 3.0,
+                mean_cpu_psi: Some(30.0),
+            })
+        );
+        apply_event(&mut pending, start("/d.drv", "d", 950));
+        let ApplyOutcome::ForwardFinish(d) = apply_event(&mut pending, finish("/d.drv", "d", 990))
+        else {
+            panic!("expected forward");
+        };
+        assert_eq!(d.contention, None);
+    }
+
+    #[test]
+    fn cancelled_build_is_confirmed_gone_once_its_builder_exits() {
+        let store = tempdir();
+        std::fs::create_dir_all(&store).unwrap();
+        let out = format!(
+            "/nix/store/{}-{}-cancelled",
+            std::process::id(),
+            now_ms_u64()
+        );
+        let drv = "/nix/store/zzz-cancelled-1.0.drv";
+        std::fs::write(
+            store.join("zzz-cancelled-1.0.drv"),
+            format!(r#"Derive([("out","{out}","","")],[],[],"x86_64-linux","/bin/sh",[],[("out","{out}")])"#),
+        )
+        .unwrap();
+        let mut builder = std::process::Command::new("sleep")
+            .arg("0.6")
+            .env("out", &out)
+            .spawn()
+            .unwrap();
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            assert_eq!(lingering_builders(&store, drv).await, Some(0));
+            assert_eq!(
+                lingering_builders(&store, "/nix/store/yyy-unknown.drv").await,
+                None
+            );
+        });
+        builder.wait().unwrap();
+        let _ = std::fs::remove_dir_all(&store);
+    }
+
     #[test]
     fn tick_processes_files_in_ulid_order() {
         // ULID-style filenames lex-sort by time. Write Start with later
@@ -423,18 +1016,7 @@ mod tests {
         let dir = tempdir();
         std::fs::create_dir_all(&dir).unwrap();
 
-        let cfg_path = dir.clone();
-        let state = Arc::new(Mutex::new(AgentState {
-            config: AgentConfig {
-                bind_addr: "127.0.0.1:0".parse().unwrap(),
-                spool_dir: cfg_path,
-                hostname: "tsugumi".into(),
-                system: "x86_64-linux".into(),
-                capacity: 1,
-            },
-            pending_starts: HashMap::new(),
-            writer: None,
-        }));
+        let state = test_state(&dir);
 
         // Earlier ULID = Start; later ULID = Finish.
         write_spool(&dir, "01HAA-start", &start("/d.drv", "foo", 100));
@@ -466,17 +1048,7 @@ mod tests {
         std::fs::create_dir_all(&dir).unwrap();
         std::fs::write(dir.join("bad.evt"), b"\xff\xff\xff\xff garbage").unwrap();
 
-        let state = Arc::new(Mutex::new(AgentState {
-            config: AgentConfig {
-                bind_addr: "127.0.0.1:0".parse().unwrap(),
-                spool_dir: dir.clone(),
-                hostname: "tsugumi".into(),
-                system: "x86_64-linux".into(),
-                capacity: 1,
-            },
-            pending_starts: HashMap::new(),
-            writer: None,
-        }));
+        let state = test_state(&dir);
         let rt = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
@@ -494,17 +1066,7 @@ mod tests {
         let dir = tempdir();
         std::fs::create_dir_all(&dir).unwrap();
         std::fs::write(dir.join("foo.tmp"), b"in-flight write").unwrap();
-        let state = Arc::new(Mutex::new(AgentState {
-            config: AgentConfig {
-                bind_addr: "127.0.0.1:0".parse().unwrap(),
-                spool_dir: dir.clone(),
-                hostname: "tsugumi".into(),
-                system: "x86_64-linux".into(),
-                capacity: 1,
-            },
-            pending_starts: HashMap::new(),
-            writer: None,
-        }));
+        let state = test_state(&dir);
         let rt = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
@@ -517,6 +1079,182 @@ mod tests {
         let _ = std::fs::remove_dir_all(&dir);
     }
 
+    #[test]
+    fn undelivered_finish_is_compacted_with_its_duration() {
+        let dir = tempdir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let state = test_state(&dir);
+        write_spool(&dir, "01HAA-start", &start("/d.drv", "foo", 100));
+        write_spool(&dir, "01HAB-finish", &finish("/d.drv", "foo", 500));
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            tick(&state).await.unwrap();
+            // A second scan skips the parked entry and keeps the duration
+            // even though the start is gone from memory.
+            tick(&state).await.unwrap();
+        });
+
+        let bytes = std::fs::read(dir.join("01HAB-finish.evt")).unwrap();
+        let (event, _) = bincode::decode_from_slice::<SpoolEvent, _>(
+            &bytes,
+            crate::protocol::frame::bincode_config(),
+        )
+        .unwrap();
+        let SpoolEvent::Matched(event) = event else {
+            panic!("expected a compacted entry, got {event:?}");
+        };
+        assert_eq!(event.duration_ms, Some(400));
+        assert_eq!(spool::list_events(&dir).unwrap().len(), 1);
+        assert_eq!(
+            state.lock().unwrap().backlog,
+            SpoolBacklog {
+                entries: 1,
+                full: false
+            }
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn admitted_build_finishes_under_its_correlation() {
+        let dir = tempdir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let state = test_state(&dir);
+        state.lock().unwrap().note_admitted(
+            BuildAdmitted {
+                drv_path: "/d.drv".to_string(),
+                correlation: "01JAXKQ4V6M0S7Y8E2N5D3W1HC".to_string(),
+            },
+            now_ms_u64(),
+        );
+        write_spool(&dir, "01HAA-start", &start("/d.drv", "foo", 100));
+        write_spool(&dir, "01HAB-finish", &finish("/d.drv", "foo", 500));
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            tick(&state).await.unwrap();
+        });
+
+        let bytes = std::fs::read(dir.join("01HAB-finish.evt")).unwrap();
+        let (event, _) = bincode::decode_from_slice::<SpoolEvent, _>(
+            &bytes,
+            crate::protocol::frame::bincode_config(),
+        )
+        .unwrap();
+        let SpoolEvent::Matched(event) = event else {
+            panic!("expected a compacted entry, got {event:?}");
+        };
+        assert_eq!(
+            event.correlation.as_deref(),
+            Some("01JAXKQ4V6M0S7Y8E2N5D3W1HC")
+        );
+        assert!(state.lock().unwrap().admitted.is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// A stand-in `nix-store` for which only the paths listed in
+    /// `<dir>/registered` are valid.
+    fn fake_nix_store(dir: &Path) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+        let registry = dir.join("registered");
+        std::fs::write(&registry, "").unwrap();
+        let bin = dir.join("nix-store");
+        std::fs::write(
+            &bin,
+            format!(
+                "#!/bin/sh\n\
+                 [ \"$1 $2\" = \"--check-validity --print-invalid\" ] || exit 2\n\
+                 shift 2\n\
+                 for p in \"$@\"; do grep -qxF \"$p\" '{}' || echo \"$p\"; done\n",
+                registry.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        bin
+    }
+
+    fn register(dir: &Path, path: &Path) {
+        let mut registry = std::fs::read_to_string(dir.join("registered")).unwrap();
+        registry.push_str(&format!("{}\n", path.display()));
+        std::fs::write(dir.join("registered"), registry).unwrap();
+    }
+
+    fn present(store: &Path, nix_store: &Path, paths: &[&str]) -> Vec<String> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let paths = paths.iter().map(|p| p.to_string()).collect();
+        rt.block_on(present_paths(store, nix_store, paths))
+    }
+
+    #[test]
+    fn present_paths_checks_the_store_by_name() {
+        let dir = tempdir();
+        let store = dir.join("store");
+        std::fs::create_dir_all(store.join("aaa-hello-2.12")).unwrap();
+        let nix_store = fake_nix_store(&dir);
+        register(&dir, &store.join("aaa-hello-2.12"));
+        let present = present(
+            &store,
+            &nix_store,
+            &[
+                "/nix/store/aaa-hello-2.12",
+                "/nix/store/bbb-hello-2.12-man",
+                "/nix/store/..",
+            ],
+        );
+        assert_eq!(present, vec!["/nix/store/aaa-hello-2.12".to_string()]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn present_paths_skips_paths_nix_does_not_know() {
+        let dir = tempdir();
+        let store = dir.join("store");
+        // A substitution still in progress: on disk, not registered.
+        std::fs::create_dir_all(store.join("aaa-hello-2.12")).unwrap();
+        std::fs::create_dir_all(store.join("ccc-hello-2.12-doc")).unwrap();
+        let nix_store = fake_nix_store(&dir);
+        register(&dir, &store.join("ccc-hello-2.12-doc"));
+        let paths = ["/nix/store/aaa-hello-2.12", "/nix/store/ccc-hello-2.12-doc"];
+        assert_eq!(
+            present(&store, &nix_store, &paths),
+            vec!["/nix/store/ccc-hello-2.12-doc".to_string()]
+        );
+
+        // No verdict from Nix, no paths.
+        assert!(present(&store, &dir.join("no-such-nix-store"), &paths).is_empty());
+        assert!(present(&store, Path::new("false"), &paths).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    fn test_state(dir: &Path) -> Arc<Mutex<AgentState>> {
+        Arc::new(Mutex::new(AgentState::new(AgentConfig {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            spool_dir: dir.to_path_buf(),
+            hostname: "tsugumi".into(),
+            system: "x86_64-linux".into(),
+            capacity: 1,
+            spool_poll_interval: Duration::from_secs(1),
+            spool_limits: SpoolLimits::default(),
+            build_cgroup: None,
+            store_dir: dir.join("store"),
+            nix_store_bin: PathBuf::from("nix-store"),
+            suspend_command: None,
+        })))
+    }
+
     fn write_spool(dir: &Path, name: &str, event: &SpoolEvent) {
         let bytes =
             bincode::encode_to_vec(event, crate::protocol::frame::bincode_config()).unwrap();
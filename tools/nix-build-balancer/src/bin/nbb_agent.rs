@@ -2,21 +2,32 @@ use std::io;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::process::ExitCode;
+use std::time::Duration;
 
 use clap::Parser;
 
-use nbb::agent::{run, AgentConfig};
+use nbb::agent::{diagnostics, run, AgentConfig};
+use nbb::logging::{self, LogOutput};
+use nbb::spool::SpoolLimits;
 use nbb::telemetry;
 use nbb::util::hostname_fallback;
 
 #[derive(Parser, Debug)]
 #[command(name = "nbb-agent", about = "nix-build-balancer agent")]
 struct Args {
-    /// Print one telemetry snapshot and exit. Replaces the old `telemetry`
-    /// CLI.
+    /// Print one telemetry snapshot, the build slot files, and the spool
+    /// backlog and pending starts, then exit. Needs no controller.
     #[arg(long)]
     once: bool,
 
+    /// With `--once`: print a JSON object instead of `key=value` lines.
+    #[arg(long, requires = "once")]
+    json: bool,
+
+    /// Nix build slot directory inspected by `--once`.
+    #[arg(long, default_value = telemetry::SLOT_DIR)]
+    slot_dir: PathBuf,
+
     /// TCP bind address for the controller's polling connection.
     #[arg(long, default_value = "0.0.0.0:8765")]
     bind: SocketAddr,
@@ -37,33 +48,65 @@ struct Args {
     /// controller may queue against this host).
     #[arg(long, default_value_t = 1)]
     capacity: u32,
+
+    /// Spool directory poll interval.
+    #[arg(long, default_value_t = 1000)]
+    spool_poll_ms: u64,
+
+    /// Spool entries kept while the controller is unreachable; beyond
+    /// this the oldest are dropped and `nbb-event` stops writing.
+    #[arg(long, default_value_t = 10_000)]
+    spool_max_entries: usize,
+
+    /// Spool entries older than this are dropped undelivered.
+    #[arg(long, default_value_t = 168)]
+    spool_max_age_hours: u64,
+
+    /// cgroup v2 directory whose CPU time is attributed to the builds in
+    /// progress, so the controller can learn how many cores each pname
+    /// uses. Empty disables CPU accounting.
+    #[arg(long, default_value = telemetry::BUILD_CGROUP)]
+    build_cgroup: PathBuf,
+
+    /// Nix store consulted when the controller asks whether this host
+    /// already has a derivation's outputs; its free space is reported in
+    /// telemetry and build outputs are measured there.
+    #[arg(long, default_value = "/nix/store")]
+    store_dir: PathBuf,
+
+    /// `nix-store` used to check that paths found in the store are valid
+    /// before telling the controller this host has them.
+    #[arg(long, default_value = "/run/current-system/sw/bin/nix-store")]
+    nix_store_bin: PathBuf,
+
+    /// Shell command that suspends this host, run when the controller
+    /// reports it idle and no build is in progress. Unset ignores those
+    /// hints.
+    #[arg(long)]
+    suspend_command: Option<String>,
 }
 
 fn main() -> ExitCode {
     let args = Args::parse();
 
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_env("NBB_LOG")
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
-        )
-        .with_writer(std::io::stderr)
-        .init();
+    logging::init(LogOutput::Daemon);
 
     if args.once {
-        match telemetry::sample() {
-            Ok(t) => {
-                println!("mem_available_kb={}", t.mem_available_kb);
-                match t.psi_memory_some_avg10 {
-                    Some(v) => println!("psi_memory_some_avg10={v}"),
-                    None => println!("psi_memory_some_avg10=none"),
+        match diagnostics::collect(&args.spool_dir, &args.slot_dir, &args.store_dir) {
+            Ok(diag) => {
+                if args.json {
+                    println!("{}", diag.to_json());
+                } else {
+                    print!("{}", diag.to_text());
+                }
+                if diag.telemetry.is_ok() {
+                    ExitCode::SUCCESS
+                } else {
+                    ExitCode::FAILURE
                 }
-                println!("nix_slots_active={}", t.nix_slots_active);
-                println!("sampled_at_ms={}", t.sampled_at_ms);
-                ExitCode::SUCCESS
             }
             Err(err) => {
-                eprintln!("telemetry sample failed: {err}");
+                eprintln!("reading spool {}: {err}", args.spool_dir.display());
                 ExitCode::FAILURE
             }
         }
@@ -86,6 +129,15 @@ fn run_async(args: Args) -> io::Result<()> {
         hostname,
         system: args.system,
         capacity: args.capacity,
+        spool_poll_interval: Duration::from_millis(args.spool_poll_ms),
+        spool_limits: SpoolLimits {
+            max_entries: args.spool_max_entries,
+            max_age_ms: args.spool_max_age_hours * 3_600_000,
+        },
+        build_cgroup: Some(args.build_cgroup).filter(|p| !p.as_os_str().is_empty()),
+        store_dir: args.store_dir,
+        nix_store_bin: args.nix_store_bin,
+        suspend_command: args.suspend_command,
     };
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -1,13 +1,30 @@
+use std::fs::File;
+use std::io::BufWriter;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-use nbb::controller::{run, ControllerConfig};
+use nbb::controller::{
+    run, ControllerConfig, RemoteHookConfig, MAX_UNAUTHENTICATED_HOOKS, REMOTE_AUTH_TIMEOUT,
+};
 use nbb::estimator;
+use nbb::health::QuarantinePolicy;
+use nbb::link::{self, LinkProbePolicy};
+use nbb::logging::{self, LogOutput};
+use nbb::persistence::maintenance::MaintenancePolicy;
+use nbb::persistence::{self, decisions, failure_tails, history, links};
+use nbb::protocol::auth;
+use nbb::protocol::frame::{read_frame_sync, write_frame_sync, Frame};
+use nbb::protocol::handshake::perform_handshake_sync;
+use nbb::protocol::ops::{
+    op, Priority, SessionPriorityRequest, SessionSummary, SessionSummaryRequest,
+};
 use nbb::scheduler::{SchedulerPolicy, Target};
+use nbb::wake::{self, WakeOnLan, WakePolicy};
 
 #[derive(Parser, Debug)]
 #[command(name = "nbb-controller", about = "nix-build-balancer controller")]
@@ -28,9 +45,23 @@ struct Args {
     #[arg(long, default_value = "/run/nbb/decide.sock")]
     hook_socket: PathBuf,
 
-    /// One or more targets, each `name=tcp_addr,capacity,store_uri,builder_line[,is_local][,speed=X]`.
-    /// Repeat the flag for additional targets. Commas inside the
-    /// `builder_line` need quoting from the shell.
+    /// Also accept hooks from other hosts on this TCP address. Requires
+    /// `--hook-key-file`.
+    #[arg(long, requires = "hook_key_file")]
+    hook_listen: Option<SocketAddr>,
+
+    /// Shared key remote hooks authenticate with.
+    #[arg(long)]
+    hook_key_file: Option<PathBuf>,
+
+    /// One or more targets, each
+    /// `name=tcp_addr|capacity|store_uri|builder_line` followed by any of
+    /// `|is_local`, `|speed=X`, `|cores=N`, `|mac=M` and `|wol=ADDR:PORT`.
+    /// Fields are split on `|` so `builder_line` can keep its commas;
+    /// quote the whole value from the shell. `mac` makes a sleeping target
+    /// wakeable; `wol` is the broadcast address for its magic packet
+    /// (default `255.255.255.255:9`). Repeat the flag for additional
+    /// targets.
     #[arg(long = "target", value_parser = parse_target)]
     targets: Vec<Target>,
 
@@ -40,9 +71,32 @@ struct Args {
     #[arg(long, default_value_t = 1_000_000)]
     min_remote_mem_available_kb: u64,
 
+    /// Store space a target must have free beyond the candidate's
+    /// expected output size (the largest of its pname's recent builds).
+    #[arg(long, default_value_t = 10 << 30)]
+    min_store_free_bytes: u64,
+
+    /// Free inodes a target's store filesystem must have, where it has an
+    /// inode limit.
+    #[arg(long, default_value_t = 100_000)]
+    min_store_free_inodes: u64,
+
     #[arg(long, default_value_t = 60_000)]
     unknown_p95_ms: u64,
 
+    /// Decline candidates whose duration estimate is below this without
+    /// consulting targets; copying closures over ssh-ng costs more than
+    /// building them. 0 disables.
+    #[arg(long, default_value_t = 5_000)]
+    cheap_threshold_ms: u64,
+
+    /// Share of each target's build slots, rounded down, kept for
+    /// candidates with NBB_PRIORITY=high. Normal candidates are not
+    /// delegated into it; low ones wait for a free unreserved slot.
+    /// Must be in [0, 1).
+    #[arg(long, default_value_t = 0.25, value_parser = parse_reserve)]
+    high_priority_reserve: f64,
+
     #[arg(long, default_value_t = 200)]
     max_samples_per_pname: u32,
 
@@ -58,6 +112,181 @@ struct Args {
     /// over-estimation.
     #[arg(long, default_value_t = estimator::Z_P95, value_parser = parse_z)]
     ewma_z: f64,
+
+    /// Hours of decision log to keep in `state.db`.
+    #[arg(long, default_value_t = 168)]
+    decision_log_retention_hours: u64,
+
+    /// Seconds between WAL checkpoints of `state.db`; the observation cap
+    /// is applied on the same schedule.
+    #[arg(long, default_value_t = 3600)]
+    checkpoint_interval_secs: u64,
+
+    /// Hours between `VACUUM`s of `state.db`; 0 (the default) disables.
+    /// Decisions wait while it runs, seconds on a large database.
+    #[arg(long, default_value_t = 0)]
+    vacuum_interval_hours: u64,
+
+    /// Most build observations kept across all pnames, on top of the
+    /// per-pname cap; the oldest are pruned first. 0 disables.
+    #[arg(long, default_value_t = 200_000)]
+    max_observations: u64,
+
+    /// `export-history` output to import on startup if this controller has
+    /// no build history yet.
+    #[arg(long)]
+    seed_history: Option<PathBuf>,
+
+    /// Seconds a target is skipped after a hook reports that delegating to
+    /// it failed for infrastructure reasons (ssh dropped, store unreachable).
+    #[arg(long, default_value_t = 120)]
+    unhealthy_cooldown_secs: u64,
+
+    /// Log tails of failed delegated builds to keep per pname.
+    #[arg(long, default_value_t = 5)]
+    failure_tails_per_pname: u32,
+
+    /// Window over which each target's failed and cancelled builds are
+    /// counted for quarantine.
+    #[arg(long, default_value_t = 1800)]
+    failure_window_secs: u64,
+
+    /// Failures within the window needed before a target can be
+    /// quarantined. 0 disables quarantine.
+    #[arg(long, default_value_t = 3)]
+    quarantine_min_failures: u32,
+
+    /// Share of failed or declined builds in the window, in [0, 1], at
+    /// which a target is quarantined.
+    #[arg(long, default_value_t = 0.5, value_parser = parse_rate)]
+    quarantine_failure_rate: f64,
+
+    /// Seconds a quarantined target is skipped.
+    #[arg(long, default_value_t = 600)]
+    quarantine_secs: u64,
+
+    /// `pname=cores`: how many cores a pname keeps busy while building,
+    /// overriding what agents' CPU accounting has taught the controller.
+    /// Repeat for more pnames.
+    #[arg(long = "pname-cores", value_parser = parse_pname_cores)]
+    pname_cores: Vec<(String, f64)>,
+
+    /// Wake a sleeping target that has a `mac` when a candidate would queue
+    /// longer than this on every live target. 0 disables.
+    #[arg(long, default_value_t = 300_000)]
+    wake_queue_ms: u64,
+
+    /// Seconds a woken target has to answer before it may be woken again.
+    #[arg(long, default_value_t = 180)]
+    wake_timeout_secs: u64,
+
+    /// Seconds without admissions after which a target that has a `mac` is
+    /// told it may suspend. 0 disables.
+    #[arg(long, default_value_t = 1800)]
+    idle_suspend_secs: u64,
+
+    /// Milliseconds to wait for agents to report whether they already
+    /// have a candidate's outputs; one that does is accepted so Nix just
+    /// copies them back. 0 disables the probe.
+    #[arg(long, default_value_t = 0)]
+    substitute_probe_ms: u64,
+
+    /// Seconds between probes of each agent's round-trip time and
+    /// throughput. 0 disables.
+    #[arg(long, default_value_t = 600)]
+    link_probe_interval_secs: u64,
+
+    /// Bytes sent to measure throughput, at most 1047552.
+    #[arg(long, default_value_t = 1_000_000, value_parser = parse_probe_bytes)]
+    link_probe_bytes: u32,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print logged scheduling decisions from `<data-dir>/state.db`
+    /// instead of running the controller.
+    Decisions {
+        /// Only decisions for this `.drv` path.
+        #[arg(long)]
+        drv: Option<String>,
+        /// Only decisions at or after this Unix time (ms).
+        #[arg(long)]
+        since_ms: Option<u64>,
+        /// Only decisions before this Unix time (ms).
+        #[arg(long)]
+        until_ms: Option<u64>,
+        #[arg(long, default_value_t = 100)]
+        limit: u32,
+    },
+    /// Print how long logged decisions took, as a histogram per outcome,
+    /// from `<data-dir>/state.db`.
+    Latency {
+        /// Only decisions at or after this Unix time (ms).
+        #[arg(long)]
+        since_ms: Option<u64>,
+        /// Only decisions before this Unix time (ms).
+        #[arg(long)]
+        until_ms: Option<u64>,
+    },
+    /// Print the end of the remote build log for recently failed
+    /// delegated builds, newest first, from `<data-dir>/state.db`.
+    Failures {
+        /// Only failures of this pname.
+        #[arg(long)]
+        pname: Option<String>,
+        #[arg(long, default_value_t = 10)]
+        limit: u32,
+    },
+    /// Print link probe results (round-trip time and throughput from
+    /// this host to each target), newest first, from `<data-dir>/state.db`.
+    Links {
+        /// Only probes of this target.
+        #[arg(long)]
+        target: Option<String>,
+        #[arg(long, default_value_t = 20)]
+        limit: u32,
+    },
+    /// Ask the running controller (over `--hook-socket`) where a
+    /// rebuild session's derivations went and how long they took.
+    SessionSummary {
+        /// Session id; defaults to the session of the latest decision.
+        #[arg(long)]
+        session: Option<String>,
+        /// Number of longest builds to list.
+        #[arg(long, default_value_t = 10)]
+        top: u32,
+    },
+    /// Tell the running controller (over `--hook-socket`) to schedule a
+    /// rebuild session's candidates at `PRIORITY` (`low`, `normal` or
+    /// `high`), overriding `NBB_PRIORITY`, which multi-user Nix takes from
+    /// the daemon's environment.
+    Priority {
+        #[arg(value_parser = parse_priority)]
+        priority: Priority,
+        /// Session id; defaults to the session of the latest decision.
+        #[arg(long)]
+        session: Option<String>,
+    },
+    /// Write `<data-dir>/state.db`'s build observations as CSV, for
+    /// `import-history` or `--seed-history` on another controller.
+    ExportHistory {
+        /// Output file; standard output if omitted.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Merge an `export-history` file into `<data-dir>/state.db`. Rows
+    /// already present (same drv and finish time) are skipped.
+    ImportHistory {
+        /// Input file, or `-` for standard input.
+        input: PathBuf,
+    },
+}
+
+fn parse_priority(s: &str) -> Result<Priority, String> {
+    Priority::parse(s).ok_or_else(|| format!("priority must be low, normal or high, got {s:?}"))
 }
 
 fn parse_alpha(s: &str) -> Result<f64, String> {
@@ -76,8 +305,43 @@ fn parse_z(s: &str) -> Result<f64, String> {
     Ok(v)
 }
 
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let v: f64 = s
+        .parse()
+        .map_err(|e| format!("bad quarantine-failure-rate: {e}"))?;
+    if !(0.0..=1.0).contains(&v) {
+        return Err(format!(
+            "quarantine-failure-rate must be in [0, 1], got {v}"
+        ));
+    }
+    Ok(v)
+}
+
+fn parse_reserve(s: &str) -> Result<f64, String> {
+    let v: f64 = s
+        .parse()
+        .map_err(|e| format!("bad high-priority-reserve: {e}"))?;
+    if !(0.0..1.0).contains(&v) {
+        return Err(format!("high-priority-reserve must be in [0, 1), got {v}"));
+    }
+    Ok(v)
+}
+
+fn parse_probe_bytes(s: &str) -> Result<u32, String> {
+    let v: u32 = s
+        .parse()
+        .map_err(|e| format!("bad link-probe-bytes: {e}"))?;
+    if v > link::MAX_PROBE_BYTES {
+        return Err(format!(
+            "link-probe-bytes must be at most {}, got {v}",
+            link::MAX_PROBE_BYTES
+        ));
+    }
+    Ok(v)
+}
+
 fn parse_target(s: &str) -> Result<Target, String> {
-    // Expected: name=tcp_addr|capacity|store_uri|builder_line[|is_local][|speed=X]
+    // Expected: name=tcp_addr|capacity|store_uri|builder_line[|is_local][|speed=X][|cores=N][|mac=M][|wol=ADDR]
     // Pipe-separated to avoid clashing with commas in builder_line.
     let (name, rest) = s
         .split_once('=')
@@ -92,42 +356,142 @@ fn parse_target(s: &str) -> Result<Target, String> {
     let builder_line = parts[3].to_string();
     let mut is_controller_host = false;
     let mut speed_multiplier: f64 = 1.0;
+    let mut cores = None;
+    let mut mac = None;
+    let mut broadcast = None;
     for extra in &parts[4..] {
         if *extra == "is_local" {
             is_controller_host = true;
         } else if let Some(v) = extra.strip_prefix("speed=") {
             speed_multiplier = v.parse().map_err(|e| format!("bad speed: {e}"))?;
+        } else if let Some(v) = extra.strip_prefix("cores=") {
+            cores = Some(v.parse().map_err(|e| format!("bad cores: {e}"))?);
+        } else if let Some(v) = extra.strip_prefix("mac=") {
+            mac = Some(wake::parse_mac(v)?);
+        } else if let Some(v) = extra.strip_prefix("wol=") {
+            let addr: SocketAddr = v.parse().map_err(|e| format!("bad wol: {e}"))?;
+            broadcast = Some(addr);
         } else {
             return Err(format!("unknown target option: {extra}"));
         }
     }
+    let wake = match (mac, broadcast) {
+        (Some(mac), broadcast) => Some(WakeOnLan {
+            mac,
+            broadcast: broadcast.unwrap_or_else(|| {
+                wake::DEFAULT_BROADCAST
+                    .parse()
+                    .expect("default broadcast parses")
+            }),
+        }),
+        (None, Some(_)) => return Err("wol= needs mac=".to_string()),
+        (None, None) => None,
+    };
     Ok(Target {
         name: name.to_string(),
         tcp_addr,
         store_uri,
         builder_line,
         capacity,
+        cores,
         speed_multiplier,
         is_controller_host,
+        wake,
     })
 }
 
+fn parse_pname_cores(s: &str) -> Result<(String, f64), String> {
+    let (pname, cores) = s
+        .split_once('=')
+        .ok_or_else(|| "pname-cores must be pname=cores".to_string())?;
+    let cores: f64 = cores.parse().map_err(|e| format!("bad cores: {e}"))?;
+    if !(cores.is_finite() && cores > 0.0) {
+        return Err(format!("pname-cores must be positive, got {cores}"));
+    }
+    Ok((pname.to_string(), cores))
+}
+
 fn main() -> ExitCode {
     let args = Args::parse();
 
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_env("NBB_LOG")
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
-        )
-        .with_writer(std::io::stderr)
-        .init();
+    logging::init(LogOutput::Daemon);
+
+    let query_result = match args.command {
+        Some(Command::Decisions {
+            drv,
+            since_ms,
+            until_ms,
+            limit,
+        }) => {
+            let query = decisions::DecisionQuery {
+                drv_path: drv,
+                since_ms,
+                until_ms,
+                limit: Some(limit),
+            };
+            Some(print_decisions(&args.data_dir, &query))
+        }
+        Some(Command::Latency { since_ms, until_ms }) => {
+            Some(print_latency(&args.data_dir, since_ms, until_ms))
+        }
+        Some(Command::Failures { pname, limit }) => {
+            Some(print_failures(&args.data_dir, pname.as_deref(), limit))
+        }
+        Some(Command::Links { target, limit }) => {
+            Some(print_links(&args.data_dir, target.as_deref(), limit))
+        }
+        Some(Command::SessionSummary { session, top }) => Some(print_session_summary(
+            &args.hook_socket,
+            SessionSummaryRequest {
+                session,
+                top_n: top,
+            },
+        )),
+        Some(Command::Priority { priority, session }) => Some(set_session_priority(
+            &args.hook_socket,
+            SessionPriorityRequest { session, priority },
+        )),
+        Some(Command::ExportHistory { output }) => {
+            Some(export_history(&args.data_dir, output.as_deref()))
+        }
+        Some(Command::ImportHistory { input }) => Some(import_history(
+            &args.data_dir,
+            &input,
+            args.max_samples_per_pname,
+        )),
+        None => None,
+    };
+    if let Some(result) = query_result {
+        return match result {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                eprintln!("nbb-controller: {err}");
+                ExitCode::FAILURE
+            }
+        };
+    }
 
     if args.targets.is_empty() {
         eprintln!("nbb-controller: at least one --target required");
         return ExitCode::FAILURE;
     }
 
+    let remote_hooks = match (args.hook_listen, &args.hook_key_file) {
+        (Some(listen), Some(key_file)) => match auth::read_key(key_file) {
+            Ok(key) => Some(RemoteHookConfig {
+                listen,
+                key,
+                auth_timeout: REMOTE_AUTH_TIMEOUT,
+                max_unauthenticated: MAX_UNAUTHENTICATED_HOOKS,
+            }),
+            Err(err) => {
+                eprintln!("nbb-controller: {}: {err}", key_file.display());
+                return ExitCode::FAILURE;
+            }
+        },
+        _ => None,
+    };
+
     let config = ControllerConfig {
         system: args.system,
         data_dir: args.data_dir,
@@ -137,11 +501,46 @@ fn main() -> ExitCode {
         poll_interval: Duration::from_millis(args.poll_interval_ms),
         policy: SchedulerPolicy {
             min_remote_mem_available_kb: args.min_remote_mem_available_kb,
+            min_store_free_bytes: args.min_store_free_bytes,
+            min_store_free_inodes: args.min_store_free_inodes,
             unknown_p95_ms: args.unknown_p95_ms,
+            cheap_threshold_ms: args.cheap_threshold_ms,
+            high_priority_reserve: args.high_priority_reserve,
         },
         max_samples_per_pname: args.max_samples_per_pname,
         ewma_alpha: args.ewma_alpha,
         ewma_z: args.ewma_z,
+        decision_log_retention: Duration::from_secs(
+            args.decision_log_retention_hours.saturating_mul(3600),
+        ),
+        unhealthy_cooldown: Duration::from_secs(args.unhealthy_cooldown_secs),
+        failure_tails_per_pname: args.failure_tails_per_pname,
+        quarantine: QuarantinePolicy {
+            window_ms: args.failure_window_secs.saturating_mul(1000),
+            min_failures: args.quarantine_min_failures,
+            failure_rate: args.quarantine_failure_rate,
+            cooldown_ms: args.quarantine_secs.saturating_mul(1000),
+        },
+        pname_cores: args.pname_cores.into_iter().collect(),
+        substitute_probe_timeout: (args.substitute_probe_ms > 0)
+            .then(|| Duration::from_millis(args.substitute_probe_ms)),
+        maintenance: MaintenancePolicy {
+            checkpoint_interval: Duration::from_secs(args.checkpoint_interval_secs),
+            vacuum_interval: (args.vacuum_interval_hours > 0)
+                .then(|| Duration::from_secs(args.vacuum_interval_hours.saturating_mul(3600))),
+            max_observations: args.max_observations,
+        },
+        seed_history: args.seed_history,
+        remote_hooks,
+        wake: WakePolicy {
+            queue_ms: args.wake_queue_ms,
+            timeout_ms: args.wake_timeout_secs.saturating_mul(1000),
+            idle_suspend_ms: args.idle_suspend_secs.saturating_mul(1000),
+        },
+        link_probe: (args.link_probe_interval_secs > 0).then(|| LinkProbePolicy {
+            interval: Duration::from_secs(args.link_probe_interval_secs),
+            bytes: args.link_probe_bytes,
+        }),
     };
 
     let rt = match tokio::runtime::Builder::new_multi_thread()
@@ -163,3 +562,196 @@ fn main() -> ExitCode {
         }
     }
 }
+
+fn print_decisions(data_dir: &Path, query: &decisions::DecisionQuery) -> std::io::Result<()> {
+    let conn = persistence::open_read_only(data_dir.join("state.db"))?;
+    for d in decisions::query(&conn, query)? {
+        println!(
+            "{} {} {} {} pname={} estimate_ms={} tier={} latency_us={}",
+            d.decided_at_ms,
+            d.outcome,
+            d.winner.as_deref().unwrap_or("-"),
+            d.drv_path,
+            d.pname,
+            d.estimate_ms.map_or("-".to_string(), |ms| ms.to_string()),
+            d.estimate_tier,
+            d.latency_us.map_or("-".to_string(), |us| us.to_string()),
+        );
+        for t in &d.targets {
+            match (&t.excluded, t.queue_ms, t.package_ms, t.completion_ms) {
+                (Some(reason), ..) => println!("    {} excluded={reason}", t.target_name),
+                (None, Some(queue), Some(package), Some(completion)) => println!(
+                    "    {} queue_ms={queue} package_ms={package} completion_ms={completion}",
+                    t.target_name
+                ),
+                _ => println!("    {}", t.target_name),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_latency(
+    data_dir: &Path,
+    since_ms: Option<u64>,
+    until_ms: Option<u64>,
+) -> std::io::Result<()> {
+    let conn = persistence::open_read_only(data_dir.join("state.db"))?;
+    let ms = |us: u64| us as f64 / 1000.0;
+    for h in decisions::latency_histograms(&conn, since_ms, until_ms)? {
+        println!(
+            "{} n={} p50<={:.1}ms p95<={:.1}ms p99<={:.1}ms max={:.1}ms",
+            h.outcome,
+            h.total(),
+            ms(h.quantile_us(0.5)),
+            ms(h.quantile_us(0.95)),
+            ms(h.quantile_us(0.99)),
+            ms(h.max_us),
+        );
+        for (bucket, &count) in h.counts.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            match decisions::LATENCY_BUCKETS_US.get(bucket) {
+                Some(&bound) => println!("    <={}ms {count}", ms(bound)),
+                None => println!(
+                    "    >{}ms {count}",
+                    ms(*decisions::LATENCY_BUCKETS_US.last().expect("buckets"))
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_failures(data_dir: &Path, pname: Option<&str>, limit: u32) -> std::io::Result<()> {
+    let conn = persistence::open_read_only(data_dir.join("state.db"))?;
+    for f in failure_tails::list(&conn, pname, limit)? {
+        println!(
+            "{} {} {} on {} pname={}",
+            f.finished_at_ms, f.status, f.drv_path, f.target_name, f.pname
+        );
+        for line in f.tail.lines() {
+            println!("    {line}");
+        }
+    }
+    Ok(())
+}
+
+fn print_links(data_dir: &Path, target: Option<&str>, limit: u32) -> std::io::Result<()> {
+    let conn = persistence::open_read_only(data_dir.join("state.db"))?;
+    for p in links::list(&conn, target, limit)? {
+        println!(
+            "{} {} rtt={:.1}ms throughput={:.1}MB/s",
+            p.probed_at_ms,
+            p.target_name,
+            p.rtt_us as f64 / 1000.0,
+            p.bytes_per_sec as f64 / 1e6,
+        );
+    }
+    Ok(())
+}
+
+fn export_history(data_dir: &Path, output: Option<&Path>) -> std::io::Result<()> {
+    let conn = persistence::open_read_only(data_dir.join("state.db"))?;
+    let rows = match output {
+        Some(path) => history::export(&conn, BufWriter::new(File::create(path)?))?,
+        None => history::export(&conn, std::io::stdout().lock())?,
+    };
+    eprintln!("exported {rows} observations");
+    Ok(())
+}
+
+fn import_history(data_dir: &Path, input: &Path, max_samples: u32) -> std::io::Result<()> {
+    let conn = persistence::open(data_dir.join("state.db"))?;
+    let stats = if input == Path::new("-") {
+        history::import(&conn, std::io::stdin().lock(), max_samples)?
+    } else {
+        history::import(&conn, File::open(input)?, max_samples)?
+    };
+    eprintln!(
+        "imported {} of {} observations ({} already present)",
+        stats.inserted,
+        stats.rows,
+        stats.rows - stats.inserted
+    );
+    Ok(())
+}
+
+fn set_session_priority(socket: &Path, request: SessionPriorityRequest) -> std::io::Result<()> {
+    let mut stream = UnixStream::connect(socket)?;
+    perform_handshake_sync(&mut stream)?;
+    write_frame_sync(
+        &mut stream,
+        &Frame::with_body(op::SESSION_PRIORITY_SET, &request)?,
+    )?;
+    let reply = read_frame_sync(&mut stream)?;
+    if reply.op_id != op::SESSION_PRIORITY {
+        return Err(std::io::Error::other(format!(
+            "expected SESSION_PRIORITY reply, got op_id {}",
+            reply.op_id
+        )));
+    }
+    let Some(session): Option<String> = reply.decode_body()? else {
+        return Err(std::io::Error::other("no session to prioritise yet"));
+    };
+    println!("session {session}: {}", request.priority.as_str());
+    Ok(())
+}
+
+fn print_session_summary(socket: &Path, request: SessionSummaryRequest) -> std::io::Result<()> {
+    let mut stream = UnixStream::connect(socket)?;
+    perform_handshake_sync(&mut stream)?;
+    write_frame_sync(
+        &mut stream,
+        &Frame::with_body(op::SESSION_SUMMARY_GET, &request)?,
+    )?;
+    let reply = read_frame_sync(&mut stream)?;
+    if reply.op_id != op::SESSION_SUMMARY {
+        return Err(std::io::Error::other(format!(
+            "expected SESSION_SUMMARY reply, got op_id {}",
+            reply.op_id
+        )));
+    }
+    let Some(summary): Option<SessionSummary> = reply.decode_body()? else {
+        return Err(std::io::Error::other("no such session"));
+    };
+    let secs = |ms: u64| format!("{:.1}s", ms as f64 / 1000.0);
+    let opt_secs = |ms: Option<u64>| ms.map_or("-".to_string(), secs);
+    println!(
+        "session {}: {} derivations ({} unfinished) over {}",
+        summary.session,
+        summary.derivations,
+        summary.unfinished,
+        secs(
+            summary
+                .last_decision_ms
+                .saturating_sub(summary.first_decision_ms)
+        ),
+    );
+    println!(
+        "  predicted {}  actual {}",
+        secs(summary.predicted_ms),
+        secs(summary.actual_ms)
+    );
+    for d in &summary.destinations {
+        println!(
+            "  {:<16} {:>5} drvs  predicted {:>10}  actual {:>10}",
+            d.destination,
+            d.derivations,
+            secs(d.predicted_ms),
+            secs(d.actual_ms)
+        );
+    }
+    println!("longest builds:");
+    for b in &summary.slowest {
+        println!(
+            "  {:>10} (predicted {:>10}) {:<16} {}",
+            opt_secs(b.actual_ms),
+            opt_secs(b.predicted_ms),
+            b.destination,
+            b.drv_path
+        );
+    }
+    Ok(())
+}
@@ -8,7 +8,7 @@ use std::process::ExitCode;
 use clap::Parser;
 
 use nbb::protocol::ops::{BuildStatus, SpoolEvent};
-use nbb::spool::write_event;
+use nbb::spool::write_event_capped;
 use nbb::util::{hostname_fallback, now_ms_u64, pname_from_drv};
 
 #[derive(Parser, Debug)]
@@ -42,6 +42,11 @@ struct Args {
     /// `--kind finish`).
     #[arg(long, default_value = "")]
     out_paths: String,
+
+    /// Drop the event instead of spooling it when this many are already
+    /// waiting (the agent has been unreachable for a long time).
+    #[arg(long, default_value_t = 10_000)]
+    max_spool_entries: usize,
 }
 
 fn main() -> ExitCode {
@@ -81,7 +86,12 @@ fn submit(args: Args) -> std::io::Result<()> {
         }
     };
 
-    write_event(&args.spool_dir, &event)?;
+    if write_event_capped(&args.spool_dir, &event, args.max_spool_entries)?.is_none() {
+        return Err(std::io::Error::other(format!(
+            "spool {} is full; event dropped",
+            args.spool_dir.display()
+        )));
+    }
     Ok(())
 }
 
@@ -1,9 +1,13 @@
 use std::path::PathBuf;
 use std::process::ExitCode;
+use std::time::Duration;
 
 use clap::Parser;
 
-use nbb::hook::{run_hook, HookConfig};
+use nbb::hook::{run_hook, HookConfig, RemoteController, DEFAULT_DECISION_TIMEOUT};
+use nbb::logging::{self, LogOutput};
+use nbb::protocol::auth;
+use nbb::util::hostname_fallback;
 
 #[derive(Parser, Debug)]
 #[command(name = "nbb-hook", about = "Nix build-hook talking to nbb-controller")]
@@ -12,6 +16,21 @@ struct Args {
     #[arg(long, default_value = "/run/nbb/decide.sock")]
     controller_socket: PathBuf,
 
+    /// Ask a controller on another host, at this `host:port` (its
+    /// `--hook-listen`), instead of the Unix socket. Requires
+    /// `--hook-key-file`.
+    #[arg(long, requires = "hook_key_file")]
+    controller_addr: Option<String>,
+
+    /// Shared key for authenticating to a remote controller.
+    #[arg(long)]
+    hook_key_file: Option<PathBuf>,
+
+    /// Name to authenticate to a remote controller as; this host's target
+    /// name in the controller's configuration. Defaults to the hostname.
+    #[arg(long)]
+    hostname: Option<String>,
+
     /// Tmpfs directory where the hook writes its sentinel.
     #[arg(long, default_value = "/run/nbb/inflight")]
     inflight_dir: PathBuf,
@@ -26,6 +45,16 @@ struct Args {
     #[arg(long, default_value = "0")]
     verbosity: String,
 
+    /// How long to wait for each answer from the controller, connecting
+    /// included, before declining the candidate. Must exceed the
+    /// controller's --substitute-probe-ms when that is set.
+    #[arg(
+        long,
+        default_value_t = DEFAULT_DECISION_TIMEOUT.as_millis() as u64,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    decision_timeout_ms: u64,
+
     /// Verbosity passed positionally by Nix when invoking the hook. Captured
     /// here so clap's positional argument doesn't choke; overrides `--verbosity`.
     positional_verbosity: Option<String>,
@@ -34,20 +63,32 @@ struct Args {
 fn main() -> ExitCode {
     let args = Args::parse();
 
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_env("NBB_LOG")
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
-        )
-        .with_writer(std::io::stderr)
-        .init();
+    logging::init(LogOutput::Hook);
 
+    let remote_controller = match (args.controller_addr, &args.hook_key_file) {
+        (Some(addr), Some(key_file)) => match auth::read_key(key_file) {
+            Ok(key) => Some(RemoteController {
+                addr,
+                host: args.hostname.unwrap_or_else(hostname_fallback),
+                key,
+            }),
+            Err(err) => {
+                // Exiting now would crash the Nix daemon mid-protocol; carry
+                // on against the Unix socket, which declines if absent.
+                tracing::error!(key_file = %key_file.display(), ?err, "hook key unreadable");
+                None
+            }
+        },
+        _ => None,
+    };
     let verbosity = args.positional_verbosity.unwrap_or(args.verbosity);
     let config = HookConfig {
         controller_socket: args.controller_socket,
+        remote_controller,
         inflight_dir: args.inflight_dir,
         nix_bin: args.nix_bin,
         verbosity,
+        decision_timeout: Duration::from_millis(args.decision_timeout_ms),
     };
 
     match run_hook(config) {
@@ -7,13 +7,44 @@
 //!   unsolicited `EVENT_BUILD_FINISH` pushes.
 //! - Accept Unix-socket connections from `nbb-hook` and reply
 //!   `DECIDE_CANDIDATE → DECISION`; record matching `Admission` rows.
-//!   Handle later `ADMISSION_FINISH` arrivals on the same protocol.
+//!   Optionally accept the same protocol over TCP from hooks on other
+//!   hosts, authenticated with a shared key ([`crate::protocol::auth`]),
+//!   and schedule their candidates relative to the requesting host.
+//!   Handle later `ADMISSION_FINISH` arrivals on the same protocol, and
+//!   `SESSION_SUMMARY_GET` queries from `nbb-controller session-summary`
+//!   and `SESSION_PRIORITY_SET` from `nbb-controller priority`.
+//! - Optionally ask agents (`PATHS_QUERY`) whether they already hold a
+//!   candidate's outputs, and accept onto one that does so Nix copies
+//!   them back instead of building.
+//! - Mint a correlation id for every candidate ([`crate::logging`]),
+//!   log everything about it in a span carrying the id, and tell the
+//!   target's agent about each admission (`BUILD_ADMITTED`) so it logs the
+//!   build under the same id.
+//! - When a hook reports a delegated build `Cancelled`, tell the target's
+//!   agent (`BUILD_CANCELLED`) and log its `BUILD_GONE` answer on whether
+//!   the build's processes are gone.
+//! - Probe each agent's link (`LINK_PROBE`) for round-trip time and
+//!   throughput, keep the history, and let the scheduler charge slow links
+//!   for copying outputs back ([`crate::link`]).
+//! - Wake sleeping targets with Wake-on-LAN when live ones are backed up,
+//!   and hint to idle ones that they may suspend ([`crate::wake`]).
 //! - Run a 5-second watchdog that retires admissions via:
 //!     1. Sentinel sweep (`/run/nbb/inflight/*`): if the hook PID is
 //!        `ESRCH`, retire the admission and unlink the sentinel.
 //!     2. Wall-clock TTL: anything older than `max(predicted_ms × 2,
 //!        60_000)` is retired regardless.
-//! - Own the SQLite database. Clears the `admissions` table on startup.
+//!     3. Decision-log rotation: rows older than
+//!        `decision_log_retention` are pruned.
+//!     4. Database maintenance on the [`MaintenancePolicy`] schedule: WAL
+//!        checkpoint, observation cap, `VACUUM`.
+//!     5. `SUSPEND_HINT` to wakeable targets idle for
+//!        [`WakePolicy::idle_suspend_ms`].
+//! - Own the SQLite database. Clears the `admissions` table on startup,
+//!   and seeds an empty build history from `seed_history` if configured.
+//! - Shut down gracefully on `SIGTERM` or Ctrl-C ([`shutdown`]): stop
+//!   accepting hooks, answer the ones already connected, send agents
+//!   `GOODBYE` so they hold their finishes until a controller returns, and
+//!   checkpoint the WAL.
 //!
 //! Spec notes: SOCK_SEQPACKET was specified for the hook socket, but
 //! length-prefixed framing makes ordinary SOCK_STREAM equally safe and
@@ -21,28 +52,49 @@
 
 use std::collections::HashMap;
 use std::io;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use rusqlite::Connection;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::{TcpStream, UnixListener};
-use tokio::sync::Mutex as AsyncMutex;
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{
+    mpsc, oneshot, watch, Mutex as AsyncMutex, Notify, OwnedSemaphorePermit, Semaphore,
+};
+use tokio::task::JoinSet;
 use tokio::time::{interval, MissedTickBehavior};
+use tracing::Instrument;
 
+use crate::drv;
+use crate::health::{QuarantinePolicy, TargetHealth};
 use crate::inflight::{pid_is_dead, read_sentinel};
-use crate::persistence::{self, admissions, observations};
+use crate::link::{LinkProbePolicy, LinkProber, LinkStats};
+use crate::logging::{build_span, new_correlation};
+use crate::persistence::decisions::{self, DecisionRow, DecisionTargetRow};
+use crate::persistence::maintenance::{self, MaintenanceClock, MaintenancePolicy};
+use crate::persistence::observations::EstimateTier;
+use crate::persistence::{
+    self, admissions, failure_tails, history, links, observations, parallelism, sessions,
+    target_outcomes,
+};
+use crate::protocol::auth;
 use crate::protocol::frame::{read_frame_async, write_frame_async, Frame};
 use crate::protocol::handshake::perform_handshake_async;
 use crate::protocol::ops::{
-    op, AdmissionFinish, AgentHello, DecideCandidate, Decision, EventBuildFinish, TelemetryBody,
+    op, AcceptTarget, AdmissionFinish, AgentHello, BuildAdmitted, BuildCancelled, BuildGone,
+    BuildStatus, DecideCandidate, Decision, DrvDetails, EventBuildFinish, LinkProbeReply,
+    PathsPresent, PathsQuery, SessionPriorityRequest, SessionSummaryRequest, TelemetryBody,
 };
 use crate::scheduler::{
-    self, SchedulerDecision, SchedulerInputs, SchedulerPolicy, Target, TargetState,
+    self, Evaluation, LocalityHints, SchedulerDecision, SchedulerInputs, SchedulerPolicy, Target,
+    TargetState, TargetVerdict,
 };
 pub use crate::util::now_ms_u64;
 use crate::util::pname_from_drv;
+use crate::wake::{self, TargetWake, WakePolicy};
 
 #[derive(Clone, Debug)]
 pub struct ControllerConfig {
@@ -61,25 +113,119 @@ pub struct ControllerConfig {
     pub ewma_alpha: f64,
     /// Standard-normal quantile read by the estimator; `1.645 ≈ Φ⁻¹(0.95)`.
     pub ewma_z: f64,
+    /// How long decision-log rows are kept before the watchdog prunes them.
+    pub decision_log_retention: Duration,
+    /// How long a target is excluded after a hook reports an
+    /// infrastructure failure delegating to it.
+    pub unhealthy_cooldown: Duration,
+    /// Log tails of failed delegated builds kept per pname.
+    pub failure_tails_per_pname: u32,
+    pub quarantine: QuarantinePolicy,
+    /// Configured cores-per-pname, overriding what
+    /// [`crate::persistence::parallelism`] has learned.
+    pub pname_cores: HashMap<String, f64>,
+    /// How long to wait for agents to say whether they already hold a
+    /// candidate's outputs; `None` skips the probe.
+    pub substitute_probe_timeout: Option<Duration>,
+    pub maintenance: MaintenancePolicy,
+    /// `nbb-controller export-history` output imported on startup while
+    /// `build_observations` is empty, so a new controller starts with
+    /// another's learned durations.
+    pub seed_history: Option<PathBuf>,
+    /// TCP listener for hooks on other hosts; `None` serves only the local
+    /// Unix socket.
+    pub remote_hooks: Option<RemoteHookConfig>,
+    pub wake: WakePolicy,
+    /// How often agents' links are probed; `None` never probes them.
+    pub link_probe: Option<LinkProbePolicy>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RemoteHookConfig {
+    pub listen: SocketAddr,
+    /// Shared key remote hooks sign the challenge with.
+    pub key: Vec<u8>,
+    /// How long a connection has for the handshake and the challenge
+    /// ([`REMOTE_AUTH_TIMEOUT`] outside tests).
+    pub auth_timeout: Duration,
+    /// Connections allowed to be authenticating at once; further ones are
+    /// closed on accept ([`MAX_UNAUTHENTICATED_HOOKS`] outside tests).
+    pub max_unauthenticated: usize,
 }
 
+/// Default [`RemoteHookConfig::auth_timeout`]: a real hook answers in
+/// milliseconds, and one that says nothing must not hold a task and a
+/// file descriptor until shutdown.
+pub const REMOTE_AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Default [`RemoteHookConfig::max_unauthenticated`].
+pub const MAX_UNAUTHENTICATED_HOOKS: usize = 16;
+
 /// Tracked liveness per target. Updated by the target poller, read by the
 /// scheduler and watchdog.
 #[derive(Clone, Debug, Default)]
 pub struct TargetRuntime {
     pub last_pong_ms: Option<u64>,
     pub last_telemetry: Option<TelemetryBody>,
+    /// `PATHS_QUERY` requests for the live agent session, if any.
+    pub path_queries: Option<mpsc::UnboundedSender<PathsRequest>>,
+    /// `BUILD_ADMITTED` and `BUILD_CANCELLED` for the live agent session,
+    /// if any.
+    pub build_notices: Option<mpsc::UnboundedSender<BuildNotice>>,
+    /// Makes the live agent session send `SUSPEND_HINT`.
+    pub suspend_hint: Option<Arc<Notify>>,
+    /// `SUSPEND_HINT` has been sent during this session.
+    pub suspend_hinted: bool,
+}
+
+/// What a target's session task tells its agent about one build.
+#[derive(Debug)]
+pub enum BuildNotice {
+    Admitted(BuildAdmitted),
+    Cancelled(BuildCancelled),
+}
+
+/// One store-path probe handed to a target's session task.
+#[derive(Debug)]
+pub struct PathsRequest {
+    pub paths: Vec<String>,
+    pub reply: oneshot::Sender<Vec<String>>,
 }
 
 pub struct ControllerState {
     pub config: ControllerConfig,
     pub conn: AsyncMutex<Connection>,
     pub target_runtimes: std::sync::Mutex<HashMap<String, TargetRuntime>>,
+    pub health: std::sync::Mutex<TargetHealth>,
+    pub maintenance: std::sync::Mutex<MaintenanceClock>,
+    pub wake: std::sync::Mutex<TargetWake>,
+    /// Latest link figures per probed target. Like `health` and `wake` it
+    /// outlives agent sessions; it is seeded from the probe history.
+    pub links: std::sync::Mutex<HashMap<String, LinkStats>>,
+    /// Per target: cuts its poller's reconnect backoff short, so a woken
+    /// target is dialled as soon as it can answer.
+    pub reconnect: HashMap<String, Arc<Notify>>,
+    /// Set by [`shutdown`]; every task spawned by [`spawn_tasks`] winds
+    /// down when it flips to `true`.
+    pub shutdown: watch::Sender<bool>,
 }
 
 impl ControllerState {
+    pub fn begin_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Resolves once [`Self::begin_shutdown`] has been called.
+    pub async fn shutting_down(&self) {
+        let mut rx = self.shutdown.subscribe();
+        let _ = rx.wait_for(|&down| down).await;
+    }
+
     pub fn build_target_states(&self) -> Vec<TargetState> {
         let runtimes = self.target_runtimes.lock().expect("target_runtimes");
+        let health = self.health.lock().expect("health");
+        let wake = self.wake.lock().expect("wake");
+        let links = self.links.lock().expect("links");
+        let now = now_ms_u64();
         self.config
             .targets
             .iter()
@@ -89,6 +235,10 @@ impl ControllerState {
                     target: t.clone(),
                     last_pong_ms: rt.last_pong_ms,
                     last_telemetry: rt.last_telemetry,
+                    unhealthy_until_ms: health.unhealthy_until(&t.name, now),
+                    quarantined_until_ms: health.quarantined_until(&t.name, now),
+                    waking_since_ms: wake.waking_since(&t.name, now, self.config.wake.timeout_ms),
+                    link: links.get(&t.name).copied(),
                 }
             })
             .collect()
@@ -102,67 +252,188 @@ pub async fn open_state(config: ControllerConfig) -> io::Result<Arc<ControllerSt
     }
     let conn = persistence::open(&db_path)?;
     persistence::clear_admissions(&conn)?;
+    if let Some(seed) = &config.seed_history {
+        seed_history(&conn, seed, config.max_samples_per_pname)?;
+    }
+    let mut link_stats = HashMap::new();
+    for target in &config.targets {
+        if let Some(stats) = links::stats(&conn, &target.name)? {
+            link_stats.insert(target.name.clone(), stats);
+        }
+    }
     let target_runtimes: HashMap<String, TargetRuntime> = config
         .targets
         .iter()
         .map(|t| (t.name.clone(), TargetRuntime::default()))
         .collect();
+    let reconnect = config
+        .targets
+        .iter()
+        .map(|t| (t.name.clone(), Arc::new(Notify::new())))
+        .collect();
     Ok(Arc::new(ControllerState {
         config,
         conn: AsyncMutex::new(conn),
         target_runtimes: std::sync::Mutex::new(target_runtimes),
+        health: std::sync::Mutex::new(TargetHealth::default()),
+        maintenance: std::sync::Mutex::new(MaintenanceClock::starting_at(now_ms_u64())),
+        wake: std::sync::Mutex::new(TargetWake::default()),
+        links: std::sync::Mutex::new(link_stats),
+        reconnect,
+        shutdown: watch::Sender::new(false),
     }))
 }
 
+fn seed_history(conn: &Connection, seed: &std::path::Path, max_samples: u32) -> io::Result<()> {
+    if !history::is_empty(conn)? {
+        return Ok(());
+    }
+    // Best effort: a controller without history still schedules.
+    match std::fs::File::open(seed).and_then(|file| history::import(conn, file, max_samples)) {
+        Ok(stats) => tracing::info!(
+            seed = %seed.display(),
+            rows = stats.rows,
+            inserted = stats.inserted,
+            "seeded build history"
+        ),
+        Err(err) => tracing::warn!(seed = %seed.display(), ?err, "build history not seeded"),
+    }
+    Ok(())
+}
+
 pub async fn run(config: ControllerConfig) -> io::Result<()> {
     let state = open_state(config).await?;
     tracing::info!(
         targets = ?state.config.targets.iter().map(|t| &t.name).collect::<Vec<_>>(),
         "nbb-controller starting"
     );
+    let tasks = spawn_tasks(&state);
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
+    tracing::info!("nbb-controller shutting down");
+    shutdown(&state, tasks).await
+}
+
+/// How long [`shutdown`] waits for hooks and agents before abandoning them.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+/// How long an agent session waits for the agent to answer `GOODBYE`.
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Stop the tasks from [`spawn_tasks`] in order: the listeners close and
+/// finish the hook connections they have, each agent session sends
+/// `GOODBYE` and records the finishes that were already on the wire, the
+/// watchdog stops. Whatever is still running after [`SHUTDOWN_GRACE`] is
+/// aborted. Then the WAL is checkpointed so the database file is complete
+/// on its own.
+pub async fn shutdown(state: &Arc<ControllerState>, mut tasks: JoinSet<()>) -> io::Result<()> {
+    state.begin_shutdown();
+    let drained = tokio::time::timeout(SHUTDOWN_GRACE, async {
+        while tasks.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        tracing::warn!(
+            remaining = tasks.len(),
+            "tasks still running after the shutdown grace period; aborting them"
+        );
+        tasks.shutdown().await;
+    }
+    let conn = state.conn.lock().await;
+    maintenance::checkpoint(&conn)?;
+    tracing::info!("database flushed");
+    Ok(())
+}
+
+/// Spawn the controller's long-running tasks: one poller per target, the
+/// hook socket listener and the watchdog. [`shutdown`] stops them
+/// gracefully; dropping the returned set aborts them, which is how the
+/// integration harness simulates a controller crash.
+pub fn spawn_tasks(state: &Arc<ControllerState>) -> JoinSet<()> {
+    // A controller that was shut down may be started again.
+    state.shutdown.send_replace(false);
+    let mut tasks = JoinSet::new();
 
-    // Spawn one poller per target.
     for target in &state.config.targets {
         let target = target.clone();
-        let state = Arc::clone(&state);
-        tokio::spawn(async move { target_poller_loop(target, state).await });
+        let state = Arc::clone(state);
+        tasks.spawn(async move { target_poller_loop(target, state).await });
     }
 
-    // Hook socket listener.
-    let hook_state = Arc::clone(&state);
-    tokio::spawn(async move {
+    let hook_state = Arc::clone(state);
+    tasks.spawn(async move {
         if let Err(err) = hook_socket_listener(hook_state).await {
             tracing::error!(?err, "hook socket listener exited");
         }
     });
 
-    // Watchdog.
-    let wd_state = Arc::clone(&state);
-    tokio::spawn(async move { watchdog_loop(wd_state).await });
+    if let Some(remote) = state.config.remote_hooks.clone() {
+        let remote_state = Arc::clone(state);
+        tasks.spawn(async move {
+            if let Err(err) = remote_hook_listener(remote, remote_state).await {
+                tracing::error!(?err, "remote hook listener exited");
+            }
+        });
+    }
 
-    tokio::signal::ctrl_c().await?;
-    tracing::info!("nbb-controller shutting down");
-    Ok(())
+    let wd_state = Arc::clone(state);
+    tasks.spawn(async move { watchdog_loop(wd_state).await });
+
+    tasks
 }
 
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+/// A suspended host drops SYNs rather than refusing them; without a bound
+/// the kernel's retries would keep the poller from noticing it woke.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 async fn target_poller_loop(target: Target, state: Arc<ControllerState>) {
-    let mut backoff = Duration::from_millis(500);
+    let mut backoff = RECONNECT_BACKOFF_MIN;
     loop {
-        let outcome = match TcpStream::connect(target.tcp_addr).await {
-            Ok(stream) => run_target_session(stream, &target, &state).await,
-            Err(err) => Err(err),
+        let connect = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(target.tcp_addr));
+        let connected = tokio::select! {
+            connected = connect => connected,
+            _ = state.shutting_down() => return,
         };
-        if let Err(err) = outcome {
-            tracing::warn!(target = %target.name, ?err, "agent connection ended");
-            // Clear runtime so the scheduler considers this target stale.
-            state
-                .target_runtimes
-                .lock()
-                .expect("target_runtimes")
-                .insert(target.name.clone(), TargetRuntime::default());
+        let outcome = match connected {
+            Ok(Ok(stream)) => run_target_session(stream, &target, &state).await,
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out")),
+        };
+        let shutting_down = *state.shutdown.borrow();
+        match outcome {
+            Ok(()) if shutting_down => {}
+            Ok(()) => tracing::warn!(target = %target.name, "agent closed the connection"),
+            Err(err) => tracing::warn!(target = %target.name, ?err, "agent connection ended"),
+        }
+        // Clear runtime so the scheduler considers this target stale. A
+        // session that got as far as a PONG was healthy, so the next attempt
+        // starts from the short backoff again rather than wherever earlier
+        // outages left it.
+        let was_live = state
+            .target_runtimes
+            .lock()
+            .expect("target_runtimes")
+            .insert(target.name.clone(), TargetRuntime::default())
+            .is_some_and(|rt| rt.last_pong_ms.is_some());
+        if shutting_down {
+            return;
+        }
+        if was_live {
+            backoff = RECONNECT_BACKOFF_MIN;
+        }
+        let woken = state.reconnect[&target.name].notified();
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {
+                backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+            }
+            _ = woken => backoff = RECONNECT_BACKOFF_MIN,
+            _ = state.shutting_down() => return,
         }
-        tokio::time::sleep(backoff).await;
-        backoff = (backoff * 2).min(Duration::from_secs(30));
     }
 }
 
@@ -185,9 +456,34 @@ where
     }
     let _hello: AgentHello = hello_frame.decode_body()?;
     tracing::info!(target = %target.name, "agent handshake complete");
+    // A target that just came up is not idle yet.
+    state
+        .wake
+        .lock()
+        .expect("wake")
+        .note_busy(&target.name, now_ms_u64());
+
+    let (query_tx, mut query_rx) = mpsc::unbounded_channel();
+    let (notice_tx, mut notice_rx) = mpsc::unbounded_channel();
+    let suspend_hint = Arc::new(Notify::new());
+    {
+        let mut runtimes = state.target_runtimes.lock().expect("target_runtimes");
+        let rt = runtimes.entry(target.name.clone()).or_default();
+        rt.path_queries = Some(query_tx);
+        rt.build_notices = Some(notice_tx);
+        rt.suspend_hint = Some(Arc::clone(&suspend_hint));
+    }
+    let mut pending_queries: HashMap<u64, oneshot::Sender<Vec<String>>> = HashMap::new();
+    let mut next_query_id = 0u64;
 
     let mut ticker = interval(state.config.poll_interval);
     ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut prober = LinkProber::new(state.config.link_probe.as_ref());
+
+    // A frame read abandoned halfway would desync the stream, so one read
+    // stays in flight across iterations instead of restarting per select.
+    let (reader, mut stream) = tokio::io::split(stream);
+    let mut next_frame = Box::pin(read_owned(reader));
 
     loop {
         tokio::select! {
@@ -195,8 +491,72 @@ where
                 write_frame_async(&mut stream, &Frame::empty(op::PING)).await?;
                 write_frame_async(&mut stream, &Frame::empty(op::TELEMETRY_GET)).await?;
             }
-            frame_result = read_frame_async(&mut stream) => {
+            Some(request) = query_rx.recv() => {
+                // Callers that gave up waiting leave their slot behind.
+                pending_queries.retain(|_, reply| !reply.is_closed());
+                next_query_id += 1;
+                let query = PathsQuery { id: next_query_id, paths: request.paths };
+                write_frame_async(&mut stream, &Frame::with_body(op::PATHS_QUERY, &query)?).await?;
+                pending_queries.insert(next_query_id, request.reply);
+            }
+            Some(notice) = notice_rx.recv() => {
+                let frame = match notice {
+                    BuildNotice::Admitted(admitted) => Frame::with_body(op::BUILD_ADMITTED, &admitted)?,
+                    BuildNotice::Cancelled(cancelled) => Frame::with_body(op::BUILD_CANCELLED, &cancelled)?,
+                };
+                write_frame_async(&mut stream, &frame).await?;
+            }
+            _ = prober.due() => {
+                let probe = prober.rtt_probe(std::time::Instant::now());
+                write_frame_async(&mut stream, &Frame::with_body(op::LINK_PROBE, &probe)?).await?;
+                let probe = prober.bulk_probe(std::time::Instant::now());
+                write_frame_async(&mut stream, &Frame::with_body(op::LINK_PROBE, &probe)?).await?;
+            }
+            _ = suspend_hint.notified() => {
+                write_frame_async(&mut stream, &Frame::empty(op::SUSPEND_HINT)).await?;
+            }
+            _ = state.shutting_down() => {
+                write_frame_async(&mut stream, &Frame::empty(op::GOODBYE)).await?;
+                // Finishes the agent pushed before it saw GOODBYE are still
+                // on the wire and already gone from its spool; its own
+                // GOODBYE says there are no more.
+                let drain = async {
+                    loop {
+                        let (reader, frame_result) = (&mut next_frame).await;
+                        next_frame.set(read_owned(reader));
+                        match frame_result {
+                            Ok(frame) if frame.op_id == op::GOODBYE => return Ok(()),
+                            // Nobody is waiting for probe answers any more.
+                            Ok(frame)
+                                if frame.op_id == op::PATHS_PRESENT
+                                    || frame.op_id == op::LINK_PROBE_REPLY => {}
+                            Ok(frame) => handle_from_agent(&target.name, frame, state).await?,
+                            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                            Err(err) => return Err(err),
+                        }
+                    }
+                };
+                return tokio::time::timeout(GOODBYE_TIMEOUT, drain)
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(io::Error::new(io::ErrorKind::TimedOut, "agent did not answer GOODBYE"))
+                    });
+            }
+            (reader, frame_result) = &mut next_frame => {
+                next_frame.set(read_owned(reader));
                 match frame_result {
+                    Ok(frame) if frame.op_id == op::PATHS_PRESENT => {
+                        let present: PathsPresent = frame.decode_body()?;
+                        if let Some(reply) = pending_queries.remove(&present.id) {
+                            let _ = reply.send(present.present);
+                        }
+                    }
+                    Ok(frame) if frame.op_id == op::LINK_PROBE_REPLY => {
+                        let reply: LinkProbeReply = frame.decode_body()?;
+                        if let Some(sample) = prober.answered(&reply, std::time::Instant::now()) {
+                            record_link_sample(state, &target.name, sample).await?;
+                        }
+                    }
                     Ok(frame) => handle_from_agent(&target.name, frame, state).await?,
                     Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
                     Err(err) => return Err(err),
@@ -206,6 +566,47 @@ where
     }
 }
 
+/// Probe rows kept per target: a week's worth at the default interval.
+const LINK_PROBES_KEPT: u32 = 1000;
+
+/// Store one probe round's figures and refresh what the scheduler sees.
+async fn record_link_sample(
+    state: &Arc<ControllerState>,
+    target: &str,
+    sample: LinkStats,
+) -> io::Result<()> {
+    let row = links::LinkProbeRow {
+        target_name: target.to_string(),
+        probed_at_ms: now_ms_u64(),
+        rtt_us: sample.rtt_us,
+        bytes_per_sec: sample.bytes_per_sec,
+    };
+    let stats = {
+        let conn = state.conn.lock().await;
+        links::record(&conn, &row, LINK_PROBES_KEPT)?;
+        links::stats(&conn, target)?
+    };
+    tracing::debug!(
+        target,
+        rtt_us = sample.rtt_us,
+        bytes_per_sec = sample.bytes_per_sec,
+        "link probed"
+    );
+    if let Some(stats) = stats {
+        state
+            .links
+            .lock()
+            .expect("links")
+            .insert(target.to_string(), stats);
+    }
+    Ok(())
+}
+
+async fn read_owned<R: AsyncRead + Unpin>(mut reader: R) -> (R, io::Result<Frame>) {
+    let result = read_frame_async(&mut reader).await;
+    (reader, result)
+}
+
 async fn handle_from_agent(
     target_name: &str,
     frame: Frame,
@@ -214,23 +615,57 @@ async fn handle_from_agent(
     match frame.op_id {
         op::PONG => {
             let now = now_ms_u64();
-            let mut runtimes = state.target_runtimes.lock().expect("target_runtimes");
-            runtimes
+            state
+                .target_runtimes
+                .lock()
+                .expect("target_runtimes")
                 .entry(target_name.to_string())
                 .or_default()
                 .last_pong_ms = Some(now);
+            if let Some(since) = state.wake.lock().expect("wake").woke(target_name) {
+                tracing::info!(
+                    target = %target_name,
+                    after_ms = now.saturating_sub(since),
+                    "woken target is answering"
+                );
+            }
         }
         op::TELEMETRY => {
             let body: TelemetryBody = frame.decode_body()?;
             let mut runtimes = state.target_runtimes.lock().expect("target_runtimes");
-            runtimes
-                .entry(target_name.to_string())
-                .or_default()
-                .last_telemetry = Some(body);
+            let rt = runtimes.entry(target_name.to_string()).or_default();
+            let was_full = rt.last_telemetry.as_ref().is_some_and(|t| t.spool_full);
+            if body.spool_full && !was_full {
+                tracing::warn!(
+                    target = %target_name,
+                    backlog = body.spool_backlog,
+                    "agent spool is full; its build events are being dropped"
+                );
+            }
+            rt.last_telemetry = Some(body);
         }
         op::EVENT_BUILD_FINISH => {
             let event: EventBuildFinish = frame.decode_body()?;
-            record_finish(state, event).await?;
+            record_finish(state, target_name, event).await?;
+        }
+        op::BUILD_GONE => {
+            let gone: BuildGone = frame.decode_body()?;
+            let _span = build_span(Some(&gone.correlation), &gone.drv_path).entered();
+            match gone.lingering {
+                Some(0) => tracing::info!(
+                    target = %target_name,
+                    "cancelled build is gone from its target"
+                ),
+                Some(lingering) => tracing::warn!(
+                    target = %target_name,
+                    lingering,
+                    "cancelled build still has processes on its target"
+                ),
+                None => tracing::debug!(
+                    target = %target_name,
+                    "target cannot tell whether the cancelled build is gone"
+                ),
+            }
         }
         other => {
             tracing::warn!(target = %target_name, op = other, "controller got unexpected op");
@@ -239,19 +674,44 @@ async fn handle_from_agent(
     Ok(())
 }
 
-/// Apply one EVENT_BUILD_FINISH: maybe write an observation row (only when
-/// `duration_ms` is `Some`), and unconditionally retire the matching
-/// admission.
+/// Apply one EVENT_BUILD_FINISH from `target_name`'s agent: maybe write an
+/// observation row (only when `duration_ms` is `Some`), note the outcome
+/// against the target, and unconditionally retire the matching admission.
 pub async fn record_finish(
     state: &Arc<ControllerState>,
+    target_name: &str,
     event: EventBuildFinish,
 ) -> io::Result<()> {
     let max = state.config.max_samples_per_pname;
     let drv = event.drv_path.clone();
     let conn = state.conn.lock().await;
     let wrote = observations::record_finish(&conn, &event, max)?;
+    target_outcomes::record(&conn, &event.pname, target_name, event.status, event.ts_ms)?;
+    if let (true, Some(duration_ms), Some(cpu_ms)) = (
+        event.status == BuildStatus::Success,
+        event.duration_ms.filter(|&d| d > 0),
+        event.cpu_ms,
+    ) {
+        let cores = cpu_ms as f64 / duration_ms as f64;
+        parallelism::record(
+            &conn,
+            &event.pname,
+            cores,
+            state.config.ewma_alpha,
+            event.ts_ms,
+        )?;
+    }
+    let admission = admissions::get(&conn, &drv)?;
     admissions::retire(&conn, &drv)?;
     drop(conn);
+    let correlation = event
+        .correlation
+        .clone()
+        .or_else(|| admission.as_ref().and_then(|a| a.correlation.clone()));
+    let _span = build_span(correlation.as_deref(), &drv).entered();
+    if let Some(admission) = admission {
+        note_outcome(state, &admission.target_name, event.status);
+    }
     if wrote {
         tracing::info!(
             host = %event.host,
@@ -285,40 +745,183 @@ async fn hook_socket_listener(state: Arc<ControllerState>) -> io::Result<()> {
     let _ = std::fs::remove_file(&path);
     let listener = UnixListener::bind(&path)?;
     tracing::info!(socket = %path.display(), "hook socket listening");
+    let mut connections = JoinSet::new();
     loop {
-        let (stream, _addr) = listener.accept().await?;
+        let (stream, _addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = state.shutting_down() => break,
+        };
+        while connections.try_join_next().is_some() {}
         let state = Arc::clone(&state);
-        tokio::spawn(async move {
+        connections.spawn(async move {
             if let Err(err) = handle_hook_connection(stream, state).await {
                 tracing::warn!(?err, "hook connection ended");
             }
         });
     }
+    // New hooks fail to connect and decline; connected ones get answers.
+    drop(listener);
+    let _ = std::fs::remove_file(&path);
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
+
+async fn remote_hook_listener(
+    remote: RemoteHookConfig,
+    state: Arc<ControllerState>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(remote.listen).await?;
+    tracing::info!(addr = %remote.listen, "remote hook listener up");
+    let key: Arc<[u8]> = remote.key.into();
+    let unauthenticated = Arc::new(Semaphore::new(remote.max_unauthenticated));
+    let mut connections = JoinSet::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = state.shutting_down() => break,
+        };
+        while connections.try_join_next().is_some() {}
+        let Ok(permit) = Arc::clone(&unauthenticated).try_acquire_owned() else {
+            tracing::warn!(%peer, "too many remote hooks authenticating; closing");
+            continue;
+        };
+        let state = Arc::clone(&state);
+        let key = Arc::clone(&key);
+        let auth_timeout = remote.auth_timeout;
+        connections.spawn(async move {
+            let served =
+                handle_remote_hook_connection(stream, state, &key, auth_timeout, permit).await;
+            if let Err(err) = served {
+                tracing::warn!(%peer, ?err, "remote hook connection ended");
+            }
+        });
+    }
+    drop(listener);
+    while connections.join_next().await.is_some() {}
+    Ok(())
 }
 
+/// Serve a hook on the controller host over the Unix socket.
 pub async fn handle_hook_connection<S>(mut stream: S, state: Arc<ControllerState>) -> io::Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     perform_handshake_async(&mut stream).await?;
+    serve_hook(stream, state, None).await
+}
 
+/// Serve a hook on another host: after the handshake it must authenticate
+/// with `key` within `auth_timeout`, and its candidates are scheduled
+/// relative to the host name it authenticated as. `unauthenticated` is
+/// held until then.
+pub async fn handle_remote_hook_connection<S>(
+    mut stream: S,
+    state: Arc<ControllerState>,
+    key: &[u8],
+    auth_timeout: Duration,
+    unauthenticated: OwnedSemaphorePermit,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let authenticated = tokio::time::timeout(auth_timeout, async {
+        perform_handshake_async(&mut stream).await?;
+        auth::challenge_async(&mut stream, key).await
+    })
+    .await;
+    drop(unauthenticated);
+    let host = authenticated.map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            "remote hook did not authenticate in time",
+        )
+    })??;
+    tracing::debug!(%host, "remote hook authenticated");
+    serve_hook(stream, state, Some(host)).await
+}
+
+async fn serve_hook<S>(
+    mut stream: S,
+    state: Arc<ControllerState>,
+    requesting_host: Option<String>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     loop {
-        let frame = match read_frame_async(&mut stream).await {
-            Ok(f) => f,
-            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
-            Err(err) => return Err(err),
+        // A hook keeps its connection between requests, for as long as it
+        // runs; shutdown closes idle ones rather than waiting them out.
+        let frame = tokio::select! {
+            frame = read_frame_async(&mut stream) => match frame {
+                Ok(f) => f,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            },
+            _ = state.shutting_down() => return Ok(()),
         };
         match frame.op_id {
             op::DECIDE_CANDIDATE => {
                 let candidate: DecideCandidate = frame.decode_body()?;
-                let decision = make_decision(&state, &candidate).await?;
+                let decision =
+                    make_decision_for(&state, &candidate, requesting_host.as_deref()).await?;
                 let reply = Frame::with_body(op::DECISION, &decision)?;
-                write_frame_async(&mut stream, &reply).await?;
+                if let Err(err) = write_frame_async(&mut stream, &reply).await {
+                    if let Decision::Accept { correlation, .. } = &decision {
+                        undelivered_accept(&state, &candidate.drv_path)
+                            .instrument(build_span(Some(correlation), &candidate.drv_path))
+                            .await;
+                    }
+                    return Err(err);
+                }
             }
             op::ADMISSION_FINISH => {
                 let finish: AdmissionFinish = frame.decode_body()?;
-                let conn = state.conn.lock().await;
-                admissions::retire(&conn, &finish.drv_path)?;
+                admission_finished(&state, &finish)
+                    .instrument(build_span(Some(&finish.correlation), &finish.drv_path))
+                    .await?;
+            }
+            op::SESSION_SUMMARY_GET => {
+                let request: SessionSummaryRequest = frame.decode_body()?;
+                let summary = {
+                    let conn = state.conn.lock().await;
+                    let session = match request.session {
+                        Some(session) => Some(session),
+                        None => sessions::latest(&conn)?,
+                    };
+                    match session {
+                        Some(session) => sessions::summary(&conn, &session, request.top_n)?,
+                        None => None,
+                    }
+                };
+                let reply = Frame::with_body(op::SESSION_SUMMARY, &summary)?;
+                write_frame_async(&mut stream, &reply).await?;
+            }
+            op::SESSION_PRIORITY_SET => {
+                let request: SessionPriorityRequest = frame.decode_body()?;
+                // Only `nbb-controller priority`, on this host's socket,
+                // registers priorities; a remote hook could otherwise
+                // reorder another host's rebuild.
+                let session = if let Some(host) = &requesting_host {
+                    tracing::warn!(%host, "remote hook tried to set a session priority");
+                    None
+                } else {
+                    let conn = state.conn.lock().await;
+                    let session = match request.session {
+                        Some(session) => Some(session),
+                        None => sessions::latest(&conn)?,
+                    };
+                    if let Some(session) = &session {
+                        sessions::set_priority(&conn, session, request.priority, now_ms_u64())?;
+                        tracing::info!(
+                            %session,
+                            priority = request.priority.as_str(),
+                            "session priority set"
+                        );
+                    }
+                    session
+                };
+                let reply = Frame::with_body(op::SESSION_PRIORITY, &session)?;
+                write_frame_async(&mut stream, &reply).await?;
             }
             other => {
                 tracing::warn!(op = other, "hook sent unexpected op");
@@ -327,18 +930,229 @@ where
     }
 }
 
+/// The hook gave up on an `Accept` before it arrived (its decision
+/// timeout ran out), and Nix builds the candidate locally: the admission
+/// would only hold a slot until the TTL.
+async fn undelivered_accept(state: &ControllerState, drv_path: &str) {
+    tracing::warn!("hook went away before its decision arrived; retiring the admission");
+    let conn = state.conn.lock().await;
+    if let Err(err) = admissions::retire(&conn, drv_path) {
+        tracing::warn!(?err, "admission not retired");
+    }
+}
+
+async fn admission_finished(
+    state: &Arc<ControllerState>,
+    finish: &AdmissionFinish,
+) -> io::Result<()> {
+    let conn = state.conn.lock().await;
+    let admission = admissions::get(&conn, &finish.drv_path)?;
+    if let (Some(admission), Some(tail)) = (&admission, &finish.log_tail) {
+        let row = failure_tails::FailureTailRow {
+            pname: pname_from_drv(&finish.drv_path),
+            drv_path: finish.drv_path.clone(),
+            target_name: admission.target_name.clone(),
+            status: finish.status.as_str().to_string(),
+            finished_at_ms: now_ms_u64(),
+            tail: tail.clone(),
+        };
+        if let Err(err) = failure_tails::record(&conn, &row, state.config.failure_tails_per_pname) {
+            tracing::warn!(?err, drv = %finish.drv_path, "failure tail not recorded");
+        }
+    }
+    if let Some(admission) = &admission {
+        target_outcomes::record(
+            &conn,
+            &pname_from_drv(&finish.drv_path),
+            &admission.target_name,
+            finish.status,
+            now_ms_u64(),
+        )?;
+    }
+    if finish.status == BuildStatus::InfraFailure {
+        if let Some(admission) = &admission {
+            let until = now_ms_u64().saturating_add(
+                state
+                    .config
+                    .unhealthy_cooldown
+                    .as_millis()
+                    .min(u128::from(u64::MAX)) as u64,
+            );
+            tracing::warn!(
+                drv = %finish.drv_path,
+                target = %admission.target_name,
+                until_ms = until,
+                "infrastructure failure; marking target unhealthy"
+            );
+            state
+                .health
+                .lock()
+                .expect("health")
+                .mark_unhealthy(&admission.target_name, until);
+        }
+    }
+    admissions::retire(&conn, &finish.drv_path)?;
+    drop(conn);
+    if let Some(admission) = admission {
+        note_outcome(state, &admission.target_name, finish.status);
+        if finish.status == BuildStatus::Cancelled {
+            let cancelled = BuildCancelled {
+                drv_path: finish.drv_path.clone(),
+                correlation: finish.correlation.clone(),
+            };
+            notify_agent(
+                state,
+                &admission.target_name,
+                BuildNotice::Cancelled(cancelled),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Pass an admission or a hook's cancellation on to the target's agent,
+/// if connected.
+fn notify_agent(state: &ControllerState, target: &str, notice: BuildNotice) {
+    let runtimes = state.target_runtimes.lock().expect("target_runtimes");
+    if let Some(sender) = runtimes
+        .get(target)
+        .and_then(|rt| rt.build_notices.as_ref())
+    {
+        let _ = sender.send(notice);
+    }
+}
+
+/// Count the outcome of a retired admission towards its target's failure
+/// rate. Only the first report for an admission gets here — whichever of
+/// the hook's `ADMISSION_FINISH` and the agent's `EVENT_BUILD_FINISH`
+/// retires it — so each build counts once.
+fn note_outcome(state: &ControllerState, target: &str, status: BuildStatus) {
+    let failed = match status {
+        BuildStatus::Success => false,
+        // A target `nix __build-remote` keeps turning down is as useless
+        // as one whose builds fail.
+        BuildStatus::Failure | BuildStatus::Declined => true,
+        // Benched through `unhealthy_cooldown` instead.
+        BuildStatus::InfraFailure => return,
+        // The client went away: not the builder's verdict, and one
+        // interrupted rebuild would otherwise quarantine every target it
+        // was using.
+        BuildStatus::Cancelled => return,
+    };
+    let quarantined = state.health.lock().expect("health").record_outcome(
+        target,
+        now_ms_u64(),
+        failed,
+        &state.config.quarantine,
+    );
+    if let Some(until) = quarantined {
+        tracing::warn!(
+            target,
+            until_ms = until,
+            "failure burst; quarantining target"
+        );
+    }
+}
+
+/// Decide a candidate from the controller host's own hook.
 pub async fn make_decision(
     state: &Arc<ControllerState>,
     candidate: &DecideCandidate,
+) -> io::Result<Decision> {
+    make_decision_for(state, candidate, None).await
+}
+
+/// Decide a candidate from the hook on `requesting_host` (`None`: the
+/// controller host). A win for that host's target is `RouteLocal`, except
+/// on a retry, which Nix can no longer build locally and so is accepted
+/// onto it.
+pub async fn make_decision_for(
+    state: &Arc<ControllerState>,
+    candidate: &DecideCandidate,
+    requesting_host: Option<&str>,
+) -> io::Result<Decision> {
+    let started = Instant::now();
+    let correlation = candidate
+        .correlation
+        .clone()
+        .unwrap_or_else(new_correlation);
+    let span = build_span(Some(&correlation), &candidate.drv_path);
+    decide(state, candidate, requesting_host, correlation, started)
+        .instrument(span)
+        .await
+}
+
+async fn decide(
+    state: &Arc<ControllerState>,
+    candidate: &DecideCandidate,
+    requesting_host: Option<&str>,
+    correlation: String,
+    started: Instant,
 ) -> io::Result<Decision> {
     let pname = pname_from_drv(&candidate.drv_path);
-    let (estimate, admissions_rows) = {
+    // The hook's reading of the `.drv` wins: a remote hook's candidate is
+    // usually not in this host's store. It feeds the estimator's
+    // builder-kind tier, the scheduler's local-only fast path and the
+    // substitution probe.
+    let drv_details = match &candidate.drv {
+        Some(details) => Some(details.clone()),
+        None => read_drv_details(&candidate.drv_path).await,
+    };
+    let builder_kind = drv_details.as_ref().map(|d| d.builder_kind.clone());
+    let hints = drv_details
+        .as_ref()
+        .map(LocalityHints::from_drv)
+        .unwrap_or_default();
+    let (prediction, output_bytes, admissions_rows, failed_on, mut pname_cores, registered) = {
         let conn = state.conn.lock().await;
+        if let Some(kind) = builder_kind.as_deref() {
+            observations::record_builder_kind(&conn, &pname, kind)?;
+        }
+        let admissions_rows = admissions::list(&conn)?;
+        let admitted_pnames: Vec<String> = admissions_rows
+            .iter()
+            .map(|a| pname_from_drv(&a.drv_path))
+            .collect();
         (
-            observations::predict_ms(&conn, &pname, state.config.ewma_alpha, state.config.ewma_z)?,
-            admissions::list(&conn)?,
+            observations::predict(
+                &conn,
+                &pname,
+                builder_kind.as_deref(),
+                state.config.ewma_alpha,
+                state.config.ewma_z,
+            )?,
+            observations::predict_output_bytes(&conn, &pname)?,
+            admissions_rows,
+            target_outcomes::failed_targets(&conn, &pname)?,
+            parallelism::get_many(&conn, admitted_pnames.iter().map(String::as_str))?,
+            match &candidate.session {
+                Some(session) => sessions::priority(&conn, session)?,
+                None => None,
+            },
         )
     };
+    // A priority registered for the session beats the hook's
+    // `NBB_PRIORITY`, which under multi-user Nix is the daemon's.
+    let reprioritised;
+    let candidate = match registered {
+        Some(priority) if priority != candidate.priority => {
+            reprioritised = DecideCandidate {
+                priority,
+                ..candidate.clone()
+            };
+            &reprioritised
+        }
+        _ => candidate,
+    };
+    pname_cores.extend(
+        state
+            .config
+            .pname_cores
+            .iter()
+            .map(|(pname, cores)| (pname.clone(), *cores)),
+    );
+    let estimate = prediction.map(|p| p.ms);
+    let estimate_tier = prediction.map_or(EstimateTier::Default, |p| p.tier);
 
     let target_states = state.build_target_states();
     let inputs = SchedulerInputs {
@@ -354,18 +1168,85 @@ pub async fn make_decision(
         admissions: &admissions_rows,
         targets: &target_states,
         duration_estimate_ms: estimate,
+        output_bytes_estimate: output_bytes,
+        hints,
+        failed_on: &failed_on,
+        pname_cores: &pname_cores,
+        requesting_host,
     };
 
-    match scheduler::decide(&inputs) {
+    let mut evaluation = scheduler::evaluate(&inputs);
+    if let Some(target) = wake::target_to_wake(&evaluation, &target_states, &state.config.wake) {
+        wake_target(state, target);
+    }
+    let mut substituted = false;
+    let output_paths = drv_details.and_then(|d| d.output_paths);
+    if let (Some(timeout), Some(paths)) = (state.config.substitute_probe_timeout, output_paths) {
+        let holder = find_output_holder(state, &evaluation, paths, timeout, requesting_host).await;
+        if let Some(holder) = holder {
+            tracing::info!(
+                drv = %candidate.drv_path,
+                target = %holder.name,
+                "outputs already present on target; accepting to copy them back"
+            );
+            evaluation.decision = SchedulerDecision::Accept {
+                target: AcceptTarget {
+                    name: holder.name.clone(),
+                    store_uri: holder.store_uri.clone(),
+                    builder_line: holder.builder_line.clone(),
+                },
+                predicted_ms: 1,
+            };
+            substituted = true;
+        }
+    }
+    let row = decision_row(
+        candidate,
+        &pname,
+        estimate,
+        estimate_tier,
+        &evaluation,
+        substituted,
+        started.elapsed(),
+    );
+    {
+        let conn = state.conn.lock().await;
+        if let Err(err) = decisions::record(&conn, &row) {
+            tracing::warn!(drv = %candidate.drv_path, ?err, "failed to append decision log");
+        }
+    }
+
+    match evaluation.decision {
         SchedulerDecision::Decline => {
             tracing::info!(
                 drv = %candidate.drv_path,
                 pname = %pname,
                 system = %candidate.system,
                 estimate_ms = ?estimate,
+                estimate_tier = estimate_tier.as_str(),
                 "decision: decline"
             );
-            Ok(Decision::Decline)
+            Ok(Decision::Decline { correlation })
+        }
+        SchedulerDecision::Postpone => {
+            tracing::info!(
+                drv = %candidate.drv_path,
+                pname = %pname,
+                priority = candidate.priority.as_str(),
+                "decision: postpone (every target busy)"
+            );
+            Ok(Decision::Postpone { correlation })
+        }
+        SchedulerDecision::LocalFastPath { reason } => {
+            tracing::info!(
+                drv = %candidate.drv_path,
+                pname = %pname,
+                reason = reason.as_str(),
+                estimate_ms = ?estimate,
+                estimate_tier = estimate_tier.as_str(),
+                "decision: decline (local fast path)"
+            );
+            Ok(Decision::Decline { correlation })
         }
         SchedulerDecision::RouteLocal {
             target_name,
@@ -378,17 +1259,21 @@ pub async fn make_decision(
                 &target_name,
                 now_ms_u64(),
                 predicted_ms,
+                &correlation,
             )?;
             drop(conn);
+            notify_admitted(state, &target_name, &candidate.drv_path, &correlation);
             tracing::info!(
+                requesting_host = ?requesting_host,
                 drv = %candidate.drv_path,
                 pname = %pname,
                 target = %target_name,
                 predicted_ms,
                 estimate_ms = ?estimate,
+                estimate_tier = estimate_tier.as_str(),
                 "decision: route-local (admission recorded; nix builds locally)"
             );
-            Ok(Decision::Decline)
+            Ok(Decision::Decline { correlation })
         }
         SchedulerDecision::Accept {
             target,
@@ -401,26 +1286,205 @@ pub async fn make_decision(
                 &target.name,
                 now_ms_u64(),
                 predicted_ms,
+                &correlation,
             )?;
             drop(conn);
+            notify_admitted(state, &target.name, &candidate.drv_path, &correlation);
             tracing::info!(
+                requesting_host = ?requesting_host,
                 drv = %candidate.drv_path,
                 pname = %pname,
                 target = %target.name,
                 predicted_ms,
                 estimate_ms = ?estimate,
+                estimate_tier = estimate_tier.as_str(),
                 "decision: accept"
             );
-            Ok(Decision::Accept { target })
+            Ok(Decision::Accept {
+                target,
+                correlation,
+            })
         }
     }
 }
 
+/// Read the candidate's `.drv` from this host's store.
+async fn read_drv_details(drv_path: &str) -> Option<DrvDetails> {
+    match tokio::fs::read_to_string(drv_path).await {
+        Ok(text) => match drv::parse(&text) {
+            Ok(info) => Some(info.details()),
+            Err(err) => {
+                tracing::debug!(drv = %drv_path, ?err, "unparseable .drv");
+                None
+            }
+        },
+        Err(err) => {
+            tracing::debug!(drv = %drv_path, ?err, ".drv not readable");
+            None
+        }
+    }
+}
+
+fn notify_admitted(state: &ControllerState, target: &str, drv_path: &str, correlation: &str) {
+    let admitted = BuildAdmitted {
+        drv_path: drv_path.to_string(),
+        correlation: correlation.to_string(),
+    };
+    notify_agent(state, target, BuildNotice::Admitted(admitted));
+}
+
+/// Send `target` a magic packet and mark it waking. Its poller is nudged
+/// so the agent is dialled as soon as it is up, not after the backoff.
+fn wake_target(state: &ControllerState, target: &Target) {
+    let Some(wol) = &target.wake else {
+        return;
+    };
+    match wake::send_magic_packet(wol) {
+        Ok(()) => tracing::info!(
+            target = %target.name,
+            broadcast = %wol.broadcast,
+            "live targets are backed up; waking target"
+        ),
+        Err(err) => {
+            tracing::warn!(target = %target.name, ?err, "sending Wake-on-LAN packet failed")
+        }
+    }
+    state
+        .wake
+        .lock()
+        .expect("wake")
+        .mark_waking(&target.name, now_ms_u64());
+    if let Some(reconnect) = state.reconnect.get(&target.name) {
+        reconnect.notify_one();
+    }
+}
+
+/// The first target other than the requesting host, in configured order,
+/// that the scheduler scored for this candidate and whose agent reports
+/// every one of `paths` in its store. Targets are asked concurrently; any
+/// that have not answered within `timeout` count as not holding them.
+async fn find_output_holder<'a>(
+    state: &'a ControllerState,
+    evaluation: &Evaluation,
+    paths: Vec<String>,
+    timeout: Duration,
+    requesting_host: Option<&str>,
+) -> Option<&'a Target> {
+    let scored = |name: &str| {
+        evaluation
+            .targets
+            .iter()
+            .any(|t| t.name == name && matches!(t.verdict, TargetVerdict::Scored { .. }))
+    };
+    let mut asked = Vec::new();
+    {
+        let runtimes = state.target_runtimes.lock().expect("target_runtimes");
+        for target in &state.config.targets {
+            // Nix only asks the hook about outputs the local store lacks.
+            if target.is_requesting_host(requesting_host) || !scored(&target.name) {
+                continue;
+            }
+            let Some(queries) = runtimes
+                .get(&target.name)
+                .and_then(|rt| rt.path_queries.as_ref())
+            else {
+                continue;
+            };
+            let (reply, answer) = oneshot::channel();
+            let request = PathsRequest {
+                paths: paths.clone(),
+                reply,
+            };
+            if queries.send(request).is_ok() {
+                asked.push((target, answer));
+            }
+        }
+    }
+    let deadline = tokio::time::Instant::now() + timeout;
+    for (target, answer) in asked {
+        match tokio::time::timeout_at(deadline, answer).await {
+            Ok(Ok(present)) if paths.iter().all(|p| present.contains(p)) => return Some(target),
+            Ok(_) => {}
+            Err(_) => {
+                tracing::debug!(target = %target.name, "output probe timed out");
+            }
+        }
+    }
+    None
+}
+
+fn decision_row(
+    candidate: &DecideCandidate,
+    pname: &str,
+    estimate: Option<u64>,
+    estimate_tier: EstimateTier,
+    evaluation: &Evaluation,
+    substituted: bool,
+    latency: Duration,
+) -> DecisionRow {
+    let (outcome, winner) = match &evaluation.decision {
+        SchedulerDecision::Accept { target, .. } if substituted => {
+            ("substitute".to_string(), Some(target.name.clone()))
+        }
+        SchedulerDecision::Decline => ("decline".to_string(), None),
+        SchedulerDecision::Postpone => ("postpone".to_string(), None),
+        SchedulerDecision::LocalFastPath { reason } => {
+            (format!("fast-path:{}", reason.as_str()), None)
+        }
+        SchedulerDecision::RouteLocal { target_name, .. } => {
+            ("route-local".to_string(), Some(target_name.clone()))
+        }
+        SchedulerDecision::Accept { target, .. } => {
+            ("accept".to_string(), Some(target.name.clone()))
+        }
+    };
+    let targets = evaluation
+        .targets
+        .iter()
+        .map(|t| match t.verdict {
+            TargetVerdict::Excluded(exclusion) => DecisionTargetRow {
+                target_name: t.name.clone(),
+                excluded: Some(exclusion.as_str().to_string()),
+                queue_ms: None,
+                package_ms: None,
+                completion_ms: None,
+            },
+            TargetVerdict::Scored {
+                queue_ms,
+                package_ms,
+                completion_ms,
+            } => DecisionTargetRow {
+                target_name: t.name.clone(),
+                excluded: None,
+                queue_ms: Some(queue_ms),
+                package_ms: Some(package_ms),
+                completion_ms: Some(completion_ms),
+            },
+        })
+        .collect();
+    DecisionRow {
+        decided_at_ms: now_ms_u64(),
+        drv_path: candidate.drv_path.clone(),
+        pname: pname.to_string(),
+        system: candidate.system.clone(),
+        estimate_ms: estimate,
+        estimate_tier: estimate_tier.as_str().to_string(),
+        outcome,
+        winner,
+        session: candidate.session.clone(),
+        latency_us: Some(latency.as_micros().min(u128::from(u64::MAX)) as u64),
+        targets,
+    }
+}
+
 async fn watchdog_loop(state: Arc<ControllerState>) {
     let mut ticker = interval(Duration::from_secs(5));
     ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
     loop {
-        ticker.tick().await;
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = state.shutting_down() => return,
+        }
         if let Err(err) = watchdog_tick(&state).await {
             tracing::warn!(?err, "watchdog tick failed");
         }
@@ -432,6 +1496,9 @@ async fn watchdog_loop(state: Arc<ControllerState>) {
 pub async fn watchdog_tick(state: &Arc<ControllerState>) -> io::Result<()> {
     sweep_sentinels(state).await?;
     sweep_wall_clock_ttl(state).await?;
+    prune_decision_log(state).await?;
+    maintain_database(state).await?;
+    hint_idle_targets(state).await?;
     Ok(())
 }
 
@@ -449,12 +1516,15 @@ async fn sweep_sentinels(state: &Arc<ControllerState>) -> io::Result<()> {
             continue;
         };
         if pid_is_dead(sentinel.pid) {
-            tracing::warn!(
-                drv = %sentinel.drv_path,
-                pid = sentinel.pid,
-                "sentinel PID is dead; retiring admission"
-            );
             let conn = state.conn.lock().await;
+            let correlation =
+                admissions::get(&conn, &sentinel.drv_path)?.and_then(|a| a.correlation);
+            build_span(correlation.as_deref(), &sentinel.drv_path).in_scope(|| {
+                tracing::warn!(
+                    pid = sentinel.pid,
+                    "sentinel PID is dead; retiring admission"
+                )
+            });
             admissions::retire(&conn, &sentinel.drv_path)?;
             drop(conn);
             let _ = std::fs::remove_file(&path);
@@ -468,8 +1538,90 @@ async fn sweep_wall_clock_ttl(state: &Arc<ControllerState>) -> io::Result<()> {
     let conn = state.conn.lock().await;
     let stale = admissions::stale_drvs(&conn, now)?;
     for drv in stale {
-        tracing::warn!(drv = %drv, "wall-clock TTL retiring stale admission");
+        let correlation = admissions::get(&conn, &drv)?.and_then(|a| a.correlation);
+        build_span(correlation.as_deref(), &drv)
+            .in_scope(|| tracing::warn!("wall-clock TTL retiring stale admission"));
         admissions::retire(&conn, &drv)?;
     }
     Ok(())
 }
+
+async fn prune_decision_log(state: &Arc<ControllerState>) -> io::Result<()> {
+    let retention_ms = state
+        .config
+        .decision_log_retention
+        .as_millis()
+        .min(u128::from(u64::MAX)) as u64;
+    let cutoff = now_ms_u64().saturating_sub(retention_ms);
+    let conn = state.conn.lock().await;
+    let removed = decisions::prune_before(&conn, cutoff)?;
+    if removed > 0 {
+        tracing::debug!(removed, "pruned decision log");
+    }
+    sessions::prune_priorities_before(&conn, cutoff)?;
+    Ok(())
+}
+
+/// Send `SUSPEND_HINT` once per agent session to wakeable targets that
+/// have had no admissions for `idle_suspend_ms`. Targets without
+/// Wake-on-LAN are never told to sleep: nothing could wake them.
+async fn hint_idle_targets(state: &Arc<ControllerState>) -> io::Result<()> {
+    let idle_suspend_ms = state.config.wake.idle_suspend_ms;
+    if idle_suspend_ms == 0 {
+        return Ok(());
+    }
+    let admitted: Vec<String> = {
+        let conn = state.conn.lock().await;
+        admissions::list(&conn)?
+            .into_iter()
+            .map(|a| a.target_name)
+            .collect()
+    };
+    let now = now_ms_u64();
+    let mut wake = state.wake.lock().expect("wake");
+    let mut runtimes = state.target_runtimes.lock().expect("target_runtimes");
+    for target in state.config.targets.iter().filter(|t| t.wake.is_some()) {
+        if admitted.contains(&target.name) {
+            wake.note_busy(&target.name, now);
+            continue;
+        }
+        let Some(rt) = runtimes.get_mut(&target.name) else {
+            continue;
+        };
+        let Some(hint) = rt.suspend_hint.as_ref().filter(|_| !rt.suspend_hinted) else {
+            continue;
+        };
+        let Some(idle_ms) = wake
+            .idle_for_ms(&target.name, now)
+            .filter(|&idle| idle >= idle_suspend_ms)
+        else {
+            continue;
+        };
+        tracing::info!(target = %target.name, idle_ms, "target idle; hinting that it may suspend");
+        hint.notify_one();
+        rt.suspend_hinted = true;
+    }
+    Ok(())
+}
+
+async fn maintain_database(state: &Arc<ControllerState>) -> io::Result<()> {
+    let policy = &state.config.maintenance;
+    let due = state
+        .maintenance
+        .lock()
+        .expect("maintenance")
+        .take_due(now_ms_u64(), policy);
+    let conn = state.conn.lock().await;
+    if due.checkpoint {
+        let removed = maintenance::cap_observations(&conn, policy.max_observations)?;
+        if removed > 0 {
+            tracing::info!(removed, "observation cap reached; pruned the oldest rows");
+        }
+        maintenance::checkpoint(&conn)?;
+    }
+    if due.vacuum {
+        maintenance::vacuum(&conn)?;
+        tracing::info!("database vacuumed");
+    }
+    Ok(())
+}
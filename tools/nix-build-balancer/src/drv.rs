@@ -0,0 +1,315 @@
+//! Minimal reader for Nix `.drv` files (ATerm `Derive(...)`).
+//!
+//! `nbb-hook` reads the candidate's `.drv` on its own host and sends the
+//! controller what it needs ([`DrvDetails`]); the controller reads it
+//! itself only when a hook sent nothing. We only need the output paths,
+//! the builder and a handful of environment attributes, so this parses the
+//! ATerm grammar generically and picks fields out by position instead of
+//! modelling every Nix version's derivation layout.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use crate::protocol::ops::DrvDetails;
+
+/// The parts of a derivation the controller looks at.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DrvInfo {
+    /// `(output name, store path)`. The path is empty for floating
+    /// content-addressed outputs, which are only known once built.
+    pub outputs: Vec<(String, String)>,
+    pub builder: String,
+    pub env: HashMap<String, String>,
+}
+
+impl DrvInfo {
+    /// Coarse builder classification used by the estimator's builder-kind
+    /// fallback tier.
+    ///
+    /// - `builtin:fetchurl` (and other `builtin:*` builders) keep their name.
+    /// - Fixed-output derivations (`outputHash` set) are `fixed-output`:
+    ///   fetchers whose cost is network-bound, not CPU-bound.
+    /// - Anything built through `stdenv` is `stdenv`.
+    /// - Everything else is `other`.
+    pub fn builder_kind(&self) -> String {
+        if self.builder.starts_with("builtin:") {
+            return self.builder.clone();
+        }
+        if self.env.contains_key("outputHash") {
+            return "fixed-output".to_string();
+        }
+        if self.env.contains_key("stdenv") {
+            return "stdenv".to_string();
+        }
+        "other".to_string()
+    }
+
+    /// `preferLocalBuild = true`: Nix's own "not worth shipping to a
+    /// builder" marker, set by `runCommandLocal`, `writeText` and friends.
+    pub fn prefer_local_build(&self) -> bool {
+        self.bool_attr("preferLocalBuild") == Some(true)
+    }
+
+    /// `allowSubstitutes = false`: usually paired with `preferLocalBuild`
+    /// on trivial builders whose output is cheaper to rebuild than fetch.
+    pub fn allow_substitutes(&self) -> bool {
+        self.bool_attr("allowSubstitutes") != Some(false)
+    }
+
+    /// Every output's store path, or `None` if any is not yet known.
+    pub fn output_paths(&self) -> Option<Vec<String>> {
+        if self.outputs.is_empty() {
+            return None;
+        }
+        self.outputs
+            .iter()
+            .map(|(_, path)| (!path.is_empty()).then(|| path.clone()))
+            .collect()
+    }
+
+    /// What goes to the controller in a `DECIDE_CANDIDATE`.
+    pub fn details(&self) -> DrvDetails {
+        DrvDetails {
+            builder_kind: self.builder_kind(),
+            prefer_local_build: self.prefer_local_build(),
+            allow_substitutes: self.allow_substitutes(),
+            output_paths: self.output_paths(),
+        }
+    }
+
+    /// Read a boolean derivation attribute. Plain attributes serialise
+    /// `true` as `"1"` and `false` as `""`; with `__structuredAttrs` they
+    /// live inside the compact `__json` blob instead.
+    fn bool_attr(&self, name: &str) -> Option<bool> {
+        if let Some(value) = self.env.get(name) {
+            return Some(value == "1");
+        }
+        let json = self.env.get("__json")?;
+        if json.contains(&format!("\"{name}\":true")) {
+            Some(true)
+        } else if json.contains(&format!("\"{name}\":false")) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn read(path: &Path) -> io::Result<DrvInfo> {
+    parse(&std::fs::read_to_string(path)?)
+}
+
+pub fn parse(text: &str) -> io::Result<DrvInfo> {
+    let mut parser = Parser {
+        bytes: text.as_bytes(),
+        pos: 0,
+    };
+    let term = parser.term()?;
+    let Term::Ctor(name, args) = term else {
+        return Err(invalid("derivation is not a constructor term"));
+    };
+    // `Derive(outputs, inputDrvs, inputSrcs, system, builder, args, env)`;
+    // dynamic-derivation drvs prefix a version string.
+    let fields = match name.as_str() {
+        "Derive" => &args[..],
+        "DrvWithVersion" => args.get(1..).unwrap_or(&[]),
+        other => return Err(invalid(&format!("unknown derivation constructor {other}"))),
+    };
+    let [Term::List(outputs), _input_drvs, _input_srcs, _system, Term::Str(builder), _args, Term::List(env)] =
+        fields
+    else {
+        return Err(invalid("unexpected Derive field layout"));
+    };
+    let outputs = outputs
+        .iter()
+        .filter_map(|output| match output {
+            Term::Tuple(fields) => match &fields[..] {
+                [Term::Str(name), Term::Str(path), ..] => Some((name.clone(), path.clone())),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+    let mut env_map = HashMap::new();
+    for pair in env {
+        if let Term::Tuple(kv) = pair {
+            if let [Term::Str(k), Term::Str(v)] = &kv[..] {
+                env_map.insert(k.clone(), v.clone());
+            }
+        }
+    }
+    Ok(DrvInfo {
+        outputs,
+        builder: builder.clone(),
+        env: env_map,
+    })
+}
+
+#[derive(Debug)]
+enum Term {
+    Str(String),
+    List(Vec<Term>),
+    Tuple(Vec<Term>),
+    Ctor(String, Vec<Term>),
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn term(&mut self) -> io::Result<Term> {
+        match self.peek()? {
+            b'"' => self.string().map(Term::Str),
+            b'[' => self.sequence(b'[', b']').map(Term::List),
+            b'(' => self.sequence(b'(', b')').map(Term::Tuple),
+            c if c.is_ascii_alphabetic() => {
+                let start = self.pos;
+                while self.pos < self.bytes.len() && self.bytes[self.pos].is_ascii_alphanumeric() {
+                    self.pos += 1;
+                }
+                let name = String::from_utf8_lossy(&self.bytes[start..self.pos]).into_owned();
+                let args = self.sequence(b'(', b')')?;
+                Ok(Term::Ctor(name, args))
+            }
+            c => Err(invalid(&format!(
+                "unexpected byte {c:#04x} at {}",
+                self.pos
+            ))),
+        }
+    }
+
+    fn sequence(&mut self, open: u8, close: u8) -> io::Result<Vec<Term>> {
+        self.expect(open)?;
+        let mut items = Vec::new();
+        if self.peek()? == close {
+            self.pos += 1;
+            return Ok(items);
+        }
+        loop {
+            items.push(self.term()?);
+            match self.next()? {
+                b',' => continue,
+                c if c == close => return Ok(items),
+                c => return Err(invalid(&format!("expected ',' or close, got {c:#04x}"))),
+            }
+        }
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        self.expect(b'"')?;
+        let mut out = Vec::new();
+        loop {
+            match self.next()? {
+                b'"' => break,
+                b'\\' => out.push(match self.next()? {
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    b't' => b'\t',
+                    other => other,
+                }),
+                c => out.push(c),
+            }
+        }
+        Ok(String::from_utf8_lossy(&out).into_owned())
+    }
+
+    fn expect(&mut self, want: u8) -> io::Result<()> {
+        let got = self.next()?;
+        if got != want {
+            return Err(invalid(&format!(
+                "expected {:?}, got {:?}",
+                want as char, got as char
+            )));
+        }
+        Ok(())
+    }
+
+    fn peek(&self) -> io::Result<u8> {
+        self.bytes
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "truncated derivation"))
+    }
+
+    fn next(&mut self) -> io::Result<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Ok(c)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STDENV_DRV: &str = r#"Derive([("out","/nix/store/aaa-hello-2.12","","")],[("/nix/store/bbb-bash-5.2.drv",["out"])],["/nix/store/ccc-default-builder.sh"],"x86_64-linux","/nix/store/ddd-bash-5.2/bin/bash",["-e","/nix/store/ccc-default-builder.sh"],[("name","hello-2.12"),("stdenv","/nix/store/eee-stdenv-linux"),("description","say \"hi\"\nplease")])"#;
+
+    #[test]
+    fn parses_stdenv_derivation() {
+        let info = parse(STDENV_DRV).unwrap();
+        assert_eq!(info.builder, "/nix/store/ddd-bash-5.2/bin/bash");
+        assert_eq!(info.env.get("name").unwrap(), "hello-2.12");
+        assert_eq!(info.env.get("description").unwrap(), "say \"hi\"\nplease");
+        assert_eq!(info.builder_kind(), "stdenv");
+        assert_eq!(
+            info.output_paths(),
+            Some(vec!["/nix/store/aaa-hello-2.12".to_string()])
+        );
+    }
+
+    #[test]
+    fn floating_ca_outputs_have_no_paths() {
+        let ca = r#"Derive([("dev","","r:sha256",""),("out","","r:sha256","")],[],[],"x86_64-linux","/bin/sh",[],[])"#;
+        let info = parse(ca).unwrap();
+        assert_eq!(info.outputs.len(), 2);
+        assert_eq!(info.output_paths(), None);
+    }
+
+    #[test]
+    fn classifies_builtin_and_fixed_output() {
+        let fetchurl = r#"Derive([("out","/nix/store/aaa-src.tar.gz","sha256","abc")],[],[],"builtin","builtin:fetchurl",[],[("outputHash","abc"),("url","https://example.org/src.tar.gz")])"#;
+        assert_eq!(parse(fetchurl).unwrap().builder_kind(), "builtin:fetchurl");
+
+        let fod = r#"Derive([("out","/nix/store/aaa-source","r:sha256","abc")],[],[],"x86_64-linux","/bin/sh",[],[("outputHash","abc"),("stdenv","/nix/store/s")])"#;
+        assert_eq!(parse(fod).unwrap().builder_kind(), "fixed-output");
+
+        let other = r#"Derive([("out","/nix/store/aaa-x","","")],[],[],"x86_64-linux","/bin/sh",["-c","true"],[])"#;
+        assert_eq!(parse(other).unwrap().builder_kind(), "other");
+    }
+
+    #[test]
+    fn reads_locality_hints() {
+        let trivial = r#"Derive([("out","/nix/store/aaa-x","","")],[],[],"x86_64-linux","/bin/sh",[],[("allowSubstitutes",""),("preferLocalBuild","1")])"#;
+        let info = parse(trivial).unwrap();
+        assert!(info.prefer_local_build());
+        assert!(!info.allow_substitutes());
+
+        let plain = parse(STDENV_DRV).unwrap();
+        assert!(!plain.prefer_local_build());
+        assert!(plain.allow_substitutes());
+
+        let structured = r#"Derive([("out","/nix/store/aaa-x","","")],[],[],"x86_64-linux","/bin/sh",[],[("__json","{\"allowSubstitutes\":false,\"preferLocalBuild\":true}")])"#;
+        let info = parse(structured).unwrap();
+        assert!(info.prefer_local_build());
+        assert!(!info.allow_substitutes());
+    }
+
+    #[test]
+    fn accepts_versioned_derivation_wrapper() {
+        let drv = r#"DrvWithVersion("xp-dyn-drv",[("out","","","")],[],[],"x86_64-linux","/bin/sh",[],[("stdenv","/s")])"#;
+        assert_eq!(parse(drv).unwrap().builder_kind(), "stdenv");
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        let err = parse(&STDENV_DRV[..40]).expect_err("must fail");
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -89,6 +89,25 @@
 //! `admissions` row as `predicted_ms` and reused by the wall-clock TTL
 //! `max(predicted_ms × 2, 60_000)`; both want an upper-tail estimate, not
 //! a centre estimate.
+//!
+//! # Contention
+//!
+//! The agent records how crowded the host was over each build's lifetime
+//! (see [`Sample`]). The estimate is meant for an idle target — queueing
+//! behind other builds is the scheduler's `queue_ms` — so samples are
+//! corrected before they enter the recurrence:
+//!
+//! - **Normalised** by CPU pressure: PSI `some` is the share of wall time
+//!   in which runnable tasks waited for a CPU, so the duration is scaled
+//!   by `1 − psi/100` (capped at [`MAX_CPU_PSI`], a 4× correction). That
+//!   is an upper bound on the stall — not every waiting task was the
+//!   build's — so it errs towards removing too little.
+//! - **Weighted** by concurrency: memory bandwidth, disk and page cache are
+//!   shared too and PSI does not see them, so a build that ran alongside
+//!   `n − 1` others moves the estimate by `α / n` instead of `α`. A run of
+//!   crowded samples still shifts it, just more slowly than quiet ones.
+//!
+//! Samples without contention data count as uncontended.
 
 /// `(ln 1.2)²`. See module docs — the variance floor representing baseline
 /// multiplicative jitter of about ±20 % at 1σ in log-space.
@@ -101,6 +120,46 @@ pub const Z_P95: f64 = 1.645;
 /// Default EWMA smoothing factor. Half-life ≈ 3.1 observations.
 pub const ALPHA_DEFAULT: f64 = 0.2;
 
+/// Highest CPU pressure (percent) a duration is normalised by.
+pub const MAX_CPU_PSI: f64 = 75.0;
+
+/// One observed duration and the contention it was measured under.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Sample {
+    pub duration_ms: u64,
+    /// Mean builds in progress on the host, this one included.
+    pub mean_builds: Option<f64>,
+    /// Mean CPU PSI `some avg10`, percent.
+    pub mean_cpu_psi: Option<f64>,
+}
+
+impl Sample {
+    pub fn uncontended(duration_ms: u64) -> Self {
+        Self {
+            duration_ms,
+            ..Self::default()
+        }
+    }
+
+    /// The duration with the CPU stall taken out.
+    pub fn idle_equivalent_ms(&self) -> f64 {
+        let psi = self
+            .mean_cpu_psi
+            .filter(|p| p.is_finite())
+            .unwrap_or(0.0)
+            .clamp(0.0, MAX_CPU_PSI);
+        self.duration_ms as f64 * (1.0 - psi / 100.0)
+    }
+
+    /// Share of the smoothing factor this sample gets: `1 / mean_builds`.
+    pub fn weight(&self) -> f64 {
+        match self.mean_builds {
+            Some(builds) if builds.is_finite() && builds > 1.0 => 1.0 / builds,
+            _ => 1.0,
+        }
+    }
+}
+
 /// One-pass EWMA over `ln(durations)`, returning a conservative log-normal
 /// upper-quantile estimate in milliseconds.
 ///
@@ -128,17 +187,31 @@ pub const ALPHA_DEFAULT: f64 = 0.2;
 /// - Two or more samples → `exp(μ + z · √max(S, var_floor))`, saturating
 ///   into `u64`.
 pub fn predict_lognormal_ms(durations: &[u64], alpha: f64, z: f64, var_floor: f64) -> Option<u64> {
+    let samples: Vec<Sample> = durations.iter().copied().map(Sample::uncontended).collect();
+    predict_idle_ms(&samples, alpha, z, var_floor)
+}
+
+/// [`predict_lognormal_ms`] over contention-corrected samples (see the
+/// module docs): each sample enters as its
+/// [`Sample::idle_equivalent_ms`] with smoothing factor
+/// `alpha × Sample::weight()`.
+pub fn predict_idle_ms(samples: &[Sample], alpha: f64, z: f64, var_floor: f64) -> Option<u64> {
     // ln(0) = -∞ would poison the recurrence, and a zero-duration
     // "observation" is meaningless anyway.
-    let mut it = durations.iter().copied().filter(|&d| d > 0);
+    let mut it = samples
+        .iter()
+        .map(|s| (s.idle_equivalent_ms(), s.weight()))
+        .filter(|&(d, _)| d >= 1.0);
 
-    let first = it.next()?;
-    let mut mean = (first as f64).ln();
+    let (first_ms, _) = it.next()?;
+    let first = first_ms as u64;
+    let mut mean = first_ms.ln();
     let mut var = 0.0_f64;
     let mut count: u32 = 1;
 
-    for d in it {
-        let y = (d as f64).ln();
+    for (d, weight) in it {
+        let y = d.ln();
+        let alpha = alpha * weight;
         // West (1979): residual is computed against the *previous* mean,
         // and the variance update uses the same δ.
         let delta = y - mean;
@@ -316,6 +389,56 @@ mod tests {
         assert_eq!(with_zeros, without);
     }
 
+    #[test]
+    fn cpu_pressure_is_taken_out_of_durations() {
+        // Half the wall time waiting for a CPU: 20 s under pressure is a
+        // 10 s build on an idle host.
+        let stalled: Vec<Sample> = (0..5)
+            .map(|_| Sample {
+                duration_ms: 20_000,
+                mean_builds: None,
+                mean_cpu_psi: Some(50.0),
+            })
+            .collect();
+        assert_eq!(
+            predict_idle_ms(&stalled, ALPHA_DEFAULT, Z_P95, MIN_LN_VAR),
+            predict_lognormal_ms(&[10_000; 5], ALPHA_DEFAULT, Z_P95, MIN_LN_VAR)
+        );
+        // Capped, and nonsense is ignored.
+        let sample = |psi| Sample {
+            duration_ms: 8_000,
+            mean_builds: None,
+            mean_cpu_psi: Some(psi),
+        };
+        assert_eq!(sample(99.0).idle_equivalent_ms(), 2_000.0);
+        assert_eq!(sample(f64::NAN).idle_equivalent_ms(), 8_000.0);
+        assert_eq!(sample(-5.0).idle_equivalent_ms(), 8_000.0);
+    }
+
+    #[test]
+    fn crowded_samples_move_the_estimate_less() {
+        let mut quiet: Vec<Sample> = vec![Sample::uncontended(10_000); 20];
+        let crowded = Sample {
+            duration_ms: 40_000,
+            mean_builds: Some(16.0),
+            mean_cpu_psi: None,
+        };
+        quiet.extend([crowded; 3]);
+        let weighted = predict_idle_ms(&quiet, ALPHA_DEFAULT, Z_P95, MIN_LN_VAR).unwrap();
+
+        let mut unweighted = vec![10_000_u64; 20];
+        unweighted.extend([40_000; 3]);
+        let naive = predict_lognormal_ms(&unweighted, ALPHA_DEFAULT, Z_P95, MIN_LN_VAR).unwrap();
+
+        assert!(
+            weighted < naive / 2,
+            "crowded builds should barely shift a quiet history: {weighted} vs {naive}"
+        );
+        assert!(weighted > 10_000, "but they still count: {weighted}");
+        assert_eq!(Sample::uncontended(1).weight(), 1.0);
+        assert_eq!(crowded.weight(), 1.0 / 16.0);
+    }
+
     #[test]
     fn lognormal_p95_matches_closed_form_on_stationary_data() {
         // Generate 500 samples drawn from a known log-normal so the EW
@@ -0,0 +1,229 @@
+//! Controller-side target health, separate from liveness.
+//!
+//! A target can answer `PING` promptly and still be unusable for builds —
+//! the ssh-ng path the hook delegates over is a different connection from
+//! the controller's agent session. When a hook reports
+//! [`BuildStatus::InfraFailure`](crate::protocol::ops::BuildStatus) for a
+//! target, the controller marks it unhealthy for a cooldown and the
+//! scheduler excludes it until then.
+//!
+//! It also keeps a sliding window of each target's build outcomes. A
+//! target whose recent builds mostly fail or get declined is quarantined
+//! for [`QuarantinePolicy::cooldown_ms`], however well it answers pings.
+//!
+//! Kept apart from `TargetRuntime` on purpose: the poller resets that on
+//! every reconnect, and a reconnect says nothing about ssh-ng.
+
+use std::collections::{HashMap, VecDeque};
+
+/// When a burst of failures quarantines a target.
+#[derive(Clone, Debug)]
+pub struct QuarantinePolicy {
+    /// Outcomes older than this are forgotten.
+    pub window_ms: u64,
+    /// Fewer failures than this in the window never quarantine, whatever
+    /// the rate. `0` disables quarantine.
+    pub min_failures: u32,
+    /// Share of failed or declined builds in the window, `0.0..=1.0`, at
+    /// or above which the target is quarantined.
+    pub failure_rate: f64,
+    pub cooldown_ms: u64,
+}
+
+/// Finished builds counted towards a target's failure rate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OutcomeCounts {
+    pub total: u32,
+    pub failed: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct TargetHealth {
+    unhealthy_until_ms: HashMap<String, u64>,
+    quarantined_until_ms: HashMap<String, u64>,
+    /// `(finished_at_ms, failed)` per target, oldest first.
+    outcomes: HashMap<String, VecDeque<(u64, bool)>>,
+}
+
+impl TargetHealth {
+    /// Exclude `target` from scheduling until `until_ms`. A later mark
+    /// extends the cooldown; an earlier one never shortens it.
+    pub fn mark_unhealthy(&mut self, target: &str, until_ms: u64) {
+        let entry = self
+            .unhealthy_until_ms
+            .entry(target.to_string())
+            .or_default();
+        *entry = (*entry).max(until_ms);
+    }
+
+    /// End of the current cooldown for `target`, if it is still running at
+    /// `now_ms`.
+    pub fn unhealthy_until(&self, target: &str, now_ms: u64) -> Option<u64> {
+        self.unhealthy_until_ms
+            .get(target)
+            .copied()
+            .filter(|&until| until > now_ms)
+    }
+
+    /// Count one finished build on `target`. Returns the end of a new
+    /// quarantine when this outcome tips the window over the policy; the
+    /// window then starts afresh so the old burst cannot re-trigger it.
+    pub fn record_outcome(
+        &mut self,
+        target: &str,
+        at_ms: u64,
+        failed: bool,
+        policy: &QuarantinePolicy,
+    ) -> Option<u64> {
+        let window = self.outcomes.entry(target.to_string()).or_default();
+        window.push_back((at_ms, failed));
+        let horizon = at_ms.saturating_sub(policy.window_ms);
+        while window.front().is_some_and(|&(t, _)| t < horizon) {
+            window.pop_front();
+        }
+        let counts = counts(window.iter());
+        if policy.min_failures == 0
+            || counts.failed < policy.min_failures
+            || f64::from(counts.failed) < policy.failure_rate * f64::from(counts.total)
+        {
+            return None;
+        }
+        window.clear();
+        let until = at_ms.saturating_add(policy.cooldown_ms);
+        let entry = self
+            .quarantined_until_ms
+            .entry(target.to_string())
+            .or_default();
+        *entry = (*entry).max(until);
+        Some(*entry)
+    }
+
+    /// End of the current quarantine for `target`, if it is still running
+    /// at `now_ms`.
+    pub fn quarantined_until(&self, target: &str, now_ms: u64) -> Option<u64> {
+        self.quarantined_until_ms
+            .get(target)
+            .copied()
+            .filter(|&until| until > now_ms)
+    }
+
+    /// Outcomes for `target` still inside the window at `now_ms`.
+    pub fn recent_outcomes(&self, target: &str, now_ms: u64, window_ms: u64) -> OutcomeCounts {
+        let horizon = now_ms.saturating_sub(window_ms);
+        self.outcomes
+            .get(target)
+            .map(|w| counts(w.iter().filter(|&&(t, _)| t >= horizon)))
+            .unwrap_or_default()
+    }
+}
+
+fn counts<'a>(outcomes: impl IntoIterator<Item = &'a (u64, bool)>) -> OutcomeCounts {
+    outcomes
+        .into_iter()
+        .fold(OutcomeCounts::default(), |mut c, &(_, failed)| {
+            c.total += 1;
+            c.failed += u32::from(failed);
+            c
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cooldown_expires() {
+        let mut health = TargetHealth::default();
+        assert_eq!(health.unhealthy_until("tsugumi", 0), None);
+        health.mark_unhealthy("tsugumi", 1_000);
+        assert_eq!(health.unhealthy_until("tsugumi", 999), Some(1_000));
+        assert_eq!(health.unhealthy_until("tsugumi", 1_000), None);
+        assert_eq!(health.unhealthy_until("kaho", 0), None);
+    }
+
+    #[test]
+    fn earlier_mark_does_not_shorten_cooldown() {
+        let mut health = TargetHealth::default();
+        health.mark_unhealthy("tsugumi", 5_000);
+        health.mark_unhealthy("tsugumi", 2_000);
+        assert_eq!(health.unhealthy_until("tsugumi", 3_000), Some(5_000));
+    }
+
+    fn policy() -> QuarantinePolicy {
+        QuarantinePolicy {
+            window_ms: 10_000,
+            min_failures: 3,
+            failure_rate: 0.5,
+            cooldown_ms: 60_000,
+        }
+    }
+
+    #[test]
+    fn burst_of_failures_quarantines() {
+        let mut health = TargetHealth::default();
+        let p = policy();
+        assert_eq!(health.record_outcome("tsugumi", 1_000, true, &p), None);
+        assert_eq!(health.record_outcome("tsugumi", 2_000, false, &p), None);
+        assert_eq!(health.record_outcome("tsugumi", 3_000, true, &p), None);
+        // Third failure of four: 75% ≥ 50%.
+        assert_eq!(
+            health.record_outcome("tsugumi", 4_000, true, &p),
+            Some(64_000)
+        );
+        assert_eq!(health.quarantined_until("tsugumi", 63_999), Some(64_000));
+        assert_eq!(health.quarantined_until("tsugumi", 64_000), None);
+        assert_eq!(health.quarantined_until("kaho", 4_000), None);
+        // The window restarted: one more failure is not a new burst.
+        assert_eq!(health.record_outcome("tsugumi", 65_000, true, &p), None);
+    }
+
+    #[test]
+    fn failures_mostly_outnumbered_by_successes_do_not_quarantine() {
+        let mut health = TargetHealth::default();
+        let p = policy();
+        for (i, failed) in [true, false, false, true, false, false, true]
+            .into_iter()
+            .enumerate()
+        {
+            assert_eq!(
+                health.record_outcome("tsugumi", i as u64 * 100, failed, &p),
+                None
+            );
+        }
+        assert_eq!(
+            health.recent_outcomes("tsugumi", 600, p.window_ms),
+            OutcomeCounts {
+                total: 7,
+                failed: 3
+            }
+        );
+    }
+
+    #[test]
+    fn old_failures_fall_out_of_the_window() {
+        let mut health = TargetHealth::default();
+        let p = policy();
+        health.record_outcome("tsugumi", 0, true, &p);
+        health.record_outcome("tsugumi", 1_000, true, &p);
+        assert_eq!(health.record_outcome("tsugumi", 20_000, true, &p), None);
+        assert_eq!(
+            health.recent_outcomes("tsugumi", 20_000, p.window_ms),
+            OutcomeCounts {
+                total: 1,
+                failed: 1
+            }
+        );
+    }
+
+    #[test]
+    fn zero_min_failures_disables_quarantine() {
+        let mut health = TargetHealth::default();
+        let p = QuarantinePolicy {
+            min_failures: 0,
+            ..policy()
+        };
+        for i in 0..10 {
+            assert_eq!(health.record_outcome("tsugumi", i, true, &p), None);
+        }
+    }
+}
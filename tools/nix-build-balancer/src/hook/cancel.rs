@@ -0,0 +1,110 @@
+//! Noticing that the Nix client went away during a delegated build.
+//!
+//! When the user interrupts `nixos-rebuild`, the daemon worker that spawned
+//! the hook is torn down. Whatever reaches the hook of that — `SIGTERM`,
+//! `SIGINT` or `SIGHUP`, or simply being reparented when the worker exits
+//! (`PR_SET_PDEATHSIG` turns that into `SIGTERM` too) — is recorded here
+//! while a [`Delegation`] is active, so the build loop can stop
+//! `nix __build-remote` and report the build cancelled. Outside a
+//! delegation the signals keep their default effect.
+//!
+//! The hook's stdin is no signal once the build is running: Nix closes it
+//! straight after sending the wanted outputs. Only an EOF before then
+//! means the client is gone ([`CancelReason::StdinClosed`]).
+
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+static DELEGATING: AtomicBool = AtomicBool::new(false);
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Why a delegated build was abandoned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CancelReason {
+    Signal(i32),
+    /// The process that spawned the hook exited.
+    ParentExited,
+    /// Nix closed the hook's stdin before sending the build's inputs.
+    StdinClosed,
+}
+
+impl fmt::Display for CancelReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CancelReason::Signal(signo) => write!(f, "signal {signo}"),
+            CancelReason::ParentExited => f.write_str("parent exited"),
+            CancelReason::StdinClosed => f.write_str("stdin closed"),
+        }
+    }
+}
+
+extern "C" fn on_signal(signo: libc::c_int) {
+    if DELEGATING.load(Ordering::SeqCst) {
+        SIGNAL.store(signo, Ordering::SeqCst);
+        return;
+    }
+    // SAFETY: `signal` and `raise` are async-signal-safe; restoring the
+    // default disposition and re-raising terminates as if never caught.
+    unsafe {
+        libc::signal(signo, libc::SIG_DFL);
+        libc::raise(signo);
+    }
+}
+
+/// Catch the signals that mean the client is gone, and ask for `SIGTERM`
+/// when the parent exits. Called once by `nbb-hook`; in-process tests of
+/// the hook loop go without.
+pub fn install() -> io::Result<()> {
+    for signo in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        // SAFETY: `action` is fully initialised before use, and the
+        // handler only touches atomics and async-signal-safe functions.
+        let result = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signo, &action, std::ptr::null_mut())
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    // SAFETY: PR_SET_PDEATHSIG takes a signal number and no pointers.
+    if unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The span in which the hook owns a delegated build. Signals caught
+/// during it are held for [`Delegation::cancelled`] instead of killing the
+/// hook.
+pub struct Delegation {
+    parent: u32,
+}
+
+impl Delegation {
+    pub fn begin() -> Self {
+        DELEGATING.store(true, Ordering::SeqCst);
+        Self {
+            parent: std::os::unix::process::parent_id(),
+        }
+    }
+
+    /// Whether the client has gone away since [`Self::begin`].
+    pub fn cancelled(&self) -> Option<CancelReason> {
+        match SIGNAL.load(Ordering::SeqCst) {
+            0 => {}
+            signo => return Some(CancelReason::Signal(signo)),
+        }
+        // The parent may have exited before PR_SET_PDEATHSIG was set.
+        (std::os::unix::process::parent_id() != self.parent).then_some(CancelReason::ParentExited)
+    }
+}
+
+impl Drop for Delegation {
+    fn drop(&mut self) {
+        DELEGATING.store(false, Ordering::SeqCst);
+    }
+}
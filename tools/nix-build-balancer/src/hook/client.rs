@@ -0,0 +1,231 @@
+//! The hook's connection to the controller.
+//!
+//! One connection serves every request a hook process makes: the decision
+//! for each candidate Nix offers, the retries after an infrastructure
+//! failure, and the `ADMISSION_FINISH` reports. Each request, connecting
+//! and the handshake included, must be done within
+//! [`HookConfig::decision_timeout`]; otherwise it fails and the hook
+//! declines. A connection that timed out is dropped, since the late reply
+//! would answer the next request. One the controller has closed (it
+//! restarted, or is shutting down) is noticed before reuse and replaced.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use super::HookConfig;
+use crate::protocol::auth;
+use crate::protocol::frame::{read_frame_sync, write_frame_sync, Frame};
+use crate::protocol::handshake::perform_handshake_sync;
+use crate::protocol::ops::{op, AdmissionFinish, DecideCandidate, Decision};
+
+/// How long a remote hook waits for the TCP connection itself, within
+/// the decision timeout.
+const REMOTE_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Whether `err` is a request running out of time rather than the
+/// controller being unreachable.
+pub fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+/// A controller connection, opened on first use and kept for the rest of
+/// the hook process.
+pub struct ControllerClient<'a> {
+    cfg: &'a HookConfig,
+    stream: Option<ControllerStream>,
+}
+
+impl<'a> ControllerClient<'a> {
+    pub fn new(cfg: &'a HookConfig) -> Self {
+        Self { cfg, stream: None }
+    }
+
+    pub fn decide(&mut self, candidate: &DecideCandidate) -> io::Result<Decision> {
+        let frame = Frame::with_body(op::DECIDE_CANDIDATE, candidate)?;
+        let reply = self.request(&frame, true)?.expect("reply requested");
+        if reply.op_id != op::DECISION {
+            self.stream = None;
+            return Err(io::Error::other(format!(
+                "expected DECISION reply, got op_id {}",
+                reply.op_id
+            )));
+        }
+        reply.decode_body()
+    }
+
+    pub fn admission_finish(&mut self, finish: &AdmissionFinish) -> io::Result<()> {
+        let frame = Frame::with_body(op::ADMISSION_FINISH, finish)?;
+        self.request(&frame, false).map(drop)
+    }
+
+    /// Send `frame` and, if `want_reply`, read the answer, all before the
+    /// decision timeout runs out. Any failure drops the connection.
+    fn request(&mut self, frame: &Frame, want_reply: bool) -> io::Result<Option<Frame>> {
+        let deadline = Instant::now() + self.cfg.decision_timeout;
+        let result = self.exchange(frame, want_reply, deadline);
+        if result.is_err() {
+            self.stream = None;
+        }
+        result
+    }
+
+    fn exchange(
+        &mut self,
+        frame: &Frame,
+        want_reply: bool,
+        deadline: Instant,
+    ) -> io::Result<Option<Frame>> {
+        if self.stream.as_ref().is_some_and(|s| !s.is_open()) {
+            tracing::debug!("controller closed the connection; reconnecting");
+            self.stream = None;
+        }
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => self.stream.insert(connect_controller(self.cfg, deadline)?),
+        };
+        stream.set_timeout(remaining(deadline)?)?;
+        write_frame_sync(stream, frame)?;
+        if !want_reply {
+            return Ok(None);
+        }
+        read_frame_sync(stream).map(Some)
+    }
+}
+
+/// Time left until `deadline`, or a timeout error once there is none.
+fn remaining(deadline: Instant) -> io::Result<Duration> {
+    deadline
+        .checked_duration_since(Instant::now())
+        .filter(|left| !left.is_zero())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "decision timeout"))
+}
+
+/// A handshaken (and, over TCP, authenticated) controller connection.
+enum ControllerStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl ControllerStream {
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        match self {
+            ControllerStream::Unix(s) => {
+                s.set_read_timeout(Some(timeout))?;
+                s.set_write_timeout(Some(timeout))
+            }
+            ControllerStream::Tcp(s) => {
+                s.set_read_timeout(Some(timeout))?;
+                s.set_write_timeout(Some(timeout))
+            }
+        }
+    }
+
+    /// Whether the connection is still usable between requests: nothing
+    /// to read yet. End of file means the controller closed it, and bytes
+    /// nobody asked for mean it is out of step.
+    fn is_open(&self) -> bool {
+        let mut byte = 0u8;
+        // SAFETY: `recv` writes at most one byte into `byte`; MSG_PEEK
+        // leaves it queued and MSG_DONTWAIT keeps the call from blocking.
+        let n = unsafe {
+            libc::recv(
+                self.as_raw_fd(),
+                (&mut byte as *mut u8).cast(),
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::WouldBlock
+    }
+}
+
+impl AsRawFd for ControllerStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            ControllerStream::Unix(s) => s.as_raw_fd(),
+            ControllerStream::Tcp(s) => s.as_raw_fd(),
+        }
+    }
+}
+
+impl Read for ControllerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ControllerStream::Unix(s) => s.read(buf),
+            ControllerStream::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for ControllerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ControllerStream::Unix(s) => s.write(buf),
+            ControllerStream::Tcp(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ControllerStream::Unix(s) => s.flush(),
+            ControllerStream::Tcp(s) => s.flush(),
+        }
+    }
+}
+
+fn connect_controller(cfg: &HookConfig, deadline: Instant) -> io::Result<ControllerStream> {
+    let Some(remote) = &cfg.remote_controller else {
+        let mut stream = ControllerStream::Unix(UnixStream::connect(&cfg.controller_socket)?);
+        stream.set_timeout(remaining(deadline)?)?;
+        perform_handshake_sync(&mut stream)?;
+        return Ok(stream);
+    };
+    let mut last_err = io::Error::other(format!("{} did not resolve", remote.addr));
+    for addr in remote.addr.to_socket_addrs()? {
+        let timeout = remaining(deadline)?.min(REMOTE_CONNECT_TIMEOUT);
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(tcp) => {
+                tcp.set_nodelay(true)?;
+                let mut stream = ControllerStream::Tcp(tcp);
+                stream.set_timeout(remaining(deadline)?)?;
+                perform_handshake_sync(&mut stream)?;
+                auth::respond_sync(&mut stream, &remote.key, &remote.host)?;
+                return Ok(stream);
+            }
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closed_or_out_of_step_connections_are_not_reused() {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let stream = ControllerStream::Unix(ours);
+        assert!(stream.is_open());
+        theirs.write_all(b"x").unwrap();
+        assert!(!stream.is_open());
+
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let stream = ControllerStream::Unix(ours);
+        drop(theirs);
+        assert!(!stream.is_open());
+    }
+
+    #[test]
+    fn an_exhausted_budget_is_a_timeout() {
+        let err = remaining(Instant::now()).unwrap_err();
+        assert!(is_timeout(&err));
+        assert!(remaining(Instant::now() + Duration::from_secs(1)).is_ok());
+    }
+}
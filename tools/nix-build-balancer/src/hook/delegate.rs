@@ -5,12 +5,16 @@
 //! function never propagates an error before the directive is decided.
 
 use std::io::{self, BufRead, BufReader, Read, Write};
-use std::process::{ChildStderr, Command, Stdio};
+use std::process::{Child, ChildStderr, Command, ExitStatus, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::protocol::ops::AcceptTarget;
 
+use super::cancel::{CancelReason, Delegation};
 use super::candidate::{write_hook_candidate, write_hook_settings, HookCandidate};
+use super::failure::{FailureKind, FailureScan, LogTail};
 use super::guard::{DeclineKind, DirectiveGuard};
 use super::HookConfig;
 use crate::nix_protocol::{read_nix_strings, write_nix_strings};
@@ -19,12 +23,43 @@ use crate::nix_protocol::{read_nix_strings, write_nix_strings};
 pub enum DelegateOutcome {
     /// `nix __build-remote` ran to completion successfully.
     Built,
-    /// `nix __build-remote` ran but exited non-zero.
-    BuildFailed,
+    /// `nix __build-remote` ran but exited non-zero. Carries the end of
+    /// its log when there was one.
+    BuildFailed { log_tail: Option<String> },
+    /// `nix __build-remote` exited non-zero after `# accept` for
+    /// infrastructure reasons (see [`super::failure`]). Carries what Nix
+    /// already sent so the build can be handed to another target with
+    /// [`redelegate_build`].
+    InfraFailed {
+        accepted: AcceptedBuild,
+        log_tail: Option<String>,
+    },
     /// Declined before commit — child crashed, errored, or said `# decline*`.
     Declined,
+    /// The Nix client went away after `# accept`; `nix __build-remote`
+    /// was stopped.
+    Cancelled { reason: CancelReason },
+}
+
+/// What Nix sends after `# accept`: the inputs to copy to the builder and
+/// the outputs it wants back.
+pub struct AcceptedBuild {
+    inputs: Vec<String>,
+    wanted_outputs: Vec<String>,
 }
 
+/// How long to wait for the child's stderr to reach EOF after it exits.
+/// An ssh grandchild can hold the pipe open; classification then works
+/// with whatever was read so far.
+const DRAIN_GRACE: Duration = Duration::from_secs(2);
+
+/// How often a running delegated build checks whether it was cancelled.
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+/// How long `nix __build-remote` has after `SIGTERM` to close its
+/// connection to the builder before it is killed.
+const TERMINATE_GRACE: Duration = Duration::from_secs(5);
+
 pub fn delegate_remote_build<R: Read>(
     cfg: &HookConfig,
     settings: &[(String, String)],
@@ -32,6 +67,7 @@ pub fn delegate_remote_build<R: Read>(
     target: &AcceptTarget,
     parent_stdin: &mut R,
     guard: &mut DirectiveGuard,
+    delegation: &Delegation,
 ) -> DelegateOutcome {
     let mut child = match Command::new(&cfg.nix_bin)
         .arg("__build-remote")
@@ -95,8 +131,71 @@ pub fn delegate_remote_build<R: Read>(
         }
         ChildDirective::Accept { store_uri, reader } => {
             guard.accept(&store_uri);
-            spawn_stderr_drain(reader);
-            run_accepted_build(child, parent_stdin)
+            let drain = spawn_stderr_drain(reader);
+            run_accepted_build(child, parent_stdin, drain, delegation)
+        }
+    }
+}
+
+/// Hand an already-accepted build to another target after an
+/// infrastructure failure. Nix has had its `# accept` and sent the inputs,
+/// so nothing here talks to the parent: the new child's directive is
+/// consumed locally, and anything short of it accepting counts as another
+/// infrastructure failure.
+pub fn redelegate_build(
+    cfg: &HookConfig,
+    settings: &[(String, String)],
+    candidate: &HookCandidate,
+    target: &AcceptTarget,
+    accepted: AcceptedBuild,
+    delegation: &Delegation,
+) -> DelegateOutcome {
+    let mut child = match Command::new(&cfg.nix_bin)
+        .arg("__build-remote")
+        .arg(&cfg.verbosity)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .stdout(Stdio::inherit())
+        .spawn()
+    {
+        Ok(c) => c,
+        Err(err) => {
+            tracing::warn!(?err, "spawn nix __build-remote for retry failed");
+            return DelegateOutcome::InfraFailed {
+                accepted,
+                log_tail: None,
+            };
+        }
+    };
+    let directive = write_child_prefix(&mut child, settings, candidate, target).and_then(|()| {
+        let stderr = child
+            .stderr
+            .take()
+            .ok_or_else(|| io::Error::other("child stderr unavailable"))?;
+        read_child_directive(stderr)
+    });
+    match directive {
+        Ok(ChildDirective::Accept { reader, .. }) => {
+            let drain = spawn_stderr_drain(reader);
+            feed_accepted_build(child, accepted, drain, delegation)
+        }
+        Ok(_) => {
+            tracing::warn!(target = %target.name, "retry target did not accept");
+            let _ = child.kill();
+            let _ = child.wait();
+            DelegateOutcome::InfraFailed {
+                accepted,
+                log_tail: None,
+            }
+        }
+        Err(err) => {
+            tracing::warn!(target = %target.name, ?err, "retry delegation failed before accept");
+            let _ = child.kill();
+            let _ = child.wait();
+            DelegateOutcome::InfraFailed {
+                accepted,
+                log_tail: None,
+            }
         }
     }
 }
@@ -117,64 +216,159 @@ fn write_child_prefix(
 }
 
 fn run_accepted_build<R: Read>(
-    mut child: std::process::Child,
+    mut child: Child,
     parent_stdin: &mut R,
+    drain: StderrDrain,
+    delegation: &Delegation,
 ) -> DelegateOutcome {
     // We have committed by emitting `# accept`. From here on, any failure is
     // a build failure, not a protocol-violating early exit.
-    let inputs = match read_nix_strings(parent_stdin) {
-        Ok(v) => v,
-        Err(err) => {
-            tracing::warn!(?err, "reading inputs from parent stdin failed");
-            let _ = child.kill();
-            let _ = child.wait();
-            return DelegateOutcome::BuildFailed;
-        }
-    };
-    let wanted_outputs = match read_nix_strings(parent_stdin) {
+    let read = read_nix_strings(parent_stdin)
+        .and_then(|inputs| Ok((inputs, read_nix_strings(parent_stdin)?)));
+    let (inputs, wanted_outputs) = match read {
         Ok(v) => v,
         Err(err) => {
-            tracing::warn!(?err, "reading wanted_outputs failed");
             let _ = child.kill();
             let _ = child.wait();
-            return DelegateOutcome::BuildFailed;
+            if err.kind() == io::ErrorKind::UnexpectedEof {
+                return DelegateOutcome::Cancelled {
+                    reason: CancelReason::StdinClosed,
+                };
+            }
+            tracing::warn!(?err, "reading inputs from parent stdin failed");
+            return DelegateOutcome::BuildFailed { log_tail: None };
         }
     };
 
+    feed_accepted_build(
+        child,
+        AcceptedBuild {
+            inputs,
+            wanted_outputs,
+        },
+        drain,
+        delegation,
+    )
+}
+
+fn feed_accepted_build(
+    mut child: Child,
+    accepted: AcceptedBuild,
+    drain: StderrDrain,
+    delegation: &Delegation,
+) -> DelegateOutcome {
     if let Some(child_stdin) = child.stdin.as_mut() {
         let write_result = (|| -> io::Result<()> {
-            write_nix_strings(child_stdin, &inputs)?;
-            write_nix_strings(child_stdin, &wanted_outputs)?;
+            write_nix_strings(child_stdin, &accepted.inputs)?;
+            write_nix_strings(child_stdin, &accepted.wanted_outputs)?;
             child_stdin.flush()
         })();
         if let Err(err) = write_result {
+            // The child died under us; its stderr says why.
             tracing::warn!(?err, "writing inputs/wanted_outputs to child failed");
             let _ = child.kill();
-            let _ = child.wait();
-            return DelegateOutcome::BuildFailed;
         }
     }
     let _ = child.stdin.take();
 
-    match child.wait() {
-        Ok(status) if status.success() => DelegateOutcome::Built,
-        Ok(status) => {
-            tracing::warn!(?status, "delegated nix __build-remote exited non-zero");
-            DelegateOutcome::BuildFailed
+    match wait_unless_cancelled(&mut child, delegation) {
+        Err(reason) => return DelegateOutcome::Cancelled { reason },
+        Ok(Ok(status)) if status.success() => return DelegateOutcome::Built,
+        Ok(Ok(status)) => tracing::warn!(?status, "delegated nix __build-remote exited non-zero"),
+        Ok(Err(err)) => tracing::warn!(?err, "wait on delegated child failed"),
+    }
+    let drained = drain.finish();
+    let log_tail = drained.tail.text();
+    match drained.scan.classify() {
+        FailureKind::Build => DelegateOutcome::BuildFailed { log_tail },
+        FailureKind::Infrastructure => DelegateOutcome::InfraFailed { accepted, log_tail },
+    }
+}
+
+/// Wait for `child`, or stop it once the delegation is cancelled.
+fn wait_unless_cancelled(
+    child: &mut Child,
+    delegation: &Delegation,
+) -> Result<io::Result<ExitStatus>, CancelReason> {
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Ok(Ok(status)),
+            Ok(None) => {}
+            Err(err) => return Ok(Err(err)),
         }
-        Err(err) => {
-            tracing::warn!(?err, "wait on delegated child failed");
-            DelegateOutcome::BuildFailed
+        if let Some(reason) = delegation.cancelled() {
+            tracing::warn!(%reason, "client went away; stopping the delegated build");
+            terminate(child);
+            return Err(reason);
         }
+        thread::sleep(CANCEL_POLL);
+    }
+}
+
+/// `SIGTERM` lets `nix __build-remote` close its store connection, which
+/// makes the builder's daemon abandon the build; `SIGKILL` follows if it
+/// does not exit within [`TERMINATE_GRACE`].
+fn terminate(child: &mut Child) {
+    // SAFETY: `child` has not been reaped, so its PID still names it.
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    let deadline = Instant::now() + TERMINATE_GRACE;
+    while Instant::now() < deadline {
+        if !matches!(child.try_wait(), Ok(None)) {
+            return;
+        }
+        thread::sleep(CANCEL_POLL);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Background copy of the child's stderr to ours (it is the build log Nix
+/// shows the user), scanning each line for [`FailureScan`] and keeping a
+/// [`LogTail`] of it.
+struct StderrDrain {
+    state: Arc<Mutex<Drained>>,
+    done: mpsc::Receiver<()>,
+}
+
+#[derive(Clone, Default)]
+struct Drained {
+    scan: FailureScan,
+    tail: LogTail,
+}
+
+impl StderrDrain {
+    fn finish(self) -> Drained {
+        let _ = self.done.recv_timeout(DRAIN_GRACE);
+        self.state.lock().map(|s| s.clone()).unwrap_or_default()
     }
 }
 
-fn spawn_stderr_drain(reader: BufReader<ChildStderr>) {
+fn spawn_stderr_drain(reader: BufReader<ChildStderr>) -> StderrDrain {
+    let state = Arc::new(Mutex::new(Drained::default()));
+    let (tx, done) = mpsc::channel();
+    let thread_state = Arc::clone(&state);
     thread::spawn(move || {
         let mut reader = reader;
         let mut stderr = io::stderr();
-        let _ = io::copy(&mut reader, &mut stderr);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            let _ = stderr.write_all(&line);
+            if let Ok(mut state) = thread_state.lock() {
+                let text = String::from_utf8_lossy(&line);
+                state.scan.observe(&text);
+                state.tail.push(&text);
+            }
+        }
+        let _ = tx.send(());
     });
+    StderrDrain { state, done }
 }
 
 enum ChildDirective {
@@ -0,0 +1,186 @@
+//! Classify a failed `nix __build-remote` from its stderr.
+//!
+//! Nix exits 1 both when the remote builder fails the derivation and when
+//! the ssh-ng connection underneath dies, so the exit status alone cannot
+//! tell "this derivation is broken" from "tsugumi went away". The
+//! difference is in the log: a builder verdict always names the builder
+//! (`builder for '…' failed`), transport errors carry ssh / store-protocol
+//! wording and no verdict.
+//!
+//! [`LogTail`] keeps the end of the same log so the controller can be told
+//! *why* a delegated build failed.
+
+use std::collections::VecDeque;
+
+/// Lines that only appear when the builder itself reached a verdict.
+const BUILD_MARKERS: &[&str] = &["builder for '", "hash mismatch in fixed-output derivation"];
+
+/// Lines that point at the connection or remote store rather than the
+/// derivation.
+const INFRA_MARKERS: &[&str] = &[
+    "cannot connect to",
+    "failed to start SSH connection",
+    "Connection closed by",
+    "Connection reset by peer",
+    "Connection timed out",
+    "closed by remote host",
+    "Broken pipe",
+    "unexpected end-of-file",
+    "No route to host",
+    "Host key verification failed",
+    "kex_exchange_identification",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The builder ran and failed the derivation. Retrying elsewhere would
+    /// fail the same way.
+    Build,
+    /// Transport or remote-store failure with no builder verdict.
+    Infrastructure,
+}
+
+/// Accumulates what the child's stderr said, line by line.
+#[derive(Clone, Debug, Default)]
+pub struct FailureScan {
+    builder_verdict: bool,
+    transport_error: bool,
+}
+
+impl FailureScan {
+    pub fn observe(&mut self, line: &str) {
+        if BUILD_MARKERS.iter().any(|m| line.contains(m)) {
+            self.builder_verdict = true;
+        }
+        if INFRA_MARKERS.iter().any(|m| line.contains(m)) {
+            self.transport_error = true;
+        }
+    }
+
+    /// A builder verdict wins over transport noise (ssh often logs
+    /// "Connection closed" on the way out of an ordinary failure). Without
+    /// either, assume a build failure: it is the status Nix reports anyway.
+    pub fn classify(&self) -> FailureKind {
+        if self.transport_error && !self.builder_verdict {
+            FailureKind::Infrastructure
+        } else {
+            FailureKind::Build
+        }
+    }
+}
+
+/// Lines of remote build log kept for the controller.
+pub const TAIL_MAX_LINES: usize = 40;
+/// Byte cap on the kept tail; a single over-long line is cut to fit.
+pub const TAIL_MAX_BYTES: usize = 8 * 1024;
+
+/// The last [`TAIL_MAX_LINES`] lines of a log, at most [`TAIL_MAX_BYTES`]
+/// in total. Older lines are dropped as new ones arrive.
+#[derive(Clone, Debug, Default)]
+pub struct LogTail {
+    lines: VecDeque<String>,
+    bytes: usize,
+}
+
+impl LogTail {
+    pub fn push(&mut self, line: &str) {
+        let mut line = line.trim_end_matches(['\n', '\r']).to_string();
+        if line.len() > TAIL_MAX_BYTES {
+            let mut cut = line.len() - TAIL_MAX_BYTES;
+            while !line.is_char_boundary(cut) {
+                cut += 1;
+            }
+            line.drain(..cut);
+        }
+        self.bytes += line.len();
+        self.lines.push_back(line);
+        while self.lines.len() > TAIL_MAX_LINES || self.bytes > TAIL_MAX_BYTES {
+            let Some(old) = self.lines.pop_front() else {
+                break;
+            };
+            self.bytes -= old.len();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// The kept lines joined with `\n`, or `None` if nothing was logged.
+    pub fn text(&self) -> Option<String> {
+        (!self.is_empty()).then(|| Vec::from(self.lines.clone()).join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(log: &str) -> FailureKind {
+        let mut scan = FailureScan::default();
+        for line in log.lines() {
+            scan.observe(line);
+        }
+        scan.classify()
+    }
+
+    #[test]
+    fn dropped_ssh_is_infrastructure() {
+        assert_eq!(
+            classify(
+                "building '/nix/store/abc-foo.drv' on 'ssh-ng://svein@tsugumi.local'\n\
+                 client_loop: send disconnect: Broken pipe\n\
+                 error: unexpected end-of-file"
+            ),
+            FailureKind::Infrastructure
+        );
+        assert_eq!(
+            classify("error: cannot connect to 'ssh-ng://svein@tsugumi.local'"),
+            FailureKind::Infrastructure
+        );
+    }
+
+    #[test]
+    fn builder_verdict_is_a_build_failure_even_with_transport_noise() {
+        assert_eq!(
+            classify(
+                "error: builder for '/nix/store/abc-foo.drv' failed with exit code 2\n\
+                 Connection closed by 10.0.0.2 port 22"
+            ),
+            FailureKind::Build
+        );
+    }
+
+    #[test]
+    fn unrecognised_failure_defaults_to_build() {
+        assert_eq!(classify("error: something odd"), FailureKind::Build);
+        assert_eq!(classify(""), FailureKind::Build);
+    }
+
+    #[test]
+    fn log_tail_keeps_the_last_lines() {
+        let mut tail = LogTail::default();
+        assert_eq!(tail.text(), None);
+        for i in 0..TAIL_MAX_LINES + 5 {
+            tail.push(&format!("line {i}\n"));
+        }
+        let text = tail.text().unwrap();
+        assert_eq!(text.lines().count(), TAIL_MAX_LINES);
+        assert!(text.starts_with("line 5\n"));
+        assert!(text.ends_with(&format!("line {}", TAIL_MAX_LINES + 4)));
+    }
+
+    #[test]
+    fn log_tail_is_bounded_in_bytes() {
+        let mut tail = LogTail::default();
+        let long = "x".repeat(TAIL_MAX_BYTES - 4);
+        tail.push("first");
+        tail.push(&long);
+        tail.push("last");
+        assert_eq!(tail.text().unwrap(), format!("{long}\nlast"));
+
+        let mut tail = LogTail::default();
+        tail.push(&format!("é{}", "y".repeat(TAIL_MAX_BYTES)));
+        assert_eq!(tail.text().unwrap(), "y".repeat(TAIL_MAX_BYTES));
+    }
+}
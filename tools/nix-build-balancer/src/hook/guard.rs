@@ -34,7 +34,9 @@ pub trait DirectiveSink: Send {
     fn write_all(&mut self, bytes: &[u8]);
 }
 
-struct StderrSink;
+/// The production sink: the hook's own stderr, where Nix reads directives.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StderrSink;
 
 impl DirectiveSink for StderrSink {
     fn write_all(&mut self, bytes: &[u8]) {
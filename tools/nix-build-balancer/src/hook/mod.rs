@@ -1,12 +1,17 @@
 //! `nbb-hook` — the Nix build-hook process.
 //!
-//! Invoked by Nix's `build-hook` setting on the controller host. For each
-//! `try` candidate received from Nix on stdin, the hook asks the local
-//! controller (over a Unix socket) whether to delegate. On `Decline` the
+//! Invoked by Nix's `build-hook` setting. For each `try` candidate received
+//! from Nix on stdin, the hook asks the controller whether to delegate:
+//! over its Unix socket on the controller host, or over authenticated TCP
+//! ([`RemoteController`]) from any other host. On `Decline` the
 //! hook writes `# decline` to stderr and loops. On `Accept` it spawns
 //! `nix __build-remote` with the controller-supplied builder line and
 //! proxies the protocol through.
 //!
+//! One controller connection serves the whole hook process, and every
+//! request on it has [`HookConfig::decision_timeout`]; a candidate with no
+//! decision by then is declined (see [`client`]).
+//!
 //! Invariant: **every candidate is answered with exactly one directive on
 //! stderr before the hook exits or moves to the next candidate.** A missing
 //! directive crashes the Nix daemon with "unexpected EOF reading a line".
@@ -16,33 +21,77 @@
 //! Sentinel lifecycle (SPEC §"Build observation lifecycle" item 4): the
 //! hook writes `/run/nbb/inflight/<drv_hash>` after accept and unlinks it
 //! on every exit path via a `Drop` guard. The controller's watchdog sweeps
-//! these to retire admissions for crashed hooks.
+//! these to retire admissions for crashed hooks. A remote hook's sentinels
+//! are on its own host, out of the watchdog's reach; its admissions are
+//! retired by `ADMISSION_FINISH`, the agent's finish event or the TTL.
+//!
+//! Infrastructure failures (the ssh-ng connection dropped mid-build, see
+//! [`failure`]) are reported as `InfraFailure` — which makes the controller
+//! bench that target — and the same build is re-asked and handed to the
+//! next target, up to [`MAX_INFRA_RETRIES`] times, without going back to
+//! Nix.
+//!
+//! Everything the hook logs about an answered candidate is in a span with
+//! the correlation id from the controller's decision ([`crate::logging`]).
+//!
+//! If the Nix client goes away while a delegated build runs ([`cancel`]),
+//! `nix __build-remote` is stopped and the build reported `Cancelled`, so
+//! the controller retires the admission at once instead of at the TTL.
 
+pub mod cancel;
 pub mod candidate;
+pub mod client;
 pub mod delegate;
+pub mod failure;
 pub mod guard;
 
 use std::io;
 use std::io::Read;
-use std::os::unix::net::UnixStream;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use crate::drv;
 use crate::inflight::{self, Sentinel};
-use crate::protocol::frame::{read_frame_sync, write_frame_sync, Frame};
-use crate::protocol::handshake::perform_handshake_sync;
-use crate::protocol::ops::{op, AdmissionFinish, BuildStatus, DecideCandidate, Decision};
+use crate::logging::build_span;
+use crate::protocol::ops::{
+    AcceptTarget, AdmissionFinish, BuildStatus, DecideCandidate, Decision, DrvDetails, Priority,
+};
 use crate::util::now_ms_u64;
 
 use candidate::{read_hook_candidate, read_hook_settings, HookCandidate};
-use delegate::{delegate_remote_build, DelegateOutcome};
-use guard::{DeclineKind, DirectiveGuard};
+use client::{is_timeout, ControllerClient};
+use delegate::{delegate_remote_build, redelegate_build, DelegateOutcome};
+use guard::{DeclineKind, DirectiveGuard, DirectiveSink, StderrSink};
+
+/// Further targets tried after an infrastructure failure before giving the
+/// build up as failed.
+pub const MAX_INFRA_RETRIES: u32 = 2;
+
+/// Default for [`HookConfig::decision_timeout`].
+pub const DEFAULT_DECISION_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Clone, Debug)]
 pub struct HookConfig {
     pub controller_socket: PathBuf,
+    /// Talk to a controller on another host instead of `controller_socket`.
+    pub remote_controller: Option<RemoteController>,
     pub inflight_dir: PathBuf,
     pub nix_bin: PathBuf,
     pub verbosity: String,
+    /// Budget for each request to the controller, connecting included
+    /// ([`client`]). A candidate without a decision by then is declined.
+    pub decision_timeout: Duration,
+}
+
+/// A controller reached over TCP (its `--hook-listen`).
+#[derive(Clone, Debug)]
+pub struct RemoteController {
+    /// `host:port`.
+    pub addr: String,
+    /// The name this host authenticates as; must match its target name in
+    /// the controller's configuration for `RouteLocal` to apply.
+    pub host: String,
+    pub key: Vec<u8>,
 }
 
 enum CandidateOutcome {
@@ -54,26 +103,41 @@ enum CandidateOutcome {
 }
 
 pub fn run_hook(cfg: HookConfig) -> io::Result<()> {
+    if let Err(err) = cancel::install() {
+        tracing::warn!(?err, "cannot watch for the client going away");
+    }
     let stdin = io::stdin();
-    let mut stdin = stdin.lock();
-    let settings = read_hook_settings(&mut stdin)?;
+    run_hook_io(&cfg, &mut stdin.lock(), StderrSink)
+}
+
+/// The hook loop over an arbitrary Nix side: `stdin` carries what Nix
+/// writes to the hook, and every directive goes to a fresh clone of `sink`.
+/// [`run_hook`] wires this to the process's stdin and stderr; tests drive
+/// it with a recorded session or an in-process fake Nix.
+pub fn run_hook_io<R, S>(cfg: &HookConfig, stdin: &mut R, sink: S) -> io::Result<()>
+where
+    R: Read,
+    S: DirectiveSink + Clone + 'static,
+{
+    let settings = read_hook_settings(stdin)?;
+    let mut client = ControllerClient::new(cfg);
 
     loop {
-        let candidate = match read_hook_candidate(&mut stdin) {
+        let candidate = match read_hook_candidate(stdin) {
             Ok(Some(c)) => c,
             Ok(None) => return Ok(()),
             Err(err) => {
                 // Mid-`try` parse failure: Nix may already be waiting for a
                 // directive. Emit decline-permanently so it stops asking us.
                 tracing::warn!(?err, "parser failure reading try; decline-permanently");
-                let mut guard = DirectiveGuard::new();
+                let mut guard = DirectiveGuard::with_sink(Box::new(sink.clone()));
                 guard.emit_decline(DeclineKind::DeclinePermanently);
                 return Err(err);
             }
         };
 
-        let mut guard = DirectiveGuard::new();
-        let outcome = handle_candidate(&cfg, &settings, &candidate, &mut stdin, &mut guard);
+        let mut guard = DirectiveGuard::with_sink(Box::new(sink.clone()));
+        let outcome = handle_candidate(cfg, &mut client, &settings, &candidate, stdin, &mut guard);
         // Force directive emission before continuing, so the `# decline`
         // fallback (if any) lands before we read the next candidate.
         drop(guard);
@@ -87,25 +151,44 @@ pub fn run_hook(cfg: HookConfig) -> io::Result<()> {
 
 fn handle_candidate<R: Read>(
     cfg: &HookConfig,
+    client: &mut ControllerClient,
     settings: &[(String, String)],
     candidate: &HookCandidate,
     stdin: &mut R,
     guard: &mut DirectiveGuard,
 ) -> CandidateOutcome {
-    let decision = match ask_controller(cfg, candidate) {
+    let decision = match ask_controller(client, candidate, None) {
         Ok(d) => d,
+        Err(err) if is_timeout(&err) => {
+            tracing::warn!(
+                timeout_ms = cfg.decision_timeout.as_millis() as u64,
+                "no decision within the timeout; declining"
+            );
+            guard.decline();
+            return CandidateOutcome::Declined;
+        }
         Err(err) => {
             tracing::warn!(?err, "controller unreachable; declining");
-            Decision::Decline
+            guard.decline();
+            return CandidateOutcome::Declined;
         }
     };
+    let _span = build_span(Some(decision.correlation()), &candidate.drv_path).entered();
 
-    let target = match decision {
-        Decision::Decline => {
+    let (target, correlation) = match decision {
+        Decision::Decline { .. } => {
             guard.decline();
             return CandidateOutcome::Declined;
         }
-        Decision::Accept { target } => target,
+        Decision::Postpone { .. } => {
+            tracing::info!("controller postponed low-priority candidate");
+            guard.emit_decline(DeclineKind::Postpone);
+            return CandidateOutcome::Declined;
+        }
+        Decision::Accept {
+            target,
+            correlation,
+        } => (target, correlation),
     };
 
     let sentinel_path = match inflight::write_sentinel(
@@ -128,54 +211,189 @@ fn handle_candidate<R: Read>(
         path: sentinel_path,
     };
 
-    let outcome = delegate_remote_build(cfg, settings, candidate, &target, stdin, guard);
-    let (status, candidate_outcome) = match outcome {
-        DelegateOutcome::Built => (BuildStatus::Success, CandidateOutcome::Finished(Ok(()))),
-        DelegateOutcome::BuildFailed => (
-            BuildStatus::Failure,
-            CandidateOutcome::Finished(Err(io::Error::other(
-                "delegated nix __build-remote failed",
-            ))),
-        ),
-        DelegateOutcome::Declined => (BuildStatus::Cancelled, CandidateOutcome::Declined),
+    let delegation = cancel::Delegation::begin();
+    let mut outcome =
+        delegate_remote_build(cfg, settings, candidate, &target, stdin, guard, &delegation);
+    let mut retries = 0;
+    let (status, log_tail, candidate_outcome) = loop {
+        match outcome {
+            DelegateOutcome::Built => {
+                break (
+                    BuildStatus::Success,
+                    None,
+                    CandidateOutcome::Finished(Ok(())),
+                )
+            }
+            DelegateOutcome::BuildFailed { log_tail } => {
+                break (
+                    BuildStatus::Failure,
+                    log_tail,
+                    CandidateOutcome::Finished(Err(io::Error::other(
+                        "delegated nix __build-remote failed",
+                    ))),
+                )
+            }
+            DelegateOutcome::Declined => {
+                // A signal held back while the child was asked is acted on
+                // now: there is no build to stop, only the hook to end.
+                match delegation.cancelled() {
+                    Some(reason) => {
+                        break (
+                            BuildStatus::Cancelled,
+                            None,
+                            CandidateOutcome::Finished(Err(io::Error::other(format!(
+                                "client went away ({reason})"
+                            )))),
+                        )
+                    }
+                    None => break (BuildStatus::Declined, None, CandidateOutcome::Declined),
+                }
+            }
+            DelegateOutcome::Cancelled { reason } => {
+                break (
+                    BuildStatus::Cancelled,
+                    None,
+                    CandidateOutcome::Finished(Err(io::Error::other(format!(
+                        "delegated build cancelled: {reason}"
+                    )))),
+                )
+            }
+            DelegateOutcome::InfraFailed { accepted, log_tail } => {
+                // Report first: the controller benches the failed target
+                // before we ask it for another one.
+                let _ = report_admission_finish(
+                    client,
+                    &candidate.drv_path,
+                    &correlation,
+                    BuildStatus::InfraFailure,
+                    log_tail,
+                );
+                let next = if retries < MAX_INFRA_RETRIES {
+                    retry_target(client, candidate, &correlation)
+                } else {
+                    None
+                };
+                let Some(next) = next else {
+                    return CandidateOutcome::Finished(Err(io::Error::other(
+                        "delegated build failed for infrastructure reasons; no target left to retry on",
+                    )));
+                };
+                retries += 1;
+                tracing::warn!(
+                    target = %next.name,
+                    retries,
+                    "infrastructure failure; retrying on another target"
+                );
+                outcome = redelegate_build(cfg, settings, candidate, &next, accepted, &delegation);
+            }
+        }
     };
-    let _ = report_admission_finish(cfg, &candidate.drv_path, status);
+    let _ = report_admission_finish(client, &candidate.drv_path, &correlation, status, log_tail);
     candidate_outcome
 }
 
-fn ask_controller(cfg: &HookConfig, candidate: &HookCandidate) -> io::Result<Decision> {
-    let mut stream = UnixStream::connect(&cfg.controller_socket)?;
-    perform_handshake_sync(&mut stream)?;
-    let body = DecideCandidate {
+/// Ask the controller again after an infrastructure failure, under the
+/// same correlation id. Nix already has our `# accept`, so a `Decline`
+/// here means the build fails.
+fn retry_target(
+    client: &mut ControllerClient,
+    candidate: &HookCandidate,
+    correlation: &str,
+) -> Option<AcceptTarget> {
+    match ask_controller(client, candidate, Some(correlation)) {
+        Ok(Decision::Accept { target, .. }) => Some(target),
+        Ok(Decision::Decline { .. } | Decision::Postpone { .. }) => {
+            tracing::warn!("no other target available for retry");
+            None
+        }
+        Err(err) => {
+            tracing::warn!(?err, "controller unreachable for retry");
+            None
+        }
+    }
+}
+
+fn ask_controller(
+    client: &mut ControllerClient,
+    candidate: &HookCandidate,
+    correlation: Option<&str>,
+) -> io::Result<Decision> {
+    client.decide(&DecideCandidate {
         drv_path: candidate.drv_path.clone(),
         system: candidate.needed_system.clone(),
         required_features: candidate.required_features.clone(),
         hook_pid: std::process::id(),
-    };
-    write_frame_sync(&mut stream, &Frame::with_body(op::DECIDE_CANDIDATE, &body)?)?;
-    let reply = read_frame_sync(&mut stream)?;
-    if reply.op_id != op::DECISION {
-        return Err(io::Error::other(format!(
-            "expected DECISION reply, got op_id {}",
-            reply.op_id
-        )));
+        session: session_id(),
+        correlation: correlation.map(str::to_string),
+        priority: priority(),
+        drv: drv_details(&candidate.drv_path),
+    })
+}
+
+/// The candidate's `.drv` as read here, where Nix has it; a controller
+/// on another host usually does not.
+fn drv_details(drv_path: &str) -> Option<DrvDetails> {
+    match drv::read(Path::new(drv_path)) {
+        Ok(info) => Some(info.details()),
+        Err(err) => {
+            tracing::debug!(drv = %drv_path, ?err, ".drv not readable");
+            None
+        }
+    }
+}
+
+/// Session key for the candidates this hook sees.
+///
+/// `NBB_SESSION` wins when set (single-user Nix, or a deploy script that
+/// exports it into the daemon's environment). Otherwise Nix forks one
+/// daemon worker per client connection and spawns hooks from it, so the
+/// parent process identifies the `nixos-rebuild` run; its start time
+/// guards against PID reuse.
+pub fn session_id() -> Option<String> {
+    if let Ok(session) = std::env::var("NBB_SESSION") {
+        if !session.is_empty() {
+            return Some(session);
+        }
+    }
+    let ppid = std::os::unix::process::parent_id();
+    let starttime = procfs::process::Process::new(ppid as i32)
+        .and_then(|p| p.stat())
+        .ok()?
+        .starttime;
+    Some(format!("daemon-{ppid}-{starttime}"))
+}
+
+/// The candidate's priority from `NBB_PRIORITY` (`low`, `normal` or
+/// `high`); normal when unset or unrecognised.
+///
+/// Nix only passes the hook settings it knows, so there is no `nix.conf`
+/// key for this. Like `NBB_SESSION` it reaches the hook through the
+/// environment of whatever spawns it: the client with single-user Nix,
+/// the daemon otherwise. A priority registered for the session with
+/// `nbb-controller priority` takes precedence at the controller.
+pub fn priority() -> Priority {
+    match std::env::var("NBB_PRIORITY") {
+        Ok(value) if !value.is_empty() => Priority::parse(&value).unwrap_or_else(|| {
+            tracing::warn!(%value, "unknown NBB_PRIORITY; using normal");
+            Priority::Normal
+        }),
+        _ => Priority::Normal,
     }
-    reply.decode_body()
 }
 
 fn report_admission_finish(
-    cfg: &HookConfig,
+    client: &mut ControllerClient,
     drv_path: &str,
+    correlation: &str,
     status: BuildStatus,
+    log_tail: Option<String>,
 ) -> io::Result<()> {
-    let mut stream = UnixStream::connect(&cfg.controller_socket)?;
-    perform_handshake_sync(&mut stream)?;
-    let body = AdmissionFinish {
+    client.admission_finish(&AdmissionFinish {
         drv_path: drv_path.to_string(),
+        correlation: correlation.to_string(),
         status,
-    };
-    write_frame_sync(&mut stream, &Frame::with_body(op::ADMISSION_FINISH, &body)?)?;
-    Ok(())
+        log_tail,
+    })
 }
 
 struct SentinelGuard {
@@ -1,8 +1,12 @@
 pub mod agent;
 pub mod controller;
+pub mod drv;
 pub mod estimator;
+pub mod health;
 pub mod hook;
 pub mod inflight;
+pub mod link;
+pub mod logging;
 pub mod nix_protocol;
 pub mod persistence;
 pub mod protocol;
@@ -10,6 +14,7 @@ pub mod scheduler;
 pub mod spool;
 pub mod telemetry;
 pub mod util;
+pub mod wake;
 
 /// SHA-256 of the crate's source tree, computed at build time by `build.rs`.
 ///
@@ -0,0 +1,269 @@
+//! Round-trip time and throughput between the controller host and each
+//! target.
+//!
+//! `nix copy` over ssh-ng costs very different amounts to a target on the
+//! LAN and to one behind wireguard. Every [`LinkProbePolicy::interval`]
+//! the controller sends each agent, on its polling connection, an empty
+//! `LINK_PROBE` and then one carrying [`LinkProbePolicy::bytes`] of
+//! payload; the agent answers each with `LINK_PROBE_REPLY` as soon as it
+//! has read it. The empty probe's round trip is the RTT. The full one's,
+//! less that RTT, is how long its payload took to cross, which gives the
+//! throughput. Samples are kept per target
+//! ([`crate::persistence::links`]), and the median of the latest
+//! [`SUMMARY_SAMPLES`] is the target's [`LinkStats`].
+//!
+//! The probes ride the agent connection rather than ssh, so they measure
+//! the network path and not ssh's own overhead. They start at the
+//! controller host, so the scheduler only charges for copies on
+//! candidates from the controller's own hook.
+
+use std::time::{Duration, Instant};
+
+use tokio::time::{interval, Interval, MissedTickBehavior};
+
+use crate::protocol::frame::MAX_BODY_LEN;
+use crate::protocol::ops::{LinkProbe, LinkProbeReply};
+
+/// Largest payload a probe can carry and still fit in one frame.
+pub const MAX_PROBE_BYTES: u32 = MAX_BODY_LEN - 1024;
+
+/// How many of a target's latest samples make up its [`LinkStats`].
+pub const SUMMARY_SAMPLES: u32 = 5;
+
+/// How often to probe and how much to send.
+#[derive(Clone, Debug)]
+pub struct LinkProbePolicy {
+    pub interval: Duration,
+    /// Payload of the throughput probe, at most [`MAX_PROBE_BYTES`].
+    pub bytes: u32,
+}
+
+/// One probe's figures, or the median of several.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinkStats {
+    pub rtt_us: u64,
+    pub bytes_per_sec: u64,
+}
+
+impl LinkStats {
+    /// Time to copy `bytes` over the link: a round trip plus the transfer.
+    pub fn copy_ms(&self, bytes: u64) -> u64 {
+        let transfer_ms = u128::from(bytes) * 1000 / u128::from(self.bytes_per_sec.max(1));
+        (self.rtt_us / 1000).saturating_add(transfer_ms.min(u128::from(u64::MAX)) as u64)
+    }
+}
+
+/// The median RTT and the median throughput of `samples`, taken
+/// separately; `None` without samples.
+pub fn summarize(samples: &[LinkStats]) -> Option<LinkStats> {
+    fn median(mut values: Vec<u64>) -> u64 {
+        values.sort_unstable();
+        values[values.len() / 2]
+    }
+    if samples.is_empty() {
+        return None;
+    }
+    Some(LinkStats {
+        rtt_us: median(samples.iter().map(|s| s.rtt_us).collect()),
+        bytes_per_sec: median(samples.iter().map(|s| s.bytes_per_sec).collect()),
+    })
+}
+
+/// Probe bookkeeping for one agent session.
+pub struct LinkProber {
+    every: Option<Duration>,
+    /// Started by the first [`Self::due`].
+    ticker: Option<Interval>,
+    bytes: u32,
+    next_id: u64,
+    round: Option<Round>,
+}
+
+/// The probes of one measurement: which ids they went out under and when.
+struct Round {
+    rtt_id: u64,
+    rtt_sent: Instant,
+    rtt_us: Option<u64>,
+    bulk: Option<(u64, Instant)>,
+}
+
+impl LinkProber {
+    /// A prober for a new session; `None` never probes. The first round is
+    /// due straight away.
+    pub fn new(policy: Option<&LinkProbePolicy>) -> Self {
+        Self {
+            every: policy.map(|p| p.interval),
+            ticker: None,
+            bytes: policy.map_or(0, |p| p.bytes.min(MAX_PROBE_BYTES)),
+            next_id: 0,
+            round: None,
+        }
+    }
+
+    /// Resolves when the next round should start; never if probing is off.
+    pub async fn due(&mut self) {
+        let Some(every) = self.every else {
+            return std::future::pending().await;
+        };
+        self.ticker
+            .get_or_insert_with(|| {
+                let mut ticker = interval(every);
+                ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                ticker
+            })
+            .tick()
+            .await;
+    }
+
+    /// The RTT probe opening a round, sent at `now`. A round still waiting
+    /// for replies is abandoned.
+    pub fn rtt_probe(&mut self, now: Instant) -> LinkProbe {
+        self.next_id += 1;
+        self.round = Some(Round {
+            rtt_id: self.next_id,
+            rtt_sent: now,
+            rtt_us: None,
+            bulk: None,
+        });
+        LinkProbe {
+            id: self.next_id,
+            payload: Vec::new(),
+        }
+    }
+
+    /// The throughput probe following [`Self::rtt_probe`], sent at `now`.
+    pub fn bulk_probe(&mut self, now: Instant) -> LinkProbe {
+        self.next_id += 1;
+        if let Some(round) = &mut self.round {
+            round.bulk = Some((self.next_id, now));
+        }
+        LinkProbe {
+            id: self.next_id,
+            payload: vec![0; self.bytes as usize],
+        }
+    }
+
+    /// Note a reply that arrived at `now`. Returns the round's figures once
+    /// both of its probes have been answered.
+    pub fn answered(&mut self, reply: &LinkProbeReply, now: Instant) -> Option<LinkStats> {
+        let round = self.round.as_mut()?;
+        if reply.id == round.rtt_id {
+            round.rtt_us = Some(micros(now.saturating_duration_since(round.rtt_sent)));
+            return None;
+        }
+        let (bulk_id, bulk_sent) = round.bulk?;
+        if reply.id != bulk_id {
+            return None;
+        }
+        let rtt_us = round.rtt_us?;
+        self.round = None;
+        let elapsed_us = micros(now.saturating_duration_since(bulk_sent));
+        let transfer_us = elapsed_us.saturating_sub(rtt_us).max(1);
+        let bytes_per_sec = u128::from(reply.received_bytes) * 1_000_000 / u128::from(transfer_us);
+        Some(LinkStats {
+            rtt_us,
+            bytes_per_sec: bytes_per_sec.min(u128::from(u64::MAX)) as u64,
+        })
+    }
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_micros().min(u128::from(u64::MAX)) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(bytes: u32) -> LinkProbePolicy {
+        LinkProbePolicy {
+            interval: Duration::from_secs(600),
+            bytes,
+        }
+    }
+
+    fn reply(probe: &LinkProbe) -> LinkProbeReply {
+        LinkProbeReply {
+            id: probe.id,
+            received_bytes: probe.payload.len() as u64,
+        }
+    }
+
+    #[test]
+    fn round_yields_rtt_and_throughput_net_of_rtt() {
+        let mut prober = LinkProber::new(Some(&policy(1_000_000)));
+        let start = Instant::now();
+        let rtt = prober.rtt_probe(start);
+        let bulk = prober.bulk_probe(start);
+        assert!(rtt.payload.is_empty());
+        assert_eq!(bulk.payload.len(), 1_000_000);
+
+        let ms = Duration::from_millis;
+        assert_eq!(prober.answered(&reply(&rtt), start + ms(20)), None);
+        // 1 MB arriving 120 ms after it went out: 100 ms of transfer.
+        let stats = prober.answered(&reply(&bulk), start + ms(120));
+        assert_eq!(
+            stats,
+            Some(LinkStats {
+                rtt_us: 20_000,
+                bytes_per_sec: 10_000_000,
+            })
+        );
+        // The round is over; a repeated reply is ignored.
+        assert_eq!(prober.answered(&reply(&bulk), start + ms(130)), None);
+    }
+
+    #[test]
+    fn replies_from_an_abandoned_round_are_ignored() {
+        let mut prober = LinkProber::new(Some(&policy(1_000)));
+        let start = Instant::now();
+        let stale_rtt = prober.rtt_probe(start);
+        let stale_bulk = prober.bulk_probe(start);
+        let rtt = prober.rtt_probe(start);
+        let bulk = prober.bulk_probe(start);
+        let ms = Duration::from_millis;
+        assert_eq!(prober.answered(&reply(&stale_rtt), start + ms(1)), None);
+        assert_eq!(prober.answered(&reply(&stale_bulk), start + ms(2)), None);
+        assert_eq!(prober.answered(&reply(&rtt), start + ms(3)), None);
+        assert!(prober.answered(&reply(&bulk), start + ms(4)).is_some());
+    }
+
+    #[test]
+    fn probe_payload_is_capped_to_fit_a_frame() {
+        let mut prober = LinkProber::new(Some(&policy(u32::MAX)));
+        prober.rtt_probe(Instant::now());
+        let bulk = prober.bulk_probe(Instant::now());
+        assert_eq!(bulk.payload.len(), MAX_PROBE_BYTES as usize);
+        assert!(crate::protocol::frame::Frame::with_body(0, &bulk).is_ok());
+    }
+
+    #[test]
+    fn summary_takes_medians_separately() {
+        let sample = |rtt_us, bytes_per_sec| LinkStats {
+            rtt_us,
+            bytes_per_sec,
+        };
+        assert_eq!(summarize(&[]), None);
+        let samples = [
+            sample(900, 5_000_000),
+            sample(1_000, 120_000_000),
+            sample(50_000, 110_000_000),
+        ];
+        assert_eq!(summarize(&samples), Some(sample(1_000, 110_000_000)));
+    }
+
+    #[test]
+    fn copy_time_is_a_round_trip_plus_transfer() {
+        let wireguard = LinkStats {
+            rtt_us: 30_000,
+            bytes_per_sec: 10_000_000,
+        };
+        assert_eq!(wireguard.copy_ms(0), 30);
+        assert_eq!(wireguard.copy_ms(500_000_000), 50_030);
+        let broken = LinkStats {
+            rtt_us: 0,
+            bytes_per_sec: 0,
+        };
+        assert_eq!(broken.copy_ms(1_000), 1_000_000);
+    }
+}
@@ -0,0 +1,106 @@
+//! Log setup shared by `nbb-hook`, `nbb-controller` and `nbb-agent`, and
+//! the correlation id that ties one candidate's lines together.
+//!
+//! The controller mints a correlation id ([`new_correlation`]) for every
+//! candidate it decides and returns it in the [`Decision`]. The hook
+//! sends it back with `ADMISSION_FINISH` and when re-asking after an
+//! infrastructure failure; the controller passes it to the target's agent
+//! in `BUILD_ADMITTED`, and the agent puts it in the build's
+//! `EVENT_BUILD_FINISH`. Each process logs what it does for the build
+//! inside a [`build_span`], so the id is a field of every line.
+//!
+//! Under systemd the daemons log to the journal natively, with span and
+//! event fields as `NBB_`-prefixed journal fields:
+//! `journalctl NBB_CORRELATION=<id>` follows one candidate through all
+//! three processes on a host. The hook's stderr belongs to Nix, so it
+//! logs there as before and sends the journal a copy.
+//!
+//! [`Decision`]: crate::protocol::ops::Decision
+
+use std::os::fd::AsRawFd;
+
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// A fresh correlation id: a ULID, so ids sort by decision time.
+pub fn new_correlation() -> String {
+    ulid::Ulid::new().to_string()
+}
+
+/// The span a process logs one candidate's work in. `correlation` is
+/// `None` where a build turns up that no decision is known for.
+pub fn build_span(correlation: Option<&str>, drv_path: &str) -> tracing::Span {
+    let span = tracing::info_span!(
+        "build",
+        correlation = tracing::field::Empty,
+        drv = %drv_path
+    );
+    if let Some(correlation) = correlation {
+        span.record("correlation", correlation);
+    }
+    span
+}
+
+/// Where a process's log lines go besides the journal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogOutput {
+    /// A daemon: the journal when stderr is connected to it, else stderr.
+    Daemon,
+    /// `nbb-hook`: always stderr, which Nix shows to the user, and a copy
+    /// to the journal when there is one.
+    Hook,
+}
+
+/// Install the global subscriber, filtered by `NBB_LOG` (default `info`).
+pub fn init(output: LogOutput) {
+    let filter = EnvFilter::try_from_env("NBB_LOG").unwrap_or_else(|_| EnvFilter::new("info"));
+    let stderr_is_journal = stderr_is_journal();
+    let journald = (stderr_is_journal || output == LogOutput::Hook)
+        .then(|| tracing_journald::layer().ok())
+        .flatten()
+        .map(|layer| layer.with_field_prefix(Some("NBB".to_string())));
+    let fmt = (journald.is_none() || output == LogOutput::Hook).then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .boxed()
+    });
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(journald)
+        .with(fmt)
+        .init();
+}
+
+/// Whether stderr is the stream systemd connected to the journal, as
+/// `$JOURNAL_STREAM` (`<device>:<inode>`) describes it. Child processes
+/// inherit the variable with a different stderr, so it is checked.
+fn stderr_is_journal() -> bool {
+    let Some((dev, ino)) = std::env::var("JOURNAL_STREAM").ok().and_then(|value| {
+        let (dev, ino) = value.split_once(':')?;
+        Some((dev.parse::<u64>().ok()?, ino.parse::<u64>().ok()?))
+    }) else {
+        return false;
+    };
+    // SAFETY: `stat` is plain old data, filled in by `fstat` on success.
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(std::io::stderr().as_raw_fd(), &mut stat) } != 0 {
+        return false;
+    }
+    stat.st_dev as u64 == dev && stat.st_ino as u64 == ino
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn correlation_ids_are_distinct_ulids() {
+        let first = new_correlation();
+        let second = new_correlation();
+        assert_ne!(first, second);
+        for id in [&first, &second] {
+            assert!(ulid::Ulid::from_string(id).is_ok(), "{id}");
+        }
+    }
+}
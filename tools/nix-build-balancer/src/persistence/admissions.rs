@@ -1,6 +1,6 @@
 use std::io;
 
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OptionalExtension};
 
 /// One row of the `admissions` table.
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -9,6 +9,8 @@ pub struct AdmissionRow {
     pub target_name: String,
     pub admitted_at_ms: u64,
     pub predicted_ms: u64,
+    /// Of the decision that admitted the build ([`crate::logging`]).
+    pub correlation: Option<String>,
 }
 
 /// Insert or update an admission. Spec §"Build observation lifecycle" item
@@ -19,19 +21,22 @@ pub fn record(
     target_name: &str,
     admitted_at_ms: u64,
     predicted_ms: u64,
+    correlation: &str,
 ) -> io::Result<()> {
     conn.execute(
-        "INSERT INTO admissions (drv_path, target_name, admitted_at_ms, predicted_ms)
-         VALUES (?1, ?2, ?3, ?4)
+        "INSERT INTO admissions (drv_path, target_name, admitted_at_ms, predicted_ms, correlation)
+         VALUES (?1, ?2, ?3, ?4, ?5)
          ON CONFLICT(drv_path) DO UPDATE SET
            target_name    = excluded.target_name,
            admitted_at_ms = excluded.admitted_at_ms,
-           predicted_ms   = excluded.predicted_ms",
+           predicted_ms   = excluded.predicted_ms,
+           correlation    = excluded.correlation",
         params![
             drv_path,
             target_name,
             admitted_at_ms as i64,
             predicted_ms as i64,
+            correlation,
         ],
     )
     .map_err(io::Error::other)?;
@@ -52,23 +57,28 @@ pub fn retire(conn: &Connection, drv_path: &str) -> io::Result<bool> {
     Ok(changed > 0)
 }
 
+pub fn get(conn: &Connection, drv_path: &str) -> io::Result<Option<AdmissionRow>> {
+    conn.query_row(
+        "SELECT drv_path, target_name, admitted_at_ms, predicted_ms, correlation
+         FROM admissions
+         WHERE drv_path = ?1",
+        params![drv_path],
+        admission_row,
+    )
+    .optional()
+    .map_err(io::Error::other)
+}
+
 pub fn list(conn: &Connection) -> io::Result<Vec<AdmissionRow>> {
     let mut stmt = conn
         .prepare(
-            "SELECT drv_path, target_name, admitted_at_ms, predicted_ms
+            "SELECT drv_path, target_name, admitted_at_ms, predicted_ms, correlation
              FROM admissions
              ORDER BY admitted_at_ms",
         )
         .map_err(io::Error::other)?;
     let rows = stmt
-        .query_map([], |row| {
-            Ok(AdmissionRow {
-                drv_path: row.get(0)?,
-                target_name: row.get(1)?,
-                admitted_at_ms: row.get::<_, i64>(2)?.max(0) as u64,
-                predicted_ms: row.get::<_, i64>(3)?.max(0) as u64,
-            })
-        })
+        .query_map([], admission_row)
         .map_err(io::Error::other)?;
     let mut result = Vec::new();
     for row in rows {
@@ -77,6 +87,16 @@ pub fn list(conn: &Connection) -> io::Result<Vec<AdmissionRow>> {
     Ok(result)
 }
 
+fn admission_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<AdmissionRow> {
+    Ok(AdmissionRow {
+        drv_path: row.get(0)?,
+        target_name: row.get(1)?,
+        admitted_at_ms: row.get::<_, i64>(2)?.max(0) as u64,
+        predicted_ms: row.get::<_, i64>(3)?.max(0) as u64,
+        correlation: row.get(4)?,
+    })
+}
+
 /// Drv paths whose admissions have outlived `max(predicted_ms * 2, 60_000)`.
 /// The controller's watchdog reads this on every tick and retires each
 /// stale row directly — no synthesised finish event is emitted, so the
@@ -99,11 +119,29 @@ mod tests {
     use super::*;
     use crate::persistence::open_in_memory;
 
+    const CORRELATION: &str = "01JAXKQ4V6M0S7Y8E2N5D3W1HC";
+
     #[test]
     fn record_and_list_in_admission_order() {
         let conn = open_in_memory().unwrap();
-        record(&conn, "/nix/store/b-bar.drv", "tsugumi", 200, 7_000).unwrap();
-        record(&conn, "/nix/store/a-foo.drv", "tsugumi", 100, 5_000).unwrap();
+        record(
+            &conn,
+            "/nix/store/b-bar.drv",
+            "tsugumi",
+            200,
+            7_000,
+            CORRELATION,
+        )
+        .unwrap();
+        record(
+            &conn,
+            "/nix/store/a-foo.drv",
+            "tsugumi",
+            100,
+            5_000,
+            CORRELATION,
+        )
+        .unwrap();
         let rows = list(&conn).unwrap();
         assert_eq!(rows.len(), 2);
         assert_eq!(rows[0].drv_path, "/nix/store/a-foo.drv");
@@ -115,8 +153,24 @@ mod tests {
     #[test]
     fn re_admission_overwrites() {
         let conn = open_in_memory().unwrap();
-        record(&conn, "/nix/store/a-foo.drv", "tsugumi", 100, 5_000).unwrap();
-        record(&conn, "/nix/store/a-foo.drv", "saya", 300, 9_000).unwrap();
+        record(
+            &conn,
+            "/nix/store/a-foo.drv",
+            "tsugumi",
+            100,
+            5_000,
+            CORRELATION,
+        )
+        .unwrap();
+        record(
+            &conn,
+            "/nix/store/a-foo.drv",
+            "saya",
+            300,
+            9_000,
+            CORRELATION,
+        )
+        .unwrap();
         let rows = list(&conn).unwrap();
         assert_eq!(rows.len(), 1);
         assert_eq!(rows[0].target_name, "saya");
@@ -124,10 +178,36 @@ mod tests {
         assert_eq!(rows[0].predicted_ms, 9_000);
     }
 
+    #[test]
+    fn get_finds_only_the_admitted_drv() {
+        let conn = open_in_memory().unwrap();
+        record(
+            &conn,
+            "/nix/store/a-foo.drv",
+            "tsugumi",
+            100,
+            5_000,
+            CORRELATION,
+        )
+        .unwrap();
+        let row = get(&conn, "/nix/store/a-foo.drv").unwrap().unwrap();
+        assert_eq!(row.target_name, "tsugumi");
+        assert_eq!(row.correlation.as_deref(), Some(CORRELATION));
+        assert_eq!(get(&conn, "/nix/store/b-bar.drv").unwrap(), None);
+    }
+
     #[test]
     fn retire_returns_true_on_first_then_false() {
         let conn = open_in_memory().unwrap();
-        record(&conn, "/nix/store/a-foo.drv", "tsugumi", 100, 5_000).unwrap();
+        record(
+            &conn,
+            "/nix/store/a-foo.drv",
+            "tsugumi",
+            100,
+            5_000,
+            CORRELATION,
+        )
+        .unwrap();
         assert!(retire(&conn, "/nix/store/a-foo.drv").unwrap());
         assert!(!retire(&conn, "/nix/store/a-foo.drv").unwrap());
         assert!(list(&conn).unwrap().is_empty());
@@ -137,9 +217,25 @@ mod tests {
     fn stale_drvs_uses_max_of_predicted_times_two_and_sixty_seconds() {
         let conn = open_in_memory().unwrap();
         // Predicted 10s, admitted at t=0. TTL = max(20s, 60s) = 60s.
-        record(&conn, "/nix/store/short.drv", "tsugumi", 0, 10_000).unwrap();
+        record(
+            &conn,
+            "/nix/store/short.drv",
+            "tsugumi",
+            0,
+            10_000,
+            CORRELATION,
+        )
+        .unwrap();
         // Predicted 90s, admitted at t=0. TTL = max(180s, 60s) = 180s.
-        record(&conn, "/nix/store/long.drv", "tsugumi", 0, 90_000).unwrap();
+        record(
+            &conn,
+            "/nix/store/long.drv",
+            "tsugumi",
+            0,
+            90_000,
+            CORRELATION,
+        )
+        .unwrap();
 
         // At t=30s, nothing stale yet.
         assert!(stale_drvs(&conn, 30_000).unwrap().is_empty());
@@ -163,7 +259,7 @@ mod tests {
     #[test]
     fn stale_drvs_handles_zero_predicted_ms_with_sixty_second_floor() {
         let conn = open_in_memory().unwrap();
-        record(&conn, "/nix/store/zero.drv", "tsugumi", 0, 0).unwrap();
+        record(&conn, "/nix/store/zero.drv", "tsugumi", 0, 0, CORRELATION).unwrap();
         // TTL = max(0, 60_000) = 60_000.
         assert!(stale_drvs(&conn, 30_000).unwrap().is_empty());
         assert_eq!(
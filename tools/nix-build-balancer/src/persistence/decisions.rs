@@ -0,0 +1,424 @@
+//! Decision log: every `make_decision` outcome with its per-target working,
+//! kept for `decision_log_retention` so slow rebuilds can be explained
+//! after the fact (`nbb-controller decisions`). Each row also records how
+//! long the decision took, which [`latency_histograms`] buckets for
+//! `nbb-controller latency`.
+
+use std::io;
+
+use rusqlite::{params, Connection};
+
+/// One row of `decisions` plus its `decision_targets` children.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecisionRow {
+    pub decided_at_ms: u64,
+    pub drv_path: String,
+    pub pname: String,
+    pub system: String,
+    pub estimate_ms: Option<u64>,
+    pub estimate_tier: String,
+    /// `accept`, `substitute`, `route-local`, `decline`, `postpone` or
+    /// `fast-path:<reason>`.
+    pub outcome: String,
+    pub winner: Option<String>,
+    /// [`crate::protocol::ops::DecideCandidate::session`].
+    pub session: Option<String>,
+    /// From the candidate's arrival to the decision being logged; `None`
+    /// for rows logged before this was recorded.
+    pub latency_us: Option<u64>,
+    pub targets: Vec<DecisionTargetRow>,
+}
+
+/// One target's line in a decision. Exactly one of `excluded` and the
+/// three timing fields is set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecisionTargetRow {
+    pub target_name: String,
+    /// An [`crate::scheduler::Exclusion`] name, e.g. `stale` or `low-store`.
+    pub excluded: Option<String>,
+    pub queue_ms: Option<u64>,
+    pub package_ms: Option<u64>,
+    pub completion_ms: Option<u64>,
+}
+
+/// Filter for [`query`]. Empty fields match everything; the time range is
+/// half-open, `[since_ms, until_ms)`.
+#[derive(Clone, Debug, Default)]
+pub struct DecisionQuery {
+    pub drv_path: Option<String>,
+    pub since_ms: Option<u64>,
+    pub until_ms: Option<u64>,
+    pub limit: Option<u32>,
+}
+
+pub fn record(conn: &Connection, row: &DecisionRow) -> io::Result<()> {
+    let tx = conn.unchecked_transaction().map_err(io::Error::other)?;
+    tx.execute(
+        "INSERT INTO decisions
+           (decided_at_ms, drv_path, pname, system, estimate_ms, estimate_tier, outcome, winner,
+            session, latency_us)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            to_i64(row.decided_at_ms),
+            row.drv_path,
+            row.pname,
+            row.system,
+            row.estimate_ms.map(to_i64),
+            row.estimate_tier,
+            row.outcome,
+            row.winner,
+            row.session,
+            row.latency_us.map(to_i64),
+        ],
+    )
+    .map_err(io::Error::other)?;
+    let decision_id = tx.last_insert_rowid();
+    for (position, target) in row.targets.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO decision_targets
+               (decision_id, position, target_name, excluded, queue_ms, package_ms, completion_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                decision_id,
+                position as i64,
+                target.target_name,
+                target.excluded,
+                target.queue_ms.map(to_i64),
+                target.package_ms.map(to_i64),
+                target.completion_ms.map(to_i64),
+            ],
+        )
+        .map_err(io::Error::other)?;
+    }
+    tx.commit().map_err(io::Error::other)
+}
+
+/// Decisions matching `filter`, oldest first.
+pub fn query(conn: &Connection, filter: &DecisionQuery) -> io::Result<Vec<DecisionRow>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT id, decided_at_ms, drv_path, pname, system, estimate_ms, estimate_tier,
+                    outcome, winner, session, latency_us
+             FROM decisions
+             WHERE (?1 IS NULL OR drv_path = ?1)
+               AND (?2 IS NULL OR decided_at_ms >= ?2)
+               AND (?3 IS NULL OR decided_at_ms < ?3)
+             ORDER BY decided_at_ms, id
+             LIMIT ?4",
+        )
+        .map_err(io::Error::other)?;
+    let rows = stmt
+        .query_map(
+            params![
+                filter.drv_path,
+                filter.since_ms.map(to_i64),
+                filter.until_ms.map(to_i64),
+                filter.limit.map_or(-1, i64::from),
+            ],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    DecisionRow {
+                        decided_at_ms: row.get::<_, i64>(1)?.max(0) as u64,
+                        drv_path: row.get(2)?,
+                        pname: row.get(3)?,
+                        system: row.get(4)?,
+                        estimate_ms: row.get::<_, Option<i64>>(5)?.map(|v| v.max(0) as u64),
+                        estimate_tier: row.get(6)?,
+                        outcome: row.get(7)?,
+                        winner: row.get(8)?,
+                        session: row.get(9)?,
+                        latency_us: row.get::<_, Option<i64>>(10)?.map(|v| v.max(0) as u64),
+                        targets: Vec::new(),
+                    },
+                ))
+            },
+        )
+        .map_err(io::Error::other)?;
+    let mut result = Vec::new();
+    for row in rows {
+        let (id, mut decision) = row.map_err(io::Error::other)?;
+        decision.targets = targets_for(conn, id)?;
+        result.push(decision);
+    }
+    Ok(result)
+}
+
+fn targets_for(conn: &Connection, decision_id: i64) -> io::Result<Vec<DecisionTargetRow>> {
+    let mut stmt = conn
+        .prepare_cached(
+            "SELECT target_name, excluded, queue_ms, package_ms, completion_ms
+             FROM decision_targets
+             WHERE decision_id = ?1
+             ORDER BY position",
+        )
+        .map_err(io::Error::other)?;
+    let rows = stmt
+        .query_map(params![decision_id], |row| {
+            let ms = |idx| -> rusqlite::Result<Option<u64>> {
+                Ok(row.get::<_, Option<i64>>(idx)?.map(|v| v.max(0) as u64))
+            };
+            Ok(DecisionTargetRow {
+                target_name: row.get(0)?,
+                excluded: row.get(1)?,
+                queue_ms: ms(2)?,
+                package_ms: ms(3)?,
+                completion_ms: ms(4)?,
+            })
+        })
+        .map_err(io::Error::other)?;
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(row.map_err(io::Error::other)?);
+    }
+    Ok(result)
+}
+
+/// Upper bounds (µs) of the latency histogram buckets. A last bucket
+/// holds everything slower.
+pub const LATENCY_BUCKETS_US: [u64; 10] = [
+    1_000, 2_000, 5_000, 10_000, 20_000, 50_000, 100_000, 500_000, 1_000_000, 5_000_000,
+];
+
+/// How long the decisions with one outcome took.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    pub outcome: String,
+    /// Decisions per bucket of [`LATENCY_BUCKETS_US`], then the slower
+    /// ones.
+    pub counts: [u64; LATENCY_BUCKETS_US.len() + 1],
+    pub max_us: u64,
+}
+
+impl LatencyHistogram {
+    fn new(outcome: String) -> Self {
+        Self {
+            outcome,
+            counts: [0; LATENCY_BUCKETS_US.len() + 1],
+            max_us: 0,
+        }
+    }
+
+    fn add(&mut self, latency_us: u64) {
+        let bucket = LATENCY_BUCKETS_US
+            .iter()
+            .position(|&bound| latency_us <= bound)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.counts[bucket] += 1;
+        self.max_us = self.max_us.max(latency_us);
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// An upper bound on the `q` quantile: the bound of the bucket it
+    /// falls in, or the maximum if that is lower.
+    pub fn quantile_us(&self, q: f64) -> u64 {
+        let rank = ((self.total() as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let bound = LATENCY_BUCKETS_US.get(bucket).copied().unwrap_or(u64::MAX);
+                return bound.min(self.max_us);
+            }
+        }
+        self.max_us
+    }
+}
+
+/// Latency histograms per outcome of the decisions in `[since_ms,
+/// until_ms)`, by outcome name. Rows without a latency are left out.
+pub fn latency_histograms(
+    conn: &Connection,
+    since_ms: Option<u64>,
+    until_ms: Option<u64>,
+) -> io::Result<Vec<LatencyHistogram>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT outcome, latency_us
+             FROM decisions
+             WHERE latency_us IS NOT NULL
+               AND (?1 IS NULL OR decided_at_ms >= ?1)
+               AND (?2 IS NULL OR decided_at_ms < ?2)
+             ORDER BY outcome",
+        )
+        .map_err(io::Error::other)?;
+    let rows = stmt
+        .query_map(params![since_ms.map(to_i64), until_ms.map(to_i64)], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?.max(0) as u64,
+            ))
+        })
+        .map_err(io::Error::other)?;
+    let mut result: Vec<LatencyHistogram> = Vec::new();
+    for row in rows {
+        let (outcome, latency_us) = row.map_err(io::Error::other)?;
+        if result.last().is_none_or(|h| h.outcome != outcome) {
+            result.push(LatencyHistogram::new(outcome));
+        }
+        result.last_mut().expect("pushed").add(latency_us);
+    }
+    Ok(result)
+}
+
+/// Drop decisions made before `cutoff_ms`. Returns how many were removed.
+pub fn prune_before(conn: &Connection, cutoff_ms: u64) -> io::Result<usize> {
+    let tx = conn.unchecked_transaction().map_err(io::Error::other)?;
+    tx.execute(
+        "DELETE FROM decision_targets WHERE decision_id IN
+           (SELECT id FROM decisions WHERE decided_at_ms < ?1)",
+        params![to_i64(cutoff_ms)],
+    )
+    .map_err(io::Error::other)?;
+    let removed = tx
+        .execute(
+            "DELETE FROM decisions WHERE decided_at_ms < ?1",
+            params![to_i64(cutoff_ms)],
+        )
+        .map_err(io::Error::other)?;
+    tx.commit().map_err(io::Error::other)?;
+    Ok(removed)
+}
+
+/// `queue_ms` is `u64::MAX` for zero-capacity targets; SQLite integers are
+/// signed, so saturate rather than wrap.
+fn to_i64(value: u64) -> i64 {
+    i64::try_from(value).unwrap_or(i64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::open_in_memory;
+
+    fn decision(drv: &str, at_ms: u64) -> DecisionRow {
+        DecisionRow {
+            decided_at_ms: at_ms,
+            drv_path: drv.to_string(),
+            pname: "foo".to_string(),
+            system: "x86_64-linux".to_string(),
+            estimate_ms: Some(5_000),
+            estimate_tier: "exact".to_string(),
+            outcome: "accept".to_string(),
+            winner: Some("tsugumi".to_string()),
+            session: Some("s1".to_string()),
+            latency_us: Some(1_500),
+            targets: vec![
+                DecisionTargetRow {
+                    target_name: "tsugumi".to_string(),
+                    excluded: None,
+                    queue_ms: Some(0),
+                    package_ms: Some(5_000),
+                    completion_ms: Some(5_000),
+                },
+                DecisionTargetRow {
+                    target_name: "kaho".to_string(),
+                    excluded: Some("stale".to_string()),
+                    queue_ms: None,
+                    package_ms: None,
+                    completion_ms: None,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trips_with_targets_in_order() {
+        let conn = open_in_memory().unwrap();
+        let row = decision("/nix/store/a-foo.drv", 1_000);
+        record(&conn, &row).unwrap();
+        let got = query(&conn, &DecisionQuery::default()).unwrap();
+        assert_eq!(got, vec![row]);
+    }
+
+    #[test]
+    fn filters_by_drv_and_time_range() {
+        let conn = open_in_memory().unwrap();
+        record(&conn, &decision("/nix/store/a-foo.drv", 1_000)).unwrap();
+        record(&conn, &decision("/nix/store/b-bar.drv", 2_000)).unwrap();
+        record(&conn, &decision("/nix/store/a-foo.drv", 3_000)).unwrap();
+
+        let by_drv = query(
+            &conn,
+            &DecisionQuery {
+                drv_path: Some("/nix/store/a-foo.drv".into()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            by_drv.iter().map(|d| d.decided_at_ms).collect::<Vec<_>>(),
+            vec![1_000, 3_000]
+        );
+
+        let in_range = query(
+            &conn,
+            &DecisionQuery {
+                since_ms: Some(2_000),
+                until_ms: Some(3_000),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(in_range.len(), 1);
+        assert_eq!(in_range[0].drv_path, "/nix/store/b-bar.drv");
+    }
+
+    #[test]
+    fn prune_drops_old_decisions_and_their_targets() {
+        let conn = open_in_memory().unwrap();
+        record(&conn, &decision("/nix/store/a-foo.drv", 1_000)).unwrap();
+        record(&conn, &decision("/nix/store/b-bar.drv", 5_000)).unwrap();
+        assert_eq!(prune_before(&conn, 2_000).unwrap(), 1);
+        let left = query(&conn, &DecisionQuery::default()).unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].drv_path, "/nix/store/b-bar.drv");
+        let remaining_targets: i64 = conn
+            .query_row("SELECT COUNT(*) FROM decision_targets", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(remaining_targets, 2);
+    }
+
+    #[test]
+    fn latencies_are_bucketed_per_outcome() {
+        let conn = open_in_memory().unwrap();
+        let logged = |outcome: &str, at_ms, latency_us| {
+            let mut row = decision("/nix/store/a-foo.drv", at_ms);
+            row.outcome = outcome.to_string();
+            row.latency_us = latency_us;
+            record(&conn, &row).unwrap();
+        };
+        for latency_us in [400, 900, 1_800, 40_000] {
+            logged("accept", 1_000, Some(latency_us));
+        }
+        logged("decline", 1_000, Some(7_000_000));
+        logged("decline", 1_000, None);
+        logged("accept", 9_000, Some(1));
+
+        let histograms = latency_histograms(&conn, None, Some(5_000)).unwrap();
+        let outcomes: Vec<&str> = histograms.iter().map(|h| h.outcome.as_str()).collect();
+        assert_eq!(outcomes, vec!["accept", "decline"]);
+        let accept = &histograms[0];
+        assert_eq!(accept.counts[..6], [2, 1, 0, 0, 0, 1]);
+        assert_eq!(accept.total(), 4);
+        assert_eq!(accept.max_us, 40_000);
+        assert_eq!(accept.quantile_us(0.5), 1_000);
+        assert_eq!(accept.quantile_us(0.95), 40_000);
+        let decline = &histograms[1];
+        assert_eq!(decline.counts[LATENCY_BUCKETS_US.len()], 1);
+        assert_eq!(decline.quantile_us(0.5), 7_000_000);
+    }
+
+    #[test]
+    fn saturates_unbounded_queue_ms() {
+        let conn = open_in_memory().unwrap();
+        let mut row = decision("/nix/store/a-foo.drv", 1_000);
+        row.targets[0].queue_ms = Some(u64::MAX);
+        record(&conn, &row).unwrap();
+        let got = query(&conn, &DecisionQuery::default()).unwrap();
+        assert_eq!(got[0].targets[0].queue_ms, Some(i64::MAX as u64));
+    }
+}
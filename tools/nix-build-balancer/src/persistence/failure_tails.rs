@@ -0,0 +1,124 @@
+//! Why delegated builds failed: the log tail the hook ships with a failing
+//! `ADMISSION_FINISH`, kept per pname (`nbb-controller failures`).
+
+use std::io;
+
+use rusqlite::{params, Connection};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FailureTailRow {
+    pub pname: String,
+    pub drv_path: String,
+    pub target_name: String,
+    /// `failure` or `infra-failure`.
+    pub status: String,
+    pub finished_at_ms: u64,
+    pub tail: String,
+}
+
+/// Store `row`, then drop all but the newest `keep_per_pname` rows for its
+/// pname.
+pub fn record(conn: &Connection, row: &FailureTailRow, keep_per_pname: u32) -> io::Result<()> {
+    let tx = conn.unchecked_transaction().map_err(io::Error::other)?;
+    tx.execute(
+        "INSERT INTO failure_tails
+           (pname, drv_path, target_name, status, finished_at_ms, tail)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            row.pname,
+            row.drv_path,
+            row.target_name,
+            row.status,
+            row.finished_at_ms as i64,
+            row.tail,
+        ],
+    )
+    .map_err(io::Error::other)?;
+    tx.execute(
+        "DELETE FROM failure_tails
+         WHERE pname = ?1
+           AND id NOT IN (
+             SELECT id FROM failure_tails
+             WHERE pname = ?1
+             ORDER BY finished_at_ms DESC, id DESC
+             LIMIT ?2)",
+        params![row.pname, i64::from(keep_per_pname)],
+    )
+    .map_err(io::Error::other)?;
+    tx.commit().map_err(io::Error::other)
+}
+
+/// Stored tails, newest first, optionally for one pname only.
+pub fn list(conn: &Connection, pname: Option<&str>, limit: u32) -> io::Result<Vec<FailureTailRow>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT pname, drv_path, target_name, status, finished_at_ms, tail
+             FROM failure_tails
+             WHERE (?1 IS NULL OR pname = ?1)
+             ORDER BY finished_at_ms DESC, id DESC
+             LIMIT ?2",
+        )
+        .map_err(io::Error::other)?;
+    let rows = stmt
+        .query_map(params![pname, i64::from(limit)], |row| {
+            Ok(FailureTailRow {
+                pname: row.get(0)?,
+                drv_path: row.get(1)?,
+                target_name: row.get(2)?,
+                status: row.get(3)?,
+                finished_at_ms: row.get::<_, i64>(4)?.max(0) as u64,
+                tail: row.get(5)?,
+            })
+        })
+        .map_err(io::Error::other)?;
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(row.map_err(io::Error::other)?);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::open_in_memory;
+
+    fn tail(pname: &str, at_ms: u64) -> FailureTailRow {
+        FailureTailRow {
+            pname: pname.to_string(),
+            drv_path: format!("/nix/store/{at_ms}-{pname}.drv"),
+            target_name: "tsugumi".to_string(),
+            status: "failure".to_string(),
+            finished_at_ms: at_ms,
+            tail: format!("error: builder for '{pname}' failed at {at_ms}"),
+        }
+    }
+
+    #[test]
+    fn keeps_newest_per_pname() {
+        let conn = open_in_memory().unwrap();
+        for at in [100, 300, 200, 400] {
+            record(&conn, &tail("foo", at), 2).unwrap();
+        }
+        record(&conn, &tail("bar", 50), 2).unwrap();
+
+        let foo = list(&conn, Some("foo"), 10).unwrap();
+        let times: Vec<u64> = foo.iter().map(|r| r.finished_at_ms).collect();
+        assert_eq!(times, vec![400, 300]);
+        assert_eq!(foo[0], tail("foo", 400));
+
+        // Pruning foo never touches bar.
+        assert_eq!(list(&conn, Some("bar"), 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn list_without_pname_is_newest_first_and_limited() {
+        let conn = open_in_memory().unwrap();
+        record(&conn, &tail("foo", 100), 5).unwrap();
+        record(&conn, &tail("bar", 300), 5).unwrap();
+        record(&conn, &tail("baz", 200), 5).unwrap();
+        let rows = list(&conn, None, 2).unwrap();
+        let pnames: Vec<&str> = rows.iter().map(|r| r.pname.as_str()).collect();
+        assert_eq!(pnames, vec!["bar", "baz"]);
+    }
+}
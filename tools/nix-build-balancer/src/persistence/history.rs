@@ -0,0 +1,417 @@
+//! Moving learned build history between controllers: `build_observations`
+//! as CSV (RFC 4180, header row first), merged on import through the same
+//! `(drv_path, finished_at_ms)` uniqueness that deduplicates replayed
+//! finishes.
+//!
+//! `nbb-controller export-history` / `import-history` move it by hand;
+//! `--seed-history` imports a file into a controller whose history is empty.
+//! Exports from older versions lack the trailing columns added since
+//! (contention, output size); those import as unknown.
+
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+
+use rusqlite::{params, Connection};
+
+use crate::persistence::observations;
+use crate::protocol::ops::BuildStatus;
+
+pub const HEADER: [&str; 11] = [
+    "host",
+    "pname",
+    "drv_path",
+    "started_at_ms",
+    "finished_at_ms",
+    "duration_ms",
+    "status",
+    "out_paths",
+    "mean_builds",
+    "mean_cpu_psi",
+    "output_bytes",
+];
+
+/// Column counts of older exports, each a prefix of [`HEADER`]: without
+/// contention, and without output size.
+const OLDER_COLUMNS: [usize; 2] = [8, 10];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImportStats {
+    pub rows: usize,
+    /// Rows not already present. Rows beyond the per-pname cap are still
+    /// counted here even though pruning drops them again.
+    pub inserted: usize,
+}
+
+/// Write every observation, oldest first. Returns the number of rows.
+pub fn export<W: Write>(conn: &Connection, mut out: W) -> io::Result<usize> {
+    write_record(&mut out, &HEADER)?;
+    let mut stmt = conn
+        .prepare(
+            "SELECT host, pname, drv_path, started_at_ms, finished_at_ms, duration_ms, status,
+                    out_paths, mean_builds, mean_cpu_psi, output_bytes
+             FROM build_observations
+             ORDER BY finished_at_ms, rowid",
+        )
+        .map_err(io::Error::other)?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok([
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?.to_string(),
+                row.get::<_, i64>(4)?.to_string(),
+                row.get::<_, i64>(5)?.to_string(),
+                row.get::<_, String>(6)?,
+                row.get::<_, String>(7)?,
+                optional_real(row.get(8)?),
+                optional_real(row.get(9)?),
+                row.get::<_, Option<i64>>(10)?
+                    .map(|v| v.to_string())
+                    .unwrap_or_default(),
+            ])
+        })
+        .map_err(io::Error::other)?;
+    let mut count = 0;
+    for row in rows {
+        let row = row.map_err(io::Error::other)?;
+        write_record(&mut out, &row.each_ref().map(String::as_str))?;
+        count += 1;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+/// Merge an [`export`] into `conn` in one transaction, then trim each
+/// touched pname to `max_samples_per_pname` (0 = no trim). A malformed
+/// row rejects the whole file.
+pub fn import<R: Read>(
+    conn: &Connection,
+    mut input: R,
+    max_samples_per_pname: u32,
+) -> io::Result<ImportStats> {
+    let mut text = String::new();
+    input.read_to_string(&mut text)?;
+    let mut records = parse_records(&text)?.into_iter();
+    let columns = match records.next() {
+        Some(header)
+            if (header.len() == HEADER.len() || OLDER_COLUMNS.contains(&header.len()))
+                && header == HEADER[..header.len()] =>
+        {
+            header.len()
+        }
+        _ => return Err(invalid("missing or unexpected header row")),
+    };
+
+    let tx = conn.unchecked_transaction().map_err(io::Error::other)?;
+    let mut stats = ImportStats::default();
+    let mut pnames = HashSet::new();
+    for (line, mut record) in records.enumerate() {
+        if record.len() != columns {
+            return Err(invalid(format!(
+                "row {}: {} fields",
+                line + 1,
+                record.len()
+            )));
+        }
+        record.resize(HEADER.len(), String::new());
+        let [host, pname, drv_path, started, finished, duration, status, out_paths, mean_builds, mean_cpu_psi, output_bytes] =
+            <[String; 11]>::try_from(record).expect("resized to HEADER");
+        let ms = |field: &str, value: &str| {
+            value
+                .parse::<u64>()
+                .map_err(|_| invalid(format!("row {}: bad {field} {value:?}", line + 1)))
+        };
+        let (started, finished, duration) = (
+            ms("started_at_ms", &started)?,
+            ms("finished_at_ms", &finished)?,
+            ms("duration_ms", &duration)?,
+        );
+        if BuildStatus::parse(&status).is_none() {
+            return Err(invalid(format!("row {}: bad status {status:?}", line + 1)));
+        }
+        let real = |field: &str, value: &str| {
+            if value.is_empty() {
+                return Ok(None);
+            }
+            value
+                .parse::<f64>()
+                .ok()
+                .filter(|v| v.is_finite())
+                .map(Some)
+                .ok_or_else(|| invalid(format!("row {}: bad {field} {value:?}", line + 1)))
+        };
+        let (mean_builds, mean_cpu_psi) = (
+            real("mean_builds", &mean_builds)?,
+            real("mean_cpu_psi", &mean_cpu_psi)?,
+        );
+        let output_bytes = match output_bytes.as_str() {
+            "" => None,
+            value => Some(ms("output_bytes", value)? as i64),
+        };
+        stats.inserted += tx
+            .execute(
+                "INSERT OR IGNORE INTO build_observations
+                 (host, pname, drv_path, started_at_ms, finished_at_ms, duration_ms, status,
+                  out_paths, mean_builds, mean_cpu_psi, output_bytes)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    host,
+                    pname,
+                    drv_path,
+                    started as i64,
+                    finished as i64,
+                    duration as i64,
+                    status,
+                    out_paths,
+                    mean_builds,
+                    mean_cpu_psi,
+                    output_bytes,
+                ],
+            )
+            .map_err(io::Error::other)?;
+        stats.rows += 1;
+        pnames.insert(pname);
+    }
+    if max_samples_per_pname > 0 {
+        for pname in &pnames {
+            observations::prune_pname(&tx, pname, max_samples_per_pname)?;
+        }
+    }
+    tx.commit().map_err(io::Error::other)?;
+    Ok(stats)
+}
+
+/// `true` if no observations have been recorded yet.
+pub fn is_empty(conn: &Connection) -> io::Result<bool> {
+    conn.query_row(
+        "SELECT NOT EXISTS(SELECT 1 FROM build_observations)",
+        [],
+        |row| row.get(0),
+    )
+    .map_err(io::Error::other)
+}
+
+/// `NULL` exports as an empty field.
+fn optional_real(value: Option<f64>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn write_record<W: Write>(out: &mut W, fields: &[&str]) -> io::Result<()> {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        if field.contains([',', '"', '\n', '\r']) {
+            write!(out, "\"{}\"", field.replace('"', "\"\""))?;
+        } else {
+            out.write_all(field.as_bytes())?;
+        }
+    }
+    out.write_all(b"\n")
+}
+
+fn parse_records(text: &str) -> io::Result<Vec<Vec<String>>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut chars = text.chars().peekable();
+    let mut in_quotes = false;
+    // Distinguishes an empty last field from no field at all.
+    let mut started = false;
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => {
+                in_quotes = true;
+                started = true;
+            }
+            ',' => {
+                record.push(std::mem::take(&mut field));
+                started = true;
+            }
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                if started || !field.is_empty() {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                }
+                started = false;
+            }
+            c => {
+                field.push(c);
+                started = true;
+            }
+        }
+    }
+    if in_quotes {
+        return Err(invalid("unterminated quoted field"));
+    }
+    if started || !field.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::open_in_memory;
+    use crate::protocol::ops::EventBuildFinish;
+
+    fn finish(conn: &Connection, drv: &str, ts_ms: u64, out_paths: &[&str]) {
+        observations::record_finish(
+            conn,
+            &EventBuildFinish {
+                drv_path: drv.to_string(),
+                pname: crate::util::pname_from_drv(drv),
+                host: "tsugumi".to_string(),
+                ts_ms,
+                duration_ms: Some(2_000),
+                cpu_ms: None,
+                contention: None,
+                output_bytes: None,
+                status: BuildStatus::Success,
+                out_paths: out_paths.iter().map(|p| p.to_string()).collect(),
+                correlation: None,
+            },
+            200,
+        )
+        .unwrap();
+    }
+
+    fn count(conn: &Connection) -> i64 {
+        conn.query_row("SELECT COUNT(*) FROM build_observations", [], |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn export_then_import_merges_on_drv_and_finish_time() {
+        let source = open_in_memory().unwrap();
+        finish(
+            &source,
+            "/nix/store/aaa-foo-1.0.drv",
+            10_000,
+            &["/nix/store/x-foo"],
+        );
+        finish(
+            &source,
+            "/nix/store/bbb-bar-2.0.drv",
+            20_000,
+            &["/nix/store/y-bar", "/nix/store/y-bar-man"],
+        );
+        let mut csv = Vec::new();
+        assert_eq!(export(&source, &mut csv).unwrap(), 2);
+
+        let dest = open_in_memory().unwrap();
+        assert!(is_empty(&dest).unwrap());
+        finish(
+            &dest,
+            "/nix/store/aaa-foo-1.0.drv",
+            10_000,
+            &["/nix/store/x-foo"],
+        );
+        let stats = import(&dest, &csv[..], 200).unwrap();
+        assert_eq!(
+            stats,
+            ImportStats {
+                rows: 2,
+                inserted: 1
+            }
+        );
+        assert_eq!(count(&dest), 2);
+
+        // Multi-line out_paths survive the round trip.
+        let mut again = Vec::new();
+        export(&dest, &mut again).unwrap();
+        assert_eq!(again, csv);
+    }
+
+    #[test]
+    fn import_respects_the_per_pname_cap() {
+        let source = open_in_memory().unwrap();
+        for ts in 1..=3 {
+            finish(
+                &source,
+                &format!("/nix/store/{ts}-foo-1.0.drv"),
+                ts * 1_000,
+                &[],
+            );
+        }
+        let mut csv = Vec::new();
+        export(&source, &mut csv).unwrap();
+        let dest = open_in_memory().unwrap();
+        import(&dest, &csv[..], 2).unwrap();
+        assert_eq!(count(&dest), 2);
+    }
+
+    #[test]
+    fn malformed_input_imports_nothing() {
+        let conn = open_in_memory().unwrap();
+        let header = HEADER.join(",");
+        let good = "tsugumi,foo,/nix/store/a-foo.drv,1,2,1,success,,2.5,,4096";
+        for bad in [
+            String::new(),
+            "pname,duration_ms\n".to_string(),
+            format!("{header}\n{good}\ntsugumi,foo,/nix/store/b-foo.drv,1,x,1,success,\n"),
+            format!("{header}\n{good}\ntsugumi,foo,/nix/store/b-foo.drv,1,2,1,meh,\n"),
+            format!("{header}\n{good}\ntsugumi,foo\n"),
+            format!("{header}\n{good}\ntsugumi,foo,/nix/store/b-foo.drv,1,2,1,success,,x,,\n"),
+            format!("{header}\n{good}\ntsugumi,foo,/nix/store/b-foo.drv,1,2,1,success,,,,-1\n"),
+            format!("{header}\ntsugumi,foo,/nix/store/b-foo.drv,1,2,1,success,\n"),
+            format!("{header}\n{good}\ntsugumi,foo,\"unterminated\n"),
+        ] {
+            assert!(import(&conn, bad.as_bytes(), 200).is_err(), "{bad:?}");
+        }
+        assert_eq!(count(&conn), 0);
+        let ok = format!("{header}\r\n{good}\r\n");
+        assert_eq!(import(&conn, ok.as_bytes(), 200).unwrap().inserted, 1);
+    }
+
+    #[test]
+    fn older_exports_still_import() {
+        for columns in OLDER_COLUMNS {
+            let conn = open_in_memory().unwrap();
+            let row = [
+                "tsugumi",
+                "foo",
+                "/nix/store/a-foo.drv",
+                "1",
+                "2",
+                "1",
+                "success",
+            ]
+            .into_iter()
+            .chain(std::iter::repeat(""))
+            .take(columns)
+            .collect::<Vec<_>>();
+            let old = format!("{}\n{}\n", HEADER[..columns].join(","), row.join(","));
+            assert_eq!(import(&conn, old.as_bytes(), 200).unwrap().inserted, 1);
+            let (builds, output_bytes): (Option<f64>, Option<i64>) = conn
+                .query_row(
+                    "SELECT mean_builds, output_bytes FROM build_observations",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap();
+            assert_eq!((builds, output_bytes), (None, None));
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Link probe history per target ([`crate::link`]), read back as the
+//! figures the scheduler uses and by `nbb-controller links`.
+
+use std::io;
+
+use rusqlite::{params, Connection};
+
+use crate::link::{self, LinkStats};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LinkProbeRow {
+    pub target_name: String,
+    pub probed_at_ms: u64,
+    pub rtt_us: u64,
+    pub bytes_per_sec: u64,
+}
+
+/// Store `row`, then drop all but the newest `keep_per_target` rows for
+/// its target.
+pub fn record(conn: &Connection, row: &LinkProbeRow, keep_per_target: u32) -> io::Result<()> {
+    let tx = conn.unchecked_transaction().map_err(io::Error::other)?;
+    tx.execute(
+        "INSERT INTO link_probes (target_name, probed_at_ms, rtt_us, bytes_per_sec)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![
+            row.target_name,
+            row.probed_at_ms as i64,
+            row.rtt_us.min(i64::MAX as u64) as i64,
+            row.bytes_per_sec.min(i64::MAX as u64) as i64,
+        ],
+    )
+    .map_err(io::Error::other)?;
+    tx.execute(
+        "DELETE FROM link_probes
+         WHERE target_name = ?1
+           AND id NOT IN (
+             SELECT id FROM link_probes
+             WHERE target_name = ?1
+             ORDER BY probed_at_ms DESC, id DESC
+             LIMIT ?2)",
+        params![row.target_name, i64::from(keep_per_target)],
+    )
+    .map_err(io::Error::other)?;
+    tx.commit().map_err(io::Error::other)
+}
+
+/// Stored probes, newest first, optionally for one target only.
+pub fn list(conn: &Connection, target: Option<&str>, limit: u32) -> io::Result<Vec<LinkProbeRow>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT target_name, probed_at_ms, rtt_us, bytes_per_sec
+             FROM link_probes
+             WHERE (?1 IS NULL OR target_name = ?1)
+             ORDER BY probed_at_ms DESC, id DESC
+             LIMIT ?2",
+        )
+        .map_err(io::Error::other)?;
+    let rows = stmt
+        .query_map(params![target, i64::from(limit)], |row| {
+            Ok(LinkProbeRow {
+                target_name: row.get(0)?,
+                probed_at_ms: row.get::<_, i64>(1)?.max(0) as u64,
+                rtt_us: row.get::<_, i64>(2)?.max(0) as u64,
+                bytes_per_sec: row.get::<_, i64>(3)?.max(0) as u64,
+            })
+        })
+        .map_err(io::Error::other)?;
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(row.map_err(io::Error::other)?);
+    }
+    Ok(result)
+}
+
+/// `target`'s current figures: [`link::summarize`] over its latest
+/// [`link::SUMMARY_SAMPLES`] probes. `None` if it was never probed.
+pub fn stats(conn: &Connection, target: &str) -> io::Result<Option<LinkStats>> {
+    let samples: Vec<LinkStats> = list(conn, Some(target), link::SUMMARY_SAMPLES)?
+        .into_iter()
+        .map(|row| LinkStats {
+            rtt_us: row.rtt_us,
+            bytes_per_sec: row.bytes_per_sec,
+        })
+        .collect();
+    Ok(link::summarize(&samples))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::open_in_memory;
+
+    fn probe(target: &str, at_ms: u64, bytes_per_sec: u64) -> LinkProbeRow {
+        LinkProbeRow {
+            target_name: target.to_string(),
+            probed_at_ms: at_ms,
+            rtt_us: 1_000,
+            bytes_per_sec,
+        }
+    }
+
+    #[test]
+    fn keeps_newest_per_target() {
+        let conn = open_in_memory().unwrap();
+        for at in [100, 300, 200, 400] {
+            record(&conn, &probe("saya", at, at * 1_000), 2).unwrap();
+        }
+        record(&conn, &probe("tsugumi", 50, 1), 2).unwrap();
+
+        let saya = list(&conn, Some("saya"), 10).unwrap();
+        let times: Vec<u64> = saya.iter().map(|r| r.probed_at_ms).collect();
+        assert_eq!(times, vec![400, 300]);
+        assert_eq!(saya[0], probe("saya", 400, 400_000));
+        assert_eq!(list(&conn, Some("tsugumi"), 10).unwrap().len(), 1);
+        assert_eq!(list(&conn, None, 10).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn stats_are_medians_of_the_latest_probes() {
+        let conn = open_in_memory().unwrap();
+        assert_eq!(stats(&conn, "saya").unwrap(), None);
+        // An old slow probe falls out of the window; one outlier among the
+        // latest does not move the median.
+        let rates = [1, 100, 110, 5, 120, 105];
+        for (at, rate) in rates.iter().enumerate() {
+            record(&conn, &probe("saya", at as u64, rate * 1_000_000), 100).unwrap();
+        }
+        assert_eq!(
+            stats(&conn, "saya").unwrap(),
+            Some(LinkStats {
+                rtt_us: 1_000,
+                bytes_per_sec: 105_000_000,
+            })
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! Periodic database upkeep, driven by the controller watchdog: WAL
+//! checkpoints, `VACUUM`, and a cap on `build_observations` across all
+//! pnames on top of the per-pname one. Also the pre-upgrade backup taken
+//! by [`crate::persistence::open`].
+
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use rusqlite::{params, Connection};
+
+#[derive(Clone, Debug)]
+pub struct MaintenancePolicy {
+    /// How often the WAL is checkpointed and the observation cap applied.
+    pub checkpoint_interval: Duration,
+    /// How often the database is vacuumed; `None` (the default) never.
+    /// `VACUUM` rewrites the whole file under the controller's connection,
+    /// so decisions wait for it.
+    pub vacuum_interval: Option<Duration>,
+    /// Most `build_observations` rows kept in total, oldest pruned first;
+    /// 0 disables the cap.
+    pub max_observations: u64,
+}
+
+impl Default for MaintenancePolicy {
+    fn default() -> Self {
+        Self {
+            checkpoint_interval: Duration::from_secs(3600),
+            vacuum_interval: None,
+            max_observations: 200_000,
+        }
+    }
+}
+
+/// Which maintenance jobs a watchdog tick should run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Due {
+    pub checkpoint: bool,
+    pub vacuum: bool,
+}
+
+/// When each job last ran. Starts at controller startup, so a restart
+/// does not vacuum straight away.
+#[derive(Clone, Debug)]
+pub struct MaintenanceClock {
+    last_checkpoint_ms: u64,
+    last_vacuum_ms: u64,
+}
+
+impl MaintenanceClock {
+    pub fn starting_at(now_ms: u64) -> Self {
+        Self {
+            last_checkpoint_ms: now_ms,
+            last_vacuum_ms: now_ms,
+        }
+    }
+
+    /// The jobs due at `now_ms`, marking them as run.
+    pub fn take_due(&mut self, now_ms: u64, policy: &MaintenancePolicy) -> Due {
+        let elapsed =
+            |last: u64, every: Duration| now_ms.saturating_sub(last) as u128 >= every.as_millis();
+        let due = Due {
+            checkpoint: elapsed(self.last_checkpoint_ms, policy.checkpoint_interval),
+            vacuum: policy
+                .vacuum_interval
+                .is_some_and(|every| elapsed(self.last_vacuum_ms, every)),
+        };
+        if due.checkpoint {
+            self.last_checkpoint_ms = now_ms;
+        }
+        if due.vacuum {
+            self.last_vacuum_ms = now_ms;
+        }
+        due
+    }
+}
+
+/// Fold the WAL back into the database file and truncate it.
+pub fn checkpoint(conn: &Connection) -> io::Result<()> {
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+        .map_err(io::Error::other)
+}
+
+/// Rebuild the database file, returning the pages freed by pruning to the
+/// filesystem. Holds the write lock throughout.
+pub fn vacuum(conn: &Connection) -> io::Result<()> {
+    conn.execute_batch("VACUUM").map_err(io::Error::other)
+}
+
+/// Keep only the newest `max` observations. Returns how many were removed.
+pub fn cap_observations(conn: &Connection, max: u64) -> io::Result<usize> {
+    if max == 0 {
+        return Ok(0);
+    }
+    conn.execute(
+        "DELETE FROM build_observations WHERE rowid IN (
+           SELECT rowid FROM build_observations
+           ORDER BY finished_at_ms DESC, rowid DESC
+           LIMIT -1 OFFSET ?1
+         )",
+        params![i64::try_from(max).unwrap_or(i64::MAX)],
+    )
+    .map_err(io::Error::other)
+}
+
+/// Write a consistent copy of the database to `dest`, which must not exist.
+pub fn backup(conn: &Connection, dest: &Path) -> io::Result<()> {
+    conn.execute("VACUUM INTO ?1", params![dest.to_string_lossy()])
+        .map_err(io::Error::other)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::{observations, open_in_memory};
+    use crate::protocol::ops::{BuildStatus, EventBuildFinish};
+
+    fn finish(conn: &Connection, pname: &str, ts_ms: u64) {
+        observations::record_finish(
+            conn,
+            &EventBuildFinish {
+                drv_path: format!("/nix/store/{ts_ms}-{pname}.drv"),
+                pname: pname.to_string(),
+                host: "tsugumi".to_string(),
+                ts_ms,
+                duration_ms: Some(1_000),
+                cpu_ms: None,
+                contention: None,
+                output_bytes: None,
+                status: BuildStatus::Success,
+                out_paths: vec![],
+                correlation: None,
+            },
+            200,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn observation_cap_drops_the_oldest_across_pnames() {
+        let conn = open_in_memory().unwrap();
+        finish(&conn, "foo", 1_000);
+        finish(&conn, "bar", 2_000);
+        finish(&conn, "foo", 3_000);
+        assert_eq!(cap_observations(&conn, 0).unwrap(), 0);
+        assert_eq!(cap_observations(&conn, 2).unwrap(), 1);
+        let oldest: i64 = conn
+            .query_row(
+                "SELECT MIN(finished_at_ms) FROM build_observations",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(oldest, 2_000);
+        checkpoint(&conn).unwrap();
+        vacuum(&conn).unwrap();
+    }
+
+    #[test]
+    fn clock_runs_each_job_once_per_interval() {
+        let policy = MaintenancePolicy {
+            checkpoint_interval: Duration::from_secs(60),
+            vacuum_interval: Some(Duration::from_secs(600)),
+            max_observations: 0,
+        };
+        let mut clock = MaintenanceClock::starting_at(0);
+        assert_eq!(clock.take_due(59_999, &policy), Due::default());
+        assert_eq!(
+            clock.take_due(60_000, &policy),
+            Due {
+                checkpoint: true,
+                vacuum: false
+            }
+        );
+        assert_eq!(clock.take_due(60_001, &policy), Due::default());
+        assert_eq!(
+            clock.take_due(600_000, &policy),
+            Due {
+                checkpoint: true,
+                vacuum: true
+            }
+        );
+        let never = MaintenancePolicy {
+            vacuum_interval: None,
+            ..policy
+        };
+        assert!(!clock.take_due(u64::MAX, &never).vacuum);
+    }
+}
@@ -0,0 +1,290 @@
+//! Schema versioning keyed off `meta.schema_version`.
+//!
+//! `schema.sql` is the current schema and is applied as-is to a fresh
+//! database. An existing database is brought forward one step at a time
+//! from its recorded version, each step in its own transaction together
+//! with the version bump, so an interrupted upgrade resumes where it
+//! stopped.
+
+use std::io;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+const SCHEMA: &str = include_str!("schema.sql");
+
+/// Version written by [`migrate`].
+pub const LATEST: u32 = 10;
+
+/// `MIGRATIONS[i]` takes a database from version `i + 1` to `i + 2`.
+const MIGRATIONS: &[fn(&Connection) -> rusqlite::Result<()>] = &[
+    to_v2, to_v3, to_v4, to_v5, to_v6, to_v7, to_v8, to_v9, to_v10,
+];
+
+/// Version 1 databases were only ever extended with `CREATE … IF NOT
+/// EXISTS`, which never added `decisions.session` to an existing table.
+/// Add it, then create whatever tables and indexes they are missing.
+fn to_v2(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "decisions", "session", "TEXT")?;
+    conn.execute_batch(SCHEMA)
+}
+
+/// Observations record the contention they were measured under; older
+/// rows have none and count as uncontended.
+fn to_v3(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "build_observations", "mean_builds", "REAL")?;
+    add_column_if_missing(conn, "build_observations", "mean_cpu_psi", "REAL")
+}
+
+/// Observations record how much store space the outputs took.
+fn to_v4(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "build_observations", "output_bytes", "INTEGER")
+}
+
+/// Admissions remember the correlation id of the decision behind them.
+fn to_v5(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "admissions", "correlation", "TEXT")
+}
+
+/// Link probe history is a new table, which `schema.sql` creates.
+fn to_v6(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(SCHEMA)
+}
+
+/// Decisions record how long they took; older rows have no figure.
+fn to_v7(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "decisions", "latency_us", "INTEGER")
+}
+
+/// Per-target build verdicts are a new table, which `schema.sql` creates.
+fn to_v8(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(SCHEMA)
+}
+
+/// Session priorities are a new table, which `schema.sql` creates.
+fn to_v9(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(SCHEMA)
+}
+
+/// Observations gain an index on `finished_at_ms`, which `schema.sql`
+/// creates.
+fn to_v10(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(SCHEMA)
+}
+
+/// The database's schema version, or `None` if it has no schema yet.
+pub fn version(conn: &Connection) -> io::Result<Option<u32>> {
+    let has_meta: bool = conn
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'meta')",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(io::Error::other)?;
+    if !has_meta {
+        return Ok(None);
+    }
+    let value: Option<String> = conn
+        .query_row(
+            "SELECT value FROM meta WHERE key = 'schema_version'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(io::Error::other)?;
+    match value {
+        None => Ok(Some(1)),
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| io::Error::other(format!("unreadable schema_version {value:?}"))),
+    }
+}
+
+/// Create the schema, or upgrade it to [`LATEST`]. Refuses databases
+/// written by a newer nbb rather than guessing at their layout.
+pub fn migrate(conn: &Connection) -> io::Result<()> {
+    let Some(mut current) = version(conn)? else {
+        let tx = conn.unchecked_transaction().map_err(io::Error::other)?;
+        tx.execute_batch(SCHEMA).map_err(io::Error::other)?;
+        set_version(&tx, LATEST)?;
+        return tx.commit().map_err(io::Error::other);
+    };
+    if current > LATEST {
+        return Err(io::Error::other(format!(
+            "database schema v{current} is newer than this nbb (v{LATEST})"
+        )));
+    }
+    while current < LATEST {
+        let step = MIGRATIONS[current as usize - 1];
+        let tx = conn.unchecked_transaction().map_err(io::Error::other)?;
+        step(&tx).map_err(io::Error::other)?;
+        set_version(&tx, current + 1)?;
+        tx.commit().map_err(io::Error::other)?;
+        current += 1;
+        tracing::info!(version = current, "database schema migrated");
+    }
+    Ok(())
+}
+
+fn set_version(conn: &Connection, version: u32) -> io::Result<()> {
+    conn.execute(
+        "INSERT INTO meta(key, value) VALUES ('schema_version', ?1)
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        params![version.to_string()],
+    )
+    .map_err(io::Error::other)?;
+    Ok(())
+}
+
+/// `ALTER TABLE … ADD COLUMN` unless the column is already there. A table
+/// that does not exist yet is left for `schema.sql` to create.
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let columns: Vec<String> = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<_>>()?;
+    if columns.is_empty() || columns.iter().any(|c| c == column) {
+        return Ok(());
+    }
+    conn.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The `decisions` table and `meta` row as version 1 first shipped
+    /// them, before sessions were logged.
+    const V1_DECISIONS: &str = "
+        CREATE TABLE decisions (
+          id            INTEGER PRIMARY KEY,
+          decided_at_ms INTEGER NOT NULL,
+          drv_path      TEXT    NOT NULL,
+          pname         TEXT    NOT NULL,
+          system        TEXT    NOT NULL,
+          estimate_ms   INTEGER,
+          estimate_tier TEXT    NOT NULL,
+          outcome       TEXT    NOT NULL,
+          winner        TEXT
+        );
+        CREATE TABLE meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+        INSERT INTO meta(key, value) VALUES ('schema_version', '1');
+        INSERT INTO decisions(decided_at_ms, drv_path, pname, system, estimate_tier, outcome)
+          VALUES (1000, '/nix/store/a-foo.drv', 'foo', 'x86_64-linux', 'exact', 'decline');
+    ";
+
+    #[test]
+    fn fresh_database_starts_at_latest() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert_eq!(version(&conn).unwrap(), None);
+        migrate(&conn).unwrap();
+        assert_eq!(version(&conn).unwrap(), Some(LATEST));
+        migrate(&conn).unwrap();
+        assert_eq!(version(&conn).unwrap(), Some(LATEST));
+    }
+
+    #[test]
+    fn v1_database_gains_session_column_and_new_tables() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(V1_DECISIONS).unwrap();
+        migrate(&conn).unwrap();
+        assert_eq!(version(&conn).unwrap(), Some(LATEST));
+
+        let (drv, session): (String, Option<String>) = conn
+            .query_row("SELECT drv_path, session FROM decisions", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(drv, "/nix/store/a-foo.drv");
+        assert_eq!(session, None);
+        let latency_us: Option<i64> = conn
+            .query_row("SELECT latency_us FROM decisions", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(latency_us, None);
+        let learned: i64 = conn
+            .query_row("SELECT COUNT(*) FROM pname_parallelism", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(learned, 0);
+        let outcomes: i64 = conn
+            .query_row("SELECT COUNT(*) FROM pname_target_outcomes", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(outcomes, 0);
+        let priorities: i64 = conn
+            .query_row("SELECT COUNT(*) FROM session_priorities", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(priorities, 0);
+    }
+
+    #[test]
+    fn v2_observations_gain_contention_columns() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE build_observations (
+               host TEXT NOT NULL, pname TEXT NOT NULL, drv_path TEXT NOT NULL,
+               started_at_ms INTEGER NOT NULL, finished_at_ms INTEGER NOT NULL,
+               duration_ms INTEGER NOT NULL, status TEXT NOT NULL, out_paths TEXT NOT NULL
+             );
+             INSERT INTO build_observations VALUES
+               ('tsugumi', 'foo', '/nix/store/a-foo.drv', 0, 1000, 1000, 'success', '');
+             CREATE TABLE meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+             INSERT INTO meta(key, value) VALUES ('schema_version', '2');",
+        )
+        .unwrap();
+        migrate(&conn).unwrap();
+        let builds: Option<f64> = conn
+            .query_row("SELECT mean_builds FROM build_observations", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(builds, None);
+        let output_bytes: Option<i64> = conn
+            .query_row("SELECT output_bytes FROM build_observations", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(output_bytes, None);
+    }
+
+    #[test]
+    fn v4_database_gains_correlation_column_and_link_probes() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE admissions (
+               drv_path TEXT PRIMARY KEY, target_name TEXT NOT NULL,
+               admitted_at_ms INTEGER NOT NULL, predicted_ms INTEGER NOT NULL
+             );
+             INSERT INTO admissions VALUES ('/nix/store/a-foo.drv', 'tsugumi', 0, 1000);
+             CREATE TABLE meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+             INSERT INTO meta(key, value) VALUES ('schema_version', '4');",
+        )
+        .unwrap();
+        migrate(&conn).unwrap();
+        let correlation: Option<String> = conn
+            .query_row("SELECT correlation FROM admissions", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(correlation, None);
+        let probes: i64 = conn
+            .query_row("SELECT COUNT(*) FROM link_probes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(probes, 0);
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        set_version(&conn, LATEST + 1).unwrap();
+        assert!(migrate(&conn).is_err());
+    }
+}
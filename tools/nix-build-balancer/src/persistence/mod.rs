@@ -1,18 +1,62 @@
 pub mod admissions;
+pub mod decisions;
+pub mod failure_tails;
+pub mod history;
+pub mod links;
+pub mod maintenance;
+pub mod migrations;
 pub mod observations;
+pub mod parallelism;
+pub mod sessions;
+pub mod target_outcomes;
 
-use rusqlite::Connection;
+use rusqlite::{Connection, OpenFlags};
 use std::io;
 use std::path::Path;
 
-const SCHEMA: &str = include_str!("schema.sql");
-
+/// Open the database at `path` in WAL mode and bring its schema up to
+/// date, first copying it to `<path>.v<old>-<ms>.bak` if an upgrade is
+/// needed.
 pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Connection> {
+    let path = path.as_ref();
     let conn = Connection::open(path).map_err(io::Error::other)?;
+    // Lets `nbb-controller decisions` and friends read while the
+    // controller writes.
+    conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))
+        .map_err(io::Error::other)?;
+    if let Some(from) = migrations::version(&conn)?.filter(|&v| v < migrations::LATEST) {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".v{from}-{}.bak", crate::util::now_ms()));
+        let dest = path.with_file_name(name);
+        maintenance::backup(&conn, &dest)?;
+        tracing::info!(backup = %dest.display(), from, "backed up database before upgrade");
+    }
     init_schema(&conn)?;
     Ok(conn)
 }
 
+/// Open the database at `path` read-only, for the reporting subcommands.
+/// Nothing is created, upgraded or backed up: a schema other than
+/// [`migrations::LATEST`] is an error.
+pub fn open_read_only<P: AsRef<Path>>(path: P) -> io::Result<Connection> {
+    let path = path.as_ref();
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let conn = Connection::open_with_flags(path, flags)
+        .map_err(|err| io::Error::other(format!("{}: {err}", path.display())))?;
+    match migrations::version(&conn)? {
+        Some(migrations::LATEST) => Ok(conn),
+        Some(v) => Err(io::Error::other(format!(
+            "{} has schema v{v}, this binary reads v{}; let the controller upgrade it first",
+            path.display(),
+            migrations::LATEST
+        ))),
+        None => Err(io::Error::other(format!(
+            "{} has no nbb schema",
+            path.display()
+        ))),
+    }
+}
+
 /// Open an in-memory SQLite database. Used by tests and by lifecycle tests.
 pub fn open_in_memory() -> io::Result<Connection> {
     let conn = Connection::open_in_memory().map_err(io::Error::other)?;
@@ -20,8 +64,9 @@ pub fn open_in_memory() -> io::Result<Connection> {
     Ok(conn)
 }
 
+/// Create or upgrade the schema; see [`migrations`].
 pub fn init_schema(conn: &Connection) -> io::Result<()> {
-    conn.execute_batch(SCHEMA).map_err(io::Error::other)
+    migrations::migrate(conn)
 }
 
 /// Drop every admissions row. The controller calls this on startup
@@ -53,7 +98,7 @@ mod tests {
     }
 
     #[test]
-    fn schema_version_is_one() {
+    fn schema_version_is_latest() {
         let conn = open_in_memory().unwrap();
         let version: String = conn
             .query_row(
@@ -62,14 +107,125 @@ mod tests {
                 |row| row.get(0),
             )
             .unwrap();
-        assert_eq!(version, "1");
+        assert_eq!(version, migrations::LATEST.to_string());
+    }
+
+    #[test]
+    fn upgrading_a_file_database_backs_it_up_first() {
+        let dir = std::env::temp_dir().join(format!(
+            "nbb-persistence-{}-{}",
+            std::process::id(),
+            crate::util::now_ms()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = dir.join("state.db");
+        {
+            let conn = open(&db).unwrap();
+            conn.execute(
+                "UPDATE meta SET value = '1' WHERE key = 'schema_version'",
+                [],
+            )
+            .unwrap();
+        }
+        let conn = open(&db).unwrap();
+        assert_eq!(
+            migrations::version(&conn).unwrap(),
+            Some(migrations::LATEST)
+        );
+        let backups: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".bak"))
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert!(backups[0].starts_with("state.db.v1-"));
+        let backup = Connection::open(dir.join(&backups[0])).unwrap();
+        assert_eq!(migrations::version(&backup).unwrap(), Some(1));
+
+        // Already current: no second backup.
+        drop(conn);
+        open(&db).unwrap();
+        let count = std::fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .filter(|e| e.file_name().to_string_lossy().ends_with(".bak"))
+            .count();
+        assert_eq!(count, 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn read_only_open_refuses_other_schemas_and_changes_nothing() {
+        let dir = std::env::temp_dir().join(format!(
+            "nbb-persistence-ro-{}-{}",
+            std::process::id(),
+            crate::util::now_ms()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = dir.join("state.db");
+        assert!(open_read_only(&db).is_err());
+        assert!(!db.exists());
+
+        {
+            let conn = open(&db).unwrap();
+            admissions::record(
+                &conn,
+                "/nix/store/a-foo.drv",
+                "tsugumi",
+                100,
+                5000,
+                "01JAXKQ4V6M0S7Y8E2N5D3W1HC",
+            )
+            .unwrap();
+        }
+        let conn = open_read_only(&db).unwrap();
+        assert_eq!(admissions::list(&conn).unwrap().len(), 1);
+        assert!(clear_admissions(&conn).is_err());
+        drop(conn);
+
+        {
+            let conn = open(&db).unwrap();
+            conn.execute(
+                "UPDATE meta SET value = '1' WHERE key = 'schema_version'",
+                [],
+            )
+            .unwrap();
+        }
+        let err = open_read_only(&db).unwrap_err();
+        assert!(err.to_string().contains("schema v1"), "{err}");
+        let conn = Connection::open(&db).unwrap();
+        assert_eq!(migrations::version(&conn).unwrap(), Some(1));
+        let backups = std::fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .filter(|e| e.file_name().to_string_lossy().ends_with(".bak"))
+            .count();
+        assert_eq!(backups, 0);
+        let _ = std::fs::remove_dir_all(&dir);
     }
 
     #[test]
     fn clear_admissions_empties_table() {
         let conn = open_in_memory().unwrap();
-        admissions::record(&conn, "/nix/store/a-foo.drv", "tsugumi", 100, 5000).unwrap();
-        admissions::record(&conn, "/nix/store/b-bar.drv", "tsugumi", 200, 7000).unwrap();
+        admissions::record(
+            &conn,
+            "/nix/store/a-foo.drv",
+            "tsugumi",
+            100,
+            5000,
+            "01JAXKQ4V6M0S7Y8E2N5D3W1HC",
+        )
+        .unwrap();
+        admissions::record(
+            &conn,
+            "/nix/store/b-bar.drv",
+            "tsugumi",
+            200,
+            7000,
+            "01JAXKQ4V6M0S7Y8E2N5D3W1HC",
+        )
+        .unwrap();
         assert_eq!(admissions::list(&conn).unwrap().len(), 2);
         clear_admissions(&conn).unwrap();
         assert!(admissions::list(&conn).unwrap().is_empty());
@@ -80,7 +236,15 @@ mod tests {
         let conn = open_in_memory().unwrap();
         init_schema(&conn).unwrap();
         init_schema(&conn).unwrap();
-        admissions::record(&conn, "/nix/store/a-foo.drv", "tsugumi", 100, 5000).unwrap();
+        admissions::record(
+            &conn,
+            "/nix/store/a-foo.drv",
+            "tsugumi",
+            100,
+            5000,
+            "01JAXKQ4V6M0S7Y8E2N5D3W1HC",
+        )
+        .unwrap();
         assert_eq!(admissions::list(&conn).unwrap().len(), 1);
     }
 }
@@ -1,22 +1,61 @@
 use std::io;
 
-use rusqlite::{params, Connection};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, ToSql};
 
 use crate::estimator;
-use crate::protocol::ops::EventBuildFinish;
+use crate::protocol::ops::{BuildStatus, EventBuildFinish};
+use crate::util::{pname_family, unversioned_pname};
+
+/// Which rung of the fallback chain in [`predict`] produced an estimate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EstimateTier {
+    /// Observations for this exact pname.
+    Exact,
+    /// Same package under a different embedded version (`llvm17` for
+    /// `llvm18`).
+    OtherVersion,
+    /// Other members of the pname's family prefix (`cargo-package-*`).
+    Family,
+    /// Every pname last seen with the same builder kind.
+    BuilderKind,
+    /// Nothing to borrow from; the scheduler uses `unknown_p95_ms`.
+    Default,
+}
+
+impl EstimateTier {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            EstimateTier::Exact => "exact",
+            EstimateTier::OtherVersion => "other-version",
+            EstimateTier::Family => "family",
+            EstimateTier::BuilderKind => "builder-kind",
+            EstimateTier::Default => "default",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Prediction {
+    pub ms: u64,
+    pub tier: EstimateTier,
+}
 
 /// Insert one row into `build_observations`, then trim the per-pname history
 /// to `max_samples_per_pname` newest rows (no-op when 0).
 ///
 /// Returns `Ok(false)` and skips the insert when the event has no
 /// `duration_ms` — spec §"Build observation lifecycle" item 5: rows are only
-/// written when the duration is known. The caller (controller) still retires
-/// the admission either way.
+/// written when the duration is known. `InfraFailure` events are skipped
+/// too: the duration measures a dropped connection, not the derivation.
+/// The caller (controller) still retires the admission either way.
 pub fn record_finish(
     conn: &Connection,
     event: &EventBuildFinish,
     max_samples_per_pname: u32,
 ) -> io::Result<bool> {
+    if event.status == BuildStatus::InfraFailure {
+        return Ok(false);
+    }
     let Some(duration_ms) = event.duration_ms else {
         return Ok(false);
     };
@@ -26,8 +65,9 @@ pub fn record_finish(
     let inserted = conn
         .execute(
             "INSERT OR IGNORE INTO build_observations
-             (host, pname, drv_path, started_at_ms, finished_at_ms, duration_ms, status, out_paths)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+             (host, pname, drv_path, started_at_ms, finished_at_ms, duration_ms, status, out_paths,
+              mean_builds, mean_cpu_psi, output_bytes)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
             params![
                 &event.host,
                 &event.pname,
@@ -37,6 +77,9 @@ pub fn record_finish(
                 duration_ms as i64,
                 event.status.as_str(),
                 out_paths,
+                event.contention.map(|c| c.mean_builds),
+                event.contention.and_then(|c| c.mean_cpu_psi),
+                event.output_bytes.map(|b| b as i64),
             ],
         )
         .map_err(io::Error::other)?;
@@ -56,7 +99,7 @@ pub fn record_finish(
     Ok(true)
 }
 
-fn prune_pname(conn: &Connection, pname: &str, keep_newest: u32) -> io::Result<()> {
+pub(crate) fn prune_pname(conn: &Connection, pname: &str, keep_newest: u32) -> io::Result<()> {
     conn.execute(
         "DELETE FROM build_observations
          WHERE rowid IN (
@@ -75,45 +118,265 @@ fn prune_pname(conn: &Connection, pname: &str, keep_newest: u32) -> io::Result<(
 /// scheduler as `package_ms` and into the admission row as `predicted_ms`.
 ///
 /// Reads every successful observation for `pname` in chronological order
-/// (oldest first — order matters for the EWMA recurrence) and delegates
-/// the arithmetic to [`estimator::predict_lognormal_ms`]. See that module
-/// for the model, the references, and the rationale for picking the
-/// upper-95 % quantile of a fitted log-normal over the unweighted sample
-/// p95 it replaced.
+/// (oldest first — order matters for the EWMA recurrence) with the
+/// contention it ran under, and delegates the arithmetic to
+/// [`estimator::predict_idle_ms`]. See that module for the model, the
+/// references, the contention correction, and the rationale for picking
+/// the upper-95 % quantile of a fitted log-normal over the unweighted
+/// sample p95 it replaced.
 ///
 /// Returns `None` when there are no successful rows, so the caller falls
 /// back to the policy-level `unknown_p95_ms`.
 pub fn predict_ms(conn: &Connection, pname: &str, alpha: f64, z: f64) -> io::Result<Option<u64>> {
+    let samples = success_samples(
+        conn,
+        "SELECT duration_ms, mean_builds, mean_cpu_psi FROM build_observations
+         WHERE status = 'success' AND pname = ?1
+         ORDER BY finished_at_ms ASC, rowid ASC",
+        &[&pname],
+    )?;
+    Ok(estimator::predict_idle_ms(
+        &samples,
+        alpha,
+        z,
+        estimator::MIN_LN_VAR,
+    ))
+}
+
+/// [`predict_ms`] with a fallback chain for pnames the controller has never
+/// seen, so a new LLVM borrows from the old one and a `-source` fetch does
+/// not inherit the flat `unknown_p95_ms`:
+///
+/// 1. exact pname;
+/// 2. same pname with a different embedded version
+///    ([`unversioned_pname`]);
+/// 3. same family prefix ([`pname_family`]);
+/// 4. same builder kind (`builder_kind`, from the candidate's `.drv`),
+///    via the `pname_builders` table;
+/// 5. `None` — the caller falls back to `unknown_p95_ms`
+///    ([`EstimateTier::Default`]).
+///
+/// Borrowed tiers pool the siblings' newest [`POOLED_SAMPLES`] rows
+/// chronologically into one series, which widens the fitted spread and
+/// keeps the read-out conservative. Siblings are found by ranges over the
+/// `pname` index, so a new version is only matched to ones that share its
+/// name up to the first stripped version.
+pub fn predict(
+    conn: &Connection,
+    pname: &str,
+    builder_kind: Option<&str>,
+    alpha: f64,
+    z: f64,
+) -> io::Result<Option<Prediction>> {
+    let tiered = |samples: Vec<estimator::Sample>, tier| {
+        estimator::predict_idle_ms(&samples, alpha, z, estimator::MIN_LN_VAR)
+            .map(|ms| Prediction { ms, tier })
+    };
+
+    if let Some(ms) = predict_ms(conn, pname, alpha, z)? {
+        return Ok(Some(Prediction {
+            ms,
+            tier: EstimateTier::Exact,
+        }));
+    }
+
+    let unversioned = unversioned_pname(pname);
+    let stem = unversioned.split('-').next().unwrap_or_default();
+    let siblings: Vec<String> = pnames_starting_with(conn, stem)?
+        .into_iter()
+        .filter(|other| other != pname && unversioned_pname(other) == unversioned)
+        .collect();
+    if !siblings.is_empty() {
+        let placeholders = vec!["?"; siblings.len()].join(", ");
+        let sql = pooled_sql(&format!("pname IN ({placeholders})"));
+        let params: Vec<&dyn ToSql> = siblings.iter().map(|p| p as &dyn ToSql).collect();
+        if let Some(p) = tiered(
+            success_samples(conn, &sql, &params)?,
+            EstimateTier::OtherVersion,
+        ) {
+            return Ok(Some(p));
+        }
+    }
+
+    if let Some(family) = pname_family(pname) {
+        let prefix = format!("{family}-");
+        if let Some(end) = prefix_end(&prefix) {
+            if let Some(p) = tiered(
+                success_samples(
+                    conn,
+                    &pooled_sql("pname >= ?1 AND pname < ?2"),
+                    &[&prefix, &end],
+                )?,
+                EstimateTier::Family,
+            ) {
+                return Ok(Some(p));
+            }
+        }
+    }
+
+    if let Some(kind) = builder_kind {
+        // Newest first along `build_observations_finished`, stopping at
+        // the cap, rather than every row of a common kind like `stdenv`.
+        if let Some(p) = tiered(
+            success_samples(
+                conn,
+                "SELECT duration_ms, mean_builds, mean_cpu_psi FROM (
+                   SELECT o.duration_ms, o.mean_builds, o.mean_cpu_psi,
+                          o.finished_at_ms, o.rowid AS id
+                   FROM build_observations o CROSS JOIN pname_builders b
+                   WHERE b.pname = o.pname AND o.status = 'success'
+                     AND b.builder_kind = ?1
+                   ORDER BY o.finished_at_ms DESC, o.rowid DESC
+                   LIMIT ?2)
+                 ORDER BY finished_at_ms ASC, id ASC",
+                &[&kind, &POOLED_SAMPLES],
+            )?,
+            EstimateTier::BuilderKind,
+        ) {
+            return Ok(Some(p));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Successful builds of a pname whose output sizes
+/// [`predict_output_bytes`] looks at.
+pub const OUTPUT_SIZE_SAMPLES: i64 = 5;
+
+/// Store space `pname`'s outputs are expected to take: the largest of its
+/// newest [`OUTPUT_SIZE_SAMPLES`] measured successful builds, so a target
+/// is only excluded for sizes the pname has actually reached recently.
+/// `None` when none were measured.
+pub fn predict_output_bytes(conn: &Connection, pname: &str) -> io::Result<Option<u64>> {
+    let max: Option<i64> = conn
+        .query_row(
+            "SELECT MAX(output_bytes) FROM (
+               SELECT output_bytes FROM build_observations
+               WHERE pname = ?1 AND status = 'success' AND output_bytes IS NOT NULL
+               ORDER BY finished_at_ms DESC, rowid DESC
+               LIMIT ?2)",
+            params![pname, OUTPUT_SIZE_SAMPLES],
+            |row| row.get(0),
+        )
+        .map_err(io::Error::other)?;
+    Ok(max.map(|bytes| bytes.max(0) as u64))
+}
+
+/// Remember the builder kind last seen for `pname`, feeding the
+/// builder-kind tier of [`predict`]. Writes only when it changed, which
+/// for a pname the controller has seen before is almost never.
+pub fn record_builder_kind(conn: &Connection, pname: &str, builder_kind: &str) -> io::Result<()> {
+    let known: Option<String> = conn
+        .query_row(
+            "SELECT builder_kind FROM pname_builders WHERE pname = ?1",
+            params![pname],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(io::Error::other)?;
+    if known.as_deref() == Some(builder_kind) {
+        return Ok(());
+    }
+    conn.execute(
+        "INSERT INTO pname_builders (pname, builder_kind) VALUES (?1, ?2)
+         ON CONFLICT(pname) DO UPDATE SET builder_kind = excluded.builder_kind",
+        params![pname, builder_kind],
+    )
+    .map_err(io::Error::other)?;
+    Ok(())
+}
+
+/// Most rows a borrowed tier of [`predict`] pools, newest first.
+pub const POOLED_SAMPLES: i64 = 200;
+
+/// Pooled-tier query over the rows matching `filter`: the newest
+/// [`POOLED_SAMPLES`] successes, read back oldest first.
+fn pooled_sql(filter: &str) -> String {
+    format!(
+        "SELECT duration_ms, mean_builds, mean_cpu_psi FROM (
+           SELECT duration_ms, mean_builds, mean_cpu_psi, finished_at_ms, rowid AS id
+           FROM build_observations
+           WHERE status = 'success' AND {filter}
+           ORDER BY finished_at_ms DESC, rowid DESC
+           LIMIT {POOLED_SAMPLES})
+         ORDER BY finished_at_ms ASC, id ASC"
+    )
+}
+
+/// The first string after every one that starts with `prefix`, so
+/// `pname >= prefix AND pname < end` is a range over the `pname` index.
+/// `None` only for a prefix made entirely of `char::MAX`.
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        let next = match last {
+            '\u{D7FF}' => Some('\u{E000}'),
+            _ => char::from_u32(last as u32 + 1),
+        };
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+/// Distinct pnames with observations that start with `prefix`.
+fn pnames_starting_with(conn: &Connection, prefix: &str) -> io::Result<Vec<String>> {
+    let Some(end) = prefix_end(prefix) else {
+        return Ok(Vec::new());
+    };
     let mut stmt = conn
         .prepare(
-            "SELECT duration_ms FROM build_observations
-             WHERE status = 'success' AND pname = ?1
-             ORDER BY finished_at_ms ASC, rowid ASC",
+            "SELECT DISTINCT pname FROM build_observations
+             WHERE pname >= ?1 AND pname < ?2",
         )
         .map_err(io::Error::other)?;
     let rows = stmt
-        .query_map(params![pname], |row| row.get::<_, i64>(0))
+        .query_map(params![prefix, end], |row| row.get::<_, String>(0))
         .map_err(io::Error::other)?;
-    let mut values = Vec::new();
+    let mut result = Vec::new();
     for row in rows {
-        let duration = row.map_err(io::Error::other)?;
+        result.push(row.map_err(io::Error::other)?);
+    }
+    Ok(result)
+}
+
+/// Rows of `(duration_ms, mean_builds, mean_cpu_psi)` as estimator samples.
+fn success_samples(
+    conn: &Connection,
+    sql: &str,
+    params: &[&dyn ToSql],
+) -> io::Result<Vec<estimator::Sample>> {
+    let mut stmt = conn.prepare(sql).map_err(io::Error::other)?;
+    let rows = stmt
+        .query_map(params_from_iter(params.iter()), |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, Option<f64>>(1)?,
+                row.get::<_, Option<f64>>(2)?,
+            ))
+        })
+        .map_err(io::Error::other)?;
+    let mut samples = Vec::new();
+    for row in rows {
+        let (duration, mean_builds, mean_cpu_psi) = row.map_err(io::Error::other)?;
         if duration > 0 {
-            values.push(duration as u64);
+            samples.push(estimator::Sample {
+                duration_ms: duration as u64,
+                mean_builds,
+                mean_cpu_psi,
+            });
         }
     }
-    Ok(estimator::predict_lognormal_ms(
-        &values,
-        alpha,
-        z,
-        estimator::MIN_LN_VAR,
-    ))
+    Ok(samples)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::persistence::open_in_memory;
-    use crate::protocol::ops::BuildStatus;
 
     fn finish(pname: &str, duration_ms: u64, status: BuildStatus, ts_ms: u64) -> EventBuildFinish {
         EventBuildFinish {
@@ -122,8 +385,12 @@ mod tests {
             host: "tsugumi".to_string(),
             ts_ms,
             duration_ms: Some(duration_ms),
+            cpu_ms: None,
+            contention: None,
+            output_bytes: None,
             status,
             out_paths: vec![format!("/nix/store/yyy-{pname}")],
+            correlation: None,
         }
     }
 
@@ -149,6 +416,44 @@ mod tests {
         assert_eq!(predict_ms(&conn, "foo", ALPHA, Z).unwrap(), Some(5_000));
     }
 
+    #[test]
+    fn predict_ms_corrects_for_recorded_contention() {
+        let conn = open_in_memory().unwrap();
+        let mut event = finish("foo", 20_000, BuildStatus::Success, 100);
+        event.contention = Some(crate::protocol::ops::Contention {
+            mean_builds: 6.0,
+            mean_cpu_psi: Some(50.0),
+        });
+        record_finish(&conn, &event, 0).unwrap();
+        assert_eq!(predict_ms(&conn, "foo", ALPHA, Z).unwrap(), Some(10_000));
+    }
+
+    #[test]
+    fn output_size_is_the_recent_maximum() {
+        let conn = open_in_memory().unwrap();
+        assert_eq!(predict_output_bytes(&conn, "foo").unwrap(), None);
+        let sized = |bytes: u64, status: BuildStatus, ts_ms: u64| {
+            let mut event = finish("foo", 1_000, status, ts_ms);
+            event.output_bytes = Some(bytes);
+            event
+        };
+        record_finish(&conn, &sized(9_000, BuildStatus::Success, 100), 0).unwrap();
+        for ts_ms in 1..=OUTPUT_SIZE_SAMPLES as u64 {
+            record_finish(
+                &conn,
+                &sized(ts_ms * 100, BuildStatus::Success, 100 + ts_ms),
+                0,
+            )
+            .unwrap();
+        }
+        record_finish(&conn, &sized(50_000, BuildStatus::Failure, 200), 0).unwrap();
+        record_finish(&conn, &finish("foo", 1_000, BuildStatus::Success, 300), 0).unwrap();
+        assert_eq!(
+            predict_output_bytes(&conn, "foo").unwrap(),
+            Some(OUTPUT_SIZE_SAMPLES as u64 * 100)
+        );
+    }
+
     #[test]
     fn predict_ms_reads_rows_in_chronological_order() {
         // Insert two batches in arbitrary insert order but with distinct
@@ -212,6 +517,175 @@ mod tests {
         );
     }
 
+    #[test]
+    fn predict_prefers_exact_pname() {
+        let conn = open_in_memory().unwrap();
+        record_finish(&conn, &finish("llvm18", 7_000, BuildStatus::Success, 1), 0).unwrap();
+        record_finish(
+            &conn,
+            &finish("llvm17", 900_000, BuildStatus::Success, 2),
+            0,
+        )
+        .unwrap();
+        assert_eq!(
+            predict(&conn, "llvm18", None, ALPHA, Z).unwrap(),
+            Some(Prediction {
+                ms: 7_000,
+                tier: EstimateTier::Exact
+            })
+        );
+    }
+
+    #[test]
+    fn predict_borrows_other_version_before_family() {
+        let conn = open_in_memory().unwrap();
+        record_finish(
+            &conn,
+            &finish("llvm17", 900_000, BuildStatus::Success, 1),
+            0,
+        )
+        .unwrap();
+        let got = predict(&conn, "llvm18", None, ALPHA, Z).unwrap().unwrap();
+        assert_eq!(got.tier, EstimateTier::OtherVersion);
+        assert_eq!(got.ms, 900_000);
+    }
+
+    #[test]
+    fn predict_borrows_family_prefix() {
+        let conn = open_in_memory().unwrap();
+        record_finish(
+            &conn,
+            &finish("cargo-package-serde", 4_000, BuildStatus::Success, 1),
+            0,
+        )
+        .unwrap();
+        record_finish(
+            &conn,
+            &finish("cargoish", 999_000, BuildStatus::Success, 2),
+            0,
+        )
+        .unwrap();
+        let got = predict(&conn, "cargo-package-syn", None, ALPHA, Z)
+            .unwrap()
+            .unwrap();
+        assert_eq!(got.tier, EstimateTier::Family);
+        assert_eq!(got.ms, 4_000);
+    }
+
+    #[test]
+    fn predict_borrows_builder_kind_then_gives_up() {
+        let conn = open_in_memory().unwrap();
+        record_finish(
+            &conn,
+            &finish("foo-source", 800, BuildStatus::Success, 1),
+            0,
+        )
+        .unwrap();
+        record_builder_kind(&conn, "foo-source", "fixed-output").unwrap();
+        record_finish(
+            &conn,
+            &finish("bigthing", 900_000, BuildStatus::Success, 2),
+            0,
+        )
+        .unwrap();
+        record_builder_kind(&conn, "bigthing", "stdenv").unwrap();
+
+        let got = predict(&conn, "barsrc", Some("fixed-output"), ALPHA, Z)
+            .unwrap()
+            .unwrap();
+        assert_eq!(got.tier, EstimateTier::BuilderKind);
+        assert_eq!(got.ms, 800);
+
+        assert_eq!(predict(&conn, "barsrc", None, ALPHA, Z).unwrap(), None);
+        assert_eq!(
+            predict(&conn, "barsrc", Some("builtin:fetchurl"), ALPHA, Z).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn predict_pools_only_the_newest_rows() {
+        let conn = open_in_memory().unwrap();
+        for ts in 0..50 {
+            let event = finish("cargo-package-old", 900_000, BuildStatus::Success, ts);
+            record_finish(&conn, &event, 0).unwrap();
+        }
+        let newest = open_in_memory().unwrap();
+        for ts in 100..100 + POOLED_SAMPLES as u64 {
+            let event = finish("cargo-package-new", 4_000, BuildStatus::Success, ts);
+            record_finish(&conn, &event, 0).unwrap();
+            record_finish(&newest, &event, 0).unwrap();
+        }
+        let got = predict(&conn, "cargo-package-syn", None, ALPHA, Z)
+            .unwrap()
+            .unwrap();
+        assert_eq!(got.tier, EstimateTier::Family);
+        // The 900 s builds fell outside the pool.
+        assert_eq!(
+            Some(got),
+            predict(&newest, "cargo-package-syn", None, ALPHA, Z).unwrap()
+        );
+    }
+
+    #[test]
+    fn record_builder_kind_writes_only_changes() {
+        let conn = open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TEMP TABLE writes(n INTEGER);
+             CREATE TEMP TRIGGER pname_builders_inserted AFTER INSERT ON pname_builders
+               BEGIN INSERT INTO writes VALUES (1); END;
+             CREATE TEMP TRIGGER pname_builders_updated AFTER UPDATE ON pname_builders
+               BEGIN INSERT INTO writes VALUES (1); END;",
+        )
+        .unwrap();
+        let writes = || -> i64 {
+            conn.query_row("SELECT COUNT(*) FROM writes", [], |row| row.get(0))
+                .unwrap()
+        };
+        record_builder_kind(&conn, "foo", "stdenv").unwrap();
+        record_builder_kind(&conn, "foo", "stdenv").unwrap();
+        assert_eq!(writes(), 1);
+        record_builder_kind(&conn, "foo", "fixed-output").unwrap();
+        assert_eq!(writes(), 2);
+        let kind: String = conn
+            .query_row(
+                "SELECT builder_kind FROM pname_builders WHERE pname = 'foo'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(kind, "fixed-output");
+    }
+
+    #[test]
+    fn prefix_end_bounds_exactly_the_prefixed_strings() {
+        assert_eq!(prefix_end("cargo-").as_deref(), Some("cargo."));
+        assert_eq!(prefix_end("llvm").as_deref(), Some("llvn"));
+        assert_eq!(prefix_end("a\u{10FFFF}").as_deref(), Some("b"));
+        assert_eq!(prefix_end("a\u{D7FF}").as_deref(), Some("a\u{E000}"));
+        assert_eq!(prefix_end(""), None);
+    }
+
+    #[test]
+    fn predict_fallback_tiers_ignore_failure_rows() {
+        let conn = open_in_memory().unwrap();
+        record_finish(
+            &conn,
+            &finish("llvm17", 900_000, BuildStatus::Failure, 1),
+            0,
+        )
+        .unwrap();
+        assert_eq!(predict(&conn, "llvm18", None, ALPHA, Z).unwrap(), None);
+    }
+
+    #[test]
+    fn infra_failure_writes_no_row() {
+        let conn = open_in_memory().unwrap();
+        let event = finish("foo", 3_000, BuildStatus::InfraFailure, 999);
+        assert!(!record_finish(&conn, &event, 0).unwrap());
+        assert_eq!(predict_ms(&conn, "foo", ALPHA, Z).unwrap(), None);
+    }
+
     #[test]
     fn record_finish_with_no_duration_writes_no_row() {
         let conn = open_in_memory().unwrap();
@@ -304,11 +778,15 @@ mod tests {
             host: "tsugumi".to_string(),
             ts_ms: 1000,
             duration_ms: Some(50),
+            cpu_ms: None,
+            contention: None,
+            output_bytes: None,
             status: BuildStatus::Success,
             out_paths: vec![
                 "/nix/store/out-foo".to_string(),
                 "/nix/store/out-foo-doc".to_string(),
             ],
+            correlation: None,
         };
         record_finish(&conn, &event, 0).unwrap();
         let stored: String = conn
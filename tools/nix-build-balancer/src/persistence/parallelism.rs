@@ -0,0 +1,94 @@
+//! How many cores each pname keeps busy while it builds, learned from the
+//! CPU time agents attribute to finished builds. The scheduler charges
+//! admissions against a target's cores with it.
+
+use std::collections::HashMap;
+use std::io;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+/// Fold one build's measured parallelism into `pname`'s EWMA.
+pub fn record(
+    conn: &Connection,
+    pname: &str,
+    cores: f64,
+    alpha: f64,
+    at_ms: u64,
+) -> io::Result<()> {
+    if !cores.is_finite() || cores < 0.0 {
+        return Ok(());
+    }
+    let previous: Option<f64> = conn
+        .query_row(
+            "SELECT cores FROM pname_parallelism WHERE pname = ?1",
+            [pname],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(io::Error::other)?;
+    let smoothed = match previous {
+        Some(old) => alpha * cores + (1.0 - alpha) * old,
+        None => cores,
+    };
+    conn.execute(
+        "INSERT INTO pname_parallelism (pname, cores, samples, updated_at_ms)
+         VALUES (?1, ?2, 1, ?3)
+         ON CONFLICT(pname) DO UPDATE SET
+           cores = excluded.cores,
+           samples = samples + 1,
+           updated_at_ms = excluded.updated_at_ms",
+        params![pname, smoothed, at_ms as i64],
+    )
+    .map_err(io::Error::other)?;
+    Ok(())
+}
+
+/// Learned cores for whichever of `pnames` have been measured.
+pub fn get_many<'a>(
+    conn: &Connection,
+    pnames: impl IntoIterator<Item = &'a str>,
+) -> io::Result<HashMap<String, f64>> {
+    let mut stmt = conn
+        .prepare_cached("SELECT cores FROM pname_parallelism WHERE pname = ?1")
+        .map_err(io::Error::other)?;
+    let mut cores = HashMap::new();
+    for pname in pnames {
+        if cores.contains_key(pname) {
+            continue;
+        }
+        let learned: Option<f64> = stmt
+            .query_row([pname], |row| row.get(0))
+            .optional()
+            .map_err(io::Error::other)?;
+        if let Some(learned) = learned {
+            cores.insert(pname.to_string(), learned);
+        }
+    }
+    Ok(cores)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::open_in_memory;
+
+    #[test]
+    fn first_sample_is_taken_as_is_then_smoothed() {
+        let conn = open_in_memory().unwrap();
+        record(&conn, "firefox", 16.0, 0.25, 1).unwrap();
+        record(&conn, "firefox", 8.0, 0.25, 2).unwrap();
+        record(&conn, "hello", f64::NAN, 0.25, 3).unwrap();
+
+        let cores = get_many(&conn, ["firefox", "hello", "firefox"]).unwrap();
+        assert_eq!(cores.len(), 1);
+        assert!((cores["firefox"] - 14.0).abs() < 1e-9);
+        let samples: i64 = conn
+            .query_row(
+                "SELECT samples FROM pname_parallelism WHERE pname = 'firefox'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(samples, 2);
+    }
+}
@@ -6,7 +6,12 @@ CREATE TABLE IF NOT EXISTS build_observations (
   finished_at_ms INTEGER NOT NULL,
   duration_ms    INTEGER NOT NULL,
   status         TEXT    NOT NULL,
-  out_paths      TEXT    NOT NULL
+  out_paths      TEXT    NOT NULL,
+  -- `protocol::ops::Contention` while the build ran; NULL if unsampled.
+  mean_builds    REAL,
+  mean_cpu_psi   REAL,
+  -- Apparent size of `out_paths` on the building host; NULL if unmeasured.
+  output_bytes   INTEGER
 );
 
 CREATE INDEX IF NOT EXISTS build_observations_pname
@@ -15,16 +20,121 @@ CREATE INDEX IF NOT EXISTS build_observations_pname
 CREATE UNIQUE INDEX IF NOT EXISTS build_observations_drv_ts
   ON build_observations(drv_path, finished_at_ms);
 
+-- Newest-first walks: the builder-kind tier of `observations::predict`
+-- and the global observation cap.
+CREATE INDEX IF NOT EXISTS build_observations_finished
+  ON build_observations(finished_at_ms);
+
 CREATE TABLE IF NOT EXISTS admissions (
   drv_path       TEXT    PRIMARY KEY,
   target_name    TEXT    NOT NULL,
   admitted_at_ms INTEGER NOT NULL,
-  predicted_ms   INTEGER NOT NULL
+  predicted_ms   INTEGER NOT NULL,
+  correlation    TEXT
+);
+
+-- Last-seen builder classification per pname (see `drv::DrvInfo::builder_kind`),
+-- read by the estimator's builder-kind fallback tier.
+CREATE TABLE IF NOT EXISTS pname_builders (
+  pname        TEXT PRIMARY KEY,
+  builder_kind TEXT NOT NULL
+);
+
+-- Decision log (see `persistence::decisions`). One row per `make_decision`
+-- call, one `decision_targets` row per target it looked at.
+CREATE TABLE IF NOT EXISTS decisions (
+  id            INTEGER PRIMARY KEY,
+  decided_at_ms INTEGER NOT NULL,
+  drv_path      TEXT    NOT NULL,
+  pname         TEXT    NOT NULL,
+  system        TEXT    NOT NULL,
+  estimate_ms   INTEGER,
+  estimate_tier TEXT    NOT NULL,
+  outcome       TEXT    NOT NULL,
+  winner        TEXT,
+  session       TEXT,
+  latency_us    INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS decisions_drv
+  ON decisions(drv_path);
+
+CREATE INDEX IF NOT EXISTS decisions_time
+  ON decisions(decided_at_ms);
+
+CREATE INDEX IF NOT EXISTS decisions_session
+  ON decisions(session, decided_at_ms);
+
+CREATE TABLE IF NOT EXISTS decision_targets (
+  decision_id   INTEGER NOT NULL,
+  position      INTEGER NOT NULL,
+  target_name   TEXT    NOT NULL,
+  excluded      TEXT,
+  queue_ms      INTEGER,
+  package_ms    INTEGER,
+  completion_ms INTEGER,
+  PRIMARY KEY (decision_id, position)
+);
+
+-- End of the remote build log for failed delegated builds, as reported
+-- by the hook; the newest `failure_tails_per_pname` rows per pname kept.
+CREATE TABLE IF NOT EXISTS failure_tails (
+  id             INTEGER PRIMARY KEY,
+  pname          TEXT    NOT NULL,
+  drv_path       TEXT    NOT NULL,
+  target_name    TEXT    NOT NULL,
+  status         TEXT    NOT NULL,
+  finished_at_ms INTEGER NOT NULL,
+  tail           TEXT    NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS failure_tails_pname
+  ON failure_tails(pname, finished_at_ms);
+
+-- Latest builder verdict (`success` / `failure`) for each pname on each
+-- target, by target name; see `persistence::target_outcomes`.
+CREATE TABLE IF NOT EXISTS pname_target_outcomes (
+  pname          TEXT    NOT NULL,
+  target_name    TEXT    NOT NULL,
+  status         TEXT    NOT NULL,
+  finished_at_ms INTEGER NOT NULL,
+  PRIMARY KEY (pname, target_name)
 );
 
+-- Priority registered for a rebuild session with `nbb-controller
+-- priority`, overriding its hooks' `NBB_PRIORITY`; see
+-- `persistence::sessions`.
+CREATE TABLE IF NOT EXISTS session_priorities (
+  session   TEXT    PRIMARY KEY,
+  priority  TEXT    NOT NULL,
+  set_at_ms INTEGER NOT NULL
+);
+
+-- Cores each pname keeps busy while it builds (`cpu_ms / duration_ms`),
+-- smoothed with `ewma_alpha`; see `persistence::parallelism`.
+CREATE TABLE IF NOT EXISTS pname_parallelism (
+  pname         TEXT    PRIMARY KEY,
+  cores         REAL    NOT NULL,
+  samples       INTEGER NOT NULL,
+  updated_at_ms INTEGER NOT NULL
+);
+
+-- Round-trip time and throughput from the controller host to each
+-- target (see `crate::link`); the newest rows per target kept.
+CREATE TABLE IF NOT EXISTS link_probes (
+  id            INTEGER PRIMARY KEY,
+  target_name   TEXT    NOT NULL,
+  probed_at_ms  INTEGER NOT NULL,
+  rtt_us        INTEGER NOT NULL,
+  bytes_per_sec INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS link_probes_target
+  ON link_probes(target_name, probed_at_ms);
+
+-- `schema_version` is maintained by `persistence::migrations`; a change
+-- to this file that existing databases need also needs a migration there.
 CREATE TABLE IF NOT EXISTS meta (
   key   TEXT PRIMARY KEY,
   value TEXT NOT NULL
 );
-
-INSERT OR IGNORE INTO meta(key, value) VALUES ('schema_version', '1');
@@ -0,0 +1,343 @@
+//! Per-session rollups over the decision log and `build_observations`.
+//!
+//! A session is whatever [`crate::hook::session_id`] groups together —
+//! normally one `nixos-rebuild` run. Summaries are only as old as the
+//! decision log's retention.
+//!
+//! Sessions can also be given a priority ([`set_priority`]). Under
+//! multi-user Nix the hook's environment is the daemon's, so `NBB_PRIORITY`
+//! cannot tell one client's rebuild from another's; the session can.
+
+use std::collections::HashMap;
+use std::io;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::protocol::ops::{DestinationSummary, Priority, SessionBuild, SessionSummary};
+
+/// Destination recorded for candidates Nix built on the controller host
+/// without an admission (`decline`, `fast-path:*`).
+pub const LOCAL_DESTINATION: &str = "local";
+
+/// The session of the most recent logged decision, if any.
+pub fn latest(conn: &Connection) -> io::Result<Option<String>> {
+    conn.query_row(
+        "SELECT session FROM decisions
+         WHERE session IS NOT NULL
+         ORDER BY decided_at_ms DESC, id DESC
+         LIMIT 1",
+        [],
+        |row| row.get(0),
+    )
+    .optional()
+    .map_err(io::Error::other)
+}
+
+pub fn summary(conn: &Connection, session: &str, top_n: u32) -> io::Result<Option<SessionSummary>> {
+    // Last decision per drv wins: a candidate declined while every target
+    // was busy and later accepted counts once, where it actually went.
+    let mut stmt = conn
+        .prepare(
+            "SELECT d.drv_path, d.pname, d.decided_at_ms, d.estimate_ms, d.winner, t.package_ms
+             FROM decisions d
+             LEFT JOIN decision_targets t
+               ON t.decision_id = d.id AND t.target_name = d.winner
+             WHERE d.session = ?1
+             ORDER BY d.decided_at_ms, d.id",
+        )
+        .map_err(io::Error::other)?;
+    let rows = stmt
+        .query_map(params![session], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?.max(0) as u64,
+                row.get::<_, Option<i64>>(3)?,
+                row.get::<_, Option<String>>(4)?,
+                row.get::<_, Option<i64>>(5)?,
+            ))
+        })
+        .map_err(io::Error::other)?;
+
+    let mut order: Vec<String> = Vec::new();
+    let mut latest_by_drv: HashMap<String, (String, u64, Option<u64>, String)> = HashMap::new();
+    let mut first_decision_ms = u64::MAX;
+    let mut last_decision_ms = 0;
+    for row in rows {
+        let (drv_path, pname, decided_at_ms, estimate_ms, winner, package_ms) =
+            row.map_err(io::Error::other)?;
+        first_decision_ms = first_decision_ms.min(decided_at_ms);
+        last_decision_ms = last_decision_ms.max(decided_at_ms);
+        // Admitted candidates were predicted at the winner's scaled
+        // `package_ms`; local ones at the raw estimate.
+        let predicted_ms = package_ms.or(estimate_ms).map(|v| v.max(0) as u64);
+        let destination = winner.unwrap_or_else(|| LOCAL_DESTINATION.to_string());
+        if !latest_by_drv.contains_key(&drv_path) {
+            order.push(drv_path.clone());
+        }
+        latest_by_drv.insert(drv_path, (pname, decided_at_ms, predicted_ms, destination));
+    }
+    if order.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builds = Vec::with_capacity(order.len());
+    for drv_path in order {
+        let (pname, decided_at_ms, predicted_ms, destination) = latest_by_drv
+            .remove(&drv_path)
+            .expect("every ordered drv has a decision");
+        let actual_ms = actual_duration_ms(conn, &drv_path, decided_at_ms)?;
+        builds.push(SessionBuild {
+            drv_path,
+            pname,
+            destination,
+            predicted_ms,
+            actual_ms,
+        });
+    }
+
+    let mut destinations: Vec<DestinationSummary> = Vec::new();
+    for build in &builds {
+        let idx = match destinations
+            .iter()
+            .position(|d| d.destination == build.destination)
+        {
+            Some(idx) => idx,
+            None => {
+                destinations.push(DestinationSummary {
+                    destination: build.destination.clone(),
+                    derivations: 0,
+                    predicted_ms: 0,
+                    actual_ms: 0,
+                });
+                destinations.len() - 1
+            }
+        };
+        let entry = &mut destinations[idx];
+        entry.derivations += 1;
+        entry.predicted_ms = entry
+            .predicted_ms
+            .saturating_add(build.predicted_ms.unwrap_or(0));
+        entry.actual_ms = entry.actual_ms.saturating_add(build.actual_ms.unwrap_or(0));
+    }
+
+    let mut slowest = builds.clone();
+    slowest.sort_by_key(|b| std::cmp::Reverse(b.actual_ms.or(b.predicted_ms).unwrap_or(0)));
+    slowest.truncate(top_n as usize);
+
+    Ok(Some(SessionSummary {
+        session: session.to_string(),
+        first_decision_ms,
+        last_decision_ms,
+        derivations: builds.len() as u32,
+        unfinished: builds.iter().filter(|b| b.actual_ms.is_none()).count() as u32,
+        predicted_ms: destinations.iter().map(|d| d.predicted_ms).sum(),
+        actual_ms: destinations.iter().map(|d| d.actual_ms).sum(),
+        destinations,
+        slowest,
+    }))
+}
+
+/// Give `session`'s candidates `priority` from now on, whatever their
+/// hooks say.
+pub fn set_priority(
+    conn: &Connection,
+    session: &str,
+    priority: Priority,
+    at_ms: u64,
+) -> io::Result<()> {
+    conn.execute(
+        "INSERT INTO session_priorities (session, priority, set_at_ms)
+         VALUES (?1, ?2, ?3)
+         ON CONFLICT(session) DO UPDATE SET
+           priority = excluded.priority,
+           set_at_ms = excluded.set_at_ms",
+        params![session, priority.as_str(), at_ms as i64],
+    )
+    .map_err(io::Error::other)?;
+    Ok(())
+}
+
+/// The priority registered for `session`, if any.
+pub fn priority(conn: &Connection, session: &str) -> io::Result<Option<Priority>> {
+    let stored: Option<String> = conn
+        .prepare_cached("SELECT priority FROM session_priorities WHERE session = ?1")
+        .and_then(|mut stmt| {
+            stmt.query_row(params![session], |row| row.get(0))
+                .optional()
+        })
+        .map_err(io::Error::other)?;
+    Ok(stored.as_deref().and_then(Priority::parse))
+}
+
+/// Forget priorities set before `cutoff_ms` for sessions with nothing
+/// left in the decision log: prune that first.
+pub fn prune_priorities_before(conn: &Connection, cutoff_ms: u64) -> io::Result<usize> {
+    conn.execute(
+        "DELETE FROM session_priorities
+         WHERE set_at_ms < ?1
+           AND NOT EXISTS (
+             SELECT 1 FROM decisions d WHERE d.session = session_priorities.session)",
+        params![cutoff_ms as i64],
+    )
+    .map_err(io::Error::other)
+}
+
+fn actual_duration_ms(
+    conn: &Connection,
+    drv_path: &str,
+    decided_at_ms: u64,
+) -> io::Result<Option<u64>> {
+    conn.query_row(
+        "SELECT duration_ms FROM build_observations
+         WHERE drv_path = ?1 AND finished_at_ms >= ?2
+         ORDER BY finished_at_ms
+         LIMIT 1",
+        params![drv_path, decided_at_ms as i64],
+        |row| row.get::<_, i64>(0),
+    )
+    .optional()
+    .map(|v| v.map(|ms| ms.max(0) as u64))
+    .map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::decisions::{self, DecisionRow, DecisionTargetRow};
+    use crate::persistence::{observations, open_in_memory};
+    use crate::protocol::ops::{BuildStatus, EventBuildFinish};
+
+    fn decision(drv: &str, at_ms: u64, session: &str, winner: Option<&str>) -> DecisionRow {
+        DecisionRow {
+            decided_at_ms: at_ms,
+            drv_path: drv.to_string(),
+            pname: crate::util::pname_from_drv(drv),
+            system: "x86_64-linux".to_string(),
+            estimate_ms: Some(10_000),
+            estimate_tier: "exact".to_string(),
+            outcome: if winner.is_some() {
+                "accept"
+            } else {
+                "decline"
+            }
+            .to_string(),
+            winner: winner.map(str::to_string),
+            session: Some(session.to_string()),
+            latency_us: None,
+            targets: vec![DecisionTargetRow {
+                target_name: "tsugumi".to_string(),
+                excluded: None,
+                queue_ms: Some(0),
+                package_ms: Some(5_000),
+                completion_ms: Some(5_000),
+            }],
+        }
+    }
+
+    fn finish(conn: &Connection, drv: &str, ts_ms: u64, duration_ms: u64) {
+        observations::record_finish(
+            conn,
+            &EventBuildFinish {
+                drv_path: drv.to_string(),
+                pname: crate::util::pname_from_drv(drv),
+                host: "tsugumi".to_string(),
+                ts_ms,
+                duration_ms: Some(duration_ms),
+                cpu_ms: None,
+                contention: None,
+                output_bytes: None,
+                status: BuildStatus::Success,
+                out_paths: vec![],
+                correlation: None,
+            },
+            200,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn summarises_destinations_and_slowest_builds() {
+        let conn = open_in_memory().unwrap();
+        let a = "/nix/store/aaa-foo-1.0.drv";
+        let b = "/nix/store/bbb-bar-2.0.drv";
+        let c = "/nix/store/ccc-baz-3.0.drv";
+        // `a` is declined first, then accepted: it counts once, remotely.
+        decisions::record(&conn, &decision(a, 1_000, "s1", None)).unwrap();
+        decisions::record(&conn, &decision(a, 2_000, "s1", Some("tsugumi"))).unwrap();
+        decisions::record(&conn, &decision(b, 3_000, "s1", None)).unwrap();
+        decisions::record(&conn, &decision(c, 4_000, "s1", Some("tsugumi"))).unwrap();
+        decisions::record(&conn, &decision(c, 9_000, "other", None)).unwrap();
+        finish(&conn, a, 20_000, 18_000);
+        finish(&conn, b, 5_000, 2_000);
+
+        let summary = summary(&conn, "s1", 2).unwrap().unwrap();
+        assert_eq!(summary.derivations, 3);
+        assert_eq!(summary.unfinished, 1);
+        assert_eq!(summary.first_decision_ms, 1_000);
+        assert_eq!(summary.last_decision_ms, 4_000);
+        assert_eq!(
+            summary.destinations,
+            vec![
+                DestinationSummary {
+                    destination: "tsugumi".to_string(),
+                    derivations: 2,
+                    predicted_ms: 10_000,
+                    actual_ms: 18_000,
+                },
+                DestinationSummary {
+                    destination: LOCAL_DESTINATION.to_string(),
+                    derivations: 1,
+                    predicted_ms: 10_000,
+                    actual_ms: 2_000,
+                },
+            ]
+        );
+        assert_eq!(summary.predicted_ms, 20_000);
+        assert_eq!(summary.actual_ms, 20_000);
+        let slowest: Vec<&str> = summary
+            .slowest
+            .iter()
+            .map(|b| b.drv_path.as_str())
+            .collect();
+        // `c` never finished; its 5 s prediction still outranks `b`'s 2 s.
+        assert_eq!(slowest, vec![a, c]);
+    }
+
+    #[test]
+    fn observation_before_the_decision_is_not_this_sessions_build() {
+        let conn = open_in_memory().unwrap();
+        let a = "/nix/store/aaa-foo-1.0.drv";
+        finish(&conn, a, 500, 7_000);
+        decisions::record(&conn, &decision(a, 1_000, "s1", Some("tsugumi"))).unwrap();
+        let summary = summary(&conn, "s1", 5).unwrap().unwrap();
+        assert_eq!(summary.unfinished, 1);
+        assert_eq!(summary.slowest[0].actual_ms, None);
+    }
+
+    #[test]
+    fn unknown_session_and_latest() {
+        let conn = open_in_memory().unwrap();
+        assert_eq!(latest(&conn).unwrap(), None);
+        assert_eq!(summary(&conn, "nope", 5).unwrap(), None);
+        decisions::record(&conn, &decision("/nix/store/a-x.drv", 1_000, "s1", None)).unwrap();
+        decisions::record(&conn, &decision("/nix/store/b-y.drv", 2_000, "s2", None)).unwrap();
+        assert_eq!(latest(&conn).unwrap().as_deref(), Some("s2"));
+    }
+
+    #[test]
+    fn session_priority_lasts_while_the_session_does() {
+        let conn = open_in_memory().unwrap();
+        assert_eq!(priority(&conn, "s1").unwrap(), None);
+        set_priority(&conn, "s1", Priority::High, 1_000).unwrap();
+        set_priority(&conn, "s1", Priority::Low, 2_000).unwrap();
+        set_priority(&conn, "s2", Priority::High, 2_000).unwrap();
+        assert_eq!(priority(&conn, "s1").unwrap(), Some(Priority::Low));
+
+        // s1 is still deciding; s2 never got going.
+        decisions::record(&conn, &decision("/nix/store/a-x.drv", 9_000, "s1", None)).unwrap();
+        assert_eq!(prune_priorities_before(&conn, 5_000).unwrap(), 1);
+        assert_eq!(priority(&conn, "s1").unwrap(), Some(Priority::Low));
+        assert_eq!(priority(&conn, "s2").unwrap(), None);
+    }
+}
@@ -0,0 +1,103 @@
+//! The latest builder verdict for each pname on each target, keyed by the
+//! controller's target name. Failures come from the hook's
+//! `ADMISSION_FINISH` (the post-build-hook only runs on success); successes
+//! from it as well and from the finish events of the target's own agent.
+//! The scheduler stops sending a pname where it failed
+//! ([`failed_targets`]).
+
+use std::io;
+
+use rusqlite::{params, Connection};
+
+use crate::protocol::ops::BuildStatus;
+
+/// Remember `status` as `pname`'s latest outcome on `target_name`, unless
+/// a newer one is already known. Only the builder's verdicts count:
+/// infrastructure failures and cancellations are ignored.
+pub fn record(
+    conn: &Connection,
+    pname: &str,
+    target_name: &str,
+    status: BuildStatus,
+    at_ms: u64,
+) -> io::Result<()> {
+    if !matches!(status, BuildStatus::Success | BuildStatus::Failure) {
+        return Ok(());
+    }
+    conn.execute(
+        "INSERT INTO pname_target_outcomes (pname, target_name, status, finished_at_ms)
+         VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(pname, target_name) DO UPDATE SET
+           status = excluded.status,
+           finished_at_ms = excluded.finished_at_ms
+         WHERE excluded.finished_at_ms >= finished_at_ms",
+        params![pname, target_name, status.as_str(), at_ms as i64],
+    )
+    .map_err(io::Error::other)?;
+    Ok(())
+}
+
+/// Targets where `pname`'s most recent build failed and a later build of
+/// it succeeded on another target: the failure looks specific to that
+/// target, so the scheduler stops sending the pname there. A pname that
+/// fails everywhere is broken, not misrouted, and yields nothing.
+pub fn failed_targets(conn: &Connection, pname: &str) -> io::Result<Vec<String>> {
+    let mut stmt = conn
+        .prepare_cached(
+            "SELECT f.target_name
+             FROM pname_target_outcomes f
+             WHERE f.pname = ?1
+               AND f.status = 'failure'
+               AND EXISTS (
+                 SELECT 1 FROM pname_target_outcomes s
+                 WHERE s.pname = ?1
+                   AND s.target_name != f.target_name
+                   AND s.status = 'success'
+                   AND s.finished_at_ms > f.finished_at_ms)
+             ORDER BY f.target_name",
+        )
+        .map_err(io::Error::other)?;
+    let rows = stmt
+        .query_map(params![pname], |row| row.get::<_, String>(0))
+        .map_err(io::Error::other)?;
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(row.map_err(io::Error::other)?);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::open_in_memory;
+
+    #[test]
+    fn failed_targets_only_lists_target_specific_failures() {
+        let conn = open_in_memory().unwrap();
+        // Failed on tsugumi, nothing else yet: could be the derivation.
+        record(&conn, "foo", "tsugumi", BuildStatus::Failure, 100).unwrap();
+        assert!(failed_targets(&conn, "foo").unwrap().is_empty());
+
+        // Built on saya afterwards: tsugumi is to blame.
+        record(&conn, "foo", "saya", BuildStatus::Success, 200).unwrap();
+        assert_eq!(failed_targets(&conn, "foo").unwrap(), vec!["tsugumi"]);
+
+        // Infrastructure failures and cancellations are no verdict.
+        record(&conn, "foo", "saya", BuildStatus::InfraFailure, 250).unwrap();
+        record(&conn, "foo", "tsugumi", BuildStatus::Cancelled, 250).unwrap();
+        assert_eq!(failed_targets(&conn, "foo").unwrap(), vec!["tsugumi"]);
+
+        // Tsugumi built it since; a late report of the old failure does
+        // not bring it back.
+        record(&conn, "foo", "tsugumi", BuildStatus::Success, 300).unwrap();
+        record(&conn, "foo", "tsugumi", BuildStatus::Failure, 100).unwrap();
+        assert!(failed_targets(&conn, "foo").unwrap().is_empty());
+
+        // Latest builds failed everywhere: the pname is broken.
+        record(&conn, "foo", "tsugumi", BuildStatus::Failure, 400).unwrap();
+        record(&conn, "foo", "saya", BuildStatus::Failure, 500).unwrap();
+        assert!(failed_targets(&conn, "foo").unwrap().is_empty());
+        assert!(failed_targets(&conn, "bar").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,327 @@
+//! Shared-key authentication for hooks connecting over TCP.
+//!
+//! The source-hash handshake only proves both ends run the same build; it
+//! is no credential. After it, the controller sends an [`AuthChallenge`]
+//! with a fresh nonce and the hook answers with its host name, an
+//! HMAC-SHA256 over `nonce ‖ host` keyed with the shared hook key, and a
+//! fresh nonce of its own. The authenticated host name is what the
+//! controller schedules relative to, so it is covered by the MAC rather
+//! than trusted from the candidate.
+//!
+//! The hook runs `nix __build-remote` against whatever builder the
+//! controller names, so it needs to know the controller too: the
+//! controller answers with an [`AuthConfirm`] MAC over the hook's nonce,
+//! and the hook sends nothing else until that verifies. The controller's
+//! MAC starts with a fixed label, so no hook's answer can be passed off as
+//! one. Unix-socket hooks skip this; filesystem permissions guard the
+//! socket.
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::protocol::frame::{
+    read_frame_async, read_frame_sync, write_frame_async, write_frame_sync, Frame,
+};
+use crate::protocol::ops::{op, AuthChallenge, AuthConfirm, AuthResponse};
+
+pub const NONCE_LEN: usize = 32;
+
+/// What the controller's MAC covers ahead of the hook's nonce.
+const CONTROLLER_LABEL: &[u8] = b"nbb-controller\0";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Read a hook key file. Trailing whitespace is dropped so a key written
+/// with `echo` matches one written with `printf`.
+pub fn read_key(path: &Path) -> io::Result<Vec<u8>> {
+    let mut key = std::fs::read(path)?;
+    while key.last().is_some_and(u8::is_ascii_whitespace) {
+        key.pop();
+    }
+    if key.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("hook key file {} is empty", path.display()),
+        ));
+    }
+    Ok(key)
+}
+
+/// HMAC-SHA256 over `nonce ‖ host`. The nonce is fixed-length, so the
+/// concatenation is unambiguous.
+pub fn sign(key: &[u8], nonce: &[u8], host: &str) -> Vec<u8> {
+    mac_for(key, nonce, host).finalize().into_bytes().to_vec()
+}
+
+/// Constant-time check of a [`sign`] result.
+pub fn verify(key: &[u8], nonce: &[u8], host: &str, mac: &[u8]) -> bool {
+    mac_for(key, nonce, host).verify_slice(mac).is_ok()
+}
+
+/// The controller's proof: HMAC-SHA256 over `label ‖ nonce ‖ host`, for
+/// the hook's nonce and the host it authenticated as.
+pub fn sign_controller(key: &[u8], nonce: &[u8], host: &str) -> Vec<u8> {
+    controller_mac_for(key, nonce, host)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+/// Constant-time check of a [`sign_controller`] result.
+pub fn verify_controller(key: &[u8], nonce: &[u8], host: &str, mac: &[u8]) -> bool {
+    controller_mac_for(key, nonce, host)
+        .verify_slice(mac)
+        .is_ok()
+}
+
+fn mac_for(key: &[u8], nonce: &[u8], host: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(nonce);
+    mac.update(host.as_bytes());
+    mac
+}
+
+fn controller_mac_for(key: &[u8], nonce: &[u8], host: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(CONTROLLER_LABEL);
+    mac.update(nonce);
+    mac.update(host.as_bytes());
+    mac
+}
+
+fn denied(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, message)
+}
+
+fn fresh_nonce() -> io::Result<Vec<u8>> {
+    let mut nonce = vec![0u8; NONCE_LEN];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut nonce)?;
+    Ok(nonce)
+}
+
+/// Controller side: challenge the peer, prove the key in turn, and return
+/// the host name it authenticated as. `PermissionDenied` if the MAC does
+/// not verify.
+pub async fn challenge_async<S>(stream: &mut S, key: &[u8]) -> io::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let nonce = fresh_nonce()?;
+    let challenge = AuthChallenge {
+        nonce: nonce.clone(),
+    };
+    write_frame_async(stream, &Frame::with_body(op::AUTH_CHALLENGE, &challenge)?).await?;
+    let frame = read_frame_async(stream).await?;
+    if frame.op_id != op::AUTH_RESPONSE {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("expected AUTH_RESPONSE, got op_id {}", frame.op_id),
+        ));
+    }
+    let response: AuthResponse = frame.decode_body()?;
+    if !verify(key, &nonce, &response.host, &response.mac) {
+        return Err(denied(format!(
+            "hook key mismatch for host {:?}",
+            response.host
+        )));
+    }
+    if response.nonce.len() != NONCE_LEN {
+        return Err(denied(format!(
+            "hook nonce of {} bytes, expected {NONCE_LEN}",
+            response.nonce.len()
+        )));
+    }
+    let confirm = AuthConfirm {
+        mac: sign_controller(key, &response.nonce, &response.host),
+    };
+    write_frame_async(stream, &Frame::with_body(op::AUTH_CONFIRM, &confirm)?).await?;
+    Ok(response.host)
+}
+
+/// Hook side: answer the controller's challenge as `host`, then check the
+/// controller's own proof. `PermissionDenied` if it does not verify.
+pub fn respond_sync<S: Read + Write>(stream: &mut S, key: &[u8], host: &str) -> io::Result<()> {
+    let frame = read_frame_sync(stream)?;
+    if frame.op_id != op::AUTH_CHALLENGE {
+        return Err(io::Error::other(format!(
+            "expected AUTH_CHALLENGE, got op_id {}",
+            frame.op_id
+        )));
+    }
+    let challenge: AuthChallenge = frame.decode_body()?;
+    if challenge.nonce.len() != NONCE_LEN {
+        return Err(denied(format!(
+            "controller nonce of {} bytes, expected {NONCE_LEN}",
+            challenge.nonce.len()
+        )));
+    }
+    let nonce = fresh_nonce()?;
+    let response = AuthResponse {
+        host: host.to_string(),
+        mac: sign(key, &challenge.nonce, host),
+        nonce: nonce.clone(),
+    };
+    write_frame_sync(stream, &Frame::with_body(op::AUTH_RESPONSE, &response)?)?;
+    let frame = read_frame_sync(stream)?;
+    if frame.op_id != op::AUTH_CONFIRM {
+        return Err(denied(format!(
+            "expected AUTH_CONFIRM, got op_id {}",
+            frame.op_id
+        )));
+    }
+    let confirm: AuthConfirm = frame.decode_body()?;
+    if !verify_controller(key, &nonce, host, &confirm.mac) {
+        return Err(denied("controller key mismatch".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_binds_key_nonce_and_host() {
+        let nonce = [7u8; NONCE_LEN];
+        let mac = sign(b"secret", &nonce, "tsugumi");
+        assert!(verify(b"secret", &nonce, "tsugumi", &mac));
+        assert!(!verify(b"other", &nonce, "tsugumi", &mac));
+        assert!(!verify(b"secret", &[8u8; NONCE_LEN], "tsugumi", &mac));
+        assert!(!verify(b"secret", &nonce, "saya", &mac));
+        assert!(!verify(b"secret", &nonce, "tsugumi", &mac[..16]));
+    }
+
+    #[test]
+    fn hook_and_controller_proofs_are_not_interchangeable() {
+        let nonce = [7u8; NONCE_LEN];
+        let controller = sign_controller(b"secret", &nonce, "tsugumi");
+        assert!(verify_controller(b"secret", &nonce, "tsugumi", &controller));
+        assert!(!verify_controller(b"other", &nonce, "tsugumi", &controller));
+        assert!(!verify_controller(b"secret", &nonce, "saya", &controller));
+        assert!(!verify(b"secret", &nonce, "tsugumi", &controller));
+        let hook = sign(b"secret", &nonce, "tsugumi");
+        assert!(!verify_controller(b"secret", &nonce, "tsugumi", &hook));
+    }
+
+    /// Answer the challenge on `stream`; the nonce sent for the
+    /// controller to sign.
+    async fn answer(stream: &mut tokio::io::DuplexStream, key: &[u8], host: &str) -> Vec<u8> {
+        let challenge: AuthChallenge = read_frame_async(stream)
+            .await
+            .unwrap()
+            .decode_body()
+            .unwrap();
+        assert_eq!(challenge.nonce.len(), NONCE_LEN);
+        let response = AuthResponse {
+            host: host.to_string(),
+            mac: sign(key, &challenge.nonce, host),
+            nonce: vec![9u8; NONCE_LEN],
+        };
+        let frame = Frame::with_body(op::AUTH_RESPONSE, &response).unwrap();
+        // The controller may already have hung up on a bad key.
+        let _ = write_frame_async(stream, &frame).await;
+        response.nonce
+    }
+
+    #[tokio::test]
+    async fn challenge_accepts_the_right_key_only() {
+        let (mut controller, mut hook) = tokio::io::duplex(1024);
+        let (host, nonce) = tokio::join!(
+            challenge_async(&mut controller, b"secret"),
+            answer(&mut hook, b"secret", "tsugumi"),
+        );
+        assert_eq!(host.unwrap(), "tsugumi");
+        let confirm: AuthConfirm = read_frame_async(&mut hook)
+            .await
+            .unwrap()
+            .decode_body()
+            .unwrap();
+        assert!(verify_controller(
+            b"secret",
+            &nonce,
+            "tsugumi",
+            &confirm.mac
+        ));
+
+        let (mut controller, mut hook) = tokio::io::duplex(1024);
+        let (host, _) = tokio::join!(
+            challenge_async(&mut controller, b"secret"),
+            answer(&mut hook, b"guess", "tsugumi"),
+        );
+        assert_eq!(host.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    /// Play a controller holding `key` against [`respond_sync`] on the
+    /// other end of a socket pair.
+    fn respond_to(key: &'static [u8]) -> io::Result<()> {
+        let (mut hook, controller) = std::os::unix::net::UnixStream::pair().unwrap();
+        let controller = std::thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            rt.block_on(async move {
+                controller.set_nonblocking(true).unwrap();
+                let mut controller = tokio::net::UnixStream::from_std(controller).unwrap();
+                let _ = challenge_async(&mut controller, key).await;
+            })
+        });
+        let result = respond_sync(&mut hook, b"secret", "tsugumi");
+        drop(hook);
+        controller.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn hook_checks_the_controller_key() {
+        respond_to(b"secret").unwrap();
+        // A controller with the wrong key turns the hook away, and so
+        // hangs up on it; either way the hook sees no proof.
+        assert!(respond_to(b"guess").is_err());
+    }
+
+    #[test]
+    fn hook_rejects_a_forged_confirmation() {
+        let (mut hook, mut controller) = std::os::unix::net::UnixStream::pair().unwrap();
+        let impostor = std::thread::spawn(move || {
+            let challenge = AuthChallenge {
+                nonce: vec![1u8; NONCE_LEN],
+            };
+            write_frame_sync(
+                &mut controller,
+                &Frame::with_body(op::AUTH_CHALLENGE, &challenge).unwrap(),
+            )
+            .unwrap();
+            let response: AuthResponse = read_frame_sync(&mut controller)
+                .unwrap()
+                .decode_body()
+                .unwrap();
+            // Without the key, the best it can offer is the hook's own MAC.
+            let confirm = AuthConfirm { mac: response.mac };
+            write_frame_sync(
+                &mut controller,
+                &Frame::with_body(op::AUTH_CONFIRM, &confirm).unwrap(),
+            )
+            .unwrap();
+        });
+        let err = respond_sync(&mut hook, b"secret", "tsugumi").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        impostor.join().unwrap();
+    }
+
+    #[test]
+    fn key_file_trailing_newline_is_ignored() {
+        let dir = std::env::temp_dir().join(format!("nbb-auth-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hook.key");
+        std::fs::write(&path, "secret\n").unwrap();
+        assert_eq!(read_key(&path).unwrap(), b"secret");
+        std::fs::write(&path, "\n").unwrap();
+        assert!(read_key(&path).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,3 +1,4 @@
+pub mod auth;
 pub mod frame;
 pub mod handshake;
 pub mod ops;
@@ -10,6 +11,7 @@ pub use handshake::{
     perform_handshake_sync_with, HASH_LEN,
 };
 pub use ops::{
-    op, AcceptTarget, AdmissionFinish, AgentHello, BuildStatus, DecideCandidate, Decision,
-    EventBuildFinish, SpoolEvent, TelemetryBody,
+    op, AcceptTarget, AdmissionFinish, AgentHello, AuthChallenge, AuthResponse, BuildStatus,
+    DecideCandidate, Decision, EventBuildFinish, PathsPresent, PathsQuery, SpoolEvent,
+    TelemetryBody,
 };
@@ -14,6 +14,27 @@ pub mod op {
     pub const DECIDE_CANDIDATE: u16 = 7;
     pub const DECISION: u16 = 8;
     pub const ADMISSION_FINISH: u16 = 9;
+    pub const SESSION_SUMMARY_GET: u16 = 10;
+    pub const SESSION_SUMMARY: u16 = 11;
+    pub const PATHS_QUERY: u16 = 12;
+    pub const PATHS_PRESENT: u16 = 13;
+    pub const AUTH_CHALLENGE: u16 = 14;
+    pub const AUTH_RESPONSE: u16 = 15;
+    /// Controller → agent, no body: the host has been idle long enough
+    /// that it may suspend ([`crate::wake`]).
+    pub const SUSPEND_HINT: u16 = 16;
+    /// No body, both directions on the agent connection: the controller is
+    /// shutting down; the agent answers once it has stopped pushing
+    /// finishes, which it then keeps spooled until a controller connects.
+    pub const GOODBYE: u16 = 17;
+    pub const BUILD_CANCELLED: u16 = 18;
+    pub const BUILD_GONE: u16 = 19;
+    pub const BUILD_ADMITTED: u16 = 20;
+    pub const LINK_PROBE: u16 = 21;
+    pub const LINK_PROBE_REPLY: u16 = 22;
+    pub const AUTH_CONFIRM: u16 = 23;
+    pub const SESSION_PRIORITY_SET: u16 = 24;
+    pub const SESSION_PRIORITY: u16 = 25;
 }
 
 /// Sent by an agent immediately after the handshake, identifying itself to
@@ -31,22 +52,137 @@ pub struct TelemetryBody {
     pub mem_available_kb: u64,
     pub psi_memory_some_avg10: Option<f64>,
     pub nix_slots_active: u32,
+    /// Bytes and inodes free on the agent's store filesystem; `None` when
+    /// unknown (inodes also on filesystems without an inode limit).
+    pub store_free_bytes: Option<u64>,
+    pub store_free_inodes: Option<u64>,
     pub sampled_at_ms: u64,
+    /// `.evt` files waiting in the agent's spool.
+    pub spool_backlog: u32,
+    /// The spool is at its entry cap and `nbb-event` is dropping events.
+    pub spool_full: bool,
+}
+
+/// Controller → agent: which of `paths` are in your store? Sent on the
+/// polling connection; the reply carries the same `id`.
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct PathsQuery {
+    pub id: u64,
+    pub paths: Vec<String>,
+}
+
+/// Agent → controller: the subset of a [`PathsQuery`]'s paths it has.
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct PathsPresent {
+    pub id: u64,
+    pub present: Vec<String>,
+}
+
+/// Controller → agent: a build was admitted to this host, under the
+/// candidate's correlation id. The agent logs the build under it and puts
+/// it in the build's [`EventBuildFinish`].
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct BuildAdmitted {
+    pub drv_path: String,
+    pub correlation: String,
+}
+
+/// Controller → agent: a build delegated to this host was cancelled by
+/// its hook. The agent forgets its start and answers [`BuildGone`].
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct BuildCancelled {
+    pub drv_path: String,
+    pub correlation: String,
+}
+
+/// Agent → controller: how many processes of a cancelled build were still
+/// running when the agent stopped waiting for them; `None` if it could
+/// not tell (the `.drv` is not in its store, or its outputs are floating).
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct BuildGone {
+    pub drv_path: String,
+    pub correlation: String,
+    pub lingering: Option<u32>,
+}
+
+/// Controller → agent: answer as soon as this has been read. `payload` is
+/// filler whose only purpose is to take time to cross the link
+/// ([`crate::link`]).
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct LinkProbe {
+    pub id: u64,
+    pub payload: Vec<u8>,
+}
+
+/// Agent → controller: a [`LinkProbe`] arrived, with this much payload.
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct LinkProbeReply {
+    pub id: u64,
+    pub received_bytes: u64,
+}
+
+/// Controller → hook, first frame on a TCP hook connection: a fresh random
+/// nonce to be signed with the shared hook key.
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct AuthChallenge {
+    pub nonce: Vec<u8>,
+}
+
+/// Hook → controller: the requesting host's name,
+/// [`crate::protocol::auth::sign`] over the challenge nonce and that name,
+/// and the hook's own nonce for the controller to sign in turn.
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct AuthResponse {
+    pub host: String,
+    pub mac: Vec<u8>,
+    pub nonce: Vec<u8>,
+}
+
+/// Controller → hook, once the hook is authenticated:
+/// [`crate::protocol::auth::sign_controller`] over the hook's nonce, so
+/// the hook knows it is talking to a holder of the key before it trusts
+/// any target it is sent.
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct AuthConfirm {
+    pub mac: Vec<u8>,
 }
 
 /// Build completion observation pushed from an agent to the controller. The
 /// matching `Start` event lives only in the agent's in-memory map; when the
 /// agent restarts between start and finish, `duration_ms` is `None` and the
 /// controller retires the admission without writing an observation row.
-#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
 pub struct EventBuildFinish {
     pub drv_path: String,
     pub pname: String,
     pub host: String,
     pub ts_ms: u64,
     pub duration_ms: Option<u64>,
+    /// CPU time the agent attributed to the build from its build cgroup;
+    /// `None` without cgroup accounting or a matching start.
+    pub cpu_ms: Option<u64>,
+    /// How crowded the host was while the build ran; `None` without a
+    /// matching start or if the build finished before the agent sampled it.
+    pub contention: Option<Contention>,
+    /// Apparent size of `out_paths` in the agent's store at finish;
+    /// `None` if none of them was found there.
+    pub output_bytes: Option<u64>,
     pub status: BuildStatus,
     pub out_paths: Vec<String>,
+    /// From the agent's [`BuildAdmitted`]; `None` for builds the
+    /// controller did not admit here, or that the agent learnt of before
+    /// it restarted.
+    pub correlation: Option<String>,
+}
+
+/// Averages of the agent's samples over one build's lifetime.
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq)]
+pub struct Contention {
+    /// Builds in progress on the host, this one included.
+    pub mean_builds: f64,
+    /// CPU pressure (`/proc/pressure/cpu` `some avg10`, percent); `None`
+    /// on kernels without PSI.
+    pub mean_cpu_psi: Option<f64>,
 }
 
 #[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -54,6 +190,14 @@ pub enum BuildStatus {
     Success,
     Failure,
     Cancelled,
+    /// The delegated build never got a verdict from its builder: the ssh
+    /// connection dropped or the remote store went away. Only the hook
+    /// reports this; it says nothing about the derivation itself.
+    InfraFailure,
+    /// `nix __build-remote` declined the target it was sent to before
+    /// committing (ssh refused, no free slot there), and Nix was told to
+    /// decline. Only the hook reports this.
+    Declined,
 }
 
 impl BuildStatus {
@@ -62,6 +206,8 @@ impl BuildStatus {
             BuildStatus::Success => "success",
             BuildStatus::Failure => "failure",
             BuildStatus::Cancelled => "cancelled",
+            BuildStatus::InfraFailure => "infra-failure",
+            BuildStatus::Declined => "declined",
         }
     }
 
@@ -70,6 +216,8 @@ impl BuildStatus {
             "success" => Some(Self::Success),
             "failure" => Some(Self::Failure),
             "cancelled" => Some(Self::Cancelled),
+            "infra-failure" => Some(Self::InfraFailure),
+            "declined" => Some(Self::Declined),
             _ => None,
         }
     }
@@ -83,13 +231,91 @@ pub struct DecideCandidate {
     pub system: String,
     pub required_features: Vec<String>,
     pub hook_pid: u32,
+    /// Groups candidates from one `nixos-rebuild` / deploy run. `NBB_SESSION`
+    /// from the hook's environment if set, otherwise derived from the Nix
+    /// daemon worker serving the client (see [`crate::hook::session_id`]).
+    pub session: Option<String>,
+    /// The correlation id of an earlier decision for the same candidate,
+    /// when the hook asks again after an infrastructure failure; `None`
+    /// makes the controller mint one.
+    pub correlation: Option<String>,
+    /// `NBB_PRIORITY` from the hook's environment (see
+    /// [`crate::hook::priority`]). A priority registered for `session`
+    /// overrides it ([`SessionPriorityRequest`]).
+    pub priority: Priority,
+    /// What the hook read from the `.drv` on its own host, where the
+    /// controller may not have it; `None` if it could not.
+    pub drv: Option<DrvDetails>,
 }
 
-/// Response to [`DecideCandidate`].
+/// The parts of a candidate's `.drv` the controller routes on (see
+/// [`crate::drv::DrvInfo`]).
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct DrvDetails {
+    /// [`crate::drv::DrvInfo::builder_kind`].
+    pub builder_kind: String,
+    pub prefer_local_build: bool,
+    pub allow_substitutes: bool,
+    /// Every output's store path, or `None` if any is not yet known.
+    pub output_paths: Option<Vec<String>>,
+}
+
+/// How urgently a candidate wants a build slot. Each target keeps a share
+/// of its capacity for `High`; `Low` only runs where nothing is waiting
+/// for that share, and is otherwise postponed or built locally.
+#[derive(Encode, Decode, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "low" => Some(Self::Low),
+            "normal" => Some(Self::Normal),
+            "high" => Some(Self::High),
+            _ => None,
+        }
+    }
+}
+
+/// Response to [`DecideCandidate`]. Every variant carries the candidate's
+/// correlation id ([`crate::logging`]).
 #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
 pub enum Decision {
-    Decline,
-    Accept { target: AcceptTarget },
+    Decline {
+        correlation: String,
+    },
+    Accept {
+        target: AcceptTarget,
+        correlation: String,
+    },
+    /// A low-priority candidate that should wait: the hook answers
+    /// `# postpone`, and Nix asks again once some other build finishes.
+    Postpone {
+        correlation: String,
+    },
+}
+
+impl Decision {
+    pub fn correlation(&self) -> &str {
+        match self {
+            Decision::Decline { correlation }
+            | Decision::Accept { correlation, .. }
+            | Decision::Postpone { correlation } => correlation,
+        }
+    }
 }
 
 #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
@@ -104,14 +330,76 @@ pub struct AcceptTarget {
 #[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
 pub struct AdmissionFinish {
     pub drv_path: String,
+    /// From the [`Decision`] that admitted the build.
+    pub correlation: String,
     pub status: BuildStatus,
+    /// End of the delegated build's log (see `hook::failure::LogTail`),
+    /// when it ran far enough to produce one.
+    pub log_tail: Option<String>,
+}
+
+/// `SESSION_SUMMARY_GET` body. `session: None` asks for the session with
+/// the most recent decision.
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct SessionSummaryRequest {
+    pub session: Option<String>,
+    /// How many of the longest builds to list in [`SessionSummary::slowest`].
+    pub top_n: u32,
+}
+
+/// `SESSION_PRIORITY_SET` body, from `nbb-controller priority`.
+/// `session: None` means the session with the most recent decision. The
+/// `SESSION_PRIORITY` reply is `Option<String>`: the session now carrying
+/// `priority`, `None` if there was none to pick.
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct SessionPriorityRequest {
+    pub session: Option<String>,
+    pub priority: Priority,
+}
+
+/// `SESSION_SUMMARY` body is `Option<SessionSummary>`; `None` when the
+/// session is unknown (or older than the decision-log retention).
+///
+/// Each derivation counts once, under its last decision in the session.
+/// `actual_ms` comes from the first observation finished after that
+/// decision; builds without one are counted in `unfinished`.
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct SessionSummary {
+    pub session: String,
+    pub first_decision_ms: u64,
+    pub last_decision_ms: u64,
+    pub derivations: u32,
+    pub unfinished: u32,
+    pub predicted_ms: u64,
+    pub actual_ms: u64,
+    /// Per target name, or `local` for declined / fast-path candidates.
+    pub destinations: Vec<DestinationSummary>,
+    /// Longest builds by actual (else predicted) duration.
+    pub slowest: Vec<SessionBuild>,
+}
+
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct DestinationSummary {
+    pub destination: String,
+    pub derivations: u32,
+    pub predicted_ms: u64,
+    pub actual_ms: u64,
+}
+
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct SessionBuild {
+    pub drv_path: String,
+    pub pname: String,
+    pub destination: String,
+    pub predicted_ms: Option<u64>,
+    pub actual_ms: Option<u64>,
 }
 
 /// On-disk spool event written by `nbb-event` and consumed by `nbb-agent`.
 /// Same body schema for both start and finish; the agent matches starts
 /// in memory and forwards `Finish` events to the controller as
 /// [`EventBuildFinish`].
-#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
 pub enum SpoolEvent {
     Start {
         drv_path: String,
@@ -127,6 +415,20 @@ pub enum SpoolEvent {
         status: BuildStatus,
         out_paths: Vec<String>,
     },
+    /// A `Finish` the agent already matched with its `Start`, written back
+    /// over the original file while the controller is unreachable: one
+    /// record per build instead of two, and the duration survives an agent
+    /// restart.
+    Matched(EventBuildFinish),
+}
+
+impl SpoolEvent {
+    pub fn drv_path(&self) -> &str {
+        match self {
+            SpoolEvent::Start { drv_path, .. } | SpoolEvent::Finish { drv_path, .. } => drv_path,
+            SpoolEvent::Matched(event) => &event.drv_path,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -159,7 +461,11 @@ mod tests {
                 mem_available_kb: 12_345_678,
                 psi_memory_some_avg10: Some(0.42),
                 nix_slots_active: 7,
+                store_free_bytes: Some(42 << 30),
+                store_free_inodes: Some(1_000_000),
                 sampled_at_ms: 1_700_000_000_000,
+                spool_backlog: 0,
+                spool_full: false,
             },
             op::TELEMETRY,
         );
@@ -172,12 +478,58 @@ mod tests {
                 mem_available_kb: 0,
                 psi_memory_some_avg10: None,
                 nix_slots_active: 0,
+                store_free_bytes: None,
+                store_free_inodes: None,
                 sampled_at_ms: 0,
+                spool_backlog: 0,
+                spool_full: false,
             },
             op::TELEMETRY,
         );
     }
 
+    #[test]
+    fn paths_query_and_present_round_trip() {
+        round_trip(
+            PathsQuery {
+                id: 7,
+                paths: vec!["/nix/store/aaa-hello-2.12".to_string()],
+            },
+            op::PATHS_QUERY,
+        );
+        round_trip(
+            PathsPresent {
+                id: 7,
+                present: vec![],
+            },
+            op::PATHS_PRESENT,
+        );
+    }
+
+    #[test]
+    fn auth_challenge_and_response_round_trip() {
+        round_trip(
+            AuthChallenge {
+                nonce: vec![0xAB; 32],
+            },
+            op::AUTH_CHALLENGE,
+        );
+        round_trip(
+            AuthResponse {
+                host: "tsugumi".to_string(),
+                mac: vec![0xCD; 32],
+                nonce: vec![0xEF; 32],
+            },
+            op::AUTH_RESPONSE,
+        );
+        round_trip(
+            AuthConfirm {
+                mac: vec![0x12; 32],
+            },
+            op::AUTH_CONFIRM,
+        );
+    }
+
     #[test]
     fn event_build_finish_with_duration_round_trip() {
         round_trip(
@@ -187,8 +539,15 @@ mod tests {
                 host: "tsugumi".to_string(),
                 ts_ms: 1,
                 duration_ms: Some(5_000),
+                cpu_ms: None,
+                contention: Some(Contention {
+                    mean_builds: 2.5,
+                    mean_cpu_psi: Some(12.5),
+                }),
+                output_bytes: Some(123_456),
                 status: BuildStatus::Success,
                 out_paths: vec!["/nix/store/xyz-foo".to_string()],
+                correlation: Some("01JAXKQ4V6M0S7Y8E2N5D3W1HC".to_string()),
             },
             op::EVENT_BUILD_FINISH,
         );
@@ -203,16 +562,31 @@ mod tests {
                 host: "tsugumi".to_string(),
                 ts_ms: 1,
                 duration_ms: None,
+                cpu_ms: None,
+                contention: None,
+                output_bytes: None,
                 status: BuildStatus::Cancelled,
                 out_paths: vec![],
+                correlation: None,
             },
             op::EVENT_BUILD_FINISH,
         );
     }
 
     #[test]
-    fn decision_accept_and_decline_round_trip() {
-        round_trip(Decision::Decline, op::DECISION);
+    fn decision_variants_round_trip() {
+        round_trip(
+            Decision::Decline {
+                correlation: "01JAXKQ4V6M0S7Y8E2N5D3W1HC".to_string(),
+            },
+            op::DECISION,
+        );
+        round_trip(
+            Decision::Postpone {
+                correlation: "01JAXKQ4V6M0S7Y8E2N5D3W1HC".to_string(),
+            },
+            op::DECISION,
+        );
         round_trip(
             Decision::Accept {
                 target: AcceptTarget {
@@ -220,6 +594,7 @@ mod tests {
                     store_uri: "ssh-ng://svein@tsugumi.local".to_string(),
                     builder_line: "ssh-ng://svein@tsugumi.local x86_64-linux ... 16 1 nixos-test,kvm,big-parallel - -".to_string(),
                 },
+                correlation: "01JAXKQ4V6M0S7Y8E2N5D3W1HC".to_string(),
             },
             op::DECISION,
         );
@@ -249,17 +624,135 @@ mod tests {
         );
     }
 
+    #[test]
+    fn build_notices_round_trip() {
+        round_trip(
+            BuildAdmitted {
+                drv_path: "/nix/store/abc-foo.drv".to_string(),
+                correlation: "01JAXKQ4V6M0S7Y8E2N5D3W1HC".to_string(),
+            },
+            op::BUILD_ADMITTED,
+        );
+        round_trip(
+            BuildGone {
+                drv_path: "/nix/store/abc-foo.drv".to_string(),
+                correlation: "01JAXKQ4V6M0S7Y8E2N5D3W1HC".to_string(),
+                lingering: Some(0),
+            },
+            op::BUILD_GONE,
+        );
+    }
+
+    #[test]
+    fn link_probe_round_trip() {
+        round_trip(
+            LinkProbe {
+                id: 7,
+                payload: vec![0; 4096],
+            },
+            op::LINK_PROBE,
+        );
+        round_trip(
+            LinkProbeReply {
+                id: 7,
+                received_bytes: 4096,
+            },
+            op::LINK_PROBE_REPLY,
+        );
+    }
+
+    #[test]
+    fn decide_candidate_round_trip() {
+        round_trip(
+            DecideCandidate {
+                drv_path: "/nix/store/abc-foo.drv".to_string(),
+                system: "x86_64-linux".to_string(),
+                required_features: vec!["kvm".to_string()],
+                hook_pid: 4242,
+                session: Some("rebuild-1".to_string()),
+                correlation: None,
+                priority: Priority::High,
+                drv: Some(DrvDetails {
+                    builder_kind: "stdenv".to_string(),
+                    prefer_local_build: false,
+                    allow_substitutes: true,
+                    output_paths: Some(vec!["/nix/store/abc-foo".to_string()]),
+                }),
+            },
+            op::DECIDE_CANDIDATE,
+        );
+    }
+
+    #[test]
+    fn priority_names_round_trip() {
+        for priority in [Priority::Low, Priority::Normal, Priority::High] {
+            assert_eq!(Priority::parse(priority.as_str()), Some(priority));
+        }
+        assert_eq!(Priority::parse("urgent"), None);
+        assert_eq!(Priority::default(), Priority::Normal);
+    }
+
     #[test]
     fn admission_finish_round_trip() {
         round_trip(
             AdmissionFinish {
                 drv_path: "/nix/store/abc-foo.drv".to_string(),
-                status: BuildStatus::Success,
+                correlation: "01JAXKQ4V6M0S7Y8E2N5D3W1HC".to_string(),
+                status: BuildStatus::Failure,
+                log_tail: Some("error: builder for '/nix/store/abc-foo.drv' failed".to_string()),
             },
             op::ADMISSION_FINISH,
         );
     }
 
+    #[test]
+    fn session_priority_round_trip() {
+        round_trip(
+            SessionPriorityRequest {
+                session: Some("daemon-4242-99".to_string()),
+                priority: Priority::Low,
+            },
+            op::SESSION_PRIORITY_SET,
+        );
+        round_trip(Some("daemon-4242-99".to_string()), op::SESSION_PRIORITY);
+    }
+
+    #[test]
+    fn session_summary_round_trip() {
+        round_trip(
+            SessionSummaryRequest {
+                session: Some("daemon-4242-99".to_string()),
+                top_n: 5,
+            },
+            op::SESSION_SUMMARY_GET,
+        );
+        round_trip(
+            Some(SessionSummary {
+                session: "daemon-4242-99".to_string(),
+                first_decision_ms: 1,
+                last_decision_ms: 2,
+                derivations: 1,
+                unfinished: 0,
+                predicted_ms: 5_000,
+                actual_ms: 4_000,
+                destinations: vec![DestinationSummary {
+                    destination: "tsugumi".to_string(),
+                    derivations: 1,
+                    predicted_ms: 5_000,
+                    actual_ms: 4_000,
+                }],
+                slowest: vec![SessionBuild {
+                    drv_path: "/nix/store/abc-foo.drv".to_string(),
+                    pname: "foo".to_string(),
+                    destination: "tsugumi".to_string(),
+                    predicted_ms: Some(5_000),
+                    actual_ms: Some(4_000),
+                }],
+            }),
+            op::SESSION_SUMMARY,
+        );
+    }
+
     #[test]
     fn bincode_config_uses_varint_encoding() {
         // Sanity-check that the standard config produces compact output for
@@ -1,15 +1,34 @@
 //! Stateless build-candidate decision.
 //!
-//! Spec §"Scheduler": one function. Drop wrong-system targets, drop stale-
-//! PONG or memory-low targets, compute `completion_ms = queue_ms +
-//! package_ms × speed_multiplier`, pick the smallest, decline if the winner
-//! is the controller's own host.
+//! Spec §"Scheduler": one function. Drop wrong-system targets, decline
+//! cheap or local-only derivations outright, drop stale-PONG, memory-low or
+//! store-full targets, compute `completion_ms = queue_ms +
+//! package_ms × speed_multiplier + copy_ms`, pick the smallest, decline if
+//! the winner is the controller's own host. `copy_ms` is the time to bring
+//! the expected outputs back over the target's measured link
+//! ([`crate::link`]), where both are known.
+//!
+//! Each target keeps `high_priority_reserve` of its slots for
+//! [`Priority::High`] candidates. Normal candidates are not delegated to a
+//! target whose unreserved slots are all admitted; low ones are not sent
+//! anywhere in that state, and are postponed if that includes their own
+//! host.
+//!
+//! A candidate carrying a correlation id is a retry after an
+//! infrastructure failure ([`SchedulerInputs::is_retry`]). Nix already has
+//! the hook's `# accept`, so nothing can be built locally any more: the
+//! fast path is skipped and a win for the requesting host is an `Accept`
+//! onto it through its own builder line. Nor can it wait: a retry is
+//! never postponed and ignores the high-priority reserve.
 //!
 //! Admissions are the **only** load signal — `nix_slots_active` is reported
 //! by agents for divergence observability but does not enter this function.
 
+use std::collections::HashMap;
+
+use crate::link::LinkStats;
 use crate::persistence::admissions::AdmissionRow;
-use crate::protocol::ops::{AcceptTarget, DecideCandidate, TelemetryBody};
+use crate::protocol::ops::{AcceptTarget, DecideCandidate, Priority, TelemetryBody};
 use crate::util::pname_from_drv;
 
 /// Static description of one routable build site.
@@ -23,12 +42,30 @@ pub struct Target {
     pub store_uri: String,
     pub builder_line: String,
     pub capacity: u32,
+    /// CPU cores on the target. When set, admissions also queue as
+    /// core-time (`predicted_ms × cores used by their pname`) against
+    /// this, so wide builds don't pile onto one host.
+    pub cores: Option<u32>,
     pub speed_multiplier: f64,
-    /// `true` if this target is the controller's own agent (the host that
-    /// invokes `nixos-rebuild`). The scheduler never delegates to it; if the
-    /// minimum-completion winner is this target, the decision is `Decline`
-    /// so Nix builds locally.
+    /// `true` if this target is the controller's own agent. It stands in
+    /// for the requesting host for candidates from the controller's Unix
+    /// socket, which carry no host name (see
+    /// [`SchedulerInputs::requesting_host`]).
     pub is_controller_host: bool,
+    /// Wake-on-LAN details for a target that may be asleep; see
+    /// [`crate::wake`].
+    pub wake: Option<crate::wake::WakeOnLan>,
+}
+
+impl Target {
+    /// Whether this target is the host whose Nix asked about the candidate,
+    /// i.e. where a `Decline` builds it.
+    pub fn is_requesting_host(&self, requesting_host: Option<&str>) -> bool {
+        match requesting_host {
+            Some(host) => self.name == host,
+            None => self.is_controller_host,
+        }
+    }
 }
 
 /// Live state the controller maintains per target.
@@ -38,12 +75,74 @@ pub struct TargetState {
     /// Wall-clock time of the most recent `PONG` from this target.
     pub last_pong_ms: Option<u64>,
     pub last_telemetry: Option<TelemetryBody>,
+    /// End of an infrastructure-failure cooldown (see [`crate::health`]),
+    /// if one is running.
+    pub unhealthy_until_ms: Option<u64>,
+    /// End of a failure-rate quarantine (see [`crate::health`]), if one is
+    /// running.
+    pub quarantined_until_ms: Option<u64>,
+    /// When the controller sent this target a magic packet, while it has
+    /// yet to answer (see [`crate::wake`]).
+    pub waking_since_ms: Option<u64>,
+    /// Measured link from the controller host, if it has been probed.
+    pub link: Option<LinkStats>,
 }
 
 #[derive(Clone, Debug)]
 pub struct SchedulerPolicy {
     pub min_remote_mem_available_kb: u64,
+    /// Store space a target must keep free on top of the candidate's
+    /// expected output size.
+    pub min_store_free_bytes: u64,
+    /// Inodes a target's store filesystem must have free, where it has a
+    /// limit.
+    pub min_store_free_inodes: u64,
     pub unknown_p95_ms: u64,
+    /// Candidates whose duration estimate is below this are declined
+    /// without considering targets: shipping inputs and outputs over
+    /// ssh-ng costs more than the build. `0` disables the check.
+    pub cheap_threshold_ms: u64,
+    /// Share of each target's capacity, rounded down to whole slots, kept
+    /// free for high-priority candidates. In `[0, 1)`.
+    pub high_priority_reserve: f64,
+}
+
+/// Locality attributes from the candidate's `.drv`. The default (no hints)
+/// is what the controller uses when neither it nor the hook could read
+/// the `.drv`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LocalityHints {
+    /// `preferLocalBuild = true`.
+    pub prefer_local_build: bool,
+    /// `allowSubstitutes = false`.
+    pub no_substitutes: bool,
+}
+
+impl LocalityHints {
+    pub fn from_drv(details: &crate::protocol::ops::DrvDetails) -> Self {
+        Self {
+            prefer_local_build: details.prefer_local_build,
+            no_substitutes: !details.allow_substitutes,
+        }
+    }
+}
+
+/// Why the scheduler short-circuited to a local build.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FastPathReason {
+    PreferLocalBuild,
+    NoSubstitutes,
+    BelowThreshold,
+}
+
+impl FastPathReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FastPathReason::PreferLocalBuild => "prefer-local-build",
+            FastPathReason::NoSubstitutes => "no-substitutes",
+            FastPathReason::BelowThreshold => "below-threshold",
+        }
+    }
 }
 
 pub struct SchedulerInputs<'a> {
@@ -60,6 +159,31 @@ pub struct SchedulerInputs<'a> {
     /// has no observations for this pname (the fallback is
     /// `policy.unknown_p95_ms`). See [`crate::estimator`] for the model.
     pub duration_estimate_ms: Option<u64>,
+    /// Expected size of the candidate's outputs
+    /// ([`crate::persistence::observations::predict_output_bytes`]);
+    /// `None` when unknown, which needs only `min_store_free_bytes`.
+    pub output_bytes_estimate: Option<u64>,
+    pub hints: LocalityHints,
+    /// Targets this pname last failed on after succeeding elsewhere
+    /// ([`crate::persistence::target_outcomes::failed_targets`]).
+    pub failed_on: &'a [String],
+    /// Cores used while building, per pname, for the admitted pnames that
+    /// have one ([`crate::persistence::parallelism`] or configured).
+    /// Missing pnames count as one core.
+    pub pname_cores: &'a HashMap<String, f64>,
+    /// Host name a TCP hook authenticated as; `None` for the controller's
+    /// own hook. The target of that name is never delegated to: if it is
+    /// the minimum-completion winner the decision is `RouteLocal`.
+    pub requesting_host: Option<&'a str>,
+}
+
+impl SchedulerInputs<'_> {
+    /// Whether the hook is asking again for a candidate Nix has already
+    /// handed it, so a `Decline` fails the build instead of building it
+    /// locally.
+    pub fn is_retry(&self) -> bool {
+        self.candidate.correlation.is_some()
+    }
 }
 
 /// What the scheduler decided.
@@ -67,15 +191,26 @@ pub struct SchedulerInputs<'a> {
 /// - `Decline` — no eligible target (wrong system, all stale/low-mem, empty
 ///   list). The controller returns `Decision::Decline` to the hook and
 ///   records no admission.
-/// - `RouteLocal` — the minimum-completion winner is the controller's own
-///   host. Nix builds it locally. The controller still admits a row for
-///   that target so the local in-flight queue is reflected in `queue_ms`;
-///   the matching `EVENT_BUILD_FINISH` (from the local agent) retires it
-///   on the same path as remote builds.
-/// - `Accept` — delegate to a remote target.
+/// - `RouteLocal` — the minimum-completion winner is the requesting host,
+///   on a first ask. Nix builds it locally. The controller still admits a
+///   row for that target so its in-flight queue is reflected in
+///   `queue_ms`; the matching `EVENT_BUILD_FINISH` (from that host's
+///   agent) retires it on the same path as remote builds.
+/// - `LocalFastPath` — the derivation is too cheap, or marked local-only,
+///   to be worth routing. Like `Decline` the hook builds locally and no
+///   admission is recorded; unlike `Decline` targets were never consulted.
+/// - `Accept` — delegate to a remote target, or, on a retry, to whichever
+///   target won, the requesting host included.
+/// - `Postpone` — a low-priority candidate found every target busy,
+///   its own host included. The hook answers `# postpone` and Nix asks
+///   again later; no admission is recorded.
 #[derive(Clone, Debug, PartialEq)]
 pub enum SchedulerDecision {
     Decline,
+    Postpone,
+    LocalFastPath {
+        reason: FastPathReason,
+    },
     RouteLocal {
         target_name: String,
         predicted_ms: u64,
@@ -86,40 +221,135 @@ pub enum SchedulerDecision {
     },
 }
 
+/// Why a target was not scored for a candidate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exclusion {
+    /// The candidate's `system` is not the one this controller routes for.
+    WrongSystem,
+    /// No `PONG` or telemetry within three poll intervals.
+    Stale,
+    /// Stale, but sent a Wake-on-LAN packet recently.
+    Waking,
+    /// `mem_available_kb` below `min_remote_mem_available_kb`.
+    LowMemory,
+    /// Its store cannot hold the candidate's outputs with
+    /// `min_store_free_bytes` to spare, or is short of inodes.
+    LowStore,
+    /// A recent delegation to it failed for infrastructure reasons.
+    Unhealthy,
+    /// Too many of its recent builds failed or were cancelled.
+    Quarantined,
+    /// This pname's last build there failed, and it has since built
+    /// elsewhere.
+    FailedHere,
+    /// A normal-priority candidate, and only the slots reserved for high
+    /// priority are left.
+    Reserved,
+    /// A low-priority candidate, and no unreserved slot is left.
+    Busy,
+}
+
+impl Exclusion {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Exclusion::WrongSystem => "wrong-system",
+            Exclusion::Stale => "stale",
+            Exclusion::Waking => "waking",
+            Exclusion::LowMemory => "low-memory",
+            Exclusion::LowStore => "low-store",
+            Exclusion::Unhealthy => "unhealthy",
+            Exclusion::Quarantined => "quarantined",
+            Exclusion::FailedHere => "failed-here",
+            Exclusion::Reserved => "reserved",
+            Exclusion::Busy => "busy",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TargetVerdict {
+    Excluded(Exclusion),
+    Scored {
+        queue_ms: u64,
+        package_ms: u64,
+        completion_ms: u64,
+    },
+}
+
+/// How one target fared in [`evaluate`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct TargetEvaluation {
+    pub name: String,
+    pub verdict: TargetVerdict,
+}
+
+/// A decision plus the per-target working that led to it, in target
+/// order. `targets` is empty for the local fast path, which never looks
+/// at targets.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Evaluation {
+    pub decision: SchedulerDecision,
+    pub targets: Vec<TargetEvaluation>,
+}
+
 pub fn decide(inputs: &SchedulerInputs) -> SchedulerDecision {
+    evaluate(inputs).decision
+}
+
+/// [`decide`], keeping the per-target trace for the decision log.
+pub fn evaluate(inputs: &SchedulerInputs) -> Evaluation {
     let _pname = pname_from_drv(&inputs.candidate.drv_path);
 
     if inputs.candidate.system != inputs.system {
-        return SchedulerDecision::Decline;
+        return Evaluation {
+            decision: SchedulerDecision::Decline,
+            targets: inputs
+                .targets
+                .iter()
+                .map(|state| TargetEvaluation {
+                    name: state.target.name.clone(),
+                    verdict: TargetVerdict::Excluded(Exclusion::WrongSystem),
+                })
+                .collect(),
+        };
     }
 
-    let stale_after_ms = inputs.poll_interval_ms.saturating_mul(3);
-    let live: Vec<&TargetState> = inputs
-        .targets
-        .iter()
-        .filter(|state| is_live(state, inputs.now_ms, stale_after_ms, inputs.policy))
-        .collect();
+    if let Some(reason) = fast_path_reason(inputs).filter(|_| !inputs.is_retry()) {
+        return Evaluation {
+            decision: SchedulerDecision::LocalFastPath { reason },
+            targets: Vec::new(),
+        };
+    }
 
+    let stale_after_ms = inputs.poll_interval_ms.saturating_mul(3);
     let package_ms_base = inputs
         .duration_estimate_ms
         .unwrap_or(inputs.policy.unknown_p95_ms);
 
+    let mut trace = Vec::with_capacity(inputs.targets.len());
     let mut best: Option<(&TargetState, u64, u64)> = None;
-    for state in live {
+    for state in inputs.targets {
         let target = &state.target;
+        if let Some(exclusion) = exclusion(state, inputs, stale_after_ms) {
+            trace.push(TargetEvaluation {
+                name: target.name.clone(),
+                verdict: TargetVerdict::Excluded(exclusion),
+            });
+            continue;
+        }
         let package_ms = scaled_package_ms(package_ms_base, target.speed_multiplier);
-        let queue_load_ms: u64 = inputs
-            .admissions
-            .iter()
-            .filter(|a| a.target_name == target.name)
-            .map(|a| a.predicted_ms)
-            .sum();
-        let queue_ms = if target.capacity == 0 {
-            u64::MAX
-        } else {
-            queue_load_ms / target.capacity as u64
-        };
-        let completion_ms = queue_ms.saturating_add(package_ms);
+        let queue_ms = queue_ms(target, inputs);
+        let completion_ms = queue_ms
+            .saturating_add(package_ms)
+            .saturating_add(copy_ms(state, inputs));
+        trace.push(TargetEvaluation {
+            name: target.name.clone(),
+            verdict: TargetVerdict::Scored {
+                queue_ms,
+                package_ms,
+                completion_ms,
+            },
+        });
         let replace = match best {
             None => true,
             Some((_, best_completion, _)) => completion_ms < best_completion,
@@ -129,43 +359,182 @@ pub fn decide(inputs: &SchedulerInputs) -> SchedulerDecision {
         }
     }
 
-    let Some((winner, _completion, package_ms)) = best else {
-        return SchedulerDecision::Decline;
+    let decision = match best {
+        None if inputs.candidate.priority == Priority::Low
+            && !inputs.is_retry()
+            && inputs.targets.iter().zip(&trace).any(|(state, t)| {
+                state.target.is_requesting_host(inputs.requesting_host)
+                    && t.verdict == TargetVerdict::Excluded(Exclusion::Busy)
+            }) =>
+        {
+            SchedulerDecision::Postpone
+        }
+        None => SchedulerDecision::Decline,
+        Some((winner, _completion, package_ms))
+            if !inputs.is_retry() && winner.target.is_requesting_host(inputs.requesting_host) =>
+        {
+            SchedulerDecision::RouteLocal {
+                target_name: winner.target.name.clone(),
+                predicted_ms: package_ms.max(1),
+            }
+        }
+        Some((winner, _completion, package_ms)) => SchedulerDecision::Accept {
+            target: AcceptTarget {
+                name: winner.target.name.clone(),
+                store_uri: winner.target.store_uri.clone(),
+                builder_line: winner.target.builder_line.clone(),
+            },
+            predicted_ms: package_ms.max(1),
+        },
+    };
+    Evaluation {
+        decision,
+        targets: trace,
+    }
+}
+
+/// Time until `target` has room: its admitted work spread over its build
+/// slots and, when its core count is known, the admitted core-time spread
+/// over its cores — whichever is longer.
+fn queue_ms(target: &Target, inputs: &SchedulerInputs) -> u64 {
+    if target.capacity == 0 {
+        return u64::MAX;
+    }
+    let admitted = inputs
+        .admissions
+        .iter()
+        .filter(|a| a.target_name == target.name);
+    let slot_ms = admitted.clone().map(|a| a.predicted_ms).sum::<u64>() / target.capacity as u64;
+    let Some(cores) = target.cores.filter(|&c| c > 0) else {
+        return slot_ms;
     };
+    let core_ms: f64 = admitted
+        .map(|a| {
+            let used = inputs
+                .pname_cores
+                .get(&pname_from_drv(&a.drv_path))
+                .copied()
+                .unwrap_or(1.0)
+                .max(1.0);
+            a.predicted_ms as f64 * used
+        })
+        .sum();
+    slot_ms.max((core_ms / f64::from(cores)) as u64)
+}
 
-    if winner.target.is_controller_host {
-        return SchedulerDecision::RouteLocal {
-            target_name: winner.target.name.clone(),
-            predicted_ms: package_ms.max(1),
-        };
+/// Time to copy the candidate's expected outputs back from `state`'s
+/// target. Links are measured from the controller host, so only its own
+/// candidates are charged, and nothing is charged for building where
+/// the outputs are wanted.
+fn copy_ms(state: &TargetState, inputs: &SchedulerInputs) -> u64 {
+    if inputs.requesting_host.is_some() || state.target.is_controller_host {
+        return 0;
+    }
+    match (state.link, inputs.output_bytes_estimate) {
+        (Some(link), Some(bytes)) => link.copy_ms(bytes),
+        _ => 0,
     }
+}
 
-    SchedulerDecision::Accept {
-        target: AcceptTarget {
-            name: winner.target.name.clone(),
-            store_uri: winner.target.store_uri.clone(),
-            builder_line: winner.target.builder_line.clone(),
-        },
-        predicted_ms: package_ms.max(1),
+fn fast_path_reason(inputs: &SchedulerInputs) -> Option<FastPathReason> {
+    if inputs.hints.prefer_local_build {
+        return Some(FastPathReason::PreferLocalBuild);
+    }
+    if inputs.hints.no_substitutes {
+        return Some(FastPathReason::NoSubstitutes);
+    }
+    // Only a real estimate counts: `unknown_p95_ms` is a pessimistic
+    // placeholder and says nothing about the build being small.
+    match inputs.duration_estimate_ms {
+        Some(ms) if ms < inputs.policy.cheap_threshold_ms => Some(FastPathReason::BelowThreshold),
+        _ => None,
     }
 }
 
-fn is_live(
+fn exclusion(
     state: &TargetState,
-    now_ms: u64,
+    inputs: &SchedulerInputs,
     stale_after_ms: u64,
-    policy: &SchedulerPolicy,
-) -> bool {
+) -> Option<Exclusion> {
+    let now_ms = inputs.now_ms;
+    let policy = inputs.policy;
+    if state.unhealthy_until_ms.is_some_and(|until| until > now_ms) {
+        return Some(Exclusion::Unhealthy);
+    }
+    if state
+        .quarantined_until_ms
+        .is_some_and(|until| until > now_ms)
+    {
+        return Some(Exclusion::Quarantined);
+    }
+    if inputs.failed_on.contains(&state.target.name) {
+        return Some(Exclusion::FailedHere);
+    }
+    let stale = if state.waking_since_ms.is_some() {
+        Exclusion::Waking
+    } else {
+        Exclusion::Stale
+    };
     let Some(last_pong_ms) = state.last_pong_ms else {
-        return false;
+        return Some(stale);
     };
     if now_ms.saturating_sub(last_pong_ms) > stale_after_ms {
-        return false;
+        return Some(stale);
     }
     let Some(telemetry) = state.last_telemetry.as_ref() else {
-        return false;
+        return Some(stale);
     };
-    telemetry.mem_available_kb >= policy.min_remote_mem_available_kb
+    if telemetry.mem_available_kb < policy.min_remote_mem_available_kb {
+        return Some(Exclusion::LowMemory);
+    }
+    let needed_bytes = policy
+        .min_store_free_bytes
+        .saturating_add(inputs.output_bytes_estimate.unwrap_or(0));
+    if telemetry
+        .store_free_bytes
+        .is_some_and(|free| free < needed_bytes)
+        || telemetry
+            .store_free_inodes
+            .is_some_and(|free| free < policy.min_store_free_inodes)
+    {
+        return Some(Exclusion::LowStore);
+    }
+    slot_exclusion(&state.target, inputs)
+}
+
+/// [`Exclusion::Reserved`] or [`Exclusion::Busy`] once `target`'s
+/// admissions fill the slots not reserved for high priority. A normal
+/// candidate may still use its own host: declining builds it there anyway.
+/// A retry may use any: declining fails it.
+fn slot_exclusion(target: &Target, inputs: &SchedulerInputs) -> Option<Exclusion> {
+    if inputs.is_retry() {
+        return None;
+    }
+    let reserved = reserved_slots(target.capacity, inputs.policy.high_priority_reserve);
+    let unreserved = target.capacity - reserved;
+    let admitted = inputs
+        .admissions
+        .iter()
+        .filter(|a| a.target_name == target.name)
+        .count();
+    if admitted < unreserved as usize {
+        return None;
+    }
+    match inputs.candidate.priority {
+        Priority::High => None,
+        Priority::Normal if reserved > 0 && !target.is_requesting_host(inputs.requesting_host) => {
+            Some(Exclusion::Reserved)
+        }
+        Priority::Normal => None,
+        Priority::Low => Some(Exclusion::Busy),
+    }
+}
+
+/// Slots of `capacity` kept for high priority: `share` of it, rounded
+/// down, and never all of it.
+fn reserved_slots(capacity: u32, share: f64) -> u32 {
+    let reserved = (f64::from(capacity) * share.clamp(0.0, 1.0)).floor() as u32;
+    reserved.min(capacity.saturating_sub(1))
 }
 
 fn scaled_package_ms(base_ms: u64, speed_multiplier: f64) -> u64 {
@@ -186,7 +555,11 @@ mod tests {
     fn policy() -> SchedulerPolicy {
         SchedulerPolicy {
             min_remote_mem_available_kb: 1_000_000,
+            min_store_free_bytes: 10 << 30,
+            min_store_free_inodes: 10_000,
             unknown_p95_ms: 60_000,
+            cheap_threshold_ms: 0,
+            high_priority_reserve: 0.0,
         }
     }
 
@@ -195,7 +568,11 @@ mod tests {
             mem_available_kb: 4_000_000,
             psi_memory_some_avg10: Some(0.0),
             nix_slots_active: slots,
+            store_free_bytes: Some(100 << 30),
+            store_free_inodes: None,
             sampled_at_ms: 1_000,
+            spool_backlog: 0,
+            spool_full: false,
         }
     }
 
@@ -206,8 +583,10 @@ mod tests {
             store_uri: format!("ssh-ng://svein@{name}.local"),
             builder_line: format!("ssh-ng://svein@{name}.local x86_64-linux . 1 1 - - -"),
             capacity,
+            cores: None,
             speed_multiplier: 1.0,
             is_controller_host,
+            wake: None,
         }
     }
 
@@ -216,6 +595,10 @@ mod tests {
             target: target(name, capacity, is_local),
             last_pong_ms: Some(1_000),
             last_telemetry: Some(ok_telemetry(0)),
+            unhealthy_until_ms: None,
+            quarantined_until_ms: None,
+            waking_since_ms: None,
+            link: None,
         }
     }
 
@@ -225,6 +608,10 @@ mod tests {
             system: SYSTEM.to_string(),
             required_features: vec![],
             hook_pid: 12345,
+            session: None,
+            correlation: None,
+            priority: Priority::Normal,
+            drv: None,
         }
     }
 
@@ -244,9 +631,169 @@ mod tests {
             admissions,
             targets,
             duration_estimate_ms: p95,
+            output_bytes_estimate: None,
+            hints: LocalityHints::default(),
+            failed_on: &[],
+            pname_cores: &HashMap::new(),
+            requesting_host: None,
         })
     }
 
+    #[test]
+    fn wide_admissions_queue_against_cores() {
+        // Both targets have four slots and one admission of 100 s; on
+        // kaho it is a 16-core build, which fills its 16 cores for the
+        // whole 100 s instead of a quarter of its slots.
+        let mut kaho = fresh_state("kaho", 4, false);
+        kaho.target.cores = Some(16);
+        let mut tsugumi = fresh_state("tsugumi", 4, false);
+        tsugumi.target.cores = Some(16);
+        let admission = |drv: &str, target: &str| AdmissionRow {
+            drv_path: drv.to_string(),
+            target_name: target.to_string(),
+            admitted_at_ms: 0,
+            predicted_ms: 100_000,
+            correlation: None,
+        };
+        let admissions = [
+            admission("/nix/store/aaa-firefox-128.0.drv", "kaho"),
+            admission("/nix/store/bbb-hello-2.12.drv", "tsugumi"),
+            admission("/nix/store/ccc-sed-4.9.drv", "tsugumi"),
+        ];
+        let pname_cores = HashMap::from([("firefox".to_string(), 16.0)]);
+        let cand = candidate("/nix/store/ddd-chromium-126.0.drv");
+        let pol = policy();
+        let targets = [kaho, tsugumi];
+        let evaluation = evaluate(&SchedulerInputs {
+            system: SYSTEM,
+            candidate: &cand,
+            now_ms: 1_000,
+            poll_interval_ms: 1_000,
+            policy: &pol,
+            admissions: &admissions,
+            targets: &targets,
+            duration_estimate_ms: Some(60_000),
+            output_bytes_estimate: None,
+            hints: LocalityHints::default(),
+            failed_on: &[],
+            pname_cores: &pname_cores,
+            requesting_host: None,
+        });
+
+        let queues: Vec<u64> = evaluation
+            .targets
+            .iter()
+            .map(|t| match t.verdict {
+                TargetVerdict::Scored { queue_ms, .. } => queue_ms,
+                TargetVerdict::Excluded(e) => panic!("{} excluded: {e:?}", t.name),
+            })
+            .collect();
+        assert_eq!(queues, vec![100_000, 50_000]);
+        match evaluation.decision {
+            SchedulerDecision::Accept { target, .. } => assert_eq!(target.name, "tsugumi"),
+            other => panic!("expected Accept, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn reserved_slots_are_kept_for_high_priority() {
+        // Half of each four-slot target is reserved: two slots are open to
+        // everyone, two to high priority only.
+        let pol = SchedulerPolicy {
+            high_priority_reserve: 0.5,
+            ..policy()
+        };
+        let targets = [
+            fresh_state("tsugumi", 4, false),
+            fresh_state("saya", 4, true),
+        ];
+        let admission = |n: u32, target: &str| AdmissionRow {
+            drv_path: format!("/nix/store/{n:03}-busy-1.0.drv"),
+            target_name: target.to_string(),
+            admitted_at_ms: 0,
+            predicted_ms: 1_000,
+            correlation: None,
+        };
+        let eval = |priority, admissions: &[AdmissionRow], requesting_host| {
+            let cand = DecideCandidate {
+                priority,
+                ..candidate("/nix/store/abc-fix-1.0.drv")
+            };
+            evaluate(&SchedulerInputs {
+                system: SYSTEM,
+                candidate: &cand,
+                now_ms: 1_000,
+                poll_interval_ms: 1_000,
+                policy: &pol,
+                admissions,
+                targets: &targets,
+                duration_estimate_ms: Some(600_000),
+                output_bytes_estimate: None,
+                hints: LocalityHints::default(),
+                failed_on: &[],
+                pname_cores: &HashMap::new(),
+                requesting_host,
+            })
+        };
+        let verdicts = |e: &Evaluation| -> Vec<Option<Exclusion>> {
+            e.targets
+                .iter()
+                .map(|t| match t.verdict {
+                    TargetVerdict::Excluded(x) => Some(x),
+                    TargetVerdict::Scored { .. } => None,
+                })
+                .collect()
+        };
+
+        // tsugumi's open slots are taken, saya has one left.
+        let some = [
+            admission(1, "tsugumi"),
+            admission(2, "tsugumi"),
+            admission(3, "saya"),
+        ];
+        let high = eval(Priority::High, &some, None);
+        assert_eq!(verdicts(&high), vec![None, None]);
+        let normal = eval(Priority::Normal, &some, None);
+        assert_eq!(verdicts(&normal), vec![Some(Exclusion::Reserved), None]);
+        assert!(matches!(
+            normal.decision,
+            SchedulerDecision::RouteLocal { .. }
+        ));
+        let low = eval(Priority::Low, &some, None);
+        assert_eq!(verdicts(&low), vec![Some(Exclusion::Busy), None]);
+        assert!(matches!(low.decision, SchedulerDecision::RouteLocal { .. }));
+
+        // saya's are taken too: low waits, normal still builds at home.
+        let all = [
+            admission(4, "saya"),
+            some[0].clone(),
+            some[1].clone(),
+            some[2].clone(),
+        ];
+        let low = eval(Priority::Low, &all, None);
+        assert_eq!(
+            verdicts(&low),
+            vec![Some(Exclusion::Busy), Some(Exclusion::Busy)]
+        );
+        assert_eq!(low.decision, SchedulerDecision::Postpone);
+        let normal = eval(Priority::Normal, &all, None);
+        assert_eq!(verdicts(&normal), vec![Some(Exclusion::Reserved), None]);
+
+        // A remote host the controller does not route to builds low
+        // priority itself rather than waiting.
+        let low = eval(Priority::Low, &all, Some("kaho"));
+        assert_eq!(low.decision, SchedulerDecision::Decline);
+    }
+
+    #[test]
+    fn reserve_never_covers_a_whole_target() {
+        assert_eq!(reserved_slots(4, 0.25), 1);
+        assert_eq!(reserved_slots(3, 0.25), 0);
+        assert_eq!(reserved_slots(1, 0.99), 0);
+        assert_eq!(reserved_slots(8, 0.0), 0);
+        assert_eq!(reserved_slots(0, 0.5), 0);
+    }
+
     #[test]
     fn wrong_system_declines() {
         let cand = DecideCandidate {
@@ -254,6 +801,10 @@ mod tests {
             system: "aarch64-darwin".to_string(),
             required_features: vec![],
             hook_pid: 0,
+            session: None,
+            correlation: None,
+            priority: Priority::Normal,
+            drv: None,
         };
         let pol = policy();
         let ts = [fresh_state("tsugumi", 16, false)];
@@ -266,6 +817,11 @@ mod tests {
             admissions: &[],
             targets: &ts,
             duration_estimate_ms: None,
+            output_bytes_estimate: None,
+            hints: LocalityHints::default(),
+            failed_on: &[],
+            pname_cores: &HashMap::new(),
+            requesting_host: None,
         });
         assert_eq!(decision, SchedulerDecision::Decline);
     }
@@ -322,6 +878,120 @@ mod tests {
         }
     }
 
+    #[test]
+    fn store_must_hold_expected_outputs_plus_headroom() {
+        // tsugumi has 15 GiB free: enough for the 10 GiB headroom alone,
+        // not for a 6 GiB output on top. kaho is out of inodes.
+        let mut a = fresh_state("tsugumi", 8, false);
+        a.last_telemetry = Some(TelemetryBody {
+            store_free_bytes: Some(15 << 30),
+            ..ok_telemetry(0)
+        });
+        let mut b = fresh_state("kaho", 8, false);
+        b.last_telemetry = Some(TelemetryBody {
+            store_free_inodes: Some(500),
+            ..ok_telemetry(0)
+        });
+        let c = fresh_state("saya", 8, false);
+        let cand = candidate("/nix/store/abc-foo-1.2.3.drv");
+        let pol = policy();
+        let targets = [a, b, c];
+        let evaluate_with = |output_bytes_estimate| {
+            evaluate(&SchedulerInputs {
+                system: SYSTEM,
+                candidate: &cand,
+                now_ms: 1_000,
+                poll_interval_ms: 1_000,
+                policy: &pol,
+                admissions: &[],
+                targets: &targets,
+                duration_estimate_ms: Some(10_000),
+                output_bytes_estimate,
+                hints: LocalityHints::default(),
+                failed_on: &[],
+                pname_cores: &HashMap::new(),
+                requesting_host: None,
+            })
+        };
+
+        let unknown = evaluate_with(None);
+        assert!(matches!(
+            unknown.targets[0].verdict,
+            TargetVerdict::Scored { .. }
+        ));
+        assert_eq!(
+            unknown.targets[1].verdict,
+            TargetVerdict::Excluded(Exclusion::LowStore)
+        );
+
+        let large = evaluate_with(Some(6 << 30));
+        assert_eq!(
+            large.targets[0].verdict,
+            TargetVerdict::Excluded(Exclusion::LowStore)
+        );
+        match large.decision {
+            SchedulerDecision::Accept { target, .. } => assert_eq!(target.name, "saya"),
+            other => panic!("expected accept saya, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn slow_link_costs_the_copy_of_expected_outputs() {
+        // kaho is idle but behind a 10 MB/s link; saya has 20 s queued on
+        // the LAN. Copying 500 MB back from kaho takes longer than that.
+        // tsugumi, the controller host, would pay nothing but is busiest.
+        let admission = |drv: &str, target: &str, predicted_ms| AdmissionRow {
+            drv_path: drv.to_string(),
+            target_name: target.to_string(),
+            admitted_at_ms: 0,
+            predicted_ms,
+            correlation: None,
+        };
+        let admissions = [
+            admission("/nix/store/q-bar.drv", "saya", 20_000),
+            admission("/nix/store/q-baz.drv", "tsugumi", 1_000_000),
+        ];
+        let mut saya = fresh_state("saya", 1, false);
+        saya.link = Some(LinkStats {
+            rtt_us: 300,
+            bytes_per_sec: 100_000_000,
+        });
+        let mut kaho = fresh_state("kaho", 1, false);
+        kaho.link = Some(LinkStats {
+            rtt_us: 30_000,
+            bytes_per_sec: 10_000_000,
+        });
+        let cand = candidate("/nix/store/abc-foo-1.2.3.drv");
+        let pol = policy();
+        let targets = [fresh_state("tsugumi", 1, true), saya, kaho];
+        let winner = |output_bytes_estimate, requesting_host| {
+            let evaluation = evaluate(&SchedulerInputs {
+                system: SYSTEM,
+                candidate: &cand,
+                now_ms: 1_000,
+                poll_interval_ms: 1_000,
+                policy: &pol,
+                admissions: &admissions,
+                targets: &targets,
+                duration_estimate_ms: Some(100_000),
+                output_bytes_estimate,
+                hints: LocalityHints::default(),
+                failed_on: &[],
+                pname_cores: &HashMap::new(),
+                requesting_host,
+            });
+            match evaluation.decision {
+                SchedulerDecision::Accept { target, .. } => target.name,
+                SchedulerDecision::RouteLocal { target_name, .. } => target_name,
+                other => panic!("expected a winner, got {other:?}"),
+            }
+        };
+        assert_eq!(winner(Some(500_000_000), None), "saya");
+        assert_eq!(winner(None, None), "kaho");
+        // From another host's hook the controller's links say nothing.
+        assert_eq!(winner(Some(500_000_000), Some("kaho")), "kaho");
+    }
+
     #[test]
     fn all_targets_memory_low_declines() {
         let mut a = fresh_state("tsugumi", 8, false);
@@ -373,6 +1043,11 @@ mod tests {
             admissions: &[],
             targets: &[a, b],
             duration_estimate_ms: Some(10_000),
+            output_bytes_estimate: None,
+            hints: LocalityHints::default(),
+            failed_on: &[],
+            pname_cores: &HashMap::new(),
+            requesting_host: None,
         });
         match decision {
             SchedulerDecision::Accept { target, .. } => assert_eq!(target.name, "kaho"),
@@ -415,12 +1090,14 @@ mod tests {
                 target_name: "tsugumi".into(),
                 admitted_at_ms: 0,
                 predicted_ms: 30_000,
+                correlation: None,
             },
             AdmissionRow {
                 drv_path: "y".into(),
                 target_name: "tsugumi".into(),
                 admitted_at_ms: 0,
                 predicted_ms: 30_000,
+                correlation: None,
             },
         ];
         let decision = run(&ts, &admissions, Some(5_000));
@@ -444,6 +1121,7 @@ mod tests {
                 target_name: "kaho".to_string(),
                 admitted_at_ms: 0,
                 predicted_ms: 60_000,
+                correlation: None,
             });
         }
         let decision = run(&ts, &admissions, Some(5_000));
@@ -488,6 +1166,7 @@ mod tests {
             target_name: "tsugumi".into(),
             admitted_at_ms: 0,
             predicted_ms: 30_000,
+            correlation: None,
         }];
         let decision = run(&[local, remote], &admissions, Some(5_000));
         match decision {
@@ -498,6 +1177,161 @@ mod tests {
         }
     }
 
+    #[test]
+    fn retry_is_accepted_onto_the_requesting_host() {
+        // Nix already has the hook's `# accept`: the controller host's win
+        // must be a delegation to it, and the fast path no longer applies.
+        let saya = fresh_state("saya", 16, true);
+        let mut tsugumi = fresh_state("tsugumi", 8, false);
+        tsugumi.unhealthy_until_ms = Some(60_000);
+        let cand = DecideCandidate {
+            correlation: Some("01JAXKQ4V6M0S7Y8E2N5D3W1HC".into()),
+            ..candidate("/nix/store/abc-foo-1.2.3.drv")
+        };
+        let pol = SchedulerPolicy {
+            cheap_threshold_ms: 10_000,
+            ..policy()
+        };
+        let targets = [saya, tsugumi];
+        let decision = decide(&SchedulerInputs {
+            system: SYSTEM,
+            candidate: &cand,
+            now_ms: 1_000,
+            poll_interval_ms: 1_000,
+            policy: &pol,
+            admissions: &[],
+            targets: &targets,
+            duration_estimate_ms: Some(5_000),
+            output_bytes_estimate: None,
+            hints: LocalityHints {
+                prefer_local_build: true,
+                no_substitutes: false,
+            },
+            failed_on: &[],
+            pname_cores: &HashMap::new(),
+            requesting_host: None,
+        });
+        match decision {
+            SchedulerDecision::Accept { target, .. } => {
+                assert_eq!(target.name, "saya");
+                assert_eq!(target.builder_line, targets[0].target.builder_line);
+            }
+            other => panic!("expected accept onto saya, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn retry_is_never_postponed_or_held_to_the_reserve() {
+        // Every unreserved slot is admitted. A first ask at low priority
+        // would wait and a normal one would stay off tsugumi; a retry has
+        // nowhere to wait and no local build to fall back on.
+        let pol = SchedulerPolicy {
+            high_priority_reserve: 0.5,
+            ..policy()
+        };
+        let targets = [
+            fresh_state("tsugumi", 4, false),
+            fresh_state("saya", 4, true),
+        ];
+        let admissions: Vec<AdmissionRow> = ["tsugumi", "tsugumi", "saya", "saya"]
+            .iter()
+            .enumerate()
+            .map(|(n, target)| AdmissionRow {
+                drv_path: format!("/nix/store/{n:03}-busy-1.0.drv"),
+                target_name: target.to_string(),
+                admitted_at_ms: 0,
+                predicted_ms: 1_000,
+                correlation: None,
+            })
+            .collect();
+        for priority in [Priority::Low, Priority::Normal] {
+            let cand = DecideCandidate {
+                priority,
+                correlation: Some("01JAXKQ4V6M0S7Y8E2N5D3W1HC".into()),
+                ..candidate("/nix/store/abc-fix-1.0.drv")
+            };
+            let evaluation = evaluate(&SchedulerInputs {
+                system: SYSTEM,
+                candidate: &cand,
+                now_ms: 1_000,
+                poll_interval_ms: 1_000,
+                policy: &pol,
+                admissions: &admissions,
+                targets: &targets,
+                duration_estimate_ms: Some(600_000),
+                output_bytes_estimate: None,
+                hints: LocalityHints::default(),
+                failed_on: &[],
+                pname_cores: &HashMap::new(),
+                requesting_host: None,
+            });
+            assert!(
+                evaluation
+                    .targets
+                    .iter()
+                    .all(|t| matches!(t.verdict, TargetVerdict::Scored { .. })),
+                "{priority:?}: {:?}",
+                evaluation.targets
+            );
+            assert!(
+                matches!(evaluation.decision, SchedulerDecision::Accept { .. }),
+                "{priority:?}: {:?}",
+                evaluation.decision
+            );
+        }
+    }
+
+    #[test]
+    fn requesting_host_routes_local_and_controller_host_is_remote() {
+        // tsugumi's hook asks; saya (the controller host) is the faster
+        // target, so the build is delegated to it. If tsugumi wins it
+        // builds locally.
+        let mut saya = fresh_state("saya", 16, true);
+        saya.target.capacity = 32;
+        let tsugumi = fresh_state("tsugumi", 8, false);
+        let cand = candidate("/nix/store/abc-foo-1.2.3.drv");
+        let pol = policy();
+        let admissions = vec![AdmissionRow {
+            drv_path: "/q.drv".into(),
+            target_name: "tsugumi".into(),
+            admitted_at_ms: 0,
+            predicted_ms: 30_000,
+            correlation: None,
+        }];
+        let targets = [saya, tsugumi];
+        let decide_for = |admissions: &[AdmissionRow]| {
+            decide(&SchedulerInputs {
+                system: SYSTEM,
+                candidate: &cand,
+                now_ms: 1_000,
+                poll_interval_ms: 1_000,
+                policy: &pol,
+                admissions,
+                targets: &targets,
+                duration_estimate_ms: Some(5_000),
+                output_bytes_estimate: None,
+                hints: LocalityHints::default(),
+                failed_on: &[],
+                pname_cores: &HashMap::new(),
+                requesting_host: Some("tsugumi"),
+            })
+        };
+        match decide_for(&admissions) {
+            SchedulerDecision::Accept { target, .. } => assert_eq!(target.name, "saya"),
+            other => panic!("expected accept onto saya, got {other:?}"),
+        }
+        let busy_saya = vec![AdmissionRow {
+            target_name: "saya".into(),
+            ..admissions[0].clone()
+        }];
+        match decide_for(&busy_saya) {
+            SchedulerDecision::RouteLocal { target_name, .. } => {
+                assert_eq!(target_name, "tsugumi")
+            }
+            other => panic!("expected RouteLocal tsugumi, got {other:?}"),
+        }
+    }
+
     #[test]
     fn predicted_ms_is_at_least_one() {
         // package_ms_base = 0 (history says zero-duration), speed = 1.
@@ -509,6 +1343,254 @@ mod tests {
         }
     }
 
+    fn run_with(
+        policy: SchedulerPolicy,
+        hints: LocalityHints,
+        estimate: Option<u64>,
+    ) -> SchedulerDecision {
+        let cand = candidate("/nix/store/abc-foo-1.2.3.drv");
+        let ts = [fresh_state("tsugumi", 8, false)];
+        decide(&SchedulerInputs {
+            system: SYSTEM,
+            candidate: &cand,
+            now_ms: 1_000,
+            poll_interval_ms: 1_000,
+            policy: &policy,
+            admissions: &[],
+            targets: &ts,
+            duration_estimate_ms: estimate,
+            output_bytes_estimate: None,
+            hints,
+            failed_on: &[],
+            pname_cores: &HashMap::new(),
+            requesting_host: None,
+        })
+    }
+
+    #[test]
+    fn below_threshold_takes_fast_path() {
+        let pol = SchedulerPolicy {
+            cheap_threshold_ms: 5_000,
+            ..policy()
+        };
+        assert_eq!(
+            run_with(pol.clone(), LocalityHints::default(), Some(4_999)),
+            SchedulerDecision::LocalFastPath {
+                reason: FastPathReason::BelowThreshold
+            }
+        );
+        match run_with(pol, LocalityHints::default(), Some(5_000)) {
+            SchedulerDecision::Accept { target, .. } => assert_eq!(target.name, "tsugumi"),
+            other => panic!("expected accept at threshold, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn unknown_estimate_is_never_cheap() {
+        let pol = SchedulerPolicy {
+            cheap_threshold_ms: u64::MAX,
+            ..policy()
+        };
+        match run_with(pol, LocalityHints::default(), None) {
+            SchedulerDecision::Accept { .. } => {}
+            other => panic!("expected accept, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn drv_hints_take_fast_path_regardless_of_estimate() {
+        let prefer_local = LocalityHints {
+            prefer_local_build: true,
+            no_substitutes: true,
+        };
+        assert_eq!(
+            run_with(policy(), prefer_local, Some(3_600_000)),
+            SchedulerDecision::LocalFastPath {
+                reason: FastPathReason::PreferLocalBuild
+            }
+        );
+        let no_subst = LocalityHints {
+            prefer_local_build: false,
+            no_substitutes: true,
+        };
+        assert_eq!(
+            run_with(policy(), no_subst, None),
+            SchedulerDecision::LocalFastPath {
+                reason: FastPathReason::NoSubstitutes
+            }
+        );
+    }
+
+    #[test]
+    fn evaluate_traces_every_target() {
+        let mut low = fresh_state("kaho", 8, false);
+        low.last_telemetry = Some(TelemetryBody {
+            mem_available_kb: 100_000,
+            ..ok_telemetry(0)
+        });
+        let mut stale = fresh_state("ayumu", 8, false);
+        stale.last_pong_ms = None;
+        let ts = [fresh_state("tsugumi", 4, false), low, stale];
+        let admissions = vec![AdmissionRow {
+            drv_path: "/q.drv".into(),
+            target_name: "tsugumi".into(),
+            admitted_at_ms: 0,
+            predicted_ms: 8_000,
+            correlation: None,
+        }];
+        let cand = candidate("/nix/store/abc-foo-1.2.3.drv");
+        let pol = policy();
+        let eval = evaluate(&SchedulerInputs {
+            system: SYSTEM,
+            candidate: &cand,
+            now_ms: 1_000,
+            poll_interval_ms: 1_000,
+            policy: &pol,
+            admissions: &admissions,
+            targets: &ts,
+            duration_estimate_ms: Some(5_000),
+            output_bytes_estimate: None,
+            hints: LocalityHints::default(),
+            failed_on: &[],
+            pname_cores: &HashMap::new(),
+            requesting_host: None,
+        });
+        assert_eq!(
+            eval.targets,
+            vec![
+                TargetEvaluation {
+                    name: "tsugumi".into(),
+                    verdict: TargetVerdict::Scored {
+                        queue_ms: 2_000,
+                        package_ms: 5_000,
+                        completion_ms: 7_000,
+                    },
+                },
+                TargetEvaluation {
+                    name: "kaho".into(),
+                    verdict: TargetVerdict::Excluded(Exclusion::LowMemory),
+                },
+                TargetEvaluation {
+                    name: "ayumu".into(),
+                    verdict: TargetVerdict::Excluded(Exclusion::Stale),
+                },
+            ]
+        );
+        assert!(matches!(eval.decision, SchedulerDecision::Accept { .. }));
+    }
+
+    #[test]
+    fn wrong_system_excludes_every_target() {
+        let cand = DecideCandidate {
+            system: "aarch64-linux".to_string(),
+            ..candidate("/nix/store/abc-foo.drv")
+        };
+        let pol = policy();
+        let ts = [
+            fresh_state("tsugumi", 8, false),
+            fresh_state("saya", 8, true),
+        ];
+        let eval = evaluate(&SchedulerInputs {
+            system: SYSTEM,
+            candidate: &cand,
+            now_ms: 1_000,
+            poll_interval_ms: 1_000,
+            policy: &pol,
+            admissions: &[],
+            targets: &ts,
+            duration_estimate_ms: None,
+            output_bytes_estimate: None,
+            hints: LocalityHints::default(),
+            failed_on: &[],
+            pname_cores: &HashMap::new(),
+            requesting_host: None,
+        });
+        assert_eq!(eval.decision, SchedulerDecision::Decline);
+        assert!(eval
+            .targets
+            .iter()
+            .all(|t| t.verdict == TargetVerdict::Excluded(Exclusion::WrongSystem)));
+    }
+
+    #[test]
+    fn unhealthy_target_excluded_until_cooldown_ends() {
+        let mut a = fresh_state("tsugumi", 8, false);
+        a.unhealthy_until_ms = Some(1_001);
+        let b = fresh_state("kaho", 8, false);
+        match run(&[a.clone(), b.clone()], &[], Some(5_000)) {
+            SchedulerDecision::Accept { target, .. } => assert_eq!(target.name, "kaho"),
+            other => panic!("expected kaho, got {other:?}"),
+        }
+        a.unhealthy_until_ms = Some(1_000);
+        match run(&[a, b], &[], Some(5_000)) {
+            SchedulerDecision::Accept { target, .. } => assert_eq!(target.name, "tsugumi"),
+            other => panic!("expected tsugumi after cooldown, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn quarantined_target_excluded_until_quarantine_ends() {
+        let mut a = fresh_state("tsugumi", 8, false);
+        a.quarantined_until_ms = Some(1_001);
+        let b = fresh_state("kaho", 8, false);
+        let eval = evaluate(&SchedulerInputs {
+            system: SYSTEM,
+            candidate: &candidate("/nix/store/abc-foo-1.2.3.drv"),
+            now_ms: 1_000,
+            poll_interval_ms: 1_000,
+            policy: &policy(),
+            admissions: &[],
+            targets: &[a.clone(), b.clone()],
+            duration_estimate_ms: Some(5_000),
+            output_bytes_estimate: None,
+            hints: LocalityHints::default(),
+            failed_on: &[],
+            pname_cores: &HashMap::new(),
+            requesting_host: None,
+        });
+        assert_eq!(
+            eval.targets[0].verdict,
+            TargetVerdict::Excluded(Exclusion::Quarantined)
+        );
+        a.quarantined_until_ms = Some(1_000);
+        match run(&[a, b], &[], Some(5_000)) {
+            SchedulerDecision::Accept { target, .. } => assert_eq!(target.name, "tsugumi"),
+            other => panic!("expected tsugumi after quarantine, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn pname_is_not_sent_back_where_it_failed() {
+        let ts = [
+            fresh_state("tsugumi", 8, false),
+            fresh_state("saya", 8, true),
+        ];
+        let failed_on = ["tsugumi".to_string()];
+        let eval = evaluate(&SchedulerInputs {
+            system: SYSTEM,
+            candidate: &candidate("/nix/store/abc-foo-1.2.3.drv"),
+            now_ms: 1_000,
+            poll_interval_ms: 1_000,
+            policy: &policy(),
+            admissions: &[],
+            targets: &ts,
+            duration_estimate_ms: Some(5_000),
+            output_bytes_estimate: None,
+            hints: LocalityHints::default(),
+            failed_on: &failed_on,
+            pname_cores: &HashMap::new(),
+            requesting_host: None,
+        });
+        assert_eq!(
+            eval.targets[0].verdict,
+            TargetVerdict::Excluded(Exclusion::FailedHere)
+        );
+        assert!(matches!(
+            eval.decision,
+            SchedulerDecision::RouteLocal { ref target_name, .. } if target_name == "saya"
+        ));
+    }
+
     #[test]
     fn zero_capacity_target_never_wins_over_normal() {
         let mut broken = fresh_state("zero", 0, false);
@@ -1,8 +1,16 @@
-//! Spool-file writer used by `nbb-event`.
+//! Spool-file writer used by `nbb-event`, and the agent's bookkeeping over
+//! the same directory.
 //!
 //! Writes one bincode-encoded [`SpoolEvent`] to `<spool_dir>/<ulid>.evt.tmp`,
 //! fsyncs, then atomically renames to `<ulid>.evt`. The agent's spool
 //! watcher reads `.evt` files in ULID order (which is time order).
+//!
+//! The spool is bounded: the agent drops entries older than
+//! [`SpoolLimits::max_age_ms`] and the oldest beyond
+//! [`SpoolLimits::max_entries`], and keeps [`FULL_MARKER`] present while
+//! the cap is reached. `nbb-event` checks the marker (one `stat`) and,
+//! when it is absent, counts at most `max_entries` files itself — the
+//! agent may be the thing that is down.
 
 use std::fs;
 use std::io::{self, Write};
@@ -11,19 +19,154 @@ use std::path::{Path, PathBuf};
 use crate::protocol::frame::bincode_config;
 use crate::protocol::ops::SpoolEvent;
 
+/// Present in the spool directory while it holds `max_entries` events.
+pub const FULL_MARKER: &str = ".full";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpoolLimits {
+    pub max_entries: usize,
+    pub max_age_ms: u64,
+}
+
+impl Default for SpoolLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: 10_000,
+            max_age_ms: 7 * 24 * 3_600_000,
+        }
+    }
+}
+
+/// What [`enforce_limits`] left behind.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpoolBacklog {
+    pub entries: usize,
+    pub full: bool,
+}
+
 pub fn write_event(spool_dir: &Path, event: &SpoolEvent) -> io::Result<PathBuf> {
     fs::create_dir_all(spool_dir)?;
-    let bytes = bincode::encode_to_vec(event, bincode_config()).map_err(io::Error::other)?;
     let id = ulid::Ulid::new().to_string();
     let final_path = spool_dir.join(format!("{id}.evt"));
-    let tmp_path = spool_dir.join(format!("{id}.evt.tmp"));
+    write_atomically(&final_path, event)?;
+    Ok(final_path)
+}
+
+/// [`write_event`], unless the spool already holds `max_entries` events.
+/// Returns `None` when the event was dropped.
+pub fn write_event_capped(
+    spool_dir: &Path,
+    event: &SpoolEvent,
+    max_entries: usize,
+) -> io::Result<Option<PathBuf>> {
+    let marker = spool_dir.join(FULL_MARKER);
+    if marker.exists() {
+        return Ok(None);
+    }
+    let queued = match fs::read_dir(spool_dir) {
+        Ok(iter) => iter
+            .flatten()
+            .filter(|e| is_event(&e.path()))
+            .take(max_entries)
+            .count(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+        Err(err) => return Err(err),
+    };
+    if queued >= max_entries {
+        fs::File::create(&marker)?;
+        return Ok(None);
+    }
+    write_event(spool_dir, event).map(Some)
+}
+
+/// Replace an existing spool entry's contents, keeping its name (and so
+/// its place in the queue).
+pub fn rewrite_event(path: &Path, event: &SpoolEvent) -> io::Result<()> {
+    write_atomically(path, event)
+}
+
+fn write_atomically(path: &Path, event: &SpoolEvent) -> io::Result<()> {
+    let bytes = bincode::encode_to_vec(event, bincode_config()).map_err(io::Error::other)?;
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
     {
         let mut f = fs::File::create(&tmp_path)?;
         f.write_all(&bytes)?;
         f.sync_all()?;
     }
-    fs::rename(&tmp_path, &final_path)?;
-    Ok(final_path)
+    fs::rename(&tmp_path, path)
+}
+
+fn is_event(path: &Path) -> bool {
+    path.extension().and_then(|s| s.to_str()) == Some("evt")
+}
+
+/// The spool's `.evt` files, oldest first.
+pub fn list_events(spool_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut entries: Vec<PathBuf> = match fs::read_dir(spool_dir) {
+        Ok(iter) => iter
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| is_event(p))
+            .collect(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    entries.sort();
+    Ok(entries)
+}
+
+/// When `nbb-event` wrote the entry, from its ULID name. `None` for names
+/// that are not ULIDs.
+pub fn written_at_ms(path: &Path) -> Option<u64> {
+    let stem = path.file_stem()?.to_str()?;
+    ulid::Ulid::from_string(stem)
+        .ok()
+        .map(|id| id.timestamp_ms())
+}
+
+/// Drop expired entries, then the oldest beyond the cap, and set or clear
+/// [`FULL_MARKER`] to match.
+pub fn enforce_limits(
+    spool_dir: &Path,
+    limits: SpoolLimits,
+    now_ms: u64,
+) -> io::Result<SpoolBacklog> {
+    let mut entries = list_events(spool_dir)?;
+    let cutoff = now_ms.saturating_sub(limits.max_age_ms);
+    let before = entries.len();
+    entries.retain(|path| match written_at_ms(path) {
+        Some(at) if at < cutoff => fs::remove_file(path).is_err(),
+        _ => true,
+    });
+    let expired = before - entries.len();
+    let excess = entries.len().saturating_sub(limits.max_entries);
+    for path in entries.drain(..excess) {
+        let _ = fs::remove_file(path);
+    }
+    if expired > 0 || excess > 0 {
+        tracing::warn!(
+            expired,
+            excess,
+            "dropped spool entries over the spool limits"
+        );
+    }
+
+    let full = entries.len() >= limits.max_entries;
+    let marker = spool_dir.join(FULL_MARKER);
+    if full {
+        fs::File::create(&marker)?;
+    } else {
+        match fs::remove_file(&marker) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    Ok(SpoolBacklog {
+        entries: entries.len(),
+        full,
+    })
 }
 
 #[cfg(test)]
@@ -81,4 +224,66 @@ mod tests {
         assert_eq!(decoded, event);
         let _ = fs::remove_dir_all(&dir);
     }
+
+    fn start(ts_ms: u64) -> SpoolEvent {
+        SpoolEvent::Start {
+            drv_path: "/nix/store/abc-foo.drv".to_string(),
+            pname: "foo".to_string(),
+            host: "tsugumi".to_string(),
+            ts_ms,
+        }
+    }
+
+    #[test]
+    fn capped_writer_drops_events_once_the_spool_is_full() {
+        let dir =
+            std::env::temp_dir().join(format!("nbb-spool-cap-{}-{}", std::process::id(), now_ms()));
+        assert!(write_event_capped(&dir, &start(1), 2).unwrap().is_some());
+        assert!(write_event_capped(&dir, &start(2), 2).unwrap().is_some());
+        assert!(write_event_capped(&dir, &start(3), 2).unwrap().is_none());
+        assert!(dir.join(FULL_MARKER).exists());
+        assert_eq!(list_events(&dir).unwrap().len(), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn enforce_limits_expires_then_trims_oldest_and_clears_marker() {
+        let dir = std::env::temp_dir().join(format!(
+            "nbb-spool-limits-{}-{}",
+            std::process::id(),
+            now_ms()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let now = 10_000_000;
+        let mut names = Vec::new();
+        for at in [1_000, 9_000_000, 9_100_000, 9_200_000] {
+            let id = ulid::Ulid::from_parts(at, 0).to_string();
+            let path = dir.join(format!("{id}.evt"));
+            rewrite_event(&path, &start(at)).unwrap();
+            names.push(path);
+        }
+        fs::File::create(dir.join(FULL_MARKER)).unwrap();
+
+        let limits = SpoolLimits {
+            max_entries: 2,
+            max_age_ms: 5_000_000,
+        };
+        let backlog = enforce_limits(&dir, limits, now).unwrap();
+        assert_eq!(
+            backlog,
+            SpoolBacklog {
+                entries: 2,
+                full: true
+            }
+        );
+        assert_eq!(list_events(&dir).unwrap(), names[2..].to_vec());
+
+        let roomy = SpoolLimits {
+            max_entries: 3,
+            ..limits
+        };
+        assert!(!enforce_limits(&dir, roomy, now).unwrap().full);
+        assert!(!dir.join(FULL_MARKER).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
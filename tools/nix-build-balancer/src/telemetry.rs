@@ -1,8 +1,10 @@
-use procfs::{Current, Meminfo, MemoryPressure};
+use procfs::{CpuPressure, Current, Meminfo, MemoryPressure};
+use std::ffi::CString;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io;
 use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 
 use crate::util::now_ms;
@@ -27,19 +29,138 @@ pub struct Telemetry {
     pub mem_available_kb: u64,
     pub psi_memory_some_avg10: Option<f64>,
     pub nix_slots_active: usize,
+    /// Space and inodes left for unprivileged writers on the store's
+    /// filesystem; `None` if it could not be read.
+    pub store_free: Option<StoreFree>,
     pub sampled_at_ms: u128,
 }
 
-pub fn sample() -> io::Result<Telemetry> {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StoreFree {
+    pub bytes: u64,
+    /// `None` on filesystems that allocate inodes dynamically (btrfs,
+    /// ZFS), which report no inode counts.
+    pub inodes: Option<u64>,
+}
+
+pub fn sample(store_dir: &Path) -> io::Result<Telemetry> {
     Ok(Telemetry {
         mem_available_kb: read_mem_available_kb()?,
         psi_memory_some_avg10: read_psi_memory_some_avg10().ok().flatten(),
         nix_slots_active: count_active_nix_slots(SLOT_DIR),
+        store_free: match store_free(store_dir) {
+            Ok(free) => Some(free),
+            Err(err) => {
+                tracing::debug!(store_dir = %store_dir.display(), ?err, "statvfs failed");
+                None
+            }
+        },
         sampled_at_ms: now_ms(),
     })
 }
 
-const SLOT_DIR: &str = "/nix/var/nix/current-load";
+/// `statvfs` of the filesystem holding `store_dir`.
+pub fn store_free(store_dir: &Path) -> io::Result<StoreFree> {
+    let path = CString::new(store_dir.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    // SAFETY: `path` is a valid NUL-terminated string and `stat` is a
+    // plain-data out-parameter that `statvfs` fills in on success.
+    let stat = unsafe {
+        let mut stat: libc::statvfs = std::mem::zeroed();
+        if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat
+    };
+    #[allow(clippy::unnecessary_cast)] // the field widths differ by target
+    Ok(StoreFree {
+        bytes: (stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64),
+        inodes: (stat.f_files != 0).then_some(stat.f_favail as u64),
+    })
+}
+
+/// Processes whose environment holds one of `out_paths` as a value. Nix
+/// passes each output's path to the builder (`out=/nix/store/…`), and
+/// everything the builder starts inherits it. Processes whose environment
+/// cannot be read (exited, or another user's without root) are skipped.
+pub fn builder_processes(out_paths: &[String]) -> usize {
+    let Ok(processes) = procfs::process::all_processes() else {
+        return 0;
+    };
+    processes
+        .flatten()
+        .filter(|p| {
+            p.environ().is_ok_and(|env| {
+                env.values()
+                    .any(|value| out_paths.iter().any(|out| value == out.as_str()))
+            })
+        })
+        .count()
+}
+
+/// Apparent size of the store paths `out_paths`, looked up by file name
+/// under `store_dir` and walked without following symlinks. Paths that
+/// are missing count as empty; `None` if none was found.
+pub fn store_paths_bytes(store_dir: &Path, out_paths: &[String]) -> Option<u64> {
+    let mut found = false;
+    let mut total = 0u64;
+    for path in out_paths {
+        let Some(name) = Path::new(path).file_name() else {
+            continue;
+        };
+        let root = store_dir.join(name);
+        if root.symlink_metadata().is_err() {
+            continue;
+        }
+        found = true;
+        total = total.saturating_add(tree_bytes(&root));
+    }
+    found.then_some(total)
+}
+
+fn tree_bytes(root: &Path) -> u64 {
+    let mut total = 0u64;
+    let mut stack = vec![root.to_path_buf()];
+    while let Some(path) = stack.pop() {
+        let Ok(meta) = path.symlink_metadata() else {
+            continue;
+        };
+        total = total.saturating_add(meta.len());
+        if meta.is_dir() {
+            if let Ok(entries) = fs::read_dir(&path) {
+                stack.extend(entries.flatten().map(|entry| entry.path()));
+            }
+        }
+    }
+    total
+}
+
+pub const SLOT_DIR: &str = "/nix/var/nix/current-load";
+
+/// Where `nix-daemon` runs builds on a NixOS host: the unit's cgroup, whose
+/// `cpu.stat` covers every build the daemon runs.
+pub const BUILD_CGROUP: &str = "/sys/fs/cgroup/system.slice/nix-daemon.service";
+
+/// Cumulative CPU time of a cgroup v2 group, from its `cpu.stat`.
+pub fn cgroup_cpu_usec(cgroup: &Path) -> io::Result<u64> {
+    parse_cpu_stat_usage(&fs::read_to_string(cgroup.join("cpu.stat"))?)
+}
+
+fn parse_cpu_stat_usage(text: &str) -> io::Result<u64> {
+    text.lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "cpu.stat has no usage_usec"))?
+        .trim()
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// One Nix build slot file and whether a build currently holds it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlotState {
+    pub name: String,
+    pub locked: bool,
+}
 
 fn read_mem_available_kb() -> io::Result<u64> {
     let meminfo = Meminfo::current().map_err(io::Error::other)?;
@@ -51,23 +172,38 @@ fn read_psi_memory_some_avg10() -> io::Result<Option<f64>> {
     Ok(Some(pressure.some.avg10.into()))
 }
 
+/// `some avg10` of `/proc/pressure/cpu`: the share of the last 10 s in
+/// which runnable tasks waited for a CPU, in percent. `None` without PSI.
+pub fn read_psi_cpu_some_avg10() -> Option<f64> {
+    CpuPressure::current()
+        .ok()
+        .map(|pressure| pressure.some.avg10.into())
+}
+
 /// Count flock-held Nix build slot files in `dir`. Unlocked files are stale.
 fn count_active_nix_slots<P: AsRef<Path>>(dir: P) -> usize {
+    slot_states(dir).iter().filter(|s| s.locked).count()
+}
+
+/// Every build slot file in `dir`, sorted by name, with its lock state.
+/// A missing directory has no slots.
+pub fn slot_states<P: AsRef<Path>>(dir: P) -> Vec<SlotState> {
     let Ok(entries) = fs::read_dir(dir.as_ref()) else {
-        return 0;
+        return Vec::new();
     };
-    let mut total = 0;
+    let mut slots = Vec::new();
     for entry in entries.flatten() {
-        let name = entry.file_name();
-        let name = name.to_string_lossy();
+        let name = entry.file_name().to_string_lossy().into_owned();
         if name == "main-lock" || name.ends_with(".upload-lock") {
             continue;
         }
-        if slot_file_is_locked(&entry.path()) {
-            total += 1;
-        }
+        slots.push(SlotState {
+            locked: slot_file_is_locked(&entry.path()),
+            name,
+        });
     }
-    total
+    slots.sort_by(|a, b| a.name.cmp(&b.name));
+    slots
 }
 
 fn slot_file_is_locked(path: &Path) -> bool {
@@ -112,9 +248,68 @@ mod tests {
         fs::write(&path, "").unwrap();
 
         assert!(!slot_file_is_locked(&path));
+        assert_eq!(
+            slot_states(&dir),
+            vec![SlotState {
+                name: "ssh-ng:__svein@tsugumi.local-0".to_string(),
+                locked: false,
+            }]
+        );
         let _ = fs::remove_dir_all(dir);
     }
 
+    #[test]
+    fn store_free_reads_the_filesystem() {
+        let free = store_free(&std::env::temp_dir()).unwrap();
+        assert!(free.bytes > 0);
+        assert!(store_free(Path::new("/nonexistent/nbb-store")).is_err());
+    }
+
+    #[test]
+    fn output_size_walks_trees_by_file_name() {
+        let store =
+            std::env::temp_dir().join(format!("nbb-outsize-{}-{}", std::process::id(), now_ms()));
+        let out = store.join("aaaa-foo");
+        fs::create_dir_all(out.join("bin")).unwrap();
+        fs::write(out.join("bin/foo"), vec![0u8; 1000]).unwrap();
+        fs::write(store.join("bbbb-foo-doc"), vec![0u8; 24]).unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", out.join("passwd")).unwrap();
+
+        let paths = |names: &[&str]| -> Vec<String> {
+            names.iter().map(|n| format!("/nix/store/{n}")).collect()
+        };
+        let both = store_paths_bytes(&store, &paths(&["aaaa-foo", "bbbb-foo-doc"])).unwrap();
+        let dir_entries = fs::symlink_metadata(&out).unwrap().len()
+            + fs::symlink_metadata(out.join("bin")).unwrap().len()
+            + fs::symlink_metadata(out.join("passwd")).unwrap().len();
+        assert_eq!(both, 1000 + 24 + dir_entries);
+        assert_eq!(store_paths_bytes(&store, &paths(&["cccc-gone"])), None);
+        let _ = fs::remove_dir_all(store);
+    }
+
+    #[test]
+    fn builder_processes_are_found_by_output_path() {
+        let out = format!("/nix/store/{}-{}-builder", std::process::id(), now_ms());
+        let mut child = std::process::Command::new("sleep")
+            .arg("30")
+            .env("out", &out)
+            .spawn()
+            .unwrap();
+        let outs = [out];
+        assert_eq!(builder_processes(&outs), 1);
+        assert_eq!(builder_processes(&["/nix/store/unrelated".to_string()]), 0);
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(builder_processes(&outs), 0);
+    }
+
+    #[test]
+    fn cpu_stat_usage_is_parsed() {
+        let text = "usage_usec 123456789\nuser_usec 100000000\nsystem_usec 23456789\n";
+        assert_eq!(parse_cpu_stat_usage(text).unwrap(), 123_456_789);
+        assert!(parse_cpu_stat_usage("user_usec 1\n").is_err());
+    }
+
     #[test]
     fn missing_slot_dir_yields_zero() {
         let path =
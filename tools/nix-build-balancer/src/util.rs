@@ -62,6 +62,34 @@ fn normalize_pname(name: &str) -> String {
     parts[..end].join("-")
 }
 
+/// Collapse a normalized pname further by stripping version fragments
+/// embedded in its components, so `llvm17`/`llvm18` or
+/// `python3.11-foo`/`python3.12-foo` share one key.
+///
+/// Used by the estimator's "same package, other version" fallback tier;
+/// never as a storage key.
+pub fn unversioned_pname(pname: &str) -> String {
+    let parts: Vec<&str> = pname
+        .split('-')
+        .map(|part| part.trim_end_matches(|ch: char| ch.is_ascii_digit() || ch == '.' || ch == '_'))
+        .filter(|part| !part.is_empty())
+        .collect();
+    if parts.is_empty() {
+        return pname.to_string();
+    }
+    parts.join("-")
+}
+
+/// Package family of a normalized pname: everything before its last
+/// `-` component (`cargo-package-syn` → `cargo-package`). `None` for
+/// single-component names, which have no family to borrow from.
+pub fn pname_family(pname: &str) -> Option<&str> {
+    pname
+        .rsplit_once('-')
+        .map(|(family, _)| family)
+        .filter(|family| !family.is_empty())
+}
+
 fn looks_versionish(part: &str) -> bool {
     part.chars().next().is_some_and(|ch| ch.is_ascii_digit())
         || part
@@ -89,4 +117,19 @@ mod tests {
             "linux"
         );
     }
+
+    #[test]
+    fn unversioned_pname_strips_embedded_versions() {
+        assert_eq!(unversioned_pname("llvm17"), "llvm");
+        assert_eq!(unversioned_pname("python3.11-requests"), "python-requests");
+        assert_eq!(unversioned_pname("kwin"), "kwin");
+        assert_eq!(unversioned_pname("123"), "123");
+    }
+
+    #[test]
+    fn pname_family_drops_last_component() {
+        assert_eq!(pname_family("cargo-package-syn"), Some("cargo-package"));
+        assert_eq!(pname_family("python3.12-requests"), Some("python3.12"));
+        assert_eq!(pname_family("kwin"), None);
+    }
 }
@@ -0,0 +1,304 @@
+//! Wake-on-LAN for targets that sleep when idle.
+//!
+//! A suspended target's agent does not answer, so the scheduler excludes it
+//! as stale. Targets configured with a MAC address can be woken: when a
+//! candidate would queue longer than [`WakePolicy::queue_ms`] on every
+//! live target, the controller broadcasts a magic packet to one sleeping
+//! target and marks it waking. The scheduler reports it as `waking` rather
+//! than `stale` until its agent answers `PING` again, at which point it is
+//! scheduled like any other target. A target that has not answered within
+//! [`WakePolicy::timeout_ms`] may be woken again.
+//!
+//! The reverse direction is only a hint: once a wakeable target has had
+//! no admissions for [`WakePolicy::idle_suspend_ms`], the controller sends
+//! its agent `SUSPEND_HINT`, and the agent suspends the host if it has
+//! been given a command for that and no builds are running.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::scheduler::{Evaluation, Exclusion, Target, TargetState, TargetVerdict};
+
+/// Where magic packets go when a target names no broadcast address.
+pub const DEFAULT_BROADCAST: &str = "255.255.255.255:9";
+
+/// How to wake one target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WakeOnLan {
+    pub mac: [u8; 6],
+    /// Broadcast address of the target's LAN, with the WoL port.
+    pub broadcast: SocketAddr,
+}
+
+/// When the controller wakes targets and hints that they may sleep.
+#[derive(Clone, Debug)]
+pub struct WakePolicy {
+    /// Wake a sleeping target when the shortest queue among live targets
+    /// exceeds this. `0` disables waking.
+    pub queue_ms: u64,
+    /// How long a woken target may take to answer before it is woken
+    /// again.
+    pub timeout_ms: u64,
+    /// Quiet period after which a wakeable target's agent is told it may
+    /// suspend. `0` disables the hint.
+    pub idle_suspend_ms: u64,
+}
+
+/// `aa:bb:cc:dd:ee:ff` (or with `-` separators).
+pub fn parse_mac(s: &str) -> Result<[u8; 6], String> {
+    let mut mac = [0u8; 6];
+    let mut parts = s.split([':', '-']);
+    for byte in &mut mac {
+        let part = parts
+            .next()
+            .filter(|p| p.len() == 2)
+            .ok_or_else(|| format!("bad MAC address {s:?}"))?;
+        *byte = u8::from_str_radix(part, 16).map_err(|_| format!("bad MAC address {s:?}"))?;
+    }
+    if parts.next().is_some() {
+        return Err(format!("bad MAC address {s:?}"));
+    }
+    Ok(mac)
+}
+
+/// Six `0xff` bytes followed by the MAC sixteen times.
+pub fn magic_packet(mac: [u8; 6]) -> [u8; 102] {
+    let mut packet = [0xffu8; 102];
+    for chunk in packet[6..].chunks_exact_mut(6) {
+        chunk.copy_from_slice(&mac);
+    }
+    packet
+}
+
+pub fn send_magic_packet(wol: &WakeOnLan) -> io::Result<()> {
+    let bind: SocketAddr = if wol.broadcast.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_broadcast(true)?;
+    socket.send_to(&magic_packet(wol.mac), wol.broadcast)?;
+    Ok(())
+}
+
+/// The target to wake for a candidate the scheduler has evaluated, if any:
+/// the first stale target that has [`Target::wake`] set, provided no
+/// target is already waking and the candidate would otherwise queue longer
+/// than the policy allows on every scored target (or there are none).
+/// Targets are woken one at a time so a burst of candidates does not wake
+/// every sleeping host at once.
+pub fn target_to_wake<'a>(
+    evaluation: &Evaluation,
+    targets: &'a [TargetState],
+    policy: &WakePolicy,
+) -> Option<&'a Target> {
+    if policy.queue_ms == 0 {
+        return None;
+    }
+    let mut shortest_queue: Option<u64> = None;
+    for t in &evaluation.targets {
+        match t.verdict {
+            TargetVerdict::Scored { queue_ms, .. } => {
+                shortest_queue = Some(shortest_queue.map_or(queue_ms, |q| q.min(queue_ms)));
+            }
+            TargetVerdict::Excluded(Exclusion::Waking) => return None,
+            TargetVerdict::Excluded(_) => {}
+        }
+    }
+    if shortest_queue.is_some_and(|q| q <= policy.queue_ms) {
+        return None;
+    }
+    evaluation
+        .targets
+        .iter()
+        .filter(|t| t.verdict == TargetVerdict::Excluded(Exclusion::Stale))
+        .find_map(|t| {
+            targets
+                .iter()
+                .map(|state| &state.target)
+                .find(|target| target.name == t.name && target.wake.is_some())
+        })
+}
+
+/// Per-target wake bookkeeping. Like [`crate::health::TargetHealth`] it
+/// lives outside `TargetRuntime`, which the poller resets on reconnect.
+#[derive(Debug, Default)]
+pub struct TargetWake {
+    waking_since_ms: HashMap<String, u64>,
+    /// Last time each target had admissions or its agent connected.
+    busy_at_ms: HashMap<String, u64>,
+}
+
+impl TargetWake {
+    pub fn mark_waking(&mut self, target: &str, now_ms: u64) {
+        self.waking_since_ms.insert(target.to_string(), now_ms);
+    }
+
+    /// When `target` was woken, if that is less than `timeout_ms` ago.
+    pub fn waking_since(&self, target: &str, now_ms: u64, timeout_ms: u64) -> Option<u64> {
+        self.waking_since_ms
+            .get(target)
+            .copied()
+            .filter(|&since| now_ms.saturating_sub(since) < timeout_ms)
+    }
+
+    /// `target`'s agent answered. Returns when it was woken, if it was.
+    pub fn woke(&mut self, target: &str) -> Option<u64> {
+        self.waking_since_ms.remove(target)
+    }
+
+    pub fn note_busy(&mut self, target: &str, now_ms: u64) {
+        self.busy_at_ms.insert(target.to_string(), now_ms);
+    }
+
+    /// How long `target` has been without admissions since its agent
+    /// connected; `None` if it never did.
+    pub fn idle_for_ms(&self, target: &str, now_ms: u64) -> Option<u64> {
+        self.busy_at_ms
+            .get(target)
+            .map(|&at| now_ms.saturating_sub(at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::{SchedulerDecision, TargetEvaluation};
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    #[test]
+    fn mac_addresses_parse_with_either_separator() {
+        assert_eq!(parse_mac("52:54:00:12:34:56"), Ok(MAC));
+        assert_eq!(parse_mac("52-54-00-12-34-56"), Ok(MAC));
+        for bad in [
+            "52:54:00:12:34",
+            "52:54:00:12:34:56:78",
+            "5254:00:12:34:56",
+            "zz:54:00:12:34:56",
+        ] {
+            assert!(parse_mac(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn magic_packet_repeats_the_mac() {
+        let packet = magic_packet(MAC);
+        assert_eq!(packet[..6], [0xff; 6]);
+        assert!(packet[6..].chunks(6).all(|chunk| chunk == MAC));
+    }
+
+    fn state(name: &str, wakeable: bool) -> TargetState {
+        TargetState {
+            target: Target {
+                name: name.to_string(),
+                tcp_addr: "127.0.0.1:0".parse().unwrap(),
+                store_uri: String::new(),
+                builder_line: String::new(),
+                capacity: 1,
+                cores: None,
+                speed_multiplier: 1.0,
+                is_controller_host: false,
+                wake: wakeable.then(|| WakeOnLan {
+                    mac: MAC,
+                    broadcast: DEFAULT_BROADCAST.parse().unwrap(),
+                }),
+            },
+            last_pong_ms: None,
+            last_telemetry: None,
+            unhealthy_until_ms: None,
+            quarantined_until_ms: None,
+            waking_since_ms: None,
+            link: None,
+        }
+    }
+
+    fn evaluation(verdicts: &[(&str, TargetVerdict)]) -> Evaluation {
+        Evaluation {
+            decision: SchedulerDecision::Decline,
+            targets: verdicts
+                .iter()
+                .map(|(name, verdict)| TargetEvaluation {
+                    name: name.to_string(),
+                    verdict: verdict.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    fn scored(queue_ms: u64) -> TargetVerdict {
+        TargetVerdict::Scored {
+            queue_ms,
+            package_ms: 1_000,
+            completion_ms: queue_ms + 1_000,
+        }
+    }
+
+    #[test]
+    fn wakes_the_first_sleeping_target_when_live_ones_are_busy() {
+        let targets = [
+            state("tsugumi", false),
+            state("saya", false),
+            state("kaho", true),
+        ];
+        let policy = WakePolicy {
+            queue_ms: 60_000,
+            timeout_ms: 180_000,
+            idle_suspend_ms: 0,
+        };
+        let stale = TargetVerdict::Excluded(Exclusion::Stale);
+        let pick = |verdicts: &[(&str, TargetVerdict)]| {
+            target_to_wake(&evaluation(verdicts), &targets, &policy).map(|t| t.name.clone())
+        };
+
+        let busy = [
+            ("tsugumi", scored(90_000)),
+            ("saya", stale.clone()),
+            ("kaho", stale.clone()),
+        ];
+        assert_eq!(pick(&busy), Some("kaho".to_string()));
+        assert_eq!(
+            pick(&[("tsugumi", scored(30_000)), ("kaho", stale.clone())]),
+            None
+        );
+        assert_eq!(
+            pick(&[("tsugumi", stale.clone()), ("kaho", stale.clone())]),
+            Some("kaho".to_string())
+        );
+        assert_eq!(
+            pick(&[
+                ("tsugumi", scored(90_000)),
+                ("kaho", TargetVerdict::Excluded(Exclusion::Waking))
+            ]),
+            None
+        );
+        assert_eq!(
+            pick(&[
+                ("tsugumi", scored(90_000)),
+                ("kaho", TargetVerdict::Excluded(Exclusion::Quarantined))
+            ]),
+            None
+        );
+        let disabled = WakePolicy {
+            queue_ms: 0,
+            ..policy.clone()
+        };
+        assert!(target_to_wake(&evaluation(&busy), &targets, &disabled).is_none());
+    }
+
+    #[test]
+    fn waking_expires_and_clears_on_answer() {
+        let mut wake = TargetWake::default();
+        wake.mark_waking("kaho", 1_000);
+        assert_eq!(wake.waking_since("kaho", 2_000, 5_000), Some(1_000));
+        assert_eq!(wake.waking_since("kaho", 6_000, 5_000), None);
+        assert_eq!(wake.woke("kaho"), Some(1_000));
+        assert_eq!(wake.woke("kaho"), None);
+
+        assert_eq!(wake.idle_for_ms("kaho", 10_000), None);
+        wake.note_busy("kaho", 4_000);
+        assert_eq!(wake.idle_for_ms("kaho", 10_000), Some(6_000));
+    }
+}
@@ -0,0 +1,561 @@
+//! Multi-agent scenarios on the in-process cluster in `harness`: real
+//! agents, controller, `nbb-hook` and `nbb-event` talking over loopback.
+
+mod harness;
+
+use std::time::{Duration, Instant};
+
+use harness::{eventually, AgentSpec, Cluster, HOOK_KEY, MAX_UNAUTHENTICATED, REMOTE_AUTH_TIMEOUT};
+use nbb::logging::new_correlation;
+use nbb::persistence::decisions::{self, DecisionQuery};
+use nbb::persistence::{admissions, failure_tails, links, sessions};
+use nbb::protocol::auth::respond_sync;
+use nbb::protocol::frame::{read_frame_sync, write_frame_sync, Frame};
+use nbb::protocol::handshake::perform_handshake_sync;
+use nbb::protocol::ops::{
+    op, DecideCandidate, Decision, DrvDetails, Priority, SessionPriorityRequest,
+};
+use nbb::util::now_ms_u64;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+const TWO_REMOTES: &[AgentSpec] = &[
+    AgentSpec {
+        name: "alpha",
+        capacity: 4,
+        is_local: false,
+    },
+    AgentSpec {
+        name: "beta",
+        capacity: 4,
+        is_local: false,
+    },
+];
+
+async fn admission(cluster: &Cluster, drv: &str) -> Option<admissions::AdmissionRow> {
+    let conn = cluster.state.conn.lock().await;
+    admissions::get(&conn, drv).unwrap()
+}
+
+async fn observation_count(cluster: &Cluster, pname: &str) -> usize {
+    let conn = cluster.state.conn.lock().await;
+    conn.query_row(
+        "SELECT COUNT(*) FROM build_observations WHERE pname = ?1",
+        [pname],
+        |row| row.get::<_, i64>(0),
+    )
+    .unwrap() as usize
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn routed_build_is_observed_through_the_agent_spool() {
+    let cluster = Cluster::start("routed", TWO_REMOTES).await;
+    cluster.wait_all_live().await;
+
+    let drv = "/nix/store/aaa-routed-1.0.drv";
+    let run = cluster.build(drv).await;
+    assert!(run.accepted(), "hook did not accept: {run:?}");
+    assert!(run.status.success(), "hook failed: {run:?}");
+    // Ties go to the first target.
+    assert_eq!(
+        cluster.remote_builds(),
+        vec![("alpha".to_string(), drv.to_string())]
+    );
+
+    // The fake builder's post-build hook spooled a finish on alpha; the
+    // agent forwards it and the controller retires the admission.
+    eventually("admission retired", || async {
+        admission(&cluster, drv).await.is_none()
+    })
+    .await;
+    eventually("observation recorded", || async {
+        observation_count(&cluster, "routed").await == 1
+    })
+    .await;
+    assert_eq!(cluster.agents[0].spool_backlog(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn restarted_agent_is_reconnected_promptly() {
+    let mut cluster = Cluster::start("restart", TWO_REMOTES).await;
+    cluster.wait_all_live().await;
+
+    cluster.agent("alpha").stop().await;
+    eventually("alpha dropped", || async { !cluster.is_live("alpha") }).await;
+    assert!(cluster.is_live("beta"));
+
+    // While alpha is down, work goes to beta.
+    let drv = "/nix/store/bbb-while-down-1.0.drv";
+    assert!(cluster.build(drv).await.accepted());
+    assert_eq!(cluster.remote_builds()[0].0, "beta");
+
+    cluster.agent("alpha").restart().await;
+    // A healthy session resets the poller's backoff, so the first reconnect
+    // attempt comes within the minimum backoff, not after a long sleep.
+    tokio::time::timeout(Duration::from_secs(5), cluster.wait_live("alpha"))
+        .await
+        .expect("alpha reconnected within 5s");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn finish_spooled_while_controller_is_away_is_delivered_later() {
+    let mut cluster = Cluster::start("offline", TWO_REMOTES).await;
+    cluster.wait_all_live().await;
+
+    let drv = "/nix/store/ccc-offline-1.0.drv";
+    cluster.stop_controller();
+    eventually("hook socket closed", || async {
+        std::os::unix::net::UnixStream::connect(&cluster.state.config.hook_socket).is_err()
+    })
+    .await;
+    {
+        let conn = cluster.state.conn.lock().await;
+        admissions::record(
+            &conn,
+            drv,
+            "alpha",
+            now_ms_u64(),
+            10_000,
+            &new_correlation(),
+        )
+        .unwrap();
+    }
+    let spool = cluster.agents[0].spool_dir.clone();
+    for kind in ["start", "finish"] {
+        let status = std::process::Command::new(env!("CARGO_BIN_EXE_nbb-event"))
+            .args(["--kind", kind, "--drv-path", drv, "--host", "alpha"])
+            .arg("--spool-dir")
+            .arg(&spool)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    // Nobody is listening for the forwarded events yet.
+    tokio::time::sleep(harness::POLL_INTERVAL * 5).await;
+    assert!(admission(&cluster, drv).await.is_some());
+
+    cluster.start_controller();
+    eventually("admission retired after reconnect", || async {
+        admission(&cluster, drv).await.is_none()
+    })
+    .await;
+    // The agent matched start and finish while waiting, so the late
+    // delivery still carries a duration.
+    eventually("observation recorded", || async {
+        observation_count(&cluster, "offline").await == 1
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn graceful_shutdown_closes_hooks_out_and_flushes_the_database() {
+    let mut cluster = Cluster::start("shutdown", TWO_REMOTES).await;
+    cluster.wait_all_live().await;
+
+    let drv = "/nix/store/ddd-shutdown-1.0.drv";
+    assert!(cluster.build(drv).await.accepted());
+    eventually("observation recorded", || async {
+        observation_count(&cluster, "shutdown").await == 1
+    })
+    .await;
+
+    let started = Instant::now();
+    cluster.shutdown_controller().await.unwrap();
+    // Both agents answered GOODBYE rather than being waited out.
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert!(!cluster.is_live("alpha") && !cluster.is_live("beta"));
+    assert!(!cluster.state.config.hook_socket.exists());
+    assert!(!cluster
+        .build("/nix/store/eee-refused-1.0.drv")
+        .await
+        .accepted());
+    let wal = cluster.state.config.data_dir.join("state.db-wal");
+    assert_eq!(std::fs::metadata(&wal).map(|m| m.len()).unwrap_or(0), 0);
+
+    cluster.start_controller();
+    cluster.wait_all_live().await;
+    eventually("hook socket listening again", || async {
+        std::os::unix::net::UnixStream::connect(&cluster.state.config.hook_socket).is_ok()
+    })
+    .await;
+    let drv = "/nix/store/fff-shutdown-1.0.drv";
+    assert!(cluster.build(drv).await.accepted());
+    eventually("observation recorded after restart", || async {
+        observation_count(&cluster, "shutdown").await == 2
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn interrupted_hook_stops_the_build_and_reports_it_cancelled() {
+    let mut cluster = Cluster::start("cancel", TWO_REMOTES).await;
+    cluster.hang_target = Some("alpha".to_string());
+    cluster.wait_all_live().await;
+
+    let drv = "/nix/store/ggg-cancel-1.0.drv";
+    let run = cluster.build(drv).await;
+    assert!(run.accepted(), "hook did not accept: {run:?}");
+    assert!(!run.status.success());
+    // `nix __build-remote` was asked to stop rather than left running.
+    let cancelled = std::fs::read_to_string(cluster.root.join("cancelled.log")).unwrap();
+    assert_eq!(cancelled.lines().collect::<Vec<_>>(), vec![drv]);
+    // Retired by the hook's report: its sentinel is gone and the TTL is
+    // a minute away.
+    eventually("admission retired", || async {
+        admission(&cluster, drv).await.is_none()
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn dropped_ssh_link_is_retried_on_another_target() {
+    let mut cluster = Cluster::start("infra", TWO_REMOTES).await;
+    cluster.wait_all_live().await;
+    cluster.drop_target = Some("alpha".to_string());
+
+    let drv = "/nix/store/ddd-flaky-1.0.drv";
+    let run = cluster.build(drv).await;
+    assert!(run.accepted());
+    assert!(run.status.success(), "retry did not succeed: {run:?}");
+    assert_eq!(
+        cluster.remote_builds(),
+        vec![
+            ("alpha".to_string(), drv.to_string()),
+            ("beta".to_string(), drv.to_string()),
+        ]
+    );
+    assert!(run.stderr.contains("Connection reset by peer"));
+    assert!(cluster
+        .state
+        .health
+        .lock()
+        .unwrap()
+        .unhealthy_until("alpha", nbb::util::now_ms_u64())
+        .is_some());
+    eventually("retried build observed on beta", || async {
+        observation_count(&cluster, "flaky").await == 1
+    })
+    .await;
+
+    // The dropped attempt's log tail was kept against alpha.
+    let conn = cluster.state.conn.lock().await;
+    let tails = failure_tails::list(&conn, Some("flaky"), 10).unwrap();
+    assert_eq!(tails.len(), 1);
+    assert_eq!(tails[0].target_name, "alpha");
+    assert_eq!(tails[0].status, "infra-failure");
+    assert!(tails[0].tail.contains("Connection reset by peer"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn retry_onto_the_controller_host_is_delegated_to_it() {
+    let mut cluster = Cluster::start(
+        "infra-local",
+        &[
+            AgentSpec {
+                name: "alpha",
+                capacity: 4,
+                is_local: false,
+            },
+            AgentSpec {
+                name: "saya",
+                capacity: 4,
+                is_local: true,
+            },
+        ],
+    )
+    .await;
+    cluster.wait_all_live().await;
+    cluster.drop_target = Some("alpha".to_string());
+
+    // Alpha wins the tie; once it is benched the controller host is all
+    // that is left, and Nix already has `# accept`.
+    let drv = "/nix/store/fff-flaky-local-1.0.drv";
+    let run = cluster.build(drv).await;
+    assert!(run.accepted());
+    assert!(run.status.success(), "retry did not succeed: {run:?}");
+    assert_eq!(
+        cluster.remote_builds(),
+        vec![
+            ("alpha".to_string(), drv.to_string()),
+            ("saya".to_string(), drv.to_string()),
+        ]
+    );
+    eventually("retried build observed on saya", || async {
+        observation_count(&cluster, "flaky-local").await == 1
+    })
+    .await;
+    eventually("admission retired", || async {
+        admission(&cluster, drv).await.is_none()
+    })
+    .await;
+    let conn = cluster.state.conn.lock().await;
+    assert!(admissions::list(&conn).unwrap().is_empty());
+    let logged = decisions::query(
+        &conn,
+        &DecisionQuery {
+            drv_path: Some(drv.to_string()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let outcomes: Vec<_> = logged.iter().map(|d| d.outcome.as_str()).collect();
+    assert_eq!(outcomes, vec!["accept", "accept"]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn target_holding_the_outputs_is_preferred() {
+    let cluster = Cluster::start("subst", TWO_REMOTES).await;
+    cluster.wait_all_live().await;
+
+    // Beta built this yesterday; alpha would otherwise win the tie.
+    let drv = cluster.root.join("eee-subst-1.0.drv");
+    std::fs::write(
+        &drv,
+        r#"Derive([("out","/nix/store/zzz-subst-1.0","","")],[],[],"x86_64-linux","/bin/sh",[],[])"#,
+    )
+    .unwrap();
+    std::fs::create_dir_all(cluster.agents[1].store_dir().join("zzz-subst-1.0")).unwrap();
+
+    let drv_path = drv.to_string_lossy().into_owned();
+    let candidate = DecideCandidate {
+        drv_path: drv_path.clone(),
+        system: harness::SYSTEM.to_string(),
+        required_features: vec![],
+        hook_pid: std::process::id(),
+        session: None,
+        correlation: None,
+        priority: Priority::Normal,
+        drv: None,
+    };
+    let decision = nbb::controller::make_decision(&cluster.state, &candidate)
+        .await
+        .unwrap();
+    let Decision::Accept { target, .. } = decision else {
+        panic!("expected accept, got {decision:?}");
+    };
+    assert_eq!(target.name, "beta");
+
+    let conn = cluster.state.conn.lock().await;
+    let logged = decisions::query(
+        &conn,
+        &DecisionQuery {
+            drv_path: Some(drv_path),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(logged.len(), 1);
+    assert_eq!(logged[0].outcome, "substitute");
+    assert_eq!(logged[0].winner.as_deref(), Some("beta"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn hook_sent_drv_details_stand_in_for_the_drv() {
+    let cluster = Cluster::start("subst-remote", TWO_REMOTES).await;
+    cluster.wait_all_live().await;
+
+    // The .drv lives in the remote hook's store, not here; the hook sent
+    // what it read from it.
+    std::fs::create_dir_all(cluster.agents[1].store_dir().join("yyy-remote-1.0")).unwrap();
+    let drv_path = "/nix/store/fff-remote-1.0.drv".to_string();
+    let candidate = DecideCandidate {
+        drv_path: drv_path.clone(),
+        system: harness::SYSTEM.to_string(),
+        required_features: vec![],
+        hook_pid: std::process::id(),
+        session: None,
+        correlation: None,
+        priority: Priority::Normal,
+        drv: Some(DrvDetails {
+            builder_kind: "stdenv".to_string(),
+            prefer_local_build: false,
+            allow_substitutes: true,
+            output_paths: Some(vec!["/nix/store/yyy-remote-1.0".to_string()]),
+        }),
+    };
+    let decision = nbb::controller::make_decision(&cluster.state, &candidate)
+        .await
+        .unwrap();
+    let Decision::Accept { target, .. } = decision else {
+        panic!("expected accept, got {decision:?}");
+    };
+    assert_eq!(target.name, "beta");
+
+    let conn = cluster.state.conn.lock().await;
+    let logged = decisions::query(
+        &conn,
+        &DecisionQuery {
+            drv_path: Some(drv_path),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(logged[0].outcome, "substitute");
+}
+
+/// Read from `stream` until the controller closes it, failing after
+/// `within`.
+async fn closed_within(stream: &mut TcpStream, within: Duration) -> bool {
+    let mut buf = [0u8; 256];
+    tokio::time::timeout(within, async {
+        while let Ok(1..) = stream.read(&mut buf).await {}
+    })
+    .await
+    .is_ok()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn silent_remote_peers_are_cut_off() {
+    let cluster = Cluster::start("remote-silent", TWO_REMOTES).await;
+    cluster.wait_all_live().await;
+    let addr = cluster.remote_hook_addr();
+
+    // Connect and say nothing, as many as may be authenticating at once.
+    let mut silent = Vec::new();
+    for _ in 0..MAX_UNAUTHENTICATED {
+        silent.push(TcpStream::connect(addr).await.unwrap());
+    }
+    // One more is closed on accept.
+    let mut extra = TcpStream::connect(addr).await.unwrap();
+    assert!(closed_within(&mut extra, REMOTE_AUTH_TIMEOUT / 2).await);
+    // The silent ones are closed once their time is up.
+    for stream in &mut silent {
+        assert!(closed_within(stream, REMOTE_AUTH_TIMEOUT * 4).await);
+    }
+
+    // Which frees the listener for a real hook.
+    let drv = "/nix/store/hhh-after-silence-1.0.drv";
+    let run = cluster.build_from("alpha", HOOK_KEY, drv).await;
+    assert!(run.status.success(), "hook failed: {run:?}");
+    let conn = cluster.state.conn.lock().await;
+    let logged = decisions::query(
+        &conn,
+        &DecisionQuery {
+            drv_path: Some(drv.to_string()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(logged.len(), 1, "no decision for the hook: {run:?}");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn remote_hooks_cannot_set_session_priorities() {
+    let cluster = Cluster::start("remote-priority", TWO_REMOTES).await;
+    cluster.wait_all_live().await;
+    let addr = cluster.remote_hook_addr();
+
+    // An authenticated remote peer asks to push rebuild-1 to the back.
+    let session = tokio::task::spawn_blocking(move || {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        perform_handshake_sync(&mut stream).unwrap();
+        respond_sync(&mut stream, HOOK_KEY.as_bytes(), "tsugumi").unwrap();
+        let request = SessionPriorityRequest {
+            session: Some("rebuild-1".to_string()),
+            priority: Priority::Low,
+        };
+        let frame = Frame::with_body(op::SESSION_PRIORITY_SET, &request).unwrap();
+        write_frame_sync(&mut stream, &frame).unwrap();
+        let reply = read_frame_sync(&mut stream).unwrap();
+        assert_eq!(reply.op_id, op::SESSION_PRIORITY);
+        reply.decode_body::<Option<String>>().unwrap()
+    })
+    .await
+    .unwrap();
+    assert_eq!(session, None);
+
+    let conn = cluster.state.conn.lock().await;
+    assert_eq!(sessions::priority(&conn, "rebuild-1").unwrap(), None);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn remote_hook_routes_relative_to_its_own_host() {
+    let cluster = Cluster::start(
+        "remote-hook",
+        &[
+            AgentSpec {
+                name: "saya",
+                capacity: 4,
+                is_local: true,
+            },
+            AgentSpec {
+                name: "tsugumi",
+                capacity: 4,
+                is_local: false,
+            },
+        ],
+    )
+    .await;
+    cluster.wait_all_live().await;
+
+    // A wrong key gets no decision at all; the hook declines.
+    let drv = "/nix/store/aaa-remote-1.0.drv";
+    let run = cluster.build_from("tsugumi", "guess", drv).await;
+    assert!(!run.accepted(), "unauthenticated hook accepted: {run:?}");
+    assert!(run.status.success(), "hook failed: {run:?}");
+    assert!(admission(&cluster, drv).await.is_none());
+
+    // From tsugumi, saya is just another target and wins the tie.
+    let run = cluster.build_from("tsugumi", HOOK_KEY, drv).await;
+    assert!(run.accepted(), "hook did not accept: {run:?}");
+    assert_eq!(
+        cluster.remote_builds(),
+        vec![("saya".to_string(), drv.to_string())]
+    );
+
+    // With saya busy, tsugumi's win means "build it where you are".
+    {
+        let conn = cluster.state.conn.lock().await;
+        admissions::record(
+            &conn,
+            "/nix/store/q-busy.drv",
+            "saya",
+            now_ms_u64(),
+            600_000,
+            &new_correlation(),
+        )
+        .unwrap();
+    }
+    let drv = "/nix/store/bbb-remote-1.0.drv";
+    let run = cluster.build_from("tsugumi", HOOK_KEY, drv).await;
+    assert!(!run.accepted(), "expected route-local: {run:?}");
+    assert_eq!(
+        admission(&cluster, drv).await.map(|a| a.target_name),
+        Some("tsugumi".to_string())
+    );
+    let conn = cluster.state.conn.lock().await;
+    let logged = decisions::query(
+        &conn,
+        &DecisionQuery {
+            drv_path: Some(drv.to_string()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(logged[0].outcome, "route-local");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn agent_links_are_probed_and_kept() {
+    let cluster = Cluster::start("links", TWO_REMOTES).await;
+    cluster.wait_all_live().await;
+
+    // Each round is an empty probe and a 64 KiB one over loopback.
+    eventually("both links probed twice", || async {
+        let conn = cluster.state.conn.lock().await;
+        ["alpha", "beta"]
+            .iter()
+            .all(|name| links::list(&conn, Some(name), 10).unwrap().len() >= 2)
+    })
+    .await;
+    let stats = cluster.state.links.lock().unwrap().clone();
+    for name in ["alpha", "beta"] {
+        let link = stats
+            .get(name)
+            .unwrap_or_else(|| panic!("no link for {name}"));
+        assert!(link.bytes_per_sec > 0, "{name}: {link:?}");
+    }
+    let targets = cluster.state.build_target_states();
+    assert!(targets.iter().all(|t| t.link.is_some()));
+}
@@ -0,0 +1,519 @@
+//! In-process multi-agent cluster for integration tests.
+//!
+//! Real agents ([`nbb::agent::serve`]) and a real controller
+//! ([`nbb::controller::spawn_tasks`]) on loopback ports, each with its own
+//! temp spool / inflight / data directory. Builds go through the real
+//! `nbb-hook` binary with a fake `nix` standing in for
+//! `nix __build-remote`: it accepts, swallows the hook protocol, logs which
+//! target it was handed, and plays that host's pre/post-build hooks into
+//! its agent's spool with the real `nbb-event` binary; the Nix side of
+//! each hook run is played by [`nix::NixDriver`]. Hooks can also be run
+//! as if on another host, over the controller's authenticated TCP
+//! listener ([`Cluster::build_from`]). A build on [`Cluster::hang_target`]
+//! runs until the hook is sent `SIGTERM`, as when the user interrupts
+//! `nixos-rebuild`, which the harness does once it has started. Agents can
+//! be stopped and restarted on the same port to script outages.
+
+#![allow(dead_code)]
+
+pub mod nix;
+
+use std::future::Future;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::TcpListener;
+use tokio::task::{JoinHandle, JoinSet};
+
+use nbb::agent::{serve, AgentConfig};
+use nbb::controller::{
+    open_state, shutdown, spawn_tasks, ControllerConfig, ControllerState, RemoteHookConfig,
+};
+use nbb::estimator;
+use nbb::health::QuarantinePolicy;
+use nbb::link::LinkProbePolicy;
+use nbb::scheduler::{SchedulerPolicy, Target};
+use nbb::wake::WakePolicy;
+
+use nix::{Directive, NixDriver};
+
+pub const SYSTEM: &str = "x86_64-linux";
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const SPOOL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+pub const LINK_PROBE_INTERVAL: Duration = Duration::from_millis(200);
+const EVENTUALLY_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long the remote hook listener waits for a connection to
+/// authenticate, and how many it lets try at once.
+pub const REMOTE_AUTH_TIMEOUT: Duration = Duration::from_millis(500);
+pub const MAX_UNAUTHENTICATED: usize = 2;
+/// Shared key of the controller's remote hook listener.
+pub const HOOK_KEY: &str = "cluster-hook-key";
+
+const FAKE_NIX: &str = r##"#!/bin/sh
+# Stand-in for `nix __build-remote`, see tests/harness/mod.rs.
+[ "$1" = "__build-remote" ] || exit 2
+record="$FAKE_NIX_ROOT/stdin.$$"
+echo "# accept" >&2
+echo "fake://accepted" >&2
+cat > "$record"
+target=$(grep -ao 'fake://[A-Za-z0-9_-]*' "$record" | head -n 1 | cut -c 8-)
+drv=$(grep -ao '/nix/store/[^/[:cntrl:]]*\.drv' "$record" | head -n 1)
+echo "$target $drv" >> "$FAKE_NIX_ROOT/builds.log"
+if [ "$target" = "$FAKE_NIX_DROP" ]; then
+  echo "client_loop: send disconnect: Connection reset by peer" >&2
+  echo "error: unexpected end-of-file" >&2
+  exit 1
+fi
+spool="$FAKE_NIX_ROOT/$target/spool"
+"$NBB_EVENT" --kind start --drv-path "$drv" --host "$target" --spool-dir "$spool"
+if [ "$target" = "$FAKE_NIX_HANG" ]; then
+  trap 'echo "$drv" >> "$FAKE_NIX_ROOT/cancelled.log"; kill $sleeper; exit 1' TERM
+  sleep 30 & sleeper=$!
+  echo "$drv" >> "$FAKE_NIX_ROOT/hanging.log"
+  wait $sleeper
+fi
+sleep 0.2
+"$NBB_EVENT" --kind finish --drv-path "$drv" --host "$target" --spool-dir "$spool"
+"##;
+
+pub struct AgentSpec {
+    pub name: &'static str,
+    pub capacity: u32,
+    /// The controller's own host: wins are `RouteLocal`, never delegated.
+    pub is_local: bool,
+}
+
+pub struct Agent {
+    pub name: String,
+    pub addr: SocketAddr,
+    pub spool_dir: PathBuf,
+    capacity: u32,
+    task: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Agent {
+    async fn bind(name: &str, addr: SocketAddr, spool_dir: PathBuf, capacity: u32) -> Self {
+        let mut agent = Agent {
+            name: name.to_string(),
+            addr,
+            spool_dir,
+            capacity,
+            task: None,
+        };
+        agent.start_on(addr).await;
+        agent
+    }
+
+    async fn start_on(&mut self, addr: SocketAddr) {
+        let listener = TcpListener::bind(addr).await.expect("bind agent listener");
+        self.addr = listener.local_addr().unwrap();
+        let config = AgentConfig {
+            bind_addr: self.addr,
+            spool_dir: self.spool_dir.clone(),
+            hostname: self.name.clone(),
+            system: SYSTEM.to_string(),
+            capacity: self.capacity,
+            spool_poll_interval: SPOOL_POLL_INTERVAL,
+            spool_limits: nbb::spool::SpoolLimits::default(),
+            build_cgroup: None,
+            store_dir: self.store_dir(),
+            // Every path in the stand-in store counts as valid.
+            nix_store_bin: PathBuf::from("true"),
+            suspend_command: None,
+        };
+        self.task = Some(tokio::spawn(serve(listener, config)));
+    }
+
+    /// The agent's stand-in Nix store: `<root>/<name>/store`.
+    pub fn store_dir(&self) -> PathBuf {
+        self.spool_dir.with_file_name("store")
+    }
+
+    /// Kill the agent: listener, controller connection and spool watcher.
+    pub async fn stop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+            let _ = task.await;
+        }
+    }
+
+    /// Bring the agent back on the same port with an empty in-memory state,
+    /// as after a process restart.
+    pub async fn restart(&mut self) {
+        self.stop().await;
+        self.start_on(self.addr).await;
+    }
+
+    pub fn spool_backlog(&self) -> usize {
+        std::fs::read_dir(&self.spool_dir)
+            .map(|it| {
+                it.flatten()
+                    .filter(|e| e.path().extension().is_some_and(|x| x == "evt"))
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+/// What the Nix side saw from one `nbb-hook` run.
+#[derive(Debug)]
+pub struct HookRun {
+    pub directive: Directive,
+    pub status: ExitStatus,
+    pub stderr: String,
+}
+
+impl HookRun {
+    pub fn accepted(&self) -> bool {
+        matches!(self.directive, Directive::Accept { .. })
+    }
+}
+
+pub struct Cluster {
+    pub root: PathBuf,
+    pub agents: Vec<Agent>,
+    pub state: Arc<ControllerState>,
+    controller_tasks: Option<JoinSet<()>>,
+    fake_nix: PathBuf,
+    /// Target whose ssh link the fake `nix` drops mid-build.
+    pub drop_target: Option<String>,
+    /// Target whose builds hang until the hook is interrupted.
+    pub hang_target: Option<String>,
+}
+
+impl Cluster {
+    pub async fn start(label: &str, specs: &[AgentSpec]) -> Self {
+        let root = std::env::temp_dir().join(format!(
+            "nbb-cluster-{label}-{}-{}",
+            std::process::id(),
+            nbb::util::now_ms()
+        ));
+        std::fs::create_dir_all(&root).unwrap();
+        let fake_nix = root.join("nix");
+        std::fs::write(&fake_nix, FAKE_NIX).unwrap();
+        std::fs::set_permissions(&fake_nix, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut agents = Vec::new();
+        let mut targets = Vec::new();
+        for spec in specs {
+            let agent = Agent::bind(
+                spec.name,
+                "127.0.0.1:0".parse().unwrap(),
+                root.join(spec.name).join("spool"),
+                spec.capacity,
+            )
+            .await;
+            targets.push(Target {
+                name: spec.name.to_string(),
+                tcp_addr: agent.addr,
+                store_uri: format!("fake://{}", spec.name),
+                builder_line: format!("fake://{} {SYSTEM} - {} 1 - - -", spec.name, spec.capacity),
+                capacity: spec.capacity,
+                cores: None,
+                speed_multiplier: 1.0,
+                is_controller_host: spec.is_local,
+                wake: None,
+            });
+            agents.push(agent);
+        }
+
+        let config = ControllerConfig {
+            system: SYSTEM.to_string(),
+            data_dir: root.join("controller"),
+            inflight_dir: root.join("inflight"),
+            hook_socket: root.join("decide.sock"),
+            targets,
+            poll_interval: POLL_INTERVAL,
+            policy: SchedulerPolicy {
+                // Real telemetry from the test host; never let its memory
+                // state decide routing.
+                min_remote_mem_available_kb: 0,
+                min_store_free_bytes: 0,
+                min_store_free_inodes: 0,
+                unknown_p95_ms: 60_000,
+                cheap_threshold_ms: 0,
+                high_priority_reserve: 0.0,
+            },
+            max_samples_per_pname: 200,
+            ewma_alpha: estimator::ALPHA_DEFAULT,
+            ewma_z: estimator::Z_P95,
+            decision_log_retention: Duration::from_secs(3600),
+            unhealthy_cooldown: Duration::from_secs(60),
+            failure_tails_per_pname: 5,
+            quarantine: QuarantinePolicy {
+                window_ms: 1_800_000,
+                min_failures: 3,
+                failure_rate: 0.5,
+                cooldown_ms: 600_000,
+            },
+            pname_cores: Default::default(),
+            substitute_probe_timeout: Some(Duration::from_secs(5)),
+            maintenance: Default::default(),
+            seed_history: None,
+            remote_hooks: Some(RemoteHookConfig {
+                listen: free_loopback_addr(),
+                key: HOOK_KEY.as_bytes().to_vec(),
+                auth_timeout: REMOTE_AUTH_TIMEOUT,
+                max_unauthenticated: MAX_UNAUTHENTICATED,
+            }),
+            wake: WakePolicy {
+                queue_ms: 0,
+                timeout_ms: 0,
+                idle_suspend_ms: 0,
+            },
+            link_probe: Some(LinkProbePolicy {
+                interval: LINK_PROBE_INTERVAL,
+                bytes: 64 << 10,
+            }),
+        };
+        let state = open_state(config).await.unwrap();
+        let controller_tasks = Some(spawn_tasks(&state));
+
+        let cluster = Cluster {
+            root,
+            agents,
+            state,
+            controller_tasks,
+            fake_nix,
+            drop_target: None,
+            hang_target: None,
+        };
+        let socket = cluster.state.config.hook_socket.clone();
+        eventually("hook socket listening", || {
+            let socket = socket.clone();
+            async move { std::os::unix::net::UnixStream::connect(&socket).is_ok() }
+        })
+        .await;
+        let remote = cluster.remote_hook_addr();
+        eventually("remote hook listener up", || async move {
+            std::net::TcpStream::connect(remote).is_ok()
+        })
+        .await;
+        cluster
+    }
+
+    pub fn agent(&mut self, name: &str) -> &mut Agent {
+        self.agents
+            .iter_mut()
+            .find(|a| a.name == name)
+            .unwrap_or_else(|| panic!("no agent {name}"))
+    }
+
+    /// Whether the controller currently has a PONG from `name`.
+    pub fn is_live(&self, name: &str) -> bool {
+        self.state
+            .target_runtimes
+            .lock()
+            .unwrap()
+            .get(name)
+            .is_some_and(|rt| rt.last_pong_ms.is_some())
+    }
+
+    pub async fn wait_live(&self, name: &str) {
+        eventually(&format!("{name} live"), || async { self.is_live(name) }).await;
+    }
+
+    pub async fn wait_all_live(&self) {
+        for agent in &self.agents {
+            self.wait_live(&agent.name).await;
+        }
+    }
+
+    /// Stop the controller's pollers, hook listener and watchdog.
+    pub fn stop_controller(&mut self) {
+        self.controller_tasks = None;
+    }
+
+    /// Stop the controller the way `SIGTERM` does.
+    pub async fn shutdown_controller(&mut self) -> io::Result<()> {
+        let tasks = self.controller_tasks.take().expect("controller running");
+        shutdown(&self.state, tasks).await
+    }
+
+    pub fn start_controller(&mut self) {
+        self.controller_tasks = Some(spawn_tasks(&self.state));
+    }
+
+    /// Targets the fake `nix` was handed, in order, as `(target, drv)`.
+    pub fn remote_builds(&self) -> Vec<(String, String)> {
+        std::fs::read_to_string(self.root.join("builds.log"))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|l| l.split_once(' '))
+            .map(|(t, d)| (t.to_string(), d.to_string()))
+            .collect()
+    }
+
+    pub fn remote_hook_addr(&self) -> SocketAddr {
+        self.state
+            .config
+            .remote_hooks
+            .as_ref()
+            .expect("harness always listens for remote hooks")
+            .listen
+    }
+
+    /// Offer `drv_path` to a fresh `nbb-hook` process the way the Nix
+    /// daemon would, and feed the post-accept exchange if it accepts.
+    pub async fn build(&self, drv_path: &str) -> HookRun {
+        self.run_hook(drv_path, None).await
+    }
+
+    /// [`Self::build`], but from a hook on `host` reaching the controller
+    /// over TCP with `key`.
+    pub async fn build_from(&self, host: &str, key: &str, drv_path: &str) -> HookRun {
+        let key_file = self.root.join(format!("{host}.hook.key"));
+        std::fs::write(&key_file, key).unwrap();
+        let remote = RemoteHook {
+            addr: self.remote_hook_addr(),
+            host: host.to_string(),
+            key_file,
+        };
+        self.run_hook(drv_path, Some(remote)).await
+    }
+
+    async fn run_hook(&self, drv_path: &str, remote: Option<RemoteHook>) -> HookRun {
+        let hook = HookInvocation {
+            socket: self.state.config.hook_socket.clone(),
+            remote,
+            inflight: self.state.config.inflight_dir.clone(),
+            nix: self.fake_nix.clone(),
+            root: self.root.clone(),
+            drop_target: self.drop_target.clone(),
+            hang_target: self.hang_target.clone(),
+            drv_path: drv_path.to_string(),
+        };
+        tokio::task::spawn_blocking(move || hook.run())
+            .await
+            .unwrap()
+            .expect("drive nbb-hook")
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        self.controller_tasks = None;
+        self.agents.clear();
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+/// A loopback address nothing is listening on, for the controller to bind.
+fn free_loopback_addr() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .expect("pick a free port")
+}
+
+struct RemoteHook {
+    addr: SocketAddr,
+    host: String,
+    key_file: PathBuf,
+}
+
+struct HookInvocation {
+    socket: PathBuf,
+    remote: Option<RemoteHook>,
+    inflight: PathBuf,
+    nix: PathBuf,
+    root: PathBuf,
+    drop_target: Option<String>,
+    hang_target: Option<String>,
+    drv_path: String,
+}
+
+impl HookInvocation {
+    fn run(self) -> io::Result<HookRun> {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_nbb-hook"));
+        if let Some(remote) = &self.remote {
+            command
+                .arg("--controller-addr")
+                .arg(remote.addr.to_string())
+                .arg("--hook-key-file")
+                .arg(&remote.key_file)
+                .arg("--hostname")
+                .arg(&remote.host);
+        }
+        let mut child = command
+            .arg("--controller-socket")
+            .arg(&self.socket)
+            .arg("--inflight-dir")
+            .arg(&self.inflight)
+            .arg("--nix-bin")
+            .arg(&self.nix)
+            .env("NBB_LOG", "warn")
+            .env("FAKE_NIX_ROOT", &self.root)
+            .env("FAKE_NIX_DROP", self.drop_target.as_deref().unwrap_or(""))
+            .env("FAKE_NIX_HANG", self.hang_target.as_deref().unwrap_or(""))
+            .env("NBB_EVENT", env!("CARGO_BIN_EXE_nbb-event"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("piped stdin");
+        let stderr = BufReader::new(child.stderr.take().expect("piped stderr"));
+        let mut nix = NixDriver::new(stdin, stderr);
+
+        nix.send_settings(&[])?;
+        let directive = nix.offer(&nix::candidate(SYSTEM, &self.drv_path))?;
+        if matches!(directive, Directive::Accept { .. }) {
+            nix.send_build(&["/nix/store/aaa-input"], &["out"])?;
+        }
+        if self.hang_target.is_some() {
+            self.interrupt_once_hanging(child.id());
+        }
+        nix.finish()?;
+        let status = child.wait()?;
+        Ok(HookRun {
+            directive,
+            status,
+            stderr: nix.log,
+        })
+    }
+
+    /// Send the hook `SIGTERM` once the fake `nix` is hanging on this
+    /// build. Builds on other targets never hang; give up after a while.
+    fn interrupt_once_hanging(&self, hook_pid: u32) {
+        let log = self.root.join("hanging.log");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            let hanging = std::fs::read_to_string(&log)
+                .is_ok_and(|text| text.lines().any(|l| l == self.drv_path));
+            if hanging {
+                // SAFETY: plain kill(2) on the hook we spawned and have
+                // not reaped.
+                unsafe {
+                    libc::kill(hook_pid as libc::pid_t, libc::SIGTERM);
+                }
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+/// Poll `condition` until it holds, panicking after 15 s.
+pub async fn eventually<F, Fut>(what: &str, mut condition: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let deadline = Instant::now() + EVENTUALLY_TIMEOUT;
+    while !condition().await {
+        assert!(Instant::now() < deadline, "timed out waiting for {what}");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
@@ -0,0 +1,309 @@
+//! The Nix side of the build-hook protocol, for driving `nbb-hook` (the
+//! binary, or `hook::run_hook_io` in-process) the way the daemon does:
+//! settings, then `try` candidates answered by directives on the hook's
+//! stderr, then the post-accept inputs/outputs exchange.
+//!
+//! Also here: a stub controller that answers `DECIDE_CANDIDATE` from a
+//! table, optionally slowly, and a fake `nix __build-remote` that accepts
+//! and keeps what it was sent, for hook tests that don't need a real
+//! cluster.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nbb::hook::candidate::{write_hook_candidate, HookCandidate};
+use nbb::logging::new_correlation;
+use nbb::nix_protocol::{write_nix_string, write_nix_strings, write_nix_u64};
+use nbb::protocol::frame::{read_frame_sync, write_frame_sync, Frame};
+use nbb::protocol::handshake::perform_handshake_sync;
+use nbb::protocol::ops::{
+    op, AcceptTarget, AdmissionFinish, BuildStatus, DecideCandidate, Decision,
+};
+
+/// A directive as Nix parses it off the hook's stderr.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Directive {
+    Accept { store_uri: String },
+    Decline,
+    DeclinePermanently,
+    Postpone,
+}
+
+/// Plays the Nix daemon against a hook: `to_hook` is the hook's stdin,
+/// `from_hook` its stderr. Stderr lines that are not directives (the
+/// hook's logging, a delegated child's build log) collect in `log`.
+pub struct NixDriver<W: Write, R: BufRead> {
+    to_hook: Option<W>,
+    from_hook: R,
+    pub log: String,
+}
+
+impl<W: Write, R: BufRead> NixDriver<W, R> {
+    pub fn new(to_hook: W, from_hook: R) -> Self {
+        Self {
+            to_hook: Some(to_hook),
+            from_hook,
+            log: String::new(),
+        }
+    }
+
+    fn writer(&mut self) -> io::Result<&mut W> {
+        self.to_hook
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "hook stdin closed"))
+    }
+
+    /// The settings stream Nix sends once, before the first candidate.
+    pub fn send_settings(&mut self, settings: &[(&str, &str)]) -> io::Result<()> {
+        let w = self.writer()?;
+        write_settings(w, settings)?;
+        w.flush()
+    }
+
+    /// Send one `try` and wait for the hook's answer to it.
+    pub fn offer(&mut self, candidate: &HookCandidate) -> io::Result<Directive> {
+        let w = self.writer()?;
+        write_hook_candidate(w, candidate)?;
+        w.flush()?;
+        self.read_directive()
+    }
+
+    /// After `# accept`: the input paths to copy and the outputs wanted back.
+    pub fn send_build(&mut self, inputs: &[&str], wanted_outputs: &[&str]) -> io::Result<()> {
+        let w = self.writer()?;
+        write_nix_strings(w, &strings(inputs))?;
+        write_nix_strings(w, &strings(wanted_outputs))?;
+        w.flush()
+    }
+
+    /// Close the hook's stdin (Nix is done with it) and read its stderr to
+    /// EOF.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.to_hook = None;
+        self.from_hook.read_to_string(&mut self.log)?;
+        Ok(())
+    }
+
+    fn read_directive(&mut self) -> io::Result<Directive> {
+        loop {
+            let line = self.read_line()?;
+            let directive = match line.trim_end() {
+                "# accept" => Directive::Accept {
+                    store_uri: self.read_line()?.trim_end().to_string(),
+                },
+                "# decline" => Directive::Decline,
+                "# decline-permanently" => Directive::DeclinePermanently,
+                "# postpone" => Directive::Postpone,
+                _ => {
+                    self.log.push_str(&line);
+                    continue;
+                }
+            };
+            return Ok(directive);
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.from_hook.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "hook closed stderr while Nix waited for a directive; log:\n{}",
+                    self.log
+                ),
+            ));
+        }
+        Ok(line)
+    }
+}
+
+/// Nix's settings stream: `1 name value` per setting, then `0`.
+pub fn write_settings<W: Write>(w: &mut W, settings: &[(&str, &str)]) -> io::Result<()> {
+    for (name, value) in settings {
+        write_nix_u64(w, 1)?;
+        write_nix_string(w, name)?;
+        write_nix_string(w, value)?;
+    }
+    write_nix_u64(w, 0)
+}
+
+pub fn candidate(system: &str, drv_path: &str) -> HookCandidate {
+    HookCandidate {
+        am_willing: 1,
+        needed_system: system.to_string(),
+        drv_path: drv_path.to_string(),
+        required_features: vec![],
+    }
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|s| s.to_string()).collect()
+}
+
+/// A controller that accepts exactly the drvs in its table (onto the
+/// named target, with store URI and builder line `fake://<target>`) and
+/// declines everything else. Records every candidate and
+/// `ADMISSION_FINISH` it gets.
+pub struct StubController {
+    pub socket: PathBuf,
+    candidates: Arc<Mutex<Vec<DecideCandidate>>>,
+    finishes: Arc<Mutex<Vec<(String, BuildStatus)>>>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl StubController {
+    pub fn start(socket: PathBuf, accept: HashMap<String, String>) -> io::Result<Self> {
+        Self::start_with_delay(socket, accept, Duration::ZERO)
+    }
+
+    /// A controller that takes `delay` over every decision.
+    pub fn start_with_delay(
+        socket: PathBuf,
+        accept: HashMap<String, String>,
+        delay: Duration,
+    ) -> io::Result<Self> {
+        let listener = UnixListener::bind(&socket)?;
+        let candidates = Arc::new(Mutex::new(Vec::new()));
+        let finishes = Arc::new(Mutex::new(Vec::new()));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accept = Arc::new(accept);
+        let (asked, seen, counted) = (
+            Arc::clone(&candidates),
+            Arc::clone(&finishes),
+            Arc::clone(&connections),
+        );
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { return };
+                counted.fetch_add(1, Ordering::SeqCst);
+                let (accept, asked, seen) =
+                    (Arc::clone(&accept), Arc::clone(&asked), Arc::clone(&seen));
+                thread::spawn(move || serve(&mut stream, &accept, &asked, &seen, delay));
+            }
+        });
+        Ok(Self {
+            socket,
+            candidates,
+            finishes,
+            connections,
+        })
+    }
+
+    /// The candidates asked about so far.
+    pub fn candidates(&self) -> Vec<DecideCandidate> {
+        self.candidates.lock().unwrap().clone()
+    }
+
+    /// The `ADMISSION_FINISH` reports so far, waiting up to 5 s for at
+    /// least `count` of them: the hook sends its report and exits without
+    /// waiting for a reply.
+    pub fn finishes(&self, count: usize) -> Vec<(String, BuildStatus)> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let finishes = self.finishes.lock().unwrap().clone();
+            if finishes.len() >= count || Instant::now() >= deadline {
+                return finishes;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Hook connections accepted so far.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+/// Answer requests on one hook connection until the hook closes it.
+fn serve<S: Read + Write>(
+    stream: &mut S,
+    accept: &HashMap<String, String>,
+    candidates: &Mutex<Vec<DecideCandidate>>,
+    finishes: &Mutex<Vec<(String, BuildStatus)>>,
+    delay: Duration,
+) -> io::Result<()> {
+    perform_handshake_sync(stream)?;
+    loop {
+        let frame = match read_frame_sync(stream) {
+            Ok(frame) => frame,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        match frame.op_id {
+            op::DECIDE_CANDIDATE => {
+                let candidate: DecideCandidate = frame.decode_body()?;
+                let correlation = candidate
+                    .correlation
+                    .clone()
+                    .unwrap_or_else(new_correlation);
+                let decision = match accept.get(&candidate.drv_path) {
+                    Some(target) => Decision::Accept {
+                        target: AcceptTarget {
+                            name: target.clone(),
+                            store_uri: format!("fake://{target}"),
+                            builder_line: format!(
+                                "fake://{target} {} - 1 1 - - -",
+                                candidate.system
+                            ),
+                        },
+                        correlation,
+                    },
+                    None => Decision::Decline { correlation },
+                };
+                candidates.lock().unwrap().push(candidate);
+                thread::sleep(delay);
+                write_frame_sync(stream, &Frame::with_body(op::DECISION, &decision)?)?;
+            }
+            op::ADMISSION_FINISH => {
+                let finish: AdmissionFinish = frame.decode_body()?;
+                finishes
+                    .lock()
+                    .unwrap()
+                    .push((finish.drv_path, finish.status));
+            }
+            other => {
+                return Err(io::Error::other(format!(
+                    "stub controller: unexpected op {other}"
+                )))
+            }
+        }
+    }
+}
+
+/// A `nix __build-remote` that declines whatever it is sent, as when ssh
+/// to the builder is refused.
+pub fn write_declining_nix(dir: &Path) -> io::Result<PathBuf> {
+    let path = dir.join("nix");
+    let script = "#!/bin/sh\n\
+                  [ \"$1\" = \"__build-remote\" ] || exit 2\n\
+                  echo 'cannot connect to builder' >&2\n\
+                  echo '# decline' >&2\n";
+    std::fs::write(&path, script)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+    Ok(path)
+}
+
+/// A `nix __build-remote` that accepts, stores its whole stdin at
+/// `<dir>/child-stdin`, and succeeds.
+pub fn write_accepting_nix(dir: &Path) -> io::Result<PathBuf> {
+    let path = dir.join("nix");
+    let script = format!(
+        "#!/bin/sh\n\
+         [ \"$1\" = \"__build-remote\" ] || exit 2\n\
+         echo '# accept' >&2\n\
+         echo 'fake://accepted' >&2\n\
+         cat > '{}'\n",
+        dir.join("child-stdin").display()
+    );
+    std::fs::write(&path, script)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+    Ok(path)
+}
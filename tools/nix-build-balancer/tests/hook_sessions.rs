@@ -0,0 +1,444 @@
+//! `hook::run_hook_io` against the Nix side of the build-hook protocol.
+//!
+//! `tests/hook-sessions/<name>.stdin` is the byte stream a Nix daemon
+//! wrote to its build hook; capture new ones from a real daemon with
+//!
+//! ```text
+//! build-hook = /bin/sh -c 'tee /tmp/hook.stdin | nbb-hook "$@"' nbb-hook
+//! ```
+//!
+//! and add `<name>.expect` listing the offered candidates in order, one
+//! `<drv-path> decline` or `<drv-path> accept <target>` per line. Each
+//! session is replayed against a stub controller that routes exactly the
+//! `accept` lines, and must produce exactly those directives and hand the
+//! post-accept exchange through to `nix __build-remote` unchanged.
+
+mod harness;
+
+use std::collections::HashMap;
+use std::io::{self, BufReader, Cursor, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use harness::nix::{
+    candidate, write_accepting_nix, write_declining_nix, Directive, NixDriver, StubController,
+};
+use nbb::hook::candidate::{read_hook_candidate, read_hook_settings, HookCandidate};
+use nbb::hook::guard::DirectiveSink;
+use nbb::hook::{run_hook_io, HookConfig, DEFAULT_DECISION_TIMEOUT};
+use nbb::nix_protocol::read_nix_strings;
+use nbb::protocol::ops::{BuildStatus, DrvDetails};
+
+#[derive(Clone, Default)]
+struct CaptureSink(Arc<Mutex<Vec<u8>>>);
+
+impl CaptureSink {
+    fn output(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl DirectiveSink for CaptureSink {
+    fn write_all(&mut self, bytes: &[u8]) {
+        self.0.lock().unwrap().extend_from_slice(bytes);
+    }
+}
+
+/// The hook's stderr as a socket the test reads from.
+#[derive(Clone)]
+struct StreamSink(Arc<Mutex<UnixStream>>);
+
+impl DirectiveSink for StreamSink {
+    fn write_all(&mut self, bytes: &[u8]) {
+        let mut stream = self.0.lock().unwrap();
+        let _ = stream.write_all(bytes);
+    }
+}
+
+struct Scratch {
+    dir: PathBuf,
+}
+
+impl Scratch {
+    fn new(label: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "nbb-hook-session-{label}-{}-{}",
+            std::process::id(),
+            nbb::util::now_ms()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self { dir }
+    }
+
+    fn hook_config(&self, controller: &StubController) -> HookConfig {
+        HookConfig {
+            controller_socket: controller.socket.clone(),
+            remote_controller: None,
+            inflight_dir: self.dir.join("inflight"),
+            nix_bin: write_accepting_nix(&self.dir).unwrap(),
+            verbosity: "0".to_string(),
+            decision_timeout: DEFAULT_DECISION_TIMEOUT,
+        }
+    }
+
+    /// What the fake `nix __build-remote` was sent.
+    fn child_stdin(&self) -> Vec<u8> {
+        std::fs::read(self.dir.join("child-stdin")).unwrap()
+    }
+
+    fn inflight_is_empty(&self) -> bool {
+        std::fs::read_dir(self.dir.join("inflight"))
+            .map(|mut it| it.next().is_none())
+            .unwrap_or(true)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+struct Expected {
+    drv_path: String,
+    accept_on: Option<String>,
+}
+
+fn read_expect(path: &Path) -> Vec<Expected> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| {
+            let fields: Vec<&str> = l.split_whitespace().collect();
+            match fields.as_slice() {
+                [drv, "decline"] => Expected {
+                    drv_path: drv.to_string(),
+                    accept_on: None,
+                },
+                [drv, "accept", target] => Expected {
+                    drv_path: drv.to_string(),
+                    accept_on: Some(target.to_string()),
+                },
+                _ => panic!("{}: bad expect line {l:?}", path.display()),
+            }
+        })
+        .collect()
+}
+
+/// A recorded session parsed back into what Nix said.
+struct Recording {
+    settings: Vec<(String, String)>,
+    candidates: Vec<HookCandidate>,
+    /// Inputs and wanted outputs following the last candidate, if any.
+    build: Option<(Vec<String>, Vec<String>)>,
+}
+
+fn parse_recording(bytes: &[u8], candidates: usize) -> Recording {
+    let mut cursor = Cursor::new(bytes);
+    let settings = read_hook_settings(&mut cursor).unwrap();
+    let candidates = (0..candidates)
+        .map(|_| read_hook_candidate(&mut cursor).unwrap().unwrap())
+        .collect();
+    let build = ((cursor.position() as usize) < bytes.len()).then(|| {
+        (
+            read_nix_strings(&mut cursor).unwrap(),
+            read_nix_strings(&mut cursor).unwrap(),
+        )
+    });
+    Recording {
+        settings,
+        candidates,
+        build,
+    }
+}
+
+fn replay(stdin: &Path) {
+    let name = stdin.file_stem().unwrap().to_string_lossy().into_owned();
+    let expected = read_expect(&stdin.with_extension("expect"));
+    let bytes = std::fs::read(stdin).unwrap();
+    let recording = parse_recording(&bytes, expected.len());
+
+    let scratch = Scratch::new(&name);
+    let routes: HashMap<String, String> = expected
+        .iter()
+        .filter_map(|e| Some((e.drv_path.clone(), e.accept_on.clone()?)))
+        .collect();
+    let controller = StubController::start(scratch.dir.join("decide.sock"), routes).unwrap();
+    let sink = CaptureSink::default();
+
+    run_hook_io(
+        &scratch.hook_config(&controller),
+        &mut Cursor::new(&bytes),
+        sink.clone(),
+    )
+    .unwrap_or_else(|err| panic!("{name}: run_hook_io failed: {err}"));
+
+    let directives: String = expected
+        .iter()
+        .map(|e| match &e.accept_on {
+            // The store URI is whatever `nix __build-remote` reported.
+            Some(_) => "# accept\nfake://accepted\n".to_string(),
+            None => "# decline\n".to_string(),
+        })
+        .collect();
+    assert_eq!(sink.output(), directives, "{name}: directives");
+    assert!(scratch.inflight_is_empty(), "{name}: sentinel left behind");
+
+    let Some(accepted) = expected.iter().find(|e| e.accept_on.is_some()) else {
+        assert!(controller.finishes(0).is_empty(), "{name}");
+        return;
+    };
+    let target = accepted.accept_on.as_deref().unwrap();
+    let child_bytes = scratch.child_stdin();
+    let child = parse_recording(&child_bytes, 1);
+
+    let mut want_settings: Vec<_> = recording
+        .settings
+        .iter()
+        .filter(|(k, _)| k != "builders")
+        .cloned()
+        .collect();
+    want_settings.push((
+        "builders".to_string(),
+        format!("fake://{target} x86_64-linux - 1 1 - - -"),
+    ));
+    assert_eq!(child.settings, want_settings, "{name}: settings passed on");
+    let offered = recording
+        .candidates
+        .iter()
+        .find(|c| c.drv_path == accepted.drv_path)
+        .unwrap();
+    assert_eq!(&child.candidates[0], offered, "{name}: candidate passed on");
+    assert_eq!(
+        child.build, recording.build,
+        "{name}: inputs/outputs passed on"
+    );
+    assert_eq!(
+        controller.finishes(1),
+        vec![(accepted.drv_path.clone(), BuildStatus::Success)],
+        "{name}"
+    );
+}
+
+#[test]
+fn recorded_sessions_replay_through_run_hook() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/hook-sessions");
+    let mut sessions: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|x| x == "stdin"))
+        .collect();
+    sessions.sort();
+    assert!(!sessions.is_empty(), "no sessions under {}", dir.display());
+    for session in &sessions {
+        replay(session);
+    }
+}
+
+#[test]
+fn truncated_try_is_declined_permanently() {
+    let recorded = std::fs::read(
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/hook-sessions/decline-all.stdin"),
+    )
+    .unwrap();
+    let scratch = Scratch::new("truncated");
+    let controller =
+        StubController::start(scratch.dir.join("decide.sock"), HashMap::new()).unwrap();
+    let sink = CaptureSink::default();
+    // Cut the last candidate off mid-`drv_path`.
+    let cut = &recorded[..recorded.len() - 40];
+
+    let result = run_hook_io(
+        &scratch.hook_config(&controller),
+        &mut Cursor::new(cut),
+        sink.clone(),
+    );
+
+    assert!(result.is_err());
+    assert_eq!(
+        sink.output(),
+        "# decline\n# decline\n# decline-permanently\n"
+    );
+}
+
+#[test]
+fn driver_walks_the_hook_through_a_decline_and_an_accept() -> io::Result<()> {
+    let scratch = Scratch::new("driver");
+    let declined = "/nix/store/0c1ql2n1ajlqrdfkv9h6anfkgkp0ssz3-hello-2.12.1.drv";
+    let routed = "/nix/store/6j9mqjzqj7c3gnnjgfrrh1xdsyi5bs93-firefox-unwrapped-128.0.drv";
+    let controller = StubController::start(
+        scratch.dir.join("decide.sock"),
+        HashMap::from([(routed.to_string(), "saya".to_string())]),
+    )?;
+    let cfg = scratch.hook_config(&controller);
+
+    let (nix_stdin, mut hook_stdin) = UnixStream::pair()?;
+    let (hook_stderr, nix_stderr) = UnixStream::pair()?;
+    let hook = std::thread::spawn(move || {
+        let sink = StreamSink(Arc::new(Mutex::new(hook_stderr)));
+        run_hook_io(&cfg, &mut hook_stdin, sink)
+    });
+
+    let mut nix = NixDriver::new(nix_stdin, BufReader::new(nix_stderr));
+    nix.send_settings(&[("builders", ""), ("max-jobs", "8")])?;
+    assert_eq!(
+        nix.offer(&candidate("x86_64-linux", declined))?,
+        Directive::Decline
+    );
+    assert_eq!(
+        nix.offer(&candidate("x86_64-linux", routed))?,
+        Directive::Accept {
+            store_uri: "fake://accepted".to_string()
+        }
+    );
+    nix.send_build(
+        &["/nix/store/wl2z0ahd6q3fkm3yyqvjv6a7f7f0czd0-stdenv-linux"],
+        &["out"],
+    )?;
+    nix.finish()?;
+    hook.join().unwrap()?;
+
+    let child = parse_recording(&scratch.child_stdin(), 1);
+    assert_eq!(child.candidates[0].drv_path, routed);
+    assert_eq!(
+        child.build,
+        Some((
+            vec!["/nix/store/wl2z0ahd6q3fkm3yyqvjv6a7f7f0czd0-stdenv-linux".to_string()],
+            vec!["out".to_string()],
+        ))
+    );
+    assert_eq!(
+        controller.finishes(1),
+        vec![(routed.to_string(), BuildStatus::Success)]
+    );
+    // The decline, the accept and the finish report shared a connection.
+    assert_eq!(controller.connections(), 1);
+    Ok(())
+}
+
+#[test]
+fn slow_controller_is_declined_at_the_decision_timeout() -> io::Result<()> {
+    let scratch = Scratch::new("slow");
+    let slow = "/nix/store/0c1ql2n1ajlqrdfkv9h6anfkgkp0ssz3-hello-2.12.1.drv";
+    let controller = StubController::start_with_delay(
+        scratch.dir.join("decide.sock"),
+        HashMap::from([(slow.to_string(), "saya".to_string())]),
+        Duration::from_secs(2),
+    )?;
+    let cfg = HookConfig {
+        decision_timeout: Duration::from_millis(200),
+        ..scratch.hook_config(&controller)
+    };
+
+    let (nix_stdin, mut hook_stdin) = UnixStream::pair()?;
+    let (hook_stderr, nix_stderr) = UnixStream::pair()?;
+    let hook = std::thread::spawn(move || {
+        let sink = StreamSink(Arc::new(Mutex::new(hook_stderr)));
+        run_hook_io(&cfg, &mut hook_stdin, sink)
+    });
+
+    let mut nix = NixDriver::new(nix_stdin, BufReader::new(nix_stderr));
+    nix.send_settings(&[("builders", "")])?;
+    for _ in 0..2 {
+        let asked = Instant::now();
+        assert_eq!(
+            nix.offer(&candidate("x86_64-linux", slow))?,
+            Directive::Decline
+        );
+        assert!(asked.elapsed() < Duration::from_secs(1));
+    }
+    nix.finish()?;
+    hook.join().unwrap()?;
+
+    // A connection that timed out is not reused: its late reply would
+    // answer the next candidate.
+    assert_eq!(controller.connections(), 2);
+    assert!(scratch.inflight_is_empty());
+    Ok(())
+}
+
+#[test]
+fn hook_sends_what_it_reads_from_the_drv() -> io::Result<()> {
+    let scratch = Scratch::new("drv-details");
+    // The controller may be on another host; the hook reads the .drv here.
+    let drv = scratch.dir.join("aaa-hello.txt.drv");
+    std::fs::write(
+        &drv,
+        r#"Derive([("out","/nix/store/aaa-hello.txt","","")],[],[],"x86_64-linux","/bin/sh",[],[("allowSubstitutes",""),("preferLocalBuild","1")])"#,
+    )?;
+    let drv = drv.to_string_lossy().into_owned();
+    let missing = "/nix/store/0c1ql2n1ajlqrdfkv9h6anfkgkp0ssz3-hello-2.12.1.drv";
+    let controller = StubController::start(scratch.dir.join("decide.sock"), HashMap::new())?;
+    let cfg = scratch.hook_config(&controller);
+
+    let (nix_stdin, mut hook_stdin) = UnixStream::pair()?;
+    let (hook_stderr, nix_stderr) = UnixStream::pair()?;
+    let hook = std::thread::spawn(move || {
+        let sink = StreamSink(Arc::new(Mutex::new(hook_stderr)));
+        run_hook_io(&cfg, &mut hook_stdin, sink)
+    });
+
+    let mut nix = NixDriver::new(nix_stdin, BufReader::new(nix_stderr));
+    nix.send_settings(&[("builders", "")])?;
+    for drv_path in [drv.as_str(), missing] {
+        assert_eq!(
+            nix.offer(&candidate("x86_64-linux", drv_path))?,
+            Directive::Decline
+        );
+    }
+    nix.finish()?;
+    hook.join().unwrap()?;
+
+    let asked = controller.candidates();
+    assert_eq!(
+        asked[0].drv,
+        Some(DrvDetails {
+            builder_kind: "other".to_string(),
+            prefer_local_build: true,
+            allow_substitutes: false,
+            output_paths: Some(vec!["/nix/store/aaa-hello.txt".to_string()]),
+        })
+    );
+    assert_eq!(asked[1].drv, None);
+    Ok(())
+}
+
+#[test]
+fn builder_declined_by_nix_is_reported_declined() -> io::Result<()> {
+    let scratch = Scratch::new("declined");
+    let routed = "/nix/store/6j9mqjzqj7c3gnnjgfrrh1xdsyi5bs93-firefox-unwrapped-128.0.drv";
+    let controller = StubController::start(
+        scratch.dir.join("decide.sock"),
+        HashMap::from([(routed.to_string(), "saya".to_string())]),
+    )?;
+    let mut cfg = scratch.hook_config(&controller);
+    cfg.nix_bin = write_declining_nix(&scratch.dir)?;
+
+    let (nix_stdin, mut hook_stdin) = UnixStream::pair()?;
+    let (hook_stderr, nix_stderr) = UnixStream::pair()?;
+    let hook = std::thread::spawn(move || {
+        let sink = StreamSink(Arc::new(Mutex::new(hook_stderr)));
+        run_hook_io(&cfg, &mut hook_stdin, sink)
+    });
+
+    let mut nix = NixDriver::new(nix_stdin, BufReader::new(nix_stderr));
+    nix.send_settings(&[("builders", "")])?;
+    assert_eq!(
+        nix.offer(&candidate("x86_64-linux", routed))?,
+        Directive::Decline
+    );
+    nix.finish()?;
+    hook.join().unwrap()?;
+
+    // Not `Cancelled`: the client is still there, the target is not.
+    assert_eq!(
+        controller.finishes(1),
+        vec![(routed.to_string(), BuildStatus::Declined)]
+    );
+    assert!(scratch.inflight_is_empty());
+    Ok(())
+}
@@ -10,18 +10,24 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use nbb::controller::{
-    handle_hook_connection, make_decision, now_ms_u64, open_state, record_finish, watchdog_tick,
-    ControllerConfig, ControllerState, TargetRuntime,
+    handle_hook_connection, make_decision, now_ms_u64, open_state, record_finish,
+    run_target_session, watchdog_tick, ControllerConfig, ControllerState, TargetRuntime,
 };
 use nbb::estimator;
+use nbb::health::QuarantinePolicy;
 use nbb::inflight::{drv_filename, write_sentinel, Sentinel};
-use nbb::persistence::admissions;
+use nbb::logging::new_correlation;
+use nbb::persistence::maintenance::MaintenancePolicy;
+use nbb::persistence::{admissions, decisions, failure_tails, history, parallelism};
 use nbb::protocol::frame::{read_frame_async, write_frame_async, Frame};
 use nbb::protocol::handshake::perform_handshake_async;
 use nbb::protocol::ops::{
-    op, AdmissionFinish, BuildStatus, DecideCandidate, Decision, EventBuildFinish, TelemetryBody,
+    op, AdmissionFinish, AgentHello, BuildAdmitted, BuildStatus, DecideCandidate, Decision,
+    EventBuildFinish, Priority, SessionPriorityRequest, SessionSummary, SessionSummaryRequest,
+    TelemetryBody,
 };
 use nbb::scheduler::{SchedulerPolicy, Target};
+use nbb::wake::{self, WakeOnLan, WakePolicy};
 
 const SYSTEM: &str = "x86_64-linux";
 
@@ -40,8 +46,10 @@ fn target(name: &str, capacity: u32, is_local: bool) -> Target {
         store_uri: format!("ssh-ng://svein@{name}.local"),
         builder_line: format!("ssh-ng://svein@{name}.local x86_64-linux . 1 1 - - -"),
         capacity,
+        cores: None,
         speed_multiplier: 1.0,
         is_controller_host: is_local,
+        wake: None,
     }
 }
 
@@ -55,11 +63,35 @@ fn config(data_dir: PathBuf, inflight_dir: PathBuf, hook_socket: PathBuf) -> Con
         poll_interval: Duration::from_millis(1000),
         policy: SchedulerPolicy {
             min_remote_mem_available_kb: 1_000_000,
+            min_store_free_bytes: 0,
+            min_store_free_inodes: 0,
             unknown_p95_ms: 60_000,
+            cheap_threshold_ms: 0,
+            high_priority_reserve: 0.0,
         },
         max_samples_per_pname: 200,
         ewma_alpha: estimator::ALPHA_DEFAULT,
         ewma_z: estimator::Z_P95,
+        decision_log_retention: Duration::from_secs(3600),
+        unhealthy_cooldown: Duration::from_secs(60),
+        failure_tails_per_pname: 5,
+        quarantine: QuarantinePolicy {
+            window_ms: 1_800_000,
+            min_failures: 3,
+            failure_rate: 0.5,
+            cooldown_ms: 600_000,
+        },
+        pname_cores: Default::default(),
+        substitute_probe_timeout: None,
+        maintenance: Default::default(),
+        seed_history: None,
+        remote_hooks: None,
+        wake: WakePolicy {
+            queue_ms: 0,
+            timeout_ms: 0,
+            idle_suspend_ms: 0,
+        },
+        link_probe: None,
     }
 }
 
@@ -71,8 +103,16 @@ fn fresh_target_runtime(state: &Arc<ControllerState>, name: &str) {
             mem_available_kb: 8_000_000,
             psi_memory_some_avg10: Some(0.0),
             nix_slots_active: 0,
+            store_free_bytes: None,
+            store_free_inodes: None,
             sampled_at_ms: now,
+            spool_backlog: 0,
+            spool_full: false,
         }),
+        path_queries: None,
+        suspend_hint: None,
+        suspend_hinted: false,
+        build_notices: None,
     };
     state
         .target_runtimes
@@ -87,6 +127,10 @@ fn candidate(drv: &str) -> DecideCandidate {
         system: SYSTEM.to_string(),
         required_features: vec![],
         hook_pid: 11111,
+        session: None,
+        correlation: None,
+        priority: Priority::Normal,
+        drv: None,
     }
 }
 
@@ -97,8 +141,12 @@ fn finish_event(drv: &str, pname: &str, duration_ms: Option<u64>, ts_ms: u64) ->
         host: "tsugumi".to_string(),
         ts_ms,
         duration_ms,
+        cpu_ms: None,
+        contention: None,
+        output_bytes: None,
         status: BuildStatus::Success,
         out_paths: vec!["/nix/store/out".to_string()],
+        correlation: None,
     }
 }
 
@@ -115,7 +163,7 @@ async fn happy_path_decide_then_finish_writes_observation_and_retires_admission(
     let decision = make_decision(&state, &candidate("/nix/store/aaa-foo-1.0.drv"))
         .await
         .unwrap();
-    let Decision::Accept { target } = decision else {
+    let Decision::Accept { target, .. } = decision else {
         panic!("expected Accept, got {decision:?}");
     };
     assert_eq!(target.name, "tsugumi");
@@ -134,7 +182,7 @@ async fn happy_path_decide_then_finish_writes_observation_and_retires_admission(
         Some(5_000),
         now_ms_u64(),
     );
-    record_finish(&state, event).await.unwrap();
+    record_finish(&state, "tsugumi", event).await.unwrap();
 
     let conn = state.conn.lock().await;
     assert!(admissions::list(&conn).unwrap().is_empty());
@@ -164,7 +212,7 @@ async fn finish_without_duration_retires_admission_but_writes_no_observation() {
 
     // Agent restart simulation: matching finish arrives with no duration.
     let event = finish_event("/nix/store/bbb-foo.drv", "foo", None, now_ms_u64());
-    record_finish(&state, event).await.unwrap();
+    record_finish(&state, "tsugumi", event).await.unwrap();
 
     let conn = state.conn.lock().await;
     assert!(admissions::list(&conn).unwrap().is_empty());
@@ -193,8 +241,10 @@ async fn duplicate_finish_is_idempotent() {
         .unwrap();
 
     let event = finish_event("/nix/store/ccc-foo.drv", "foo", Some(1_234), 5_000);
-    record_finish(&state, event.clone()).await.unwrap();
-    record_finish(&state, event).await.unwrap();
+    record_finish(&state, "tsugumi", event.clone())
+        .await
+        .unwrap();
+    record_finish(&state, "tsugumi", event).await.unwrap();
 
     let conn = state.conn.lock().await;
     let observation_count: i64 = conn
@@ -262,7 +312,15 @@ async fn watchdog_wall_clock_ttl_retires_overdue_admissions() {
     // Manually insert an admission far in the past.
     {
         let conn = state.conn.lock().await;
-        admissions::record(&conn, "/nix/store/eee-foo.drv", "tsugumi", 0, 10_000).unwrap();
+        admissions::record(
+            &conn,
+            "/nix/store/eee-foo.drv",
+            "tsugumi",
+            0,
+            10_000,
+            &new_correlation(),
+        )
+        .unwrap();
         assert_eq!(admissions::list(&conn).unwrap().len(), 1);
     }
 
@@ -279,6 +337,56 @@ async fn watchdog_wall_clock_ttl_retires_overdue_admissions() {
     let _ = std::fs::remove_dir_all(&data);
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn watchdog_caps_observations_across_pnames() {
+    let data = unique_subdir("cap-data");
+    let inflight = unique_subdir("cap-inflight");
+    let sock = unique_subdir("cap-sock").join("decide.sock");
+    let mut cfg = config(data.clone(), inflight, sock);
+    cfg.maintenance = MaintenancePolicy {
+        checkpoint_interval: Duration::ZERO,
+        vacuum_interval: Some(Duration::ZERO),
+        max_observations: 2,
+    };
+    let state = open_state(cfg).await.unwrap();
+
+    for (i, pname) in ["foo", "bar", "baz"].into_iter().enumerate() {
+        record_finish(
+            &state,
+            "tsugumi",
+            EventBuildFinish {
+                drv_path: format!("/nix/store/{i}-{pname}.drv"),
+                pname: pname.to_string(),
+                host: "tsugumi".to_string(),
+                ts_ms: 1_000 * (i as u64 + 1),
+                duration_ms: Some(5_000),
+                cpu_ms: None,
+                contention: None,
+                output_bytes: None,
+                status: BuildStatus::Success,
+                out_paths: vec![],
+                correlation: None,
+            },
+        )
+        .await
+        .unwrap();
+    }
+
+    watchdog_tick(&state).await.unwrap();
+
+    let conn = state.conn.lock().await;
+    let pnames: Vec<String> = conn
+        .prepare("SELECT pname FROM build_observations ORDER BY finished_at_ms")
+        .unwrap()
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(pnames, vec!["bar".to_string(), "baz".to_string()]);
+
+    let _ = std::fs::remove_dir_all(&data);
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
 async fn controller_restart_clears_admissions_table() {
     let data = unique_subdir("restart-data");
@@ -317,7 +425,7 @@ async fn hook_reports_failure_via_admission_finish_retires_without_observation()
     fresh_target_runtime(&state, "tsugumi");
 
     let drv = "/nix/store/ggg-foo.drv";
-    let _ = make_decision(&state, &candidate(drv)).await.unwrap();
+    let decision = make_decision(&state, &candidate(drv)).await.unwrap();
 
     // Simulate the hook reporting cancellation through the protocol over a
     // duplex stream rather than a real Unix socket.
@@ -330,7 +438,9 @@ async fn hook_reports_failure_via_admission_finish_retires_without_observation()
     perform_handshake_async(&mut hook_end).await.unwrap();
     let finish = AdmissionFinish {
         drv_path: drv.to_string(),
+        correlation: decision.correlation().to_string(),
         status: BuildStatus::Cancelled,
+        log_tail: None,
     };
     write_frame_async(
         &mut hook_end,
@@ -353,6 +463,183 @@ async fn hook_reports_failure_via_admission_finish_retires_without_observation()
     let _ = std::fs::remove_dir_all(&data);
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn shutdown_records_finishes_the_agent_sent_before_goodbye() {
+    let data = unique_subdir("goodbye-data");
+    let inflight = unique_subdir("goodbye-inflight");
+    let sock = unique_subdir("goodbye-sock").join("decide.sock");
+    let state = open_state(config(data.clone(), inflight, sock))
+        .await
+        .unwrap();
+    fresh_target_runtime(&state, "tsugumi");
+    let drv = "/nix/store/ggg-bye-1.0.drv";
+    make_decision(&state, &candidate(drv)).await.unwrap();
+
+    let (mut agent_end, controller_end) = tokio::io::duplex(8192);
+    let session_state = Arc::clone(&state);
+    let target = state.config.targets[0].clone();
+    let session =
+        tokio::spawn(
+            async move { run_target_session(controller_end, &target, &session_state).await },
+        );
+    perform_handshake_async(&mut agent_end).await.unwrap();
+    let hello = AgentHello {
+        name: "tsugumi".to_string(),
+        system: SYSTEM.to_string(),
+        capacity: 4,
+    };
+    write_frame_async(
+        &mut agent_end,
+        &Frame::with_body(op::AGENT_HELLO, &hello).unwrap(),
+    )
+    .await
+    .unwrap();
+
+    state.begin_shutdown();
+    loop {
+        let frame = read_frame_async(&mut agent_end).await.unwrap();
+        if frame.op_id == op::GOODBYE {
+            break;
+        }
+    }
+    // This finish crossed the GOODBYE on the wire; the agent has already
+    // dropped it from its spool, so the controller must still take it.
+    let finish = finish_event(drv, "bye", Some(1_000), now_ms_u64());
+    write_frame_async(
+        &mut agent_end,
+        &Frame::with_body(op::EVENT_BUILD_FINISH, &finish).unwrap(),
+    )
+    .await
+    .unwrap();
+    write_frame_async(&mut agent_end, &Frame::empty(op::GOODBYE))
+        .await
+        .unwrap();
+    session.await.unwrap().unwrap();
+
+    let conn = state.conn.lock().await;
+    assert!(admissions::list(&conn).unwrap().is_empty());
+    let observation_count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM build_observations", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(observation_count, 1);
+    drop(conn);
+    let _ = std::fs::remove_dir_all(&data);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn admission_reaches_the_agent_under_the_decision_correlation() {
+    let data = unique_subdir("correlate-data");
+    let inflight = unique_subdir("correlate-inflight");
+    let sock = unique_subdir("correlate-sock").join("decide.sock");
+    let state = open_state(config(data.clone(), inflight, sock))
+        .await
+        .unwrap();
+    fresh_target_runtime(&state, "tsugumi");
+
+    let (mut agent_end, controller_end) = tokio::io::duplex(8192);
+    let session_state = Arc::clone(&state);
+    let target = state.config.targets[0].clone();
+    let session =
+        tokio::spawn(
+            async move { run_target_session(controller_end, &target, &session_state).await },
+        );
+    perform_handshake_async(&mut agent_end).await.unwrap();
+    let hello = AgentHello {
+        name: "tsugumi".to_string(),
+        system: SYSTEM.to_string(),
+        capacity: 4,
+    };
+    write_frame_async(
+        &mut agent_end,
+        &Frame::with_body(op::AGENT_HELLO, &hello).unwrap(),
+    )
+    .await
+    .unwrap();
+    while state.target_runtimes.lock().unwrap()["tsugumi"]
+        .build_notices
+        .is_none()
+    {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let drv = "/nix/store/kkk-corr-1.0.drv";
+    let decision = make_decision(&state, &candidate(drv)).await.unwrap();
+    assert!(matches!(decision, Decision::Accept { .. }));
+    let admitted: BuildAdmitted = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let frame = read_frame_async(&mut agent_end).await.unwrap();
+            if frame.op_id == op::BUILD_ADMITTED {
+                return frame.decode_body().unwrap();
+            }
+        }
+    })
+    .await
+    .expect("BUILD_ADMITTED");
+    assert_eq!(admitted.drv_path, drv);
+    assert_eq!(admitted.correlation, decision.correlation());
+
+    let conn = state.conn.lock().await;
+    let admission = admissions::get(&conn, drv).unwrap().unwrap();
+    assert_eq!(
+        admission.correlation.as_deref(),
+        Some(decision.correlation())
+    );
+    drop(conn);
+
+    drop(agent_end);
+    session.await.unwrap().unwrap();
+    let _ = std::fs::remove_dir_all(&data);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn failed_build_log_tail_is_stored_against_its_target() {
+    let data = unique_subdir("failtail-data");
+    let inflight = unique_subdir("failtail-inflight");
+    let sock = unique_subdir("failtail-sock").join("decide.sock");
+    let state = open_state(config(data.clone(), inflight, sock))
+        .await
+        .unwrap();
+    fresh_target_runtime(&state, "tsugumi");
+
+    let drv = "/nix/store/jjj-foo-1.0.drv";
+    let decision = make_decision(&state, &candidate(drv)).await.unwrap();
+
+    let (mut hook_end, controller_end) = tokio::io::duplex(8192);
+    let server_state = Arc::clone(&state);
+    let server =
+        tokio::spawn(async move { handle_hook_connection(controller_end, server_state).await });
+    perform_handshake_async(&mut hook_end).await.unwrap();
+    let tail = "make: *** [Makefile:12: all] Error 2\n\
+                error: builder for '/nix/store/jjj-foo-1.0.drv' failed with exit code 2";
+    let finish = AdmissionFinish {
+        drv_path: drv.to_string(),
+        correlation: decision.correlation().to_string(),
+        status: BuildStatus::Failure,
+        log_tail: Some(tail.to_string()),
+    };
+    write_frame_async(
+        &mut hook_end,
+        &Frame::with_body(op::ADMISSION_FINISH, &finish).unwrap(),
+    )
+    .await
+    .unwrap();
+    drop(hook_end);
+    server.await.unwrap().unwrap();
+
+    let conn = state.conn.lock().await;
+    assert!(admissions::list(&conn).unwrap().is_empty());
+    let tails = failure_tails::list(&conn, Some("foo"), 10).unwrap();
+    assert_eq!(tails.len(), 1);
+    assert_eq!(tails[0].drv_path, drv);
+    assert_eq!(tails[0].target_name, "tsugumi");
+    assert_eq!(tails[0].status, "failure");
+    assert_eq!(tails[0].tail, tail);
+    drop(conn);
+    let _ = std::fs::remove_dir_all(&data);
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
 async fn route_local_records_admission_for_controller_host() {
     // Controller host (saya) is a target. With no remote target available,
@@ -370,7 +657,7 @@ async fn route_local_records_admission_for_controller_host() {
     let drv = "/nix/store/iii-foo.drv";
     let decision = make_decision(&state, &candidate(drv)).await.unwrap();
     assert!(
-        matches!(decision, Decision::Decline),
+        matches!(decision, Decision::Decline { .. }),
         "hook should see Decline, got {decision:?}"
     );
 
@@ -386,7 +673,7 @@ async fn route_local_records_admission_for_controller_host() {
     // Local agent reports the finish; admission retires and observation row written.
     let mut event = finish_event(drv, "foo", Some(2_500), now_ms_u64());
     event.host = "saya".to_string();
-    record_finish(&state, event).await.unwrap();
+    record_finish(&state, "tsugumi", event).await.unwrap();
 
     let conn = state.conn.lock().await;
     assert!(admissions::list(&conn).unwrap().is_empty());
@@ -429,7 +716,7 @@ async fn hook_decide_round_trip_over_duplex() {
     let reply = read_frame_async(&mut hook_end).await.unwrap();
     assert_eq!(reply.op_id, op::DECISION);
     let decision: Decision = reply.decode_body().unwrap();
-    let Decision::Accept { target } = decision else {
+    let Decision::Accept { target, .. } = decision else {
         panic!("expected Accept, got {decision:?}");
     };
     assert_eq!(target.name, "tsugumi");
@@ -442,3 +729,667 @@ async fn hook_decide_round_trip_over_duplex() {
 
     let _ = std::fs::remove_dir_all(&data);
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn prefer_local_build_drv_declines_without_admission() {
+    let data = unique_subdir("fastpath-data");
+    let inflight = unique_subdir("fastpath-inflight");
+    let sock = unique_subdir("fastpath-sock").join("decide.sock");
+    let state = open_state(config(data.clone(), inflight, sock))
+        .await
+        .unwrap();
+    fresh_target_runtime(&state, "tsugumi");
+
+    let drv = data.join("aaa-hello.txt.drv");
+    std::fs::write(
+        &drv,
+        r#"Derive([("out","/nix/store/aaa-hello.txt","","")],[],[],"x86_64-linux","/bin/sh",[],[("allowSubstitutes",""),("preferLocalBuild","1")])"#,
+    )
+    .unwrap();
+    let decision = make_decision(&state, &candidate(drv.to_str().unwrap()))
+        .await
+        .unwrap();
+    assert!(
+        matches!(decision, Decision::Decline { .. }),
+        "got {decision:?}"
+    );
+
+    let conn = state.conn.lock().await;
+    assert!(admissions::list(&conn).unwrap().is_empty());
+    let logged = decisions::query(&conn, &decisions::DecisionQuery::default()).unwrap();
+    assert_eq!(logged.len(), 1);
+    assert_eq!(logged[0].outcome, "fast-path:prefer-local-build");
+    assert!(logged[0].targets.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn session_summary_over_duplex_reports_latest_session() {
+    let data = unique_subdir("session-data");
+    let inflight = unique_subdir("session-inflight");
+    let sock = unique_subdir("session-sock").join("decide.sock");
+    let state = open_state(config(data.clone(), inflight, sock))
+        .await
+        .unwrap();
+    fresh_target_runtime(&state, "tsugumi");
+
+    let drv = "/nix/store/aaa-foo-1.0.drv";
+    let cand = DecideCandidate {
+        session: Some("rebuild-1".to_string()),
+        ..candidate(drv)
+    };
+    let decision = make_decision(&state, &cand).await.unwrap();
+    assert!(matches!(decision, Decision::Accept { .. }));
+    record_finish(
+        &state,
+        "tsugumi",
+        finish_event(drv, "foo", Some(4_000), now_ms_u64()),
+    )
+    .await
+    .unwrap();
+
+    let (mut client, controller_end) = tokio::io::duplex(8192);
+    let server_state = Arc::clone(&state);
+    let server =
+        tokio::spawn(async move { handle_hook_connection(controller_end, server_state).await });
+    perform_handshake_async(&mut client).await.unwrap();
+    let request = SessionSummaryRequest {
+        session: None,
+        top_n: 5,
+    };
+    write_frame_async(
+        &mut client,
+        &Frame::with_body(op::SESSION_SUMMARY_GET, &request).unwrap(),
+    )
+    .await
+    .unwrap();
+    let reply = read_frame_async(&mut client).await.unwrap();
+    assert_eq!(reply.op_id, op::SESSION_SUMMARY);
+    let summary: Option<SessionSummary> = reply.decode_body().unwrap();
+    let summary = summary.expect("latest session");
+    assert_eq!(summary.session, "rebuild-1");
+    assert_eq!(summary.derivations, 1);
+    assert_eq!(summary.actual_ms, 4_000);
+    assert_eq!(summary.destinations[0].destination, "tsugumi");
+    drop(client);
+    server.await.unwrap().unwrap();
+
+    let _ = std::fs::remove_dir_all(&data);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn registered_session_priority_overrides_the_hooks() {
+    let data = unique_subdir("sessprio-data");
+    let inflight = unique_subdir("sessprio-inflight");
+    let sock = unique_subdir("sessprio-sock").join("decide.sock");
+    let mut cfg = config(data.clone(), inflight, sock);
+    cfg.targets = vec![target("tsugumi", 1, false)];
+    let state = open_state(cfg).await.unwrap();
+    fresh_target_runtime(&state, "tsugumi");
+
+    // tsugumi's only slot is taken.
+    assert!(matches!(
+        make_decision(&state, &candidate("/nix/store/aaa-first-1.0.drv"))
+            .await
+            .unwrap(),
+        Decision::Accept { .. }
+    ));
+
+    // The daemon's environment says normal for every client; the rebuild
+    // in session rebuild-2 asked to be low.
+    let (mut client, controller_end) = tokio::io::duplex(8192);
+    let server_state = Arc::clone(&state);
+    let server =
+        tokio::spawn(async move { handle_hook_connection(controller_end, server_state).await });
+    perform_handshake_async(&mut client).await.unwrap();
+    let request = SessionPriorityRequest {
+        session: Some("rebuild-2".to_string()),
+        priority: Priority::Low,
+    };
+    write_frame_async(
+        &mut client,
+        &Frame::with_body(op::SESSION_PRIORITY_SET, &request).unwrap(),
+    )
+    .await
+    .unwrap();
+    let reply = read_frame_async(&mut client).await.unwrap();
+    assert_eq!(reply.op_id, op::SESSION_PRIORITY);
+    let session: Option<String> = reply.decode_body().unwrap();
+    assert_eq!(session.as_deref(), Some("rebuild-2"));
+    drop(client);
+    server.await.unwrap().unwrap();
+
+    // Low does not queue behind a full target; normal still does.
+    let low = "/nix/store/bbb-low-1.0.drv";
+    let cand = DecideCandidate {
+        session: Some("rebuild-2".to_string()),
+        ..candidate(low)
+    };
+    assert!(matches!(
+        make_decision(&state, &cand).await.unwrap(),
+        Decision::Decline { .. }
+    ));
+    let cand = DecideCandidate {
+        session: Some("rebuild-3".to_string()),
+        ..candidate("/nix/store/ccc-normal-1.0.drv")
+    };
+    assert!(matches!(
+        make_decision(&state, &cand).await.unwrap(),
+        Decision::Accept { .. }
+    ));
+
+    let conn = state.conn.lock().await;
+    let logged = decisions::query(
+        &conn,
+        &decisions::DecisionQuery {
+            drv_path: Some(low.to_string()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(logged[0].targets[0].excluded.as_deref(), Some("busy"));
+    drop(conn);
+    let _ = std::fs::remove_dir_all(&data);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn accept_for_a_hook_that_gave_up_is_retired_and_latency_logged() {
+    let data = unique_subdir("gone-data");
+    let inflight = unique_subdir("gone-inflight");
+    let sock = unique_subdir("gone-sock").join("decide.sock");
+    let state = open_state(config(data.clone(), inflight, sock))
+        .await
+        .unwrap();
+    fresh_target_runtime(&state, "tsugumi");
+
+    // The hook's decision timeout runs out and it hangs up before the
+    // controller answers.
+    let drv = "/nix/store/aaa-foo-1.0.drv";
+    let (mut client, controller_end) = tokio::io::duplex(8192);
+    let server_state = Arc::clone(&state);
+    let server =
+        tokio::spawn(async move { handle_hook_connection(controller_end, server_state).await });
+    perform_handshake_async(&mut client).await.unwrap();
+    write_frame_async(
+        &mut client,
+        &Frame::with_body(op::DECIDE_CANDIDATE, &candidate(drv)).unwrap(),
+    )
+    .await
+    .unwrap();
+    drop(client);
+    assert!(server.await.unwrap().is_err());
+
+    let conn = state.conn.lock().await;
+    assert!(admissions::list(&conn).unwrap().is_empty());
+    let logged = decisions::query(&conn, &decisions::DecisionQuery::default()).unwrap();
+    assert_eq!(logged.len(), 1);
+    assert_eq!(logged[0].outcome, "accept");
+    assert!(logged[0].latency_us.is_some());
+    drop(conn);
+
+    let _ = std::fs::remove_dir_all(&data);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn infra_failure_benches_target_and_next_decision_goes_elsewhere() {
+    let data = unique_subdir("infra-data");
+    let inflight = unique_subdir("infra-inflight");
+    let sock = unique_subdir("infra-sock").join("decide.sock");
+    let mut cfg = config(data.clone(), inflight, sock);
+    cfg.targets.push(target("kaho", 8, false));
+    let state = open_state(cfg).await.unwrap();
+    fresh_target_runtime(&state, "tsugumi");
+    fresh_target_runtime(&state, "kaho");
+
+    let drv = "/nix/store/aaa-foo-1.0.drv";
+    let Decision::Accept {
+        target: first,
+        correlation,
+    } = make_decision(&state, &candidate(drv)).await.unwrap()
+    else {
+        panic!("expected Accept");
+    };
+    assert_eq!(first.name, "tsugumi");
+
+    let (mut hook_end, controller_end) = tokio::io::duplex(8192);
+    let server_state = Arc::clone(&state);
+    let server =
+        tokio::spawn(async move { handle_hook_connection(controller_end, server_state).await });
+    perform_handshake_async(&mut hook_end).await.unwrap();
+    let finish = AdmissionFinish {
+        drv_path: drv.to_string(),
+        correlation: correlation.clone(),
+        status: BuildStatus::InfraFailure,
+        log_tail: None,
+    };
+    write_frame_async(
+        &mut hook_end,
+        &Frame::with_body(op::ADMISSION_FINISH, &finish).unwrap(),
+    )
+    .await
+    .unwrap();
+    drop(hook_end);
+    server.await.unwrap().unwrap();
+
+    // The hook re-asks under the correlation id it was given.
+    let retried = DecideCandidate {
+        correlation: Some(correlation.clone()),
+        ..candidate(drv)
+    };
+    let Decision::Accept {
+        target: retry,
+        correlation: retry_correlation,
+    } = make_decision(&state, &retried).await.unwrap()
+    else {
+        panic!("expected Accept on retry");
+    };
+    assert_eq!(retry.name, "kaho");
+    assert_eq!(retry_correlation, correlation);
+
+    let conn = state.conn.lock().await;
+    let observation_count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM build_observations", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(observation_count, 0);
+    drop(conn);
+    let _ = std::fs::remove_dir_all(&data);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn backed_up_targets_wake_a_sleeping_one() {
+    let data = unique_subdir("wake-data");
+    let inflight = unique_subdir("wake-inflight");
+    let sock = unique_subdir("wake-sock").join("decide.sock");
+    let lan = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    lan.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    let mut cfg = config(data.clone(), inflight, sock);
+    cfg.targets[0].capacity = 1;
+    let mut kaho = target("kaho", 8, false);
+    kaho.wake = Some(WakeOnLan {
+        mac,
+        broadcast: lan.local_addr().unwrap(),
+    });
+    cfg.targets.push(kaho);
+    cfg.wake = WakePolicy {
+        queue_ms: 30_000,
+        timeout_ms: 180_000,
+        idle_suspend_ms: 0,
+    };
+    let state = open_state(cfg).await.unwrap();
+    fresh_target_runtime(&state, "tsugumi");
+
+    // tsugumi is idle: no reason to wake anyone.
+    let first = "/nix/store/aaa-foo-1.0.drv";
+    make_decision(&state, &candidate(first)).await.unwrap();
+    // Now the next candidate would queue behind 60 s of unknown-duration
+    // work on the only live target.
+    let second = "/nix/store/bbb-bar-1.0.drv";
+    let Decision::Accept { target, .. } = make_decision(&state, &candidate(second)).await.unwrap()
+    else {
+        panic!("expected Accept");
+    };
+    assert_eq!(target.name, "tsugumi");
+    let mut packet = [0u8; 128];
+    let (len, _) = lan.recv_from(&mut packet).unwrap();
+    assert_eq!(packet[..len], wake::magic_packet(mac));
+
+    let third = "/nix/store/ccc-baz-1.0.drv";
+    make_decision(&state, &candidate(third)).await.unwrap();
+    let conn = state.conn.lock().await;
+    let excluded = |drv: &str| {
+        decisions::query(
+            &conn,
+            &decisions::DecisionQuery {
+                drv_path: Some(drv.to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap()[0]
+            .targets[1]
+            .excluded
+            .clone()
+    };
+    assert_eq!(excluded(first).as_deref(), Some("stale"));
+    assert_eq!(excluded(third).as_deref(), Some("waking"));
+    drop(conn);
+
+    // Once its agent answers it is a target like any other.
+    fresh_target_runtime(&state, "kaho");
+    let fourth = "/nix/store/ddd-qux-1.0.drv";
+    let Decision::Accept { target, .. } = make_decision(&state, &candidate(fourth)).await.unwrap()
+    else {
+        panic!("expected Accept");
+    };
+    assert_eq!(target.name, "kaho");
+    let _ = std::fs::remove_dir_all(&data);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn failure_burst_quarantines_target() {
+    let data = unique_subdir("quarantine-data");
+    let inflight = unique_subdir("quarantine-inflight");
+    let sock = unique_subdir("quarantine-sock").join("decide.sock");
+    let mut cfg = config(data.clone(), inflight, sock);
+    cfg.targets.push(target("kaho", 8, false));
+    let state = open_state(cfg).await.unwrap();
+    fresh_target_runtime(&state, "tsugumi");
+    fresh_target_runtime(&state, "kaho");
+
+    // Three different derivations fail on tsugumi in a row.
+    for pname in ["alpha", "beta", "gamma"] {
+        let drv = format!("/nix/store/aaa-{pname}-1.0.drv");
+        let Decision::Accept { target, .. } =
+            make_decision(&state, &candidate(&drv)).await.unwrap()
+        else {
+            panic!("expected Accept for {pname}");
+        };
+        assert_eq!(target.name, "tsugumi");
+        let event = EventBuildFinish {
+            status: BuildStatus::Failure,
+            ..finish_event(&drv, pname, Some(1_000), now_ms_u64())
+        };
+        record_finish(&state, "tsugumi", event).await.unwrap();
+    }
+
+    let drv = "/nix/store/bbb-delta-1.0.drv";
+    let Decision::Accept { target, .. } = make_decision(&state, &candidate(drv)).await.unwrap()
+    else {
+        panic!("expected Accept");
+    };
+    assert_eq!(target.name, "kaho");
+    let conn = state.conn.lock().await;
+    let logged = decisions::query(
+        &conn,
+        &decisions::DecisionQuery {
+            drv_path: Some(drv.to_string()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(logged[0].targets[0].target_name, "tsugumi");
+    assert_eq!(
+        logged[0].targets[0].excluded.as_deref(),
+        Some("quarantined")
+    );
+    drop(conn);
+    let _ = std::fs::remove_dir_all(&data);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn target_that_keeps_declining_is_quarantined() {
+    let data = unique_subdir("declining-data");
+    let inflight = unique_subdir("declining-inflight");
+    let sock = unique_subdir("declining-sock").join("decide.sock");
+    let mut cfg = config(data.clone(), inflight, sock);
+    cfg.targets.push(target("kaho", 8, false));
+    let state = open_state(cfg).await.unwrap();
+    fresh_target_runtime(&state, "tsugumi");
+    fresh_target_runtime(&state, "kaho");
+
+    // `nix __build-remote` turns tsugumi down three times in a row (ssh
+    // refused); the hook reports each before declining to Nix.
+    let (mut hook_end, controller_end) = tokio::io::duplex(8192);
+    let server_state = Arc::clone(&state);
+    let server =
+        tokio::spawn(async move { handle_hook_connection(controller_end, server_state).await });
+    perform_handshake_async(&mut hook_end).await.unwrap();
+    for pname in ["alpha", "beta", "gamma"] {
+        let drv = format!("/nix/store/aaa-{pname}-1.0.drv");
+        let Decision::Accept {
+            target,
+            correlation,
+        } = make_decision(&state, &candidate(&drv)).await.unwrap()
+        else {
+            panic!("expected Accept for {pname}");
+        };
+        assert_eq!(target.name, "tsugumi");
+        let finish = AdmissionFinish {
+            drv_path: drv,
+            correlation,
+            status: BuildStatus::Declined,
+            log_tail: None,
+        };
+        write_frame_async(
+            &mut hook_end,
+            &Frame::with_body(op::ADMISSION_FINISH, &finish).unwrap(),
+        )
+        .await
+        .unwrap();
+    }
+    drop(hook_end);
+    server.await.unwrap().unwrap();
+
+    let drv = "/nix/store/bbb-delta-1.0.drv";
+    let Decision::Accept { target, .. } = make_decision(&state, &candidate(drv)).await.unwrap()
+    else {
+        panic!("expected Accept");
+    };
+    assert_eq!(target.name, "kaho");
+    let conn = state.conn.lock().await;
+    assert!(admissions::list(&conn)
+        .unwrap()
+        .iter()
+        .all(|a| a.target_name == "kaho"));
+    let logged = decisions::query(
+        &conn,
+        &decisions::DecisionQuery {
+            drv_path: Some(drv.to_string()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(logged[0].targets[0].target_name, "tsugumi");
+    assert_eq!(
+        logged[0].targets[0].excluded.as_deref(),
+        Some("quarantined")
+    );
+    drop(conn);
+    let _ = std::fs::remove_dir_all(&data);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn pname_that_failed_remotely_but_built_locally_stays_local() {
+    let data = unique_subdir("failedhere-data");
+    let inflight = unique_subdir("failedhere-inflight");
+    let sock = unique_subdir("failedhere-sock").join("decide.sock");
+    let mut cfg = config(data.clone(), inflight, sock);
+    cfg.targets.push(target("saya", 8, true));
+    let state = open_state(cfg).await.unwrap();
+    fresh_target_runtime(&state, "tsugumi");
+    fresh_target_runtime(&state, "saya");
+
+    // Delegated to tsugumi, where the builder fails. Only the hook knows:
+    // the post-build-hook does not run for failed builds.
+    let failed_drv = "/nix/store/aaa-foo-1.0.drv";
+    let Decision::Accept {
+        target,
+        correlation,
+    } = make_decision(&state, &candidate(failed_drv)).await.unwrap()
+    else {
+        panic!("expected Accept for the first foo");
+    };
+    assert_eq!(target.name, "tsugumi");
+    let (mut hook_end, controller_end) = tokio::io::duplex(8192);
+    let server_state = Arc::clone(&state);
+    let server =
+        tokio::spawn(async move { handle_hook_connection(controller_end, server_state).await });
+    perform_handshake_async(&mut hook_end).await.unwrap();
+    let finish = AdmissionFinish {
+        drv_path: failed_drv.to_string(),
+        correlation,
+        status: BuildStatus::Failure,
+        log_tail: None,
+    };
+    write_frame_async(
+        &mut hook_end,
+        &Frame::with_body(op::ADMISSION_FINISH, &finish).unwrap(),
+    )
+    .await
+    .unwrap();
+    drop(hook_end);
+    server.await.unwrap().unwrap();
+
+    // Then built locally. The agent names itself differently from the
+    // target; the finish counts for the target it arrived from.
+    let local = EventBuildFinish {
+        host: "saya.lan".to_string(),
+        ..finish_event(failed_drv, "foo", Some(5_000), now_ms_u64() + 1_000)
+    };
+    record_finish(&state, "saya", local).await.unwrap();
+
+    // foo stays on saya; other pnames still go to tsugumi.
+    let drv = "/nix/store/bbb-foo-1.1.drv";
+    assert!(matches!(
+        make_decision(&state, &candidate(drv)).await.unwrap(),
+        Decision::Decline { .. }
+    ));
+    let Decision::Accept { target, .. } =
+        make_decision(&state, &candidate("/nix/store/ccc-bar-1.0.drv"))
+            .await
+            .unwrap()
+    else {
+        panic!("expected Accept for bar");
+    };
+    assert_eq!(target.name, "tsugumi");
+
+    let conn = state.conn.lock().await;
+    let logged = decisions::query(
+        &conn,
+        &decisions::DecisionQuery {
+            drv_path: Some(drv.to_string()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(logged[0].outcome, "route-local");
+    assert_eq!(
+        logged[0].targets[0].excluded.as_deref(),
+        Some("failed-here")
+    );
+    drop(conn);
+    let _ = std::fs::remove_dir_all(&data);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn measured_parallelism_charges_admissions_against_target_cores() {
+    let data = unique_subdir("cores-data");
+    let inflight = unique_subdir("cores-inflight");
+    let sock = unique_subdir("cores-sock").join("decide.sock");
+    let mut cfg = config(data.clone(), inflight, sock);
+    cfg.targets[0].cores = Some(16);
+    let state = open_state(cfg).await.unwrap();
+    fresh_target_runtime(&state, "tsugumi");
+
+    // The agent saw firefox keep 16 cores busy for its whole build.
+    let measured = EventBuildFinish {
+        cpu_ms: Some(1_600_000),
+        contention: None,
+        output_bytes: None,
+        ..finish_event(
+            "/nix/store/aaa-firefox-128.0.drv",
+            "firefox",
+            Some(100_000),
+            now_ms_u64() - 1_000,
+        )
+    };
+    record_finish(&state, "tsugumi", measured).await.unwrap();
+    {
+        let conn = state.conn.lock().await;
+        let cores = parallelism::get_many(&conn, ["firefox"]).unwrap();
+        assert!((cores["firefox"] - 16.0).abs() < 1e-9);
+    }
+
+    let firefox = "/nix/store/bbb-firefox-128.1.drv";
+    assert!(matches!(
+        make_decision(&state, &candidate(firefox)).await.unwrap(),
+        Decision::Accept { .. }
+    ));
+    let predicted = {
+        let conn = state.conn.lock().await;
+        admissions::get(&conn, firefox)
+            .unwrap()
+            .unwrap()
+            .predicted_ms
+    };
+
+    // One firefox fills all 16 cores: the next build waits for all of it,
+    // not an eighth of it as the 8-slot model alone would say.
+    let drv = "/nix/store/ccc-hello-2.12.drv";
+    make_decision(&state, &candidate(drv)).await.unwrap();
+    let conn = state.conn.lock().await;
+    let logged = decisions::query(
+        &conn,
+        &decisions::DecisionQuery {
+            drv_path: Some(drv.to_string()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(logged[0].targets[0].queue_ms, Some(predicted));
+    drop(conn);
+    let _ = std::fs::remove_dir_all(&data);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn fresh_controller_is_seeded_from_another_controllers_history() {
+    let old_data = unique_subdir("seed-old-data");
+    let new_data = unique_subdir("seed-new-data");
+    let inflight = unique_subdir("seed-inflight");
+    let sock = unique_subdir("seed-sock").join("decide.sock");
+
+    let old = open_state(config(old_data.clone(), inflight.clone(), sock.clone()))
+        .await
+        .unwrap();
+    record_finish(
+        &old,
+        "tsugumi",
+        finish_event(
+            "/nix/store/aaa-firefox-130.0.drv",
+            "firefox",
+            Some(3_600_000),
+            10_000_000,
+        ),
+    )
+    .await
+    .unwrap();
+    let seed = old_data.join("history.csv");
+    {
+        let conn = old.conn.lock().await;
+        let file = std::fs::File::create(&seed).unwrap();
+        assert_eq!(history::export(&conn, file).unwrap(), 1);
+    }
+
+    let mut cfg = config(new_data.clone(), inflight, sock);
+    cfg.seed_history = Some(seed.clone());
+    let new = open_state(cfg.clone()).await.unwrap();
+    let drv = "/nix/store/bbb-firefox-130.0.drv";
+    make_decision(&new, &candidate(drv)).await.unwrap();
+    {
+        let conn = new.conn.lock().await;
+        let logged = decisions::query(
+            &conn,
+            &decisions::DecisionQuery {
+                drv_path: Some(drv.to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(logged[0].estimate_tier, "exact");
+        assert_eq!(logged[0].estimate_ms, Some(3_600_000));
+    }
+    drop(new);
+    // Seeding only ever fills an empty history.
+    let again = open_state(cfg).await.unwrap();
+    let conn = again.conn.lock().await;
+    let rows: i64 = conn
+        .query_row("SELECT COUNT(*) FROM build_observations", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(rows, 1);
+
+    let _ = std::fs::remove_dir_all(&old_data);
+    let _ = std::fs::remove_dir_all(&new_data);
+}